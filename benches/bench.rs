@@ -98,6 +98,7 @@ mod cases;
 criterion_group!(
     benches,
     cases::parsing::group,
+    cases::read_only::group,
     cases::rewriting::group,
     cases::selector_matching::group
 );
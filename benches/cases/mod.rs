@@ -1,3 +1,4 @@
 pub mod parsing;
+pub mod read_only;
 pub mod rewriting;
 pub mod selector_matching;
@@ -0,0 +1,27 @@
+use lol_html::{Settings, element, text};
+
+define_group!(
+    "Read-only",
+    [
+        (
+            "Match-all selector",
+            Settings::new().append_element_content_handler(element!("*", noop_handler!()))
+        ),
+        (
+            "Match-all selector in the read-only mode",
+            Settings::new()
+                .with_read_only(true)
+                .append_element_content_handler(element!("*", noop_handler!()))
+        ),
+        (
+            "Text handler",
+            Settings::new().append_element_content_handler(text!("body", noop_handler!()))
+        ),
+        (
+            "Text handler in the read-only mode",
+            Settings::new()
+                .with_read_only(true)
+                .append_element_content_handler(text!("body", noop_handler!()))
+        )
+    ]
+);
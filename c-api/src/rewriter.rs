@@ -14,6 +14,29 @@ pub struct ExternOutputSink {
 /// use after the rewriter itself is dropped.
 pub struct HtmlRewriter(Option<lol_html::HtmlRewriter<'static, ExternOutputSink>>);
 
+/// Mirrors `lol_html_memory_settings_t`.
+///
+/// The layout of this type is a part of the ABI and must not change, so the settings that
+/// were added to `MemorySettings` after it was exposed keep their default values.
+#[repr(C)]
+pub struct CMemorySettings {
+    preallocated_parsing_buffer_size: size_t,
+    max_allowed_memory_usage: size_t,
+    graceful_bail_out_on_memory_limit_exceeded: bool,
+}
+
+impl From<CMemorySettings> for MemorySettings {
+    #[inline]
+    fn from(settings: CMemorySettings) -> Self {
+        Self::new()
+            .with_preallocated_parsing_buffer_size(settings.preallocated_parsing_buffer_size)
+            .with_max_allowed_memory_usage(settings.max_allowed_memory_usage)
+            .with_graceful_bail_out_on_memory_limit_exceeded(
+                settings.graceful_bail_out_on_memory_limit_exceeded,
+            )
+    }
+}
+
 impl ExternOutputSink {
     #[inline]
     fn new(
@@ -39,7 +62,7 @@ fn lol_html_rewriter_build_inner(
     builder: *mut HtmlRewriterBuilder,
     encoding: *const c_char,
     encoding_len: size_t,
-    memory_settings: MemorySettings,
+    memory_settings: CMemorySettings,
     output_sink: unsafe extern "C" fn(*const c_char, size_t, *mut c_void),
     output_sink_user_data: *mut c_void,
     strict: bool,
@@ -60,7 +83,7 @@ fn lol_html_rewriter_build_inner(
                 .try_into()
                 .or(Err(EncodingError::NonAsciiCompatibleEncoding))?,
         )
-        .with_memory_settings(memory_settings)
+        .with_memory_settings(memory_settings.into())
         .with_strict(strict)
         .with_enable_esi_tags(enable_esi_tags);
 
@@ -83,7 +106,7 @@ pub unsafe extern "C" fn lol_html_rewriter_build(
     builder: *mut HtmlRewriterBuilder,
     encoding: *const c_char,
     encoding_len: size_t,
-    memory_settings: MemorySettings,
+    memory_settings: CMemorySettings,
     output_sink: unsafe extern "C" fn(*const c_char, size_t, *mut c_void),
     output_sink_user_data: *mut c_void,
     strict: bool,
@@ -98,7 +121,7 @@ pub unsafe extern "C" fn unstable_lol_html_rewriter_build_with_esi_tags(
     builder: *mut HtmlRewriterBuilder,
     encoding: *const c_char,
     encoding_len: size_t,
-    memory_settings: MemorySettings,
+    memory_settings: CMemorySettings,
     output_sink: unsafe extern "C" fn(*const c_char, size_t, *mut c_void),
     output_sink_user_data: *mut c_void,
     strict: bool,
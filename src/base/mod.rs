@@ -1,8 +1,33 @@
 use crate::rewriter::AsciiCompatibleEncoding;
-use std::sync::Arc;
-use std::sync::OnceLock;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-pub(crate) type SharedEncoding = Arc<OnceLock<AsciiCompatibleEncoding>>;
+/// The encoding of the document that can be set only once, until it's reset for the next document.
+// Pub only for integration tests
+#[derive(Clone, Default)]
+pub struct SharedEncoding(Arc<Mutex<Option<AsciiCompatibleEncoding>>>);
+
+impl SharedEncoding {
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, Option<AsciiCompatibleEncoding>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    pub(crate) fn get(&self) -> Option<AsciiCompatibleEncoding> {
+        *self.lock()
+    }
+
+    /// Sets the encoding, unless it has already been set.
+    #[inline]
+    pub(crate) fn set(&self, encoding: AsciiCompatibleEncoding) {
+        self.lock().get_or_insert(encoding);
+    }
+
+    #[inline]
+    pub(crate) fn reset(&self) {
+        *self.lock() = None;
+    }
+}
 
 #[macro_use]
 mod debug_trace;
@@ -34,3 +59,26 @@ pub(crate) fn eq_case_insensitive(mixed_case: &[u8], lowercased: &[u8]) -> bool
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{UTF_8, WINDOWS_1251};
+
+    #[test]
+    fn shared_encoding_is_set_once() {
+        let shared = SharedEncoding::default();
+        let utf_8 = AsciiCompatibleEncoding::new(UTF_8).unwrap();
+        let windows_1251 = AsciiCompatibleEncoding::new(WINDOWS_1251).unwrap();
+
+        shared.set(windows_1251);
+        SharedEncoding::clone(&shared).set(utf_8);
+        assert_eq!(shared.get(), Some(windows_1251));
+
+        shared.reset();
+        assert_eq!(shared.get(), None);
+
+        shared.set(utf_8);
+        assert_eq!(shared.get(), Some(utf_8));
+    }
+}
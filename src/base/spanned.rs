@@ -51,23 +51,28 @@ pub(crate) struct Spanned<B> {
 #[derive(Debug, Copy, Clone)]
 pub(crate) enum RawBytes<'input> {
     Original(&'input [u8]),
-    /// Keeps the len of the original
-    Modified(usize),
+    /// Keeps the original, which is not serialized anymore
+    Modified(&'input [u8]),
 }
 
 impl<'input> SpannedRawBytes<'input> {
     #[inline]
     pub fn len(&self) -> usize {
+        self.source().len()
+    }
+
+    /// The bytes in the source document, even if they were modified
+    #[inline]
+    pub const fn source(&self) -> &'input [u8] {
         match self.bytes {
-            RawBytes::Original(s) => s.len(),
-            RawBytes::Modified(l) => l,
+            RawBytes::Original(s) | RawBytes::Modified(s) => s,
         }
     }
 
     #[inline]
     pub fn set_modified(&mut self) {
         // optimizes to branchless
-        self.bytes = RawBytes::Modified(self.len());
+        self.bytes = RawBytes::Modified(self.source());
     }
 
     #[inline]
@@ -85,18 +85,13 @@ impl LocalNameHash {
     }
 }
 
-impl fmt::Debug for LocalNameHash {
-    #[cold]
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.is_empty() {
-            return f.write_str("N/A");
-        }
-
-        let mut reverse_buf = [0u8; 12];
+impl LocalNameHash {
+    /// Decodes the ASCII lowercased name from the hash into `buf`.
+    fn decode<'b>(&self, buf: &'b mut [u8; 12]) -> &'b str {
         let mut pos = 11;
         let mut h = self.0;
         loop {
-            reverse_buf[pos] = match (h & 31) as u8 {
+            buf[pos] = match (h & 31) as u8 {
                 v @ 6.. => v + (b'a' - 6),
                 v => v + b'1',
             };
@@ -106,9 +101,18 @@ impl fmt::Debug for LocalNameHash {
             }
             pos -= 1;
         }
-        std::str::from_utf8(&reverse_buf[pos..])
-            .unwrap_or_default()
-            .fmt(f)
+        std::str::from_utf8(&buf[pos..]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for LocalNameHash {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("N/A");
+        }
+
+        self.decode(&mut [0; 12]).fmt(f)
     }
 }
 
@@ -125,6 +129,13 @@ impl From<&str> for LocalNameHash {
     }
 }
 
+impl From<Tag> for LocalNameHash {
+    #[inline]
+    fn from(tag: Tag) -> Self {
+        Self(tag as u64)
+    }
+}
+
 impl PartialEq<Tag> for LocalNameHash {
     #[inline]
     fn eq(&self, tag: &Tag) -> bool {
@@ -173,6 +184,14 @@ impl<'i> LocalName<'i> {
         }
     }
 
+    /// Returns the name, ASCII lowercased.
+    pub(crate) fn as_lowercase_string(&self, encoding: &'static Encoding) -> String {
+        match self {
+            LocalName::Hash(h) => h.decode(&mut [0; 12]).to_owned(),
+            LocalName::Bytes(b) => b.as_lowercase_string(encoding),
+        }
+    }
+
     #[inline]
     pub(crate) fn from_str_without_replacements<'s>(
         string: impl Into<Cow<'s, str>>,
@@ -234,6 +253,22 @@ mod tests {
         assert!(LocalNameHash::from("aaaaaaaaaaaaaa").is_empty());
     }
 
+    #[test]
+    fn as_lowercase_string() {
+        for (name, expected) in [
+            ("Div", "div"),
+            ("h1", "h1"),
+            ("BLOCKQUOTE", "blockquote"),
+            ("aaaaaaaaaaaa", "aaaaaaaaaaaa"),
+            ("My-Widget", "my-widget"),
+        ] {
+            let local_name =
+                LocalName::from_str_without_replacements(name, encoding_rs::UTF_8).unwrap();
+
+            assert_eq!(local_name.as_lowercase_string(encoding_rs::UTF_8), expected);
+        }
+    }
+
     #[test]
     fn bytes_variant_hash_matches_case_insensitive_eq() {
         use std::hash::{BuildHasher, RandomState};
@@ -13,6 +13,30 @@ pub use self::namespace::Namespace;
 pub use self::tag::Tag;
 pub use self::text_type::TextType;
 
+/// Whitespace that separates the tokens of attributes like `class`, as matched by the `~=`
+/// attribute selector.
+#[inline]
+pub(crate) const fn is_attr_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\n' || b == b'\r' || b == b'\t' || b == b'\x0c'
+}
+
+/// Returns `true` if the attribute `value` can be written without quotes.
+#[inline]
+pub(crate) fn is_valid_unquoted_attr_value(value: &[u8]) -> bool {
+    !value.is_empty()
+        && !value.iter().any(|&b| {
+            is_attr_whitespace(b) || matches!(b, b'"' | b'\'' | b'=' | b'<' | b'>' | b'`')
+        })
+}
+
+/// Splits the value of an attribute like `class` into its non-empty tokens.
+#[inline]
+pub(crate) fn attr_value_tokens(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value
+        .split(|&b| is_attr_whitespace(b))
+        .filter(|token| !token.is_empty())
+}
+
 /// Convert text to HTML
 #[inline]
 pub(crate) fn escape_body_text(mut content: &str, output_handler: &mut impl FnMut(&str)) {
@@ -45,11 +69,16 @@ pub(crate) fn escape_body_text(mut content: &str, output_handler: &mut impl FnMu
     }
 }
 
-/// Replace `"` with `&quot;` ONLY, leaving `&` unescaped
-pub(crate) fn escape_double_quotes_only(content: Bytes<'_>, output_handler: &mut dyn FnMut(&[u8])) {
+/// Replace the `quote` (`"` or `'`) with `&quot;` or `&#39;` ONLY, leaving `&` unescaped
+pub(crate) fn escape_quotes_only(
+    content: Bytes<'_>,
+    quote: u8,
+    output_handler: &mut dyn FnMut(&[u8]),
+) {
+    let escaped: &[u8] = if quote == b'\'' { b"&#39;" } else { b"&quot;" };
     let mut content = &*content;
     loop {
-        if let Some(pos) = memchr(b'"', content) {
+        if let Some(pos) = memchr(quote, content) {
             let Some((chunk_before, rest)) = content
                 .split_at_checked(pos)
                 .and_then(|(before, rest)| Some((before, rest.get(1..)?)))
@@ -61,7 +90,7 @@ pub(crate) fn escape_double_quotes_only(content: Bytes<'_>, output_handler: &mut
             if !chunk_before.is_empty() {
                 (output_handler)(chunk_before);
             }
-            (output_handler)(b"&quot;");
+            (output_handler)(escaped);
         } else {
             if !content.is_empty() {
                 (output_handler)(content);
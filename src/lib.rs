@@ -13,9 +13,18 @@
 //! * [`HtmlRewriter`] - a streaming HTML rewriter;
 //! * [`rewrite_str`] - one-off HTML string rewriting function.
 //!
+//! [`RewritingWriter`] and [`RewritingReader`] adapt the [`HtmlRewriter`] to [`std::io`], and
+//! `RewritingStream`, `AsyncRewritingWriter` and `AsyncRewritingReader` (with the `stream`
+//! feature) adapt it to async streams of chunks and async I/O.
+//!
+//! HTML that only needs to be read can be tokenized with the [`Tokenizer`].
+//!
 //! [Cloudflare Workers]: https://www.cloudflare.com/en-gb/products/cloudflare-workers/
 //! [`HtmlRewriter`]: struct.HtmlRewriter.html
 //! [`rewrite_str`]: fn.rewrite_str.html
+//! [`Tokenizer`]: tokenizer/struct.Tokenizer.html
+//! [`RewritingWriter`]: struct.RewritingWriter.html
+//! [`RewritingReader`]: struct.RewritingReader.html
 #![forbid(unsafe_code)]
 #![allow(clippy::default_trait_access)]
 #![allow(clippy::module_name_repetitions)]
@@ -38,16 +47,22 @@ mod parser;
 mod rewritable_units;
 mod transform_stream;
 
+/// A streaming HTML tokenizer for reading HTML without rewriting it.
+pub mod tokenizer;
+
 use cfg_if::cfg_if;
 
 pub use self::rewriter::{
     AsciiCompatibleEncoding, BailOutHandler, CommentHandler, DoctypeHandler,
     DocumentContentHandlers, ElementContentHandlers, ElementHandler, EndHandler, EndTagHandler,
     HandlerResult, HandlerTypes, HtmlRewriter, LocalHandlerTypes, MemorySettings,
-    RewriteStrSettings, Settings, TextHandler, rewrite_str,
+    RewriteStrSettings, RewritingReader, RewritingWriter, Settings, TextHandler,
+    TranscodedEncoding, rewrite_str,
 };
+#[cfg(feature = "stream")]
+pub use self::rewriter::{AsyncRewritingReader, AsyncRewritingWriter, RewritingStream};
 pub use self::selectors_vm::Selector;
-pub use self::transform_stream::OutputSink;
+pub use self::transform_stream::{DetectedEncoding, EncodingConfidence, OutputSink};
 
 /// This module contains type aliases that make the [`HtmlRewriter`] safe to move between threads (have the [`Send`] bound).
 ///
@@ -65,6 +80,21 @@ pub mod send {
 
     /// An [`HtmlRewriter`](crate::HtmlRewriter) that implements [`Send`].
     pub type HtmlRewriter<'handlers, O> = crate::HtmlRewriter<'handlers, O, SendHandlerTypes>;
+    /// A [`RewritingWriter`](crate::RewritingWriter) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    pub type RewritingWriter<'handlers, W> = crate::RewritingWriter<'handlers, W, SendHandlerTypes>;
+    /// A [`RewritingReader`](crate::RewritingReader) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    pub type RewritingReader<'handlers, R> = crate::RewritingReader<'handlers, R, SendHandlerTypes>;
+    /// A [`RewritingStream`](crate::RewritingStream) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    #[cfg(feature = "stream")]
+    pub type RewritingStream<'handlers, S> = crate::RewritingStream<'handlers, S, SendHandlerTypes>;
+    /// An [`AsyncRewritingWriter`](crate::AsyncRewritingWriter) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    #[cfg(feature = "stream")]
+    pub type AsyncRewritingWriter<'handlers, W> =
+        crate::AsyncRewritingWriter<'handlers, W, SendHandlerTypes>;
+    /// An [`AsyncRewritingReader`](crate::AsyncRewritingReader) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    #[cfg(feature = "stream")]
+    pub type AsyncRewritingReader<'handlers, R> =
+        crate::AsyncRewritingReader<'handlers, R, SendHandlerTypes>;
     /// [`Settings`](crate::Settings) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
     pub type Settings<'handlers, 'selectors> =
         crate::Settings<'handlers, 'selectors, SendHandlerTypes>;
@@ -84,10 +114,11 @@ pub mod send {
 
 /// The errors that can be produced by the crate's API.
 pub mod errors {
-    pub use super::memory::MemoryLimitExceededError;
-    pub use super::parser::ParsingAmbiguityError;
+    pub use super::memory::{MaxNestingDepthExceededError, MemoryLimitExceededError};
+    pub use super::parser::{ParsingAmbiguityError, TagLimitExceededError};
     pub use super::rewritable_units::{
-        AttributeNameError, CommentTextError, TagNameError, Utf8Error,
+        AttributeNameError, AttributeRenameError, AttributeTokenError, CommentTextError,
+        StylePropertyError, TagNameError, Utf8Error,
     };
     pub use super::rewriter::RewritingError;
     pub use super::selectors_vm::SelectorError;
@@ -96,8 +127,9 @@ pub mod errors {
 /// HTML content descriptors that can be produced and modified by a rewriter.
 pub mod html_content {
     pub use super::rewritable_units::{
-        Attribute, BailOut, Comment, ContentType, Doctype, DocumentEnd, Element, EndTag, StartTag,
-        StreamingHandler, StreamingHandlerSink, TextChunk, UserData,
+        Ancestor, Ancestors, Attribute, AttributeQuote, BailOut, Comment, ContentType, Doctype,
+        DocumentEnd, Element, ElementCloseReason, EndTag, StartTag, StreamingHandler,
+        StreamingHandlerSink, StyleDeclaration, TextChunk, UserData,
     };
 
     pub use super::base::SourceLocation;
@@ -59,6 +59,12 @@ impl Arena {
         self.data.truncate(self.data.len() - byte_count);
     }
 
+    /// Removes the data, keeping the allocated memory for the later use.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
     pub fn bytes(&self) -> &[u8] {
         &self.data
     }
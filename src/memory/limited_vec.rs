@@ -1,7 +1,7 @@
 #![allow(clippy::len_without_is_empty)]
 
 use std::mem::size_of;
-use std::ops::{Deref, Index, RangeBounds};
+use std::ops::{Deref, DerefMut, Index, RangeBounds};
 use std::vec::Drain;
 
 use super::{MemoryLimitExceededError, SharedMemoryLimiter};
@@ -45,6 +45,22 @@ impl<T> LimitedVec<T> {
         Ok(())
     }
 
+    /// Removes the last element from a vector and returns it, or None if it is empty.
+    ///
+    /// The capacity stays charged to the limiter.
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        self.vec.pop()
+    }
+
+    /// Removes all the elements from the vector.
+    ///
+    /// The capacity stays charged to the limiter.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.vec.clear();
+    }
+
     /// Returns the number of elements in the vector, also referred to as its 'length'.
     #[inline]
     pub fn len(&self) -> usize {
@@ -87,6 +103,13 @@ impl<T> Deref for LimitedVec<T> {
     }
 }
 
+impl<T> DerefMut for LimitedVec<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.vec
+    }
+}
+
 impl<T> Index<usize> for LimitedVec<T> {
     type Output = T;
 
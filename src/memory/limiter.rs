@@ -10,6 +10,14 @@ use thiserror::Error;
 #[error("The memory limit has been exceeded.")]
 pub struct MemoryLimitExceededError;
 
+/// An error that occurs when the number of simultaneously open elements exceeds the nesting
+/// depth limit specified in the [`MemorySettings`].
+///
+/// [`MemorySettings`]: ../struct.MemorySettings.html
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+#[error("The maximum nesting depth has been exceeded.")]
+pub struct MaxNestingDepthExceededError;
+
 // Pub only for integration tests
 #[derive(Debug, Clone)]
 pub struct SharedMemoryLimiter {
@@ -32,16 +40,18 @@ impl SharedMemoryLimiter {
         self.current_usage.load(Ordering::Relaxed)
     }
 
+    /// Charges `byte_count` to the limiter. The usage is left unchanged if it would exceed the
+    /// limit, so that the memory that is never allocated isn't released either.
     #[inline]
     pub fn increase_usage(&self, byte_count: usize) -> Result<(), MemoryLimitExceededError> {
-        let previous_usage = self.current_usage.fetch_add(byte_count, Ordering::Relaxed);
-        let current_usage = previous_usage + byte_count;
-
-        if current_usage > self.max {
-            Err(MemoryLimitExceededError)
-        } else {
-            Ok(())
-        }
+        self.current_usage
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                usage
+                    .checked_add(byte_count)
+                    .filter(|&new_usage| new_usage <= self.max)
+            })
+            .map(|_| ())
+            .map_err(|_| MemoryLimitExceededError)
     }
 
     #[inline]
@@ -72,5 +82,11 @@ mod tests {
         let err = limiter.increase_usage(15).unwrap_err();
 
         assert_eq!(err, MemoryLimitExceededError);
+        assert_eq!(limiter.current_usage(), 4);
+
+        let err = limiter.increase_usage(usize::MAX).unwrap_err();
+
+        assert_eq!(err, MemoryLimitExceededError);
+        assert_eq!(limiter.current_usage(), 4);
     }
 }
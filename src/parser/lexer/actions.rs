@@ -47,6 +47,10 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
                 Some(Text(self.last_text_type)),
             );
 
+            if matches!(self.last_text_type, TextType::Data | TextType::CDataSection) {
+                context.tree_builder_simulator.feed_text(&lexeme.raw())?;
+            }
+
             self.emit_lexeme(context, &lexeme)?;
         }
 
@@ -68,6 +72,13 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
             token,
         );
 
+        if let Some(Doctype(ref doctype)) = lexeme.token_outline {
+            context.tree_builder_simulator.feed_doctype(
+                lexeme.opt_part(doctype.name).as_deref(),
+                doctype.force_quirks,
+            );
+        }
+
         self.emit_lexeme(context, &lexeme)
     }
 
@@ -78,6 +89,8 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
             .take()
             .ok_or_else(|| ActionError::internal("Tag token should exist at this point"))?;
 
+        self.check_tag_limits(context, &token)?;
+
         let feedback = self.try_get_tree_builder_feedback(context, &token)?;
 
         let mut lexeme = self.create_lexeme_with_raw_inclusive(
@@ -91,7 +104,7 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
         self.set_last_text_type(TextType::Data);
 
         if let Some(feedback) = feedback {
-            self.handle_tree_builder_feedback(context, feedback, &lexeme);
+            self.handle_tree_builder_feedback(context, feedback, &lexeme)?;
         }
 
         if let StartTag {
@@ -104,8 +117,23 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
             *ns = context.tree_builder_simulator.current_ns();
         }
 
+        // NOTE: the closed elements stop matching before the tag is handled.
+        context.tree_builder_simulator.take_open_elements_change(
+            |closed_elements, inserted_element_id| {
+                context
+                    .output_sink
+                    .handle_open_elements_change(closed_elements, inserted_element_id)
+            },
+        )?;
+
         match self.emit_tag_lexeme(context, &lexeme)? {
             ParserDirective::Lex => Ok(()),
+            // NOTE: the tree builder needs to see every token in the full tree construction mode.
+            ParserDirective::WherePossibleScanForTagsOnly
+                if context.tree_builder_simulator.is_full_tree_construction() =>
+            {
+                Ok(())
+            }
             ParserDirective::WherePossibleScanForTagsOnly => self.change_parser_directive(
                 self.lexeme_start,
                 ParserDirective::WherePossibleScanForTagsOnly,
@@ -166,6 +194,7 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
 
     #[inline]
     fn create_start_tag(&mut self, _context: &mut ParserContext<S>, _input: &[u8]) {
+        self.has_too_many_attributes = false;
         self.current_tag_token = Some(StartTag {
             name: Range::default(),
             name_hash: LocalNameHash::new(),
@@ -332,10 +361,16 @@ impl<S: LexemeSink> StateMachineActions for Lexer<S> {
     }
 
     #[inline]
-    fn finish_attr(&mut self, _context: &mut ParserContext<S>, _input: &[u8]) {
+    fn finish_attr(&mut self, context: &mut ParserContext<S>, _input: &[u8]) {
         if let Some(attr) = self.current_attr.take() {
             if let Some(StartTag { attributes, .. }) = self.current_tag_token.as_mut() {
-                attributes.push(attr);
+                // NOTE: don't grow the buffer past the limit, the error is
+                // reported once the tag is complete.
+                if attributes.len() < context.tag_limits.max_attributes_per_tag {
+                    attributes.push(attr);
+                } else {
+                    self.has_too_many_attributes = true;
+                }
             }
         }
     }
@@ -8,11 +8,23 @@ pub(crate) use self::lexeme::*;
 use crate::base::{Align, Bytes, Range};
 use crate::html::{LocalNameHash, Namespace, TextType};
 use crate::parser::state_machine::{ActionResult, FeedbackDirective, StateMachine, StateResult};
-use crate::parser::{ParserContext, ParserDirective, ParsingAmbiguityError, TreeBuilderFeedback};
+use crate::parser::{ParserContext, ParserDirective, TagLimitExceededError, TreeBuilderFeedback};
+use crate::rewritable_units::ElementCloseReason;
 
+use crate::rewriter::RewritingError;
 pub(crate) trait LexemeSink {
     fn handle_tag(&mut self, lexeme: &TagLexeme<'_>) -> ActionResult<ParserDirective>;
     fn handle_non_tag_content(&mut self, lexeme: &NonTagContentLexeme<'_>) -> ActionResult;
+
+    /// Called before [`handle_tag()`][Self::handle_tag] in the full tree construction mode
+    /// with the changes of the tree builder's stack of open elements.
+    fn handle_open_elements_change(
+        &mut self,
+        _closed_elements: &mut dyn Iterator<Item = (u64, ElementCloseReason)>,
+        _inserted_element_id: Option<u64>,
+    ) -> ActionResult {
+        Ok(())
+    }
 }
 
 pub(crate) type State<S> = fn(&mut Lexer<S>, context: &mut ParserContext<S>, &[u8]) -> StateResult;
@@ -29,6 +41,7 @@ pub(crate) struct Lexer<S> {
     current_tag_token: Option<TagTokenOutline>,
     current_non_tag_content_token: Option<NonTagContentTokenOutline>,
     current_attr: Option<AttributeOutline>,
+    has_too_many_attributes: bool,
     last_start_tag_name_hash: LocalNameHash,
     closing_quote: u8,
     last_text_type: TextType,
@@ -49,6 +62,7 @@ impl<S: LexemeSink> Lexer<S> {
             current_tag_token: None,
             current_non_tag_content_token: None,
             current_attr: None,
+            has_too_many_attributes: false,
             last_start_tag_name_hash: LocalNameHash::default(),
             closing_quote: b'"',
             last_text_type: TextType::Data,
@@ -56,11 +70,45 @@ impl<S: LexemeSink> Lexer<S> {
         }
     }
 
+    #[inline]
+    pub fn is_in_start_tag(&self) -> bool {
+        matches!(
+            self.current_tag_token,
+            Some(TagTokenOutline::StartTag { .. })
+        )
+    }
+
+    fn check_tag_limits(
+        &self,
+        context: &ParserContext<S>,
+        token: &TagTokenOutline,
+    ) -> Result<(), RewritingError> {
+        let TagTokenOutline::StartTag { .. } = token else {
+            return Ok(());
+        };
+
+        let limits = context.tag_limits;
+
+        let err = if self.has_too_many_attributes {
+            TagLimitExceededError::TooManyAttributes {
+                limit: limits.max_attributes_per_tag,
+            }
+        } else if self.pos() + 1 - self.lexeme_start > limits.max_start_tag_size {
+            TagLimitExceededError::StartTagTooLarge {
+                limit: limits.max_start_tag_size,
+            }
+        } else {
+            return Ok(());
+        };
+
+        Err(RewritingError::TagLimitExceeded(err))
+    }
+
     fn try_get_tree_builder_feedback(
         &mut self,
         context: &mut ParserContext<S>,
         token: &TagTokenOutline,
-    ) -> Result<Option<TreeBuilderFeedback>, ParsingAmbiguityError> {
+    ) -> Result<Option<TreeBuilderFeedback>, RewritingError> {
         Ok(match self.feedback_directive.take() {
             FeedbackDirective::ApplyUnhandledFeedback(feedback) => Some(feedback),
             FeedbackDirective::Skip => None,
@@ -84,17 +132,19 @@ impl<S: LexemeSink> Lexer<S> {
         context: &mut ParserContext<S>,
         feedback: TreeBuilderFeedback,
         lexeme: &TagLexeme<'_>,
-    ) {
+    ) -> Result<(), RewritingError> {
         match feedback {
             TreeBuilderFeedback::SwitchTextType(text_type) => self.set_last_text_type(text_type),
             TreeBuilderFeedback::SetAllowCdata(cdata_allowed) => self.cdata_allowed = cdata_allowed,
             TreeBuilderFeedback::RequestLexeme(mut callback) => {
-                let feedback = callback(&mut context.tree_builder_simulator, lexeme);
+                let feedback = callback(&mut context.tree_builder_simulator, lexeme)?;
 
-                self.handle_tree_builder_feedback(context, feedback, lexeme);
+                self.handle_tree_builder_feedback(context, feedback, lexeme)?;
             }
             TreeBuilderFeedback::None => (),
         }
+
+        Ok(())
     }
 
     #[inline]
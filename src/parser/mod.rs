@@ -2,6 +2,7 @@
 mod state_machine;
 
 mod lexer;
+mod tag_limits;
 mod tag_scanner;
 mod tree_builder_simulator;
 
@@ -12,10 +13,13 @@ pub(crate) use self::lexer::{
 };
 use self::state_machine::StateMachine;
 pub(crate) use self::state_machine::{ActionError, ActionResult};
+pub use self::tag_limits::TagLimitExceededError;
+pub(crate) use self::tag_limits::TagLimits;
 pub(crate) use self::tag_scanner::TagHintSink;
 use self::tag_scanner::TagScanner;
 pub use self::tree_builder_simulator::ParsingAmbiguityError;
 use self::tree_builder_simulator::{TreeBuilderFeedback, TreeBuilderSimulator};
+use crate::memory::SharedMemoryLimiter;
 use crate::rewriter::RewritingError;
 use cfg_if::cfg_if;
 
@@ -35,6 +39,7 @@ pub(crate) struct ParserContext<S> {
     /// Amount of bytes consumed by previous calls to `parse()`,
     /// i.e. number of bytes from the start of the document until the start of the current input slice
     previously_consumed_byte_count: usize,
+    tag_limits: TagLimits,
 }
 
 pub(crate) trait ParserOutputSink: LexemeSink + TagHintSink {}
@@ -52,11 +57,30 @@ pub struct Parser<S> {
 impl<S: ParserOutputSink> Parser<S> {
     #[must_use]
     #[inline(never)]
-    pub fn new(output_sink: S, initial_directive: ParserDirective, strict: bool) -> Self {
+    pub fn new(
+        output_sink: S,
+        initial_directive: ParserDirective,
+        strict: bool,
+        full_tree_construction: bool,
+        memory_limiter: SharedMemoryLimiter,
+        tag_limits: TagLimits,
+    ) -> Self {
         let context = ParserContext {
             output_sink,
             previously_consumed_byte_count: 0,
-            tree_builder_simulator: TreeBuilderSimulator::new(strict),
+            tag_limits,
+            tree_builder_simulator: TreeBuilderSimulator::new(
+                strict,
+                full_tree_construction,
+                memory_limiter,
+            ),
+        };
+
+        // NOTE: tag scanner doesn't produce tokens that are required for the tree construction.
+        let initial_directive = if full_tree_construction {
+            ParserDirective::Lex
+        } else {
+            initial_directive
         };
 
         Self {
@@ -67,6 +91,24 @@ impl<S: ParserOutputSink> Parser<S> {
         }
     }
 
+    /// Restores the initial state for a new document, keeping the output sink as is.
+    pub(crate) fn reset(&mut self, initial_directive: ParserDirective) {
+        self.lexer = Lexer::new();
+        self.tag_scanner = TagScanner::new();
+        self.context.previously_consumed_byte_count = 0;
+        self.context.tree_builder_simulator.reset();
+
+        self.current_directive = if self
+            .context
+            .tree_builder_simulator
+            .is_full_tree_construction()
+        {
+            ParserDirective::Lex
+        } else {
+            initial_directive
+        };
+    }
+
     // generic methods tend to be inlined, but this one is called from a couple of places,
     // and has cheap-to-pass non-constants args, so it won't benefit from being merged into its callers.
     // It's better to outline it, and let its callers be inlined.
@@ -89,6 +131,10 @@ impl<S: ParserOutputSink> Parser<S> {
                 ActionError::EndOfInput {
                     consumed_byte_count,
                 } => {
+                    if !last {
+                        self.check_buffered_start_tag_size(input.len() - consumed_byte_count)?;
+                    }
+
                     self.context.previously_consumed_byte_count += consumed_byte_count;
                     return Ok(consumed_byte_count);
                 }
@@ -117,9 +163,43 @@ impl<S: ParserOutputSink> Parser<S> {
         }
     }
 
+    /// Unconsumed bytes of a start tag get buffered until the next input chunk, so bail out
+    /// early instead of buffering a tag that is already known to exceed the limit.
+    #[inline]
+    fn check_buffered_start_tag_size(
+        &self,
+        unconsumed_byte_count: usize,
+    ) -> Result<(), RewritingError> {
+        let limit = self.context.tag_limits.max_start_tag_size;
+
+        let is_in_start_tag = match self.current_directive {
+            ParserDirective::WherePossibleScanForTagsOnly => self.tag_scanner.is_in_start_tag(),
+            ParserDirective::Lex => self.lexer.is_in_start_tag(),
+        };
+
+        if is_in_start_tag && unconsumed_byte_count > limit {
+            Err(RewritingError::TagLimitExceeded(
+                TagLimitExceededError::StartTagTooLarge { limit },
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the number of bytes consumed from the start of the document.
+    #[inline]
+    pub(crate) const fn consumed_byte_count(&self) -> usize {
+        self.context.previously_consumed_byte_count
+    }
+
     pub fn get_dispatcher(&mut self) -> &mut S {
         &mut self.context.output_sink
     }
+
+    #[inline]
+    pub(crate) fn into_dispatcher(self) -> S {
+        self.context.output_sink
+    }
 }
 
 cfg_if! {
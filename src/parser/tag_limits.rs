@@ -0,0 +1,34 @@
+use thiserror::Error;
+
+/// Per-tag limits the parser enforces on start tags it parses.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TagLimits {
+    pub max_attributes_per_tag: usize,
+    pub max_start_tag_size: usize,
+}
+
+/// An error that occurs when a start tag exceeds one of the per-tag limits specified in the
+/// [`MemorySettings`].
+///
+/// [`MemorySettings`]: ../struct.MemorySettings.html
+#[non_exhaustive]
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TagLimitExceededError {
+    /// A start tag has more attributes than [allowed].
+    ///
+    /// [allowed]: ../struct.MemorySettings.html#method.with_max_attributes_per_tag
+    #[error("A start tag has more than {limit} attributes.")]
+    TooManyAttributes {
+        /// The configured limit.
+        limit: usize,
+    },
+
+    /// A start tag is longer than [allowed].
+    ///
+    /// [allowed]: ../struct.MemorySettings.html#method.with_max_start_tag_size
+    #[error("A start tag is longer than {limit} bytes.")]
+    StartTagTooLarge {
+        /// The configured limit.
+        limit: usize,
+    },
+}
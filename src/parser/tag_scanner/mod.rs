@@ -5,7 +5,7 @@ mod conditions;
 use crate::base::{Align, Bytes, Range};
 use crate::html::{LocalName, LocalNameHash, Namespace, TextType};
 use crate::parser::state_machine::{FeedbackDirective, StateMachine, StateResult};
-use crate::parser::{ParserContext, ParserDirective, ParsingAmbiguityError, TreeBuilderFeedback};
+use crate::parser::{ParserContext, ParserDirective, TreeBuilderFeedback};
 use crate::rewriter::RewritingError;
 use std::cmp::min;
 
@@ -100,7 +100,7 @@ impl<S: TagHintSink> TagScanner<S> {
     fn try_apply_tree_builder_feedback(
         &mut self,
         context: &mut ParserContext<S>,
-    ) -> Result<Option<TreeBuilderFeedback>, ParsingAmbiguityError> {
+    ) -> Result<Option<TreeBuilderFeedback>, RewritingError> {
         let feedback = if self.is_in_end_tag {
             context
                 .tree_builder_simulator
@@ -127,6 +127,12 @@ impl<S: TagHintSink> TagScanner<S> {
         })
     }
 
+    /// Whether the scanner is in the middle of a start tag's name.
+    #[inline]
+    pub fn is_in_start_tag(&self) -> bool {
+        self.tag_start.is_some() && !self.is_in_end_tag
+    }
+
     #[inline]
     fn take_feedback_directive(&mut self) -> FeedbackDirective {
         self.pending_text_type_change
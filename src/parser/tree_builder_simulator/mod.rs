@@ -11,27 +11,37 @@
 //! cases where we can't unambiguously determine parsing context and prefer
 //! to bail out from the tokenization in such a case
 //! (see `AmbiguityGuard` for the details).
+//!
+//! If ambiguity bailouts are not acceptable, the full tree construction stage
+//! can be used instead of the simulation (see `TreeBuilder` for the details).
 mod ambiguity_guard;
+mod tree_builder;
 
 use self::ambiguity_guard::AmbiguityGuard;
+use self::tree_builder::TreeBuilder;
 use crate::base::eq_case_insensitive;
 use crate::html::{LocalNameHash, Namespace, Tag, TextType};
+use crate::memory::{LimitedVec, SharedMemoryLimiter};
 use crate::parser::{TagLexeme, TagTokenOutline};
+use crate::rewritable_units::ElementCloseReason;
+use crate::rewriter::RewritingError;
 use TagTokenOutline::{EndTag, StartTag};
 
 pub use self::ambiguity_guard::ParsingAmbiguityError;
 
-const DEFAULT_NS_STACK_CAPACITY: usize = 256;
-
 #[must_use]
 pub(crate) enum TreeBuilderFeedback {
     SwitchTextType(TextType),
     SetAllowCdata(bool),
     #[allow(clippy::type_complexity)]
-    RequestLexeme(Box<dyn FnMut(&mut TreeBuilderSimulator, &TagLexeme<'_>) -> Self + Send>),
+    RequestLexeme(
+        Box<dyn FnMut(&mut TreeBuilderSimulator, &TagLexeme<'_>) -> FeedbackResult + Send>,
+    ),
     None,
 }
 
+pub(crate) type FeedbackResult = Result<TreeBuilderFeedback, RewritingError>;
+
 impl From<TextType> for TreeBuilderFeedback {
     #[inline]
     fn from(text_type: TextType) -> Self {
@@ -41,9 +51,7 @@ impl From<TextType> for TreeBuilderFeedback {
 
 #[inline]
 fn request_lexeme(
-    callback: impl FnMut(&mut TreeBuilderSimulator, &TagLexeme<'_>) -> TreeBuilderFeedback
-    + 'static
-    + Send,
+    callback: impl FnMut(&mut TreeBuilderSimulator, &TagLexeme<'_>) -> FeedbackResult + 'static + Send,
 ) -> TreeBuilderFeedback {
     TreeBuilderFeedback::RequestLexeme(Box::new(callback))
 }
@@ -54,7 +62,7 @@ macro_rules! expect_tag {
             $tag_pat => $action,
             _ => {
                 debug_assert!(false, "Got unexpected tag type");
-                return TreeBuilderFeedback::None;
+                return Ok(TreeBuilderFeedback::None);
             }
         }
     };
@@ -99,50 +107,70 @@ fn is_html_integration_point_in_svg(tag_name: LocalNameHash) -> bool {
     tag_is_one_of!(tag_name, [Desc, Title, ForeignObject])
 }
 
-// TODO limit ns stack
 pub(crate) struct TreeBuilderSimulator {
-    ns_stack: Vec<Namespace>,
+    /// Namespaces entered on top of the document's HTML namespace.
+    ns_stack: LimitedVec<Namespace>,
     current_ns: Namespace,
     ambiguity_guard: AmbiguityGuard,
     strict: bool,
+    tree_builder: Option<TreeBuilder>,
 }
 
 impl TreeBuilderSimulator {
     #[inline]
     #[must_use]
-    pub fn new(strict: bool) -> Self {
-        let mut simulator = Self {
-            ns_stack: Vec::with_capacity(DEFAULT_NS_STACK_CAPACITY),
+    pub fn new(
+        strict: bool,
+        full_tree_construction: bool,
+        memory_limiter: SharedMemoryLimiter,
+    ) -> Self {
+        Self {
+            ns_stack: LimitedVec::new(memory_limiter.clone()),
             current_ns: Namespace::Html,
             ambiguity_guard: AmbiguityGuard::default(),
             strict,
-        };
+            tree_builder: full_tree_construction.then(|| TreeBuilder::new(memory_limiter)),
+        }
+    }
 
-        simulator.ns_stack.push(Namespace::Html);
+    /// Restores the initial state for a new document.
+    pub fn reset(&mut self) {
+        self.ns_stack.clear();
+        self.current_ns = Namespace::Html;
+        self.ambiguity_guard = AmbiguityGuard::default();
 
-        simulator
+        if let Some(tree_builder) = &mut self.tree_builder {
+            tree_builder.reset();
+        }
     }
 
-    pub fn get_feedback_for_start_tag(
-        &mut self,
-        tag_name: LocalNameHash,
-    ) -> Result<TreeBuilderFeedback, ParsingAmbiguityError> {
+    pub fn get_feedback_for_start_tag(&mut self, tag_name: LocalNameHash) -> FeedbackResult {
+        if self.tree_builder.is_some() {
+            return Ok(request_lexeme(Self::get_feedback_from_tree_builder));
+        }
+
         if self.strict {
-            self.ambiguity_guard.track_start_tag(tag_name)?;
+            self.ambiguity_guard
+                .track_start_tag(tag_name)
+                .map_err(RewritingError::ParsingAmbiguity)?;
         }
 
-        Ok(if tag_name == Tag::Svg {
+        if tag_name == Tag::Svg {
             self.enter_ns(Namespace::Svg)
         } else if tag_name == Tag::Math {
             self.enter_ns(Namespace::MathML)
         } else if self.current_ns != Namespace::Html {
-            self.get_feedback_for_start_tag_in_foreign_content(tag_name)
+            Ok(self.get_feedback_for_start_tag_in_foreign_content(tag_name))
         } else {
-            get_text_type_adjustment(tag_name)
-        })
+            Ok(get_text_type_adjustment(tag_name))
+        }
     }
 
     pub fn get_feedback_for_end_tag(&mut self, tag_name: LocalNameHash) -> TreeBuilderFeedback {
+        if self.tree_builder.is_some() {
+            return request_lexeme(Self::get_feedback_from_tree_builder);
+        }
+
         if self.strict {
             self.ambiguity_guard.track_end_tag(tag_name);
         }
@@ -156,6 +184,66 @@ impl TreeBuilderSimulator {
         }
     }
 
+    #[inline]
+    pub const fn is_full_tree_construction(&self) -> bool {
+        self.tree_builder.is_some()
+    }
+
+    /// Passes the changes of the tree builder's stack of open elements since the previous
+    /// call to the `handler` in the full tree construction mode. No-op otherwise.
+    #[inline]
+    pub fn take_open_elements_change<E>(
+        &mut self,
+        handler: impl FnOnce(
+            &mut dyn Iterator<Item = (u64, ElementCloseReason)>,
+            Option<u64>,
+        ) -> Result<(), E>,
+    ) -> Result<(), E> {
+        match &mut self.tree_builder {
+            Some(tree_builder) => tree_builder.take_open_elements_change(handler),
+            None => Ok(()),
+        }
+    }
+
+    /// Feeds text of the `Data` or `CDataSection` type to the tree builder
+    /// in the full tree construction mode. No-op otherwise.
+    #[inline]
+    pub fn feed_text(&mut self, text: &[u8]) -> Result<(), RewritingError> {
+        if let Some(tree_builder) = &mut self.tree_builder {
+            tree_builder
+                .process_text(text)
+                .map_err(RewritingError::MemoryLimitExceeded)?;
+        }
+
+        Ok(())
+    }
+
+    /// Feeds doctype to the tree builder in the full tree construction mode. No-op otherwise.
+    #[inline]
+    pub fn feed_doctype(&mut self, name: Option<&[u8]>, force_quirks: bool) {
+        if let Some(tree_builder) = &mut self.tree_builder {
+            tree_builder.process_doctype(name, force_quirks);
+        }
+    }
+
+    fn get_feedback_from_tree_builder(&mut self, lexeme: &TagLexeme<'_>) -> FeedbackResult {
+        let Some(tree_builder) = &mut self.tree_builder else {
+            debug_assert!(
+                false,
+                "Tree builder should exist in the full tree construction mode"
+            );
+            return Ok(TreeBuilderFeedback::None);
+        };
+
+        let feedback = tree_builder
+            .process_tag(lexeme)
+            .map_err(RewritingError::MemoryLimitExceeded)?;
+
+        self.current_ns = tree_builder.token_ns();
+
+        Ok(feedback)
+    }
+
     fn should_leave_ns(&self, tag_name: LocalNameHash) -> bool {
         if self.current_ns == Namespace::Svg && tag_name == Tag::Svg
             || self.current_ns == Namespace::MathML && tag_name == Tag::Math
@@ -178,24 +266,22 @@ impl TreeBuilderSimulator {
     }
 
     #[inline]
-    fn enter_ns(&mut self, ns: Namespace) -> TreeBuilderFeedback {
-        self.ns_stack.push(ns);
+    fn enter_ns(&mut self, ns: Namespace) -> FeedbackResult {
+        self.ns_stack
+            .push(ns)
+            .map_err(RewritingError::MemoryLimitExceeded)?;
         self.current_ns = ns;
-        TreeBuilderFeedback::SetAllowCdata(ns != Namespace::Html)
+        Ok(TreeBuilderFeedback::SetAllowCdata(ns != Namespace::Html))
     }
 
     #[inline]
     fn leave_ns(&mut self) -> TreeBuilderFeedback {
-        self.ns_stack.pop();
-
-        let Some(item) = self.ns_stack.last() else {
-            debug_assert!(
-                false,
-                "Namespace stack should always have at least one item"
-            );
+        if self.ns_stack.pop().is_none() {
+            debug_assert!(false, "Can't leave the document's namespace");
             return TreeBuilderFeedback::None;
-        };
-        self.current_ns = *item;
+        }
+
+        self.current_ns = self.ns_stack.last().copied().unwrap_or(Namespace::Html);
 
         TreeBuilderFeedback::SetAllowCdata(self.current_ns != Namespace::Html)
     }
@@ -224,9 +310,9 @@ impl TreeBuilderSimulator {
             request_lexeme(|this, lexeme| {
                 expect_tag!(lexeme, EndTag { name, .. } => {
                     if eq_case_insensitive(&lexeme.part(name), b"annotation-xml") {
-                        this.leave_ns()
+                        Ok(this.leave_ns())
                     } else {
-                        TreeBuilderFeedback::None
+                        Ok(TreeBuilderFeedback::None)
                     }
                 })
             })
@@ -247,7 +333,7 @@ impl TreeBuilderSimulator {
             return request_lexeme(|this, lexeme| {
                 expect_tag!(lexeme, StartTag { self_closing, .. } => {
                     if self_closing {
-                        TreeBuilderFeedback::None
+                        Ok(TreeBuilderFeedback::None)
                     } else {
                         this.enter_ns(Namespace::Html)
                     }
@@ -267,12 +353,12 @@ impl TreeBuilderSimulator {
                             || eq_case_insensitive(&name, b"size")
                             || eq_case_insensitive(&name, b"face")
                         {
-                            return this.leave_ns();
+                            return Ok(this.leave_ns());
                         }
                     }
                 });
 
-                TreeBuilderFeedback::None
+                Ok(TreeBuilderFeedback::None)
             });
         }
 
@@ -303,7 +389,7 @@ impl TreeBuilderSimulator {
                     }
                 });
 
-                TreeBuilderFeedback::None
+                Ok(TreeBuilderFeedback::None)
             });
         }
 
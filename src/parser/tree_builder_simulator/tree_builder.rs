@@ -0,0 +1,2490 @@
+//! Full-featured tree construction stage that is used instead of the feedback
+//! simulation if the rewriter is configured to do the full tree construction.
+//!
+//! It maintains the stack of open elements, the list of active formatting elements
+//! and the insertion mode exactly as the tree construction stage of the HTML parser
+//! does (see <https://html.spec.whatwg.org/multipage/parsing.html#tree-construction>).
+//! Since we don't build an actual DOM tree, operations that only affect the tree
+//! (e.g. foster parenting or reparenting of nodes by the adoption agency algorithm)
+//! are omitted, but their effects on the stack of open elements are preserved. This
+//! gives us exact information about the context in which each token is parsed and,
+//! thus, there are no ambiguous cases that require a bailout. The elements removed from
+//! the stack of open elements are recorded, so that the selector matching can follow them.
+//!
+//! There are a few deliberate simplifications:
+//! * the scripting flag is always considered to be enabled (so, there is no
+//!   "in head noscript" insertion mode);
+//! * attributes of the active formatting elements are compared by their raw values in the
+//!   "Noah's Ark" clause, i.e. without decoding of character references;
+//! * there can be at most [`MAX_ACTIVE_FORMATTING_ELEMENTS`] elements after the last marker
+//!   in the list of active formatting elements, the earliest ones are removed from the list
+//!   once it's exceeded;
+//! * quirks mode is determined only by the presence of the `<!DOCTYPE html>`, legacy
+//!   public and system identifiers are not taken into consideration.
+use super::TreeBuilderFeedback;
+use crate::base::{Bytes, eq_case_insensitive};
+use crate::html::{LocalName, Namespace, Tag, TextType};
+use crate::memory::{MemoryLimitExceededError, SharedMemoryLimiter};
+use crate::parser::{TagLexeme, TagTokenOutline};
+use crate::rewritable_units::ElementCloseReason;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::ops::Deref;
+
+/// Bounds the time spent on the list of active formatting elements for every token.
+const MAX_ACTIVE_FORMATTING_ELEMENTS: usize = 64;
+
+macro_rules! start_tag_is_one_of {
+    ($token:expr, [$($tag:ident),+]) => {
+        $token.is_start && tag_is_one_of!($token.name, [$($tag),+])
+    };
+}
+
+macro_rules! end_tag_is_one_of {
+    ($token:expr, [$($tag:ident),+]) => {
+        !$token.is_start && tag_is_one_of!($token.name, [$($tag),+])
+    };
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    AfterHead,
+    InBody,
+    Text,
+    InTable,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    InTemplate,
+    AfterBody,
+    InFrameset,
+    AfterFrameset,
+    AfterAfterBody,
+    AfterAfterFrameset,
+}
+
+#[derive(Copy, Clone)]
+enum Scope {
+    Default,
+    ListItem,
+    Button,
+    Table,
+}
+
+#[derive(Clone, Debug)]
+struct OpenElement {
+    id: u64,
+    name: LocalName<'static>,
+    ns: Namespace,
+    is_html_integration_point: bool,
+    /// Hash of the attributes of the token the element was created for, used to compare
+    /// formatting elements.
+    attributes_hash: u64,
+}
+
+impl OpenElement {
+    /// Returns the number of bytes of the name that is stored on the heap.
+    #[inline]
+    fn name_byte_count(&self) -> usize {
+        match &self.name {
+            LocalName::Bytes(name) => name.len(),
+            LocalName::Hash(_) => 0,
+        }
+    }
+
+    #[inline]
+    fn is_html(&self, tag: Tag) -> bool {
+        self.ns == Namespace::Html && self.name == tag
+    }
+
+    #[inline]
+    fn is_html_named(&self, name: &LocalName<'_>) -> bool {
+        self.ns == Namespace::Html && self.name == *name
+    }
+
+    #[inline]
+    fn is_annotation_xml(&self) -> bool {
+        self.ns == Namespace::MathML
+            && matches!(self.name, LocalName::Bytes(ref name) if eq_case_insensitive(name, b"annotation-xml"))
+    }
+
+    #[inline]
+    fn is_math_ml_text_integration_point(&self) -> bool {
+        self.ns == Namespace::MathML && tag_is_one_of!(self.name, [Mi, Mo, Mn, Ms, Mtext])
+    }
+
+    fn is_special(&self) -> bool {
+        match self.ns {
+            Namespace::Html => tag_is_one_of!(
+                self.name,
+                [
+                    Address, Applet, Area, Article, Aside, Base, Basefont, Bgsound, Blockquote,
+                    Body, Br, Button, Caption, Center, Col, Colgroup, Dd, Details, Dir, Div, Dl,
+                    Dt, Embed, Fieldset, Figcaption, Figure, Footer, Form, Frame, Frameset, H1, H2,
+                    H3, H4, H5, H6, Head, Header, Hgroup, Hr, Html, Iframe, Img, Input, Keygen, Li,
+                    Link, Listing, Main, Marquee, Menu, Meta, Nav, Noembed, Noframes, Noscript,
+                    Object, Ol, P, Param, Plaintext, Pre, Script, Search, Section, Select, Source,
+                    Style, Summary, Table, Tbody, Td, Template, Textarea, Tfoot, Th, Thead, Title,
+                    Tr, Track, Ul, Wbr, Xmp
+                ]
+            ),
+            Namespace::MathML => {
+                self.is_math_ml_text_integration_point() || self.is_annotation_xml()
+            }
+            Namespace::Svg => tag_is_one_of!(self.name, [ForeignObject, Desc, Title]),
+        }
+    }
+
+    #[inline]
+    fn is_in_cursory_implied_end_tag_set(&self) -> bool {
+        self.ns == Namespace::Html
+            && tag_is_one_of!(
+                self.name,
+                [Dd, Dt, Li, Optgroup, Option, P, Rb, Rp, Rt, Rtc]
+            )
+    }
+
+    #[inline]
+    fn is_in_thorough_implied_end_tag_set(&self) -> bool {
+        self.is_in_cursory_implied_end_tag_set()
+            || self.ns == Namespace::Html
+                && tag_is_one_of!(
+                    self.name,
+                    [Caption, Colgroup, Tbody, Td, Tfoot, Th, Thead, Tr]
+                )
+    }
+
+    #[inline]
+    fn is_heading(&self) -> bool {
+        self.ns == Namespace::Html && tag_is_one_of!(self.name, [H1, H2, H3, H4, H5, H6])
+    }
+}
+
+impl Scope {
+    fn is_boundary(self, element: &OpenElement) -> bool {
+        let is_default_scope_boundary = || match element.ns {
+            Namespace::Html => tag_is_one_of!(
+                element.name,
+                [
+                    Applet, Caption, Html, Table, Td, Th, Marquee, Object, Select, Template
+                ]
+            ),
+            Namespace::MathML => {
+                element.is_math_ml_text_integration_point() || element.is_annotation_xml()
+            }
+            Namespace::Svg => tag_is_one_of!(element.name, [ForeignObject, Desc, Title]),
+        };
+
+        match self {
+            Self::Default => is_default_scope_boundary(),
+            Self::ListItem => {
+                is_default_scope_boundary() || element.is_html(Tag::Ol) || element.is_html(Tag::Ul)
+            }
+            Self::Button => is_default_scope_boundary() || element.is_html(Tag::Button),
+            Self::Table => {
+                element.ns == Namespace::Html
+                    && tag_is_one_of!(element.name, [Html, Table, Template])
+            }
+        }
+    }
+}
+
+enum FormattingEntry {
+    Marker,
+    Element(OpenElement),
+}
+
+trait ElementListEntry {
+    fn name_byte_count(&self) -> usize;
+}
+
+impl ElementListEntry for OpenElement {
+    #[inline]
+    fn name_byte_count(&self) -> usize {
+        Self::name_byte_count(self)
+    }
+}
+
+impl ElementListEntry for FormattingEntry {
+    #[inline]
+    fn name_byte_count(&self) -> usize {
+        match self {
+            Self::Marker => 0,
+            Self::Element(element) => element.name_byte_count(),
+        }
+    }
+}
+
+/// The stack of open elements or the list of active formatting elements.
+///
+/// Keeps count of the bytes of the element names stored on the heap, so that they are charged
+/// to the memory limiter along with the capacity of the list.
+struct ElementList<T> {
+    entries: Vec<T>,
+    name_byte_count: usize,
+}
+
+impl<T: ElementListEntry> ElementList<T> {
+    #[inline]
+    const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            name_byte_count: 0,
+        }
+    }
+
+    #[inline]
+    fn memory_usage(&self) -> usize {
+        self.entries.capacity() * size_of::<T>() + self.name_byte_count
+    }
+
+    #[inline]
+    fn push(&mut self, entry: T) {
+        self.name_byte_count += entry.name_byte_count();
+        self.entries.push(entry);
+    }
+
+    #[inline]
+    fn insert(&mut self, idx: usize, entry: T) {
+        self.name_byte_count += entry.name_byte_count();
+        self.entries.insert(idx, entry);
+    }
+
+    #[inline]
+    fn replace(&mut self, idx: usize, entry: T) -> T {
+        self.name_byte_count += entry.name_byte_count();
+        self.name_byte_count -= self.entries[idx].name_byte_count();
+
+        std::mem::replace(&mut self.entries[idx], entry)
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<T> {
+        let entry = self.entries.pop()?;
+
+        self.name_byte_count -= entry.name_byte_count();
+
+        Some(entry)
+    }
+
+    #[inline]
+    fn remove(&mut self, idx: usize) -> T {
+        let entry = self.entries.remove(idx);
+
+        self.name_byte_count -= entry.name_byte_count();
+
+        entry
+    }
+
+    /// Removes all the entries, keeping the capacity.
+    #[inline]
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.name_byte_count = 0;
+    }
+}
+
+impl<T> Deref for ElementList<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        &self.entries
+    }
+}
+
+/// An element that has been removed from the stack of open elements.
+struct ClosedElement {
+    element: OpenElement,
+    reason: ElementCloseReason,
+}
+
+enum Bookmark {
+    Replace(u64),
+    InsertAfter(u64),
+}
+
+struct TagToken<'l, 'i> {
+    lexeme: &'l TagLexeme<'i>,
+    name: LocalName<'i>,
+    is_start: bool,
+    self_closing: bool,
+}
+
+impl TagToken<'_, '_> {
+    fn attribute(&self, name: &[u8]) -> Option<Bytes<'_>> {
+        match self.lexeme.token_outline() {
+            TagTokenOutline::StartTag { attributes, .. } => attributes
+                .iter()
+                .find(|attr| eq_case_insensitive(&self.lexeme.part(attr.name), name))
+                .map(|attr| self.lexeme.part(attr.value)),
+            TagTokenOutline::EndTag { .. } => None,
+        }
+    }
+
+    /// Returns a hash of the attributes that doesn't depend on their order.
+    fn attributes_hash(&self, hasher: &RandomState) -> u64 {
+        let TagTokenOutline::StartTag { attributes, .. } = self.lexeme.token_outline() else {
+            return 0;
+        };
+
+        attributes
+            .iter()
+            .fold(attributes.len() as u64, |hash, attr| {
+                let mut attr_hasher = hasher.build_hasher();
+
+                for &b in &*self.lexeme.part(attr.name) {
+                    attr_hasher.write_u8(b.to_ascii_lowercase());
+                }
+
+                attr_hasher.write_u8(b'=');
+                attr_hasher.write(&self.lexeme.part(attr.value));
+
+                hash.wrapping_add(attr_hasher.finish())
+            })
+    }
+}
+
+#[inline]
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+pub(crate) struct TreeBuilder {
+    mode: InsertionMode,
+    original_mode: InsertionMode,
+    template_modes: Vec<InsertionMode>,
+    open_elements: ElementList<OpenElement>,
+    active_formatting_elements: ElementList<FormattingEntry>,
+    head_element: Option<OpenElement>,
+    form_element_id: Option<u64>,
+    frameset_ok: bool,
+    quirks_mode: bool,
+    next_element_id: u64,
+    text_type_switch: Option<TextType>,
+    token_ns: Namespace,
+    /// Id of the element that was inserted for the token being processed.
+    token_element_id: Option<u64>,
+    /// Id of the element that was inserted for the last processed start tag, if it's open.
+    inserted_element_id: Option<u64>,
+    /// Elements removed from the stack of open elements since they've been last taken.
+    closed_elements: Vec<ClosedElement>,
+    /// Reason of the removals caused by the token being processed.
+    close_reason: ElementCloseReason,
+    /// Randomly seeded, so that the attribute hashes of formatting elements can't be made
+    /// to collide.
+    attributes_hasher: RandomState,
+    memory_limiter: SharedMemoryLimiter,
+    /// Memory used by the element stacks and the names of their elements that is charged to
+    /// the limiter.
+    charged_memory: usize,
+}
+
+impl Drop for TreeBuilder {
+    fn drop(&mut self) {
+        self.memory_limiter.decrease_usage(self.charged_memory);
+    }
+}
+
+impl TreeBuilder {
+    #[must_use]
+    pub fn new(memory_limiter: SharedMemoryLimiter) -> Self {
+        Self {
+            mode: InsertionMode::Initial,
+            original_mode: InsertionMode::Initial,
+            template_modes: Vec::new(),
+            open_elements: ElementList::new(),
+            active_formatting_elements: ElementList::new(),
+            head_element: None,
+            form_element_id: None,
+            frameset_ok: true,
+            quirks_mode: true,
+            next_element_id: 0,
+            text_type_switch: None,
+            token_ns: Namespace::Html,
+            token_element_id: None,
+            inserted_element_id: None,
+            closed_elements: Vec::new(),
+            close_reason: ElementCloseReason::ImpliedByStartTag,
+            attributes_hasher: RandomState::new(),
+            memory_limiter,
+            charged_memory: 0,
+        }
+    }
+
+    /// Restores the initial state for a new document. The element stacks keep their capacity,
+    /// which stays charged to the limiter, while the memory of the element names is released.
+    pub fn reset(&mut self) {
+        self.mode = InsertionMode::Initial;
+        self.original_mode = InsertionMode::Initial;
+        self.template_modes.clear();
+        self.open_elements.clear();
+        self.active_formatting_elements.clear();
+        self.head_element = None;
+        self.form_element_id = None;
+        self.frameset_ok = true;
+        self.quirks_mode = true;
+        self.next_element_id = 0;
+        self.text_type_switch = None;
+        self.token_ns = Namespace::Html;
+        self.token_element_id = None;
+        self.inserted_element_id = None;
+        self.closed_elements.clear();
+
+        // NOTE: the usage only decreases here, so it can't exceed the limit.
+        let _ = self.charge_memory_usage();
+    }
+
+    /// Namespace of the element that was inserted for the last processed start tag.
+    #[inline]
+    pub const fn token_ns(&self) -> Namespace {
+        self.token_ns
+    }
+
+    pub fn process_tag(
+        &mut self,
+        lexeme: &TagLexeme<'_>,
+    ) -> Result<TreeBuilderFeedback, MemoryLimitExceededError> {
+        let token = match *lexeme.token_outline() {
+            TagTokenOutline::StartTag {
+                name,
+                name_hash,
+                self_closing,
+                ..
+            } => TagToken {
+                lexeme,
+                name: LocalName::new(*lexeme.input(), name, name_hash),
+                is_start: true,
+                self_closing,
+            },
+            TagTokenOutline::EndTag { name, name_hash } => TagToken {
+                lexeme,
+                name: LocalName::new(*lexeme.input(), name, name_hash),
+                is_start: false,
+                self_closing: false,
+            },
+        };
+
+        self.token_ns = Namespace::Html;
+        self.token_element_id = None;
+        self.close_reason = if token.is_start {
+            ElementCloseReason::ImpliedByStartTag
+        } else {
+            ElementCloseReason::ImpliedByAncestorEndTag
+        };
+
+        let closed_count = self.closed_elements.len();
+
+        self.process(&token);
+
+        if !token.is_start {
+            let closed_elements = &mut self.closed_elements[closed_count..];
+
+            // NOTE: the end tag belongs to the first closed element with its name. Otherwise,
+            // it closes an element with a different name, e.g. `</h2>` closes `<h1>`, and
+            // the elements inside of it are closed first.
+            let idx = closed_elements
+                .iter()
+                .position(|closed| closed.element.is_html_named(&token.name))
+                .or_else(|| closed_elements.len().checked_sub(1));
+
+            if let Some(idx) = idx {
+                closed_elements[idx].reason = ElementCloseReason::EndTag;
+            }
+        }
+
+        self.inserted_element_id = self
+            .token_element_id
+            .filter(|&id| self.open_elements.last().is_some_and(|e| e.id == id));
+
+        self.charge_memory_usage()?;
+
+        Ok(match self.text_type_switch.take() {
+            Some(text_type) => TreeBuilderFeedback::SwitchTextType(text_type),
+            None => TreeBuilderFeedback::SetAllowCdata(
+                self.open_elements
+                    .last()
+                    .is_some_and(|node| node.ns != Namespace::Html),
+            ),
+        })
+    }
+
+    /// Brings memory charged to the limiter in line with the capacity of the element stacks
+    /// and the names of their elements.
+    fn charge_memory_usage(&mut self) -> Result<(), MemoryLimitExceededError> {
+        let usage = self.template_modes.capacity() * size_of::<InsertionMode>()
+            + self.open_elements.memory_usage()
+            + self.active_formatting_elements.memory_usage()
+            + self.closed_elements.capacity() * size_of::<ClosedElement>()
+            + self
+                .closed_elements
+                .iter()
+                .map(|closed| closed.element.name_byte_count())
+                .sum::<usize>();
+
+        if usage > self.charged_memory {
+            self.memory_limiter
+                .increase_usage(usage - self.charged_memory)?;
+        } else {
+            self.memory_limiter
+                .decrease_usage(self.charged_memory - usage);
+        }
+
+        self.charged_memory = usage;
+
+        Ok(())
+    }
+
+    /// Passes the ids of the elements removed from the stack of open elements since the
+    /// previous call along with the reasons of their removal, and the id of the element
+    /// that was inserted for the last processed start tag if it's open, to the `handler`.
+    ///
+    /// The elements that text can't be inside of, e.g. `<head>`, are removed as if they
+    /// were implicitly closed by the next start tag.
+    pub fn take_open_elements_change<R>(
+        &mut self,
+        handler: impl FnOnce(&mut dyn Iterator<Item = (u64, ElementCloseReason)>, Option<u64>) -> R,
+    ) -> R {
+        let result = handler(
+            &mut self
+                .closed_elements
+                .iter()
+                .map(|closed| (closed.element.id, closed.reason)),
+            self.inserted_element_id.take(),
+        );
+
+        self.closed_elements.clear();
+
+        result
+    }
+
+    pub fn process_text(&mut self, text: &[u8]) -> Result<(), MemoryLimitExceededError> {
+        self.close_reason = ElementCloseReason::ImpliedByStartTag;
+
+        let has_non_whitespace = text.iter().any(|&b| !is_whitespace(b));
+
+        let is_foreign = self.open_elements.last().is_some_and(|node| {
+            node.ns != Namespace::Html
+                && !node.is_math_ml_text_integration_point()
+                && !node.is_html_integration_point
+        });
+
+        if is_foreign {
+            if has_non_whitespace {
+                self.frameset_ok = false;
+            }
+        } else {
+            self.process_text_in_mode(self.mode, has_non_whitespace);
+        }
+
+        // NOTE: the reconstruction of the active formatting elements and the elements that
+        // are implicitly inserted before the text grow the stacks.
+        self.charge_memory_usage()
+    }
+
+    pub fn process_doctype(&mut self, name: Option<&[u8]>, force_quirks: bool) {
+        if self.mode == InsertionMode::Initial {
+            self.quirks_mode =
+                force_quirks || !name.is_some_and(|n| eq_case_insensitive(n, b"html"));
+            self.mode = InsertionMode::BeforeHtml;
+        }
+    }
+
+    fn process_text_in_mode(&mut self, mode: InsertionMode, has_non_whitespace: bool) {
+        use InsertionMode::*;
+
+        match mode {
+            Initial | BeforeHtml | BeforeHead | InHead | AfterHead if has_non_whitespace => {
+                self.anything_else(mode);
+                self.process_text_in_mode(self.mode, has_non_whitespace);
+            }
+            InBody | InCaption | InCell | InTemplate => {
+                self.process_text_in_body(has_non_whitespace);
+            }
+            InTable | InTableBody | InRow => {
+                let is_table_text = self.open_elements.last().is_some_and(|node| {
+                    node.ns == Namespace::Html
+                        && tag_is_one_of!(node.name, [Table, Tbody, Tfoot, Thead, Tr])
+                });
+
+                // NOTE: whitespace-only table text is inserted as is, everything else
+                // is foster parented using the rules for the "in body" insertion mode.
+                if !is_table_text || has_non_whitespace {
+                    self.process_text_in_body(has_non_whitespace);
+                }
+            }
+            InColumnGroup if has_non_whitespace && self.current_node_is(Tag::Colgroup) => {
+                self.pop();
+                self.mode = InTable;
+                self.process_text_in_mode(InTable, has_non_whitespace);
+            }
+            AfterBody | AfterAfterBody => {
+                self.process_text_in_body(has_non_whitespace);
+
+                if has_non_whitespace {
+                    self.mode = InBody;
+                }
+            }
+            AfterAfterFrameset => self.reconstruct_active_formatting_elements(),
+            _ => (),
+        }
+    }
+
+    fn process_text_in_body(&mut self, has_non_whitespace: bool) {
+        self.reconstruct_active_formatting_elements();
+
+        if has_non_whitespace {
+            self.frameset_ok = false;
+        }
+    }
+
+    // Tree construction dispatcher
+    fn process(&mut self, token: &TagToken<'_, '_>) {
+        if self.should_use_foreign_content_rules(token) {
+            self.process_in_foreign_content(token);
+        } else {
+            self.process_in_mode(self.mode, token);
+        }
+    }
+
+    fn should_use_foreign_content_rules(&self, token: &TagToken<'_, '_>) -> bool {
+        let Some(node) = self.open_elements.last() else {
+            return false;
+        };
+
+        if node.ns == Namespace::Html {
+            return false;
+        }
+
+        if token.is_start {
+            if node.is_math_ml_text_integration_point()
+                && !tag_is_one_of!(token.name, [Mglyph, Malignmark])
+            {
+                return false;
+            }
+
+            if node.is_annotation_xml() && token.name == Tag::Svg {
+                return false;
+            }
+
+            if node.is_html_integration_point {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    #[inline]
+    fn reprocess(&mut self, mode: InsertionMode, token: &TagToken<'_, '_>) {
+        self.mode = mode;
+        self.process(token);
+    }
+
+    fn process_in_mode(&mut self, mode: InsertionMode, token: &TagToken<'_, '_>) {
+        use InsertionMode::*;
+
+        match mode {
+            Initial => self.reprocess(BeforeHtml, token),
+            BeforeHtml => self.process_before_html(token),
+            BeforeHead => self.process_before_head(token),
+            InHead => self.process_in_head(token),
+            AfterHead => self.process_after_head(token),
+            InBody => self.process_in_body(token),
+            Text => self.process_in_text(token),
+            InTable => self.process_in_table(token),
+            InCaption => self.process_in_caption(token),
+            InColumnGroup => self.process_in_column_group(token),
+            InTableBody => self.process_in_table_body(token),
+            InRow => self.process_in_row(token),
+            InCell => self.process_in_cell(token),
+            InTemplate => self.process_in_template(token),
+            AfterBody => self.process_after_body(token),
+            InFrameset => self.process_in_frameset(token),
+            AfterFrameset => self.process_after_frameset(token),
+            AfterAfterBody => self.process_after_after_body(token),
+            AfterAfterFrameset => self.process_after_after_frameset(token),
+        }
+    }
+
+    // "Anything else" branches of the initial insertion modes that are
+    // shared between tags and text.
+    fn anything_else(&mut self, mode: InsertionMode) {
+        use InsertionMode::*;
+
+        match mode {
+            Initial => self.mode = BeforeHtml,
+            BeforeHtml => {
+                self.insert_phantom(Tag::Html);
+                self.mode = BeforeHead;
+            }
+            BeforeHead => {
+                self.head_element = Some(self.insert_phantom(Tag::Head));
+                self.mode = InHead;
+            }
+            InHead => {
+                self.pop();
+                self.mode = AfterHead;
+            }
+            AfterHead => {
+                self.insert_phantom(Tag::Body);
+                self.mode = InBody;
+            }
+            _ => debug_assert!(false, "Unexpected insertion mode"),
+        }
+    }
+
+    fn process_before_html(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.insert_html_element_for(token);
+            self.mode = InsertionMode::BeforeHead;
+        } else if token.is_start || end_tag_is_one_of!(token, [Head, Body, Html, Br]) {
+            self.anything_else(InsertionMode::BeforeHtml);
+            self.process(token);
+        }
+    }
+
+    fn process_before_head(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else if start_tag_is_one_of!(token, [Head]) {
+            self.head_element = Some(self.insert_html_element_for(token));
+            self.mode = InsertionMode::InHead;
+        } else if token.is_start || end_tag_is_one_of!(token, [Head, Body, Html, Br]) {
+            self.anything_else(InsertionMode::BeforeHead);
+            self.process(token);
+        }
+    }
+
+    fn process_in_head(&mut self, token: &TagToken<'_, '_>) {
+        if token.is_start {
+            if token.name == Tag::Html {
+                self.process_in_body(token);
+            } else if tag_is_one_of!(token.name, [Base, Basefont, Bgsound, Link, Meta]) {
+                self.insert_and_pop_html_element_for(token);
+            } else if token.name == Tag::Title {
+                self.parse_raw_data(token, TextType::RCData);
+            } else if tag_is_one_of!(token.name, [Noframes, Style, Noscript]) {
+                self.parse_raw_data(token, TextType::RawText);
+            } else if token.name == Tag::Script {
+                self.parse_raw_data(token, TextType::ScriptData);
+            } else if token.name == Tag::Template {
+                self.insert_html_element_for(token);
+                self.active_formatting_elements
+                    .push(FormattingEntry::Marker);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InTemplate;
+                self.template_modes.push(InsertionMode::InTemplate);
+            } else if token.name != Tag::Head {
+                self.anything_else(InsertionMode::InHead);
+                self.process(token);
+            }
+        } else if token.name == Tag::Head {
+            self.pop();
+            self.mode = InsertionMode::AfterHead;
+        } else if tag_is_one_of!(token.name, [Body, Html, Br]) {
+            self.anything_else(InsertionMode::InHead);
+            self.process(token);
+        } else if token.name == Tag::Template && self.is_in_stack(Tag::Template) {
+            self.generate_implied_end_tags(true, None);
+            self.pop_until(|e| e.is_html(Tag::Template));
+            self.clear_active_formatting_elements_to_marker();
+            self.template_modes.pop();
+            self.reset_insertion_mode();
+        }
+    }
+
+    fn process_after_head(&mut self, token: &TagToken<'_, '_>) {
+        if token.is_start {
+            if token.name == Tag::Html {
+                self.process_in_body(token);
+            } else if token.name == Tag::Body {
+                self.insert_html_element_for(token);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InBody;
+            } else if token.name == Tag::Frameset {
+                self.insert_html_element_for(token);
+                self.mode = InsertionMode::InFrameset;
+            } else if tag_is_one_of!(
+                token.name,
+                [
+                    Base, Basefont, Bgsound, Link, Meta, Noframes, Script, Style, Template, Title
+                ]
+            ) {
+                match self.head_element.clone() {
+                    Some(head) => {
+                        let head_id = head.id;
+
+                        self.open_elements.push(head);
+                        self.process_in_head(token);
+                        self.remove_from_stack(head_id);
+                    }
+                    None => {
+                        debug_assert!(false, "Head element pointer should be set at this point");
+                        self.process_in_head(token);
+                    }
+                }
+            } else if token.name != Tag::Head {
+                self.anything_else(InsertionMode::AfterHead);
+                self.process(token);
+            }
+        } else if token.name == Tag::Template {
+            self.process_in_head(token);
+        } else if tag_is_one_of!(token.name, [Body, Html, Br]) {
+            self.anything_else(InsertionMode::AfterHead);
+            self.process(token);
+        }
+    }
+
+    fn process_in_body(&mut self, token: &TagToken<'_, '_>) {
+        if token.is_start {
+            self.process_start_tag_in_body(token);
+        } else {
+            self.process_end_tag_in_body(token);
+        }
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn process_start_tag_in_body(&mut self, token: &TagToken<'_, '_>) {
+        let name = &token.name;
+
+        if *name == Tag::Html {
+            // NOTE: attributes are merged to the existing <html> element, ignore.
+        } else if tag_is_one_of!(
+            *name,
+            [
+                Base, Basefont, Bgsound, Link, Meta, Noframes, Script, Style, Template, Title
+            ]
+        ) {
+            self.process_in_head(token);
+        } else if *name == Tag::Body {
+            if self.body_element_is_second_in_stack() && !self.is_in_stack(Tag::Template) {
+                self.frameset_ok = false;
+            }
+        } else if *name == Tag::Frameset {
+            if self.body_element_is_second_in_stack() && self.frameset_ok {
+                self.truncate_stack(1);
+                self.insert_html_element_for(token);
+                self.mode = InsertionMode::InFrameset;
+            }
+        } else if tag_is_one_of!(
+            *name,
+            [
+                Address, Article, Aside, Blockquote, Center, Details, Dialog, Dir, Div, Dl,
+                Fieldset, Figcaption, Figure, Footer, Header, Hgroup, Main, Menu, Nav, Ol, P,
+                Search, Section, Summary, Ul
+            ]
+        ) {
+            self.close_p_element_in_button_scope();
+            self.insert_html_element_for(token);
+        } else if tag_is_one_of!(*name, [H1, H2, H3, H4, H5, H6]) {
+            self.close_p_element_in_button_scope();
+
+            if self
+                .open_elements
+                .last()
+                .is_some_and(OpenElement::is_heading)
+            {
+                self.pop();
+            }
+
+            self.insert_html_element_for(token);
+        } else if tag_is_one_of!(*name, [Pre, Listing]) {
+            self.close_p_element_in_button_scope();
+            self.insert_html_element_for(token);
+            self.frameset_ok = false;
+        } else if *name == Tag::Form {
+            let is_in_template = self.is_in_stack(Tag::Template);
+
+            if self.form_element_id.is_none() || is_in_template {
+                self.close_p_element_in_button_scope();
+
+                let form = self.insert_html_element_for(token);
+
+                if !is_in_template {
+                    self.form_element_id = Some(form.id);
+                }
+            }
+        } else if tag_is_one_of!(*name, [Li, Dd, Dt]) {
+            self.frameset_ok = false;
+
+            let close_target = self.open_elements.iter().rev().find_map(|node| {
+                let can_close = if *name == Tag::Li {
+                    node.is_html(Tag::Li)
+                } else {
+                    node.is_html(Tag::Dd) || node.is_html(Tag::Dt)
+                };
+
+                if can_close {
+                    Some(Some(node.name.clone()))
+                } else if node.is_special()
+                    && !(node.is_html(Tag::Address)
+                        || node.is_html(Tag::Div)
+                        || node.is_html(Tag::P))
+                {
+                    Some(None)
+                } else {
+                    None
+                }
+            });
+
+            if let Some(Some(target)) = close_target {
+                self.generate_implied_end_tags(false, Some(&target));
+                self.pop_until(|e| e.is_html_named(&target));
+            }
+
+            self.close_p_element_in_button_scope();
+            self.insert_html_element_for(token);
+        } else if *name == Tag::Plaintext {
+            self.close_p_element_in_button_scope();
+            self.insert_html_element_for(token);
+            self.text_type_switch = Some(TextType::PlainText);
+        } else if *name == Tag::Button {
+            if self.is_in_scope(Scope::Default, Tag::Button) {
+                self.generate_implied_end_tags(false, None);
+                self.pop_until(|e| e.is_html(Tag::Button));
+            }
+
+            self.reconstruct_active_formatting_elements();
+            self.insert_html_element_for(token);
+            self.frameset_ok = false;
+        } else if *name == Tag::A {
+            self.handle_misnested_a_tags();
+            self.reconstruct_active_formatting_elements();
+            self.insert_formatting_element_for(token);
+        } else if tag_is_one_of!(
+            *name,
+            [B, Big, Code, Em, Font, I, S, Small, Strike, Strong, Tt, U]
+        ) {
+            self.reconstruct_active_formatting_elements();
+            self.insert_formatting_element_for(token);
+        } else if *name == Tag::Nobr {
+            self.reconstruct_active_formatting_elements();
+
+            if self.is_in_scope(Scope::Default, Tag::Nobr) {
+                self.run_adoption_agency(&token.name);
+                self.reconstruct_active_formatting_elements();
+            }
+
+            self.insert_formatting_element_for(token);
+        } else if tag_is_one_of!(*name, [Applet, Marquee, Object]) {
+            self.reconstruct_active_formatting_elements();
+            self.insert_html_element_for(token);
+            self.active_formatting_elements
+                .push(FormattingEntry::Marker);
+            self.frameset_ok = false;
+        } else if *name == Tag::Table {
+            if !self.quirks_mode {
+                self.close_p_element_in_button_scope();
+            }
+
+            self.insert_html_element_for(token);
+            self.frameset_ok = false;
+            self.mode = InsertionMode::InTable;
+        } else if tag_is_one_of!(*name, [Area, Br, Embed, Img, Image, Keygen, Wbr]) {
+            self.reconstruct_active_formatting_elements();
+            self.insert_and_pop_html_element_for(token);
+            self.frameset_ok = false;
+        } else if *name == Tag::Input {
+            if self.is_in_scope(Scope::Default, Tag::Select) {
+                self.pop_until(|e| e.is_html(Tag::Select));
+            }
+
+            self.reconstruct_active_formatting_elements();
+            self.insert_and_pop_html_element_for(token);
+
+            if !token
+                .attribute(b"type")
+                .is_some_and(|value| eq_case_insensitive(&value, b"hidden"))
+            {
+                self.frameset_ok = false;
+            }
+        } else if tag_is_one_of!(*name, [Param, Source, Track]) {
+            self.insert_and_pop_html_element_for(token);
+        } else if *name == Tag::Hr {
+            self.close_p_element_in_button_scope();
+
+            if self.is_in_scope(Scope::Default, Tag::Select) {
+                self.generate_implied_end_tags(false, None);
+            }
+
+            self.insert_and_pop_html_element_for(token);
+            self.frameset_ok = false;
+        } else if *name == Tag::Textarea {
+            self.frameset_ok = false;
+            self.parse_raw_data(token, TextType::RCData);
+        } else if *name == Tag::Xmp {
+            self.close_p_element_in_button_scope();
+            self.reconstruct_active_formatting_elements();
+            self.frameset_ok = false;
+            self.parse_raw_data(token, TextType::RawText);
+        } else if *name == Tag::Iframe {
+            self.frameset_ok = false;
+            self.parse_raw_data(token, TextType::RawText);
+        } else if tag_is_one_of!(*name, [Noembed, Noscript]) {
+            self.parse_raw_data(token, TextType::RawText);
+        } else if *name == Tag::Select {
+            if self.is_in_scope(Scope::Default, Tag::Select) {
+                self.pop_until(|e| e.is_html(Tag::Select));
+            } else {
+                self.reconstruct_active_formatting_elements();
+                self.insert_html_element_for(token);
+                self.frameset_ok = false;
+            }
+        } else if tag_is_one_of!(*name, [Option, Optgroup]) {
+            if self.is_in_scope(Scope::Default, Tag::Select) {
+                let except = (*name == Tag::Option).then(|| LocalName::Hash(Tag::Optgroup.into()));
+
+                self.generate_implied_end_tags(false, except.as_ref());
+            } else if self.current_node_is(Tag::Option) {
+                self.pop();
+            }
+
+            self.reconstruct_active_formatting_elements();
+            self.insert_html_element_for(token);
+        } else if tag_is_one_of!(*name, [Rb, Rtc]) {
+            if self.is_in_scope(Scope::Default, Tag::Ruby) {
+                self.generate_implied_end_tags(false, None);
+            }
+
+            self.insert_html_element_for(token);
+        } else if tag_is_one_of!(*name, [Rp, Rt]) {
+            if self.is_in_scope(Scope::Default, Tag::Ruby) {
+                self.generate_implied_end_tags(false, Some(&LocalName::Hash(Tag::Rtc.into())));
+            }
+
+            self.insert_html_element_for(token);
+        } else if *name == Tag::Math {
+            self.reconstruct_active_formatting_elements();
+            self.insert_foreign_element_for(token, Namespace::MathML);
+        } else if *name == Tag::Svg {
+            self.reconstruct_active_formatting_elements();
+            self.insert_foreign_element_for(token, Namespace::Svg);
+        } else if tag_is_one_of!(
+            *name,
+            [
+                Caption, Col, Colgroup, Frame, Head, Tbody, Td, Tfoot, Th, Thead, Tr
+            ]
+        ) {
+            // NOTE: ignore the token.
+        } else {
+            self.reconstruct_active_formatting_elements();
+            self.insert_html_element_for(token);
+        }
+    }
+
+    fn process_end_tag_in_body(&mut self, token: &TagToken<'_, '_>) {
+        let name = &token.name;
+
+        if *name == Tag::Template {
+            self.process_in_head(token);
+        } else if *name == Tag::Body {
+            if self.is_in_scope(Scope::Default, Tag::Body) {
+                self.mode = InsertionMode::AfterBody;
+            }
+        } else if *name == Tag::Html {
+            if self.is_in_scope(Scope::Default, Tag::Body) {
+                self.reprocess(InsertionMode::AfterBody, token);
+            }
+        } else if tag_is_one_of!(
+            *name,
+            [
+                Address, Article, Aside, Blockquote, Button, Center, Details, Dialog, Dir, Div, Dl,
+                Fieldset, Figcaption, Figure, Footer, Header, Hgroup, Listing, Main, Menu, Nav, Ol,
+                Pre, Search, Section, Select, Summary, Ul
+            ]
+        ) {
+            if self.is_in_scope_by(Scope::Default, |e| e.is_html_named(name)) {
+                self.generate_implied_end_tags(false, None);
+                self.pop_until(|e| e.is_html_named(name));
+            }
+        } else if *name == Tag::Form {
+            if self.is_in_stack(Tag::Template) {
+                if self.is_in_scope(Scope::Default, Tag::Form) {
+                    self.generate_implied_end_tags(false, None);
+                    self.pop_until(|e| e.is_html(Tag::Form));
+                }
+            } else if let Some(form_id) = self.form_element_id.take() {
+                if self.is_in_scope_by(Scope::Default, |e| e.id == form_id) {
+                    self.generate_implied_end_tags(false, None);
+                    self.remove_from_stack(form_id);
+                }
+            }
+        } else if *name == Tag::P {
+            if !self.is_in_scope(Scope::Button, Tag::P) {
+                self.insert_phantom(Tag::P);
+            }
+
+            self.close_p_element();
+        } else if *name == Tag::Li {
+            if self.is_in_scope(Scope::ListItem, Tag::Li) {
+                self.generate_implied_end_tags(false, Some(name));
+                self.pop_until(|e| e.is_html(Tag::Li));
+            }
+        } else if tag_is_one_of!(*name, [Dd, Dt]) {
+            if self.is_in_scope_by(Scope::Default, |e| e.is_html_named(name)) {
+                self.generate_implied_end_tags(false, Some(name));
+                self.pop_until(|e| e.is_html_named(name));
+            }
+        } else if tag_is_one_of!(*name, [H1, H2, H3, H4, H5, H6]) {
+            if self.is_in_scope_by(Scope::Default, OpenElement::is_heading) {
+                self.generate_implied_end_tags(false, None);
+                self.pop_until(OpenElement::is_heading);
+            }
+        } else if tag_is_one_of!(
+            *name,
+            [
+                A, B, Big, Code, Em, Font, I, Nobr, S, Small, Strike, Strong, Tt, U
+            ]
+        ) {
+            self.run_adoption_agency(name);
+        } else if tag_is_one_of!(*name, [Applet, Marquee, Object]) {
+            if self.is_in_scope_by(Scope::Default, |e| e.is_html_named(name)) {
+                self.generate_implied_end_tags(false, None);
+                self.pop_until(|e| e.is_html_named(name));
+                self.clear_active_formatting_elements_to_marker();
+            }
+        } else if *name == Tag::Br {
+            // NOTE: `</br>` is treated as `<br>`.
+            self.reconstruct_active_formatting_elements();
+            self.frameset_ok = false;
+        } else {
+            self.process_any_other_end_tag_in_body(name);
+        }
+    }
+
+    fn process_any_other_end_tag_in_body(&mut self, name: &LocalName<'_>) {
+        for idx in (0..self.open_elements.len()).rev() {
+            let node = &self.open_elements[idx];
+
+            if node.is_html_named(name) {
+                self.generate_implied_end_tags(false, Some(name));
+                self.truncate_stack(idx);
+                return;
+            }
+
+            if node.is_special() {
+                return;
+            }
+        }
+    }
+
+    fn process_in_text(&mut self, token: &TagToken<'_, '_>) {
+        // NOTE: the tokenizer in the text parsing state emits only the
+        // appropriate end tag that closes the current node.
+        debug_assert!(
+            !token.is_start,
+            "Only end tags are expected in the text mode"
+        );
+
+        if !token.is_start {
+            self.pop();
+            self.mode = self.original_mode;
+        }
+    }
+
+    fn process_in_table(&mut self, token: &TagToken<'_, '_>) {
+        let name = &token.name;
+
+        if token.is_start {
+            if *name == Tag::Caption {
+                self.clear_stack_back_to_table_context();
+                self.active_formatting_elements
+                    .push(FormattingEntry::Marker);
+                self.insert_html_element_for(token);
+                self.mode = InsertionMode::InCaption;
+            } else if *name == Tag::Colgroup {
+                self.clear_stack_back_to_table_context();
+                self.insert_html_element_for(token);
+                self.mode = InsertionMode::InColumnGroup;
+            } else if *name == Tag::Col {
+                self.clear_stack_back_to_table_context();
+                self.insert_phantom(Tag::Colgroup);
+                self.reprocess(InsertionMode::InColumnGroup, token);
+            } else if tag_is_one_of!(*name, [Tbody, Tfoot, Thead]) {
+                self.clear_stack_back_to_table_context();
+                self.insert_html_element_for(token);
+                self.mode = InsertionMode::InTableBody;
+            } else if tag_is_one_of!(*name, [Td, Th, Tr]) {
+                self.clear_stack_back_to_table_context();
+                self.insert_phantom(Tag::Tbody);
+                self.reprocess(InsertionMode::InTableBody, token);
+            } else if *name == Tag::Table {
+                if self.is_in_scope(Scope::Table, Tag::Table) {
+                    self.pop_until(|e| e.is_html(Tag::Table));
+                    self.reset_insertion_mode();
+                    self.process(token);
+                }
+            } else if tag_is_one_of!(*name, [Style, Script, Template]) {
+                self.process_in_head(token);
+            } else if *name == Tag::Input
+                && token
+                    .attribute(b"type")
+                    .is_some_and(|value| eq_case_insensitive(&value, b"hidden"))
+            {
+                self.insert_and_pop_html_element_for(token);
+            } else if *name == Tag::Form {
+                if self.form_element_id.is_none() && !self.is_in_stack(Tag::Template) {
+                    self.form_element_id = Some(self.insert_and_pop_html_element_for(token).id);
+                }
+            } else {
+                // NOTE: foster parenting doesn't affect the stack of open elements.
+                self.process_in_body(token);
+            }
+        } else if *name == Tag::Table {
+            if self.is_in_scope(Scope::Table, Tag::Table) {
+                self.pop_until(|e| e.is_html(Tag::Table));
+                self.reset_insertion_mode();
+            }
+        } else if *name == Tag::Template {
+            self.process_in_head(token);
+        } else if !tag_is_one_of!(
+            *name,
+            [
+                Body, Caption, Col, Colgroup, Html, Tbody, Td, Tfoot, Th, Thead, Tr
+            ]
+        ) {
+            self.process_in_body(token);
+        }
+    }
+
+    fn process_in_caption(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(
+            token,
+            [Caption, Col, Colgroup, Tbody, Td, Tfoot, Th, Thead, Tr]
+        ) || end_tag_is_one_of!(token, [Table, Caption])
+        {
+            if self.is_in_scope(Scope::Table, Tag::Caption) {
+                self.generate_implied_end_tags(false, None);
+                self.pop_until(|e| e.is_html(Tag::Caption));
+                self.clear_active_formatting_elements_to_marker();
+
+                if end_tag_is_one_of!(token, [Caption]) {
+                    self.mode = InsertionMode::InTable;
+                } else {
+                    self.reprocess(InsertionMode::InTable, token);
+                }
+            }
+        } else if !end_tag_is_one_of!(
+            token,
+            [Body, Col, Colgroup, Html, Tbody, Td, Tfoot, Th, Thead, Tr]
+        ) {
+            self.process_in_body(token);
+        }
+    }
+
+    fn process_in_column_group(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else if start_tag_is_one_of!(token, [Col]) {
+            self.insert_and_pop_html_element_for(token);
+        } else if end_tag_is_one_of!(token, [Colgroup]) {
+            if self.current_node_is(Tag::Colgroup) {
+                self.pop();
+                self.mode = InsertionMode::InTable;
+            }
+        } else if token.name == Tag::Template {
+            self.process_in_head(token);
+        } else if !end_tag_is_one_of!(token, [Col]) && self.current_node_is(Tag::Colgroup) {
+            self.pop();
+            self.reprocess(InsertionMode::InTable, token);
+        }
+    }
+
+    fn process_in_table_body(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Tr]) {
+            self.clear_stack_back_to_table_body_context();
+            self.insert_html_element_for(token);
+            self.mode = InsertionMode::InRow;
+        } else if start_tag_is_one_of!(token, [Th, Td]) {
+            self.clear_stack_back_to_table_body_context();
+            self.insert_phantom(Tag::Tr);
+            self.reprocess(InsertionMode::InRow, token);
+        } else if end_tag_is_one_of!(token, [Tbody, Tfoot, Thead]) {
+            if self.is_in_scope_by(Scope::Table, |e| e.is_html_named(&token.name)) {
+                self.clear_stack_back_to_table_body_context();
+                self.pop();
+                self.mode = InsertionMode::InTable;
+            }
+        } else if start_tag_is_one_of!(token, [Caption, Col, Colgroup, Tbody, Tfoot, Thead])
+            || end_tag_is_one_of!(token, [Table])
+        {
+            if self.is_in_scope_by(Scope::Table, |e| {
+                e.is_html(Tag::Tbody) || e.is_html(Tag::Thead) || e.is_html(Tag::Tfoot)
+            }) {
+                self.clear_stack_back_to_table_body_context();
+                self.pop();
+                self.reprocess(InsertionMode::InTable, token);
+            }
+        } else if !end_tag_is_one_of!(token, [Body, Caption, Col, Colgroup, Html, Td, Th, Tr]) {
+            self.process_in_table(token);
+        }
+    }
+
+    fn process_in_row(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Th, Td]) {
+            self.clear_stack_back_to_table_row_context();
+            self.insert_html_element_for(token);
+            self.mode = InsertionMode::InCell;
+            self.active_formatting_elements
+                .push(FormattingEntry::Marker);
+        } else if end_tag_is_one_of!(token, [Tr]) {
+            if self.is_in_scope(Scope::Table, Tag::Tr) {
+                self.clear_stack_back_to_table_row_context();
+                self.pop();
+                self.mode = InsertionMode::InTableBody;
+            }
+        } else if start_tag_is_one_of!(token, [Caption, Col, Colgroup, Tbody, Tfoot, Thead, Tr])
+            || end_tag_is_one_of!(token, [Table])
+        {
+            if self.is_in_scope(Scope::Table, Tag::Tr) {
+                self.clear_stack_back_to_table_row_context();
+                self.pop();
+                self.reprocess(InsertionMode::InTableBody, token);
+            }
+        } else if end_tag_is_one_of!(token, [Tbody, Tfoot, Thead]) {
+            if self.is_in_scope_by(Scope::Table, |e| e.is_html_named(&token.name))
+                && self.is_in_scope(Scope::Table, Tag::Tr)
+            {
+                self.clear_stack_back_to_table_row_context();
+                self.pop();
+                self.reprocess(InsertionMode::InTableBody, token);
+            }
+        } else if !end_tag_is_one_of!(token, [Body, Caption, Col, Colgroup, Html, Td, Th]) {
+            self.process_in_table(token);
+        }
+    }
+
+    fn process_in_cell(&mut self, token: &TagToken<'_, '_>) {
+        if end_tag_is_one_of!(token, [Td, Th]) {
+            if self.is_in_scope_by(Scope::Table, |e| e.is_html_named(&token.name)) {
+                self.generate_implied_end_tags(false, None);
+                self.pop_until(|e| e.is_html_named(&token.name));
+                self.clear_active_formatting_elements_to_marker();
+                self.mode = InsertionMode::InRow;
+            }
+        } else if start_tag_is_one_of!(
+            token,
+            [Caption, Col, Colgroup, Tbody, Td, Tfoot, Th, Thead, Tr]
+        ) {
+            if self.is_in_scope_by(Scope::Table, |e| e.is_html(Tag::Td) || e.is_html(Tag::Th)) {
+                self.close_the_cell();
+                self.reprocess(InsertionMode::InRow, token);
+            }
+        } else if end_tag_is_one_of!(token, [Table, Tbody, Tfoot, Thead, Tr]) {
+            if self.is_in_scope_by(Scope::Table, |e| e.is_html_named(&token.name)) {
+                self.close_the_cell();
+                self.reprocess(InsertionMode::InRow, token);
+            }
+        } else if !end_tag_is_one_of!(token, [Body, Caption, Col, Colgroup, Html]) {
+            self.process_in_body(token);
+        }
+    }
+
+    fn process_in_template(&mut self, token: &TagToken<'_, '_>) {
+        let name = &token.name;
+
+        if !token.is_start {
+            if *name == Tag::Template {
+                self.process_in_head(token);
+            }
+        } else if tag_is_one_of!(
+            *name,
+            [
+                Base, Basefont, Bgsound, Link, Meta, Noframes, Script, Style, Template, Title
+            ]
+        ) {
+            self.process_in_head(token);
+        } else {
+            let mode = if tag_is_one_of!(*name, [Caption, Colgroup, Tbody, Tfoot, Thead]) {
+                InsertionMode::InTable
+            } else if *name == Tag::Col {
+                InsertionMode::InColumnGroup
+            } else if *name == Tag::Tr {
+                InsertionMode::InTableBody
+            } else if tag_is_one_of!(*name, [Td, Th]) {
+                InsertionMode::InRow
+            } else {
+                InsertionMode::InBody
+            };
+
+            self.template_modes.pop();
+            self.template_modes.push(mode);
+            self.reprocess(mode, token);
+        }
+    }
+
+    fn process_after_body(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else if end_tag_is_one_of!(token, [Html]) {
+            self.mode = InsertionMode::AfterAfterBody;
+        } else {
+            self.reprocess(InsertionMode::InBody, token);
+        }
+    }
+
+    fn process_in_frameset(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else if start_tag_is_one_of!(token, [Frameset]) {
+            self.insert_html_element_for(token);
+        } else if end_tag_is_one_of!(token, [Frameset]) {
+            if self.open_elements.len() > 1 {
+                self.pop();
+
+                if !self.current_node_is(Tag::Frameset) {
+                    self.mode = InsertionMode::AfterFrameset;
+                }
+            }
+        } else if start_tag_is_one_of!(token, [Frame]) {
+            self.insert_and_pop_html_element_for(token);
+        } else if start_tag_is_one_of!(token, [Noframes]) {
+            self.process_in_head(token);
+        }
+    }
+
+    fn process_after_frameset(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else if end_tag_is_one_of!(token, [Html]) {
+            self.mode = InsertionMode::AfterAfterFrameset;
+        } else if start_tag_is_one_of!(token, [Noframes]) {
+            self.process_in_head(token);
+        }
+    }
+
+    fn process_after_after_body(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else {
+            self.reprocess(InsertionMode::InBody, token);
+        }
+    }
+
+    fn process_after_after_frameset(&mut self, token: &TagToken<'_, '_>) {
+        if start_tag_is_one_of!(token, [Html]) {
+            self.process_in_body(token);
+        } else if start_tag_is_one_of!(token, [Noframes]) {
+            self.process_in_head(token);
+        }
+    }
+
+    fn process_in_foreign_content(&mut self, token: &TagToken<'_, '_>) {
+        let name = &token.name;
+
+        let is_breakout = if token.is_start {
+            tag_is_one_of!(
+                *name,
+                [
+                    B, Big, Blockquote, Body, Br, Center, Code, Dd, Div, Dl, Dt, Em, Embed, H1, H2,
+                    H3, H4, H5, H6, Head, Hr, I, Img, Li, Listing, Menu, Meta, Nobr, Ol, P, Pre,
+                    Ruby, S, Small, Span, Strong, Strike, Sub, Sup, Table, Tt, U, Ul, Var
+                ]
+            ) || *name == Tag::Font
+                && [&b"color"[..], b"face", b"size"]
+                    .iter()
+                    .any(|attr| token.attribute(attr).is_some())
+        } else {
+            tag_is_one_of!(*name, [Br, P])
+        };
+
+        if is_breakout {
+            while self.open_elements.last().is_some_and(|node| {
+                node.ns != Namespace::Html
+                    && !node.is_math_ml_text_integration_point()
+                    && !node.is_html_integration_point
+            }) {
+                self.pop();
+            }
+
+            self.process_in_mode(self.mode, token);
+        } else if token.is_start {
+            let ns = self
+                .open_elements
+                .last()
+                .map_or(Namespace::Html, |node| node.ns);
+
+            self.insert_foreign_element_for(token, ns);
+        } else {
+            let mut idx = self.open_elements.len() - 1;
+
+            loop {
+                if idx == 0 {
+                    return;
+                }
+
+                if self.open_elements[idx].name == *name {
+                    self.truncate_stack(idx);
+                    return;
+                }
+
+                idx -= 1;
+
+                if self.open_elements[idx].ns == Namespace::Html {
+                    self.process_in_mode(self.mode, token);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Stack of open elements
+    #[inline]
+    fn create_element(&mut self, name: LocalName<'_>, ns: Namespace) -> OpenElement {
+        self.next_element_id += 1;
+
+        OpenElement {
+            id: self.next_element_id,
+            name: name.into_owned(),
+            ns,
+            is_html_integration_point: false,
+            attributes_hash: 0,
+        }
+    }
+
+    /// Creates a new element for the token the formatting `element` was created for.
+    fn recreate_formatting_element(&mut self, element: &OpenElement) -> OpenElement {
+        let mut new_element = self.create_element(element.name.clone(), Namespace::Html);
+
+        new_element.attributes_hash = element.attributes_hash;
+
+        new_element
+    }
+
+    fn insert_phantom(&mut self, tag: Tag) -> OpenElement {
+        let element = self.create_element(LocalName::Hash(tag.into()), Namespace::Html);
+
+        self.open_elements.push(element.clone());
+
+        element
+    }
+
+    fn insert_and_pop_html_element_for(&mut self, token: &TagToken<'_, '_>) -> OpenElement {
+        let element = self.create_element(token.name.clone(), Namespace::Html);
+
+        self.token_ns = Namespace::Html;
+        self.token_element_id = Some(element.id);
+
+        element
+    }
+
+    fn insert_html_element_for(&mut self, token: &TagToken<'_, '_>) -> OpenElement {
+        let element = self.insert_and_pop_html_element_for(token);
+
+        self.open_elements.push(element.clone());
+
+        element
+    }
+
+    fn insert_foreign_element_for(&mut self, token: &TagToken<'_, '_>, ns: Namespace) {
+        let mut element = self.create_element(token.name.clone(), ns);
+
+        element.is_html_integration_point = match ns {
+            Namespace::Svg => tag_is_one_of!(element.name, [ForeignObject, Desc, Title]),
+            Namespace::MathML => {
+                element.is_annotation_xml()
+                    && token.attribute(b"encoding").is_some_and(|value| {
+                        eq_case_insensitive(&value, b"text/html")
+                            || eq_case_insensitive(&value, b"application/xhtml+xml")
+                    })
+            }
+            Namespace::Html => false,
+        };
+
+        self.token_ns = ns;
+        self.token_element_id = Some(element.id);
+
+        if !token.self_closing {
+            self.open_elements.push(element);
+        }
+    }
+
+    fn parse_raw_data(&mut self, token: &TagToken<'_, '_>, text_type: TextType) {
+        self.insert_html_element_for(token);
+        self.original_mode = self.mode;
+        self.mode = InsertionMode::Text;
+        self.text_type_switch = Some(text_type);
+    }
+
+    #[inline]
+    fn close(&mut self, element: OpenElement) {
+        self.closed_elements.push(ClosedElement {
+            element,
+            reason: self.close_reason,
+        });
+    }
+
+    #[inline]
+    fn pop(&mut self) {
+        if let Some(node) = self.open_elements.pop() {
+            self.close(node);
+        }
+    }
+
+    fn pop_until(&mut self, predicate: impl Fn(&OpenElement) -> bool) {
+        while let Some(node) = self.open_elements.pop() {
+            let is_last = predicate(&node);
+
+            self.close(node);
+
+            if is_last {
+                break;
+            }
+        }
+    }
+
+    fn truncate_stack(&mut self, len: usize) {
+        while self.open_elements.len() > len {
+            self.pop();
+        }
+    }
+
+    fn remove_from_stack_at(&mut self, idx: usize) {
+        let node = self.open_elements.remove(idx);
+
+        self.close(node);
+    }
+
+    fn remove_from_stack(&mut self, id: u64) {
+        if let Some(idx) = self.open_elements.iter().rposition(|e| e.id == id) {
+            self.remove_from_stack_at(idx);
+        }
+    }
+
+    #[inline]
+    fn current_node_is(&self, tag: Tag) -> bool {
+        self.open_elements.last().is_some_and(|e| e.is_html(tag))
+    }
+
+    #[inline]
+    fn is_in_stack(&self, tag: Tag) -> bool {
+        self.open_elements.iter().any(|e| e.is_html(tag))
+    }
+
+    #[inline]
+    fn body_element_is_second_in_stack(&self) -> bool {
+        self.open_elements
+            .get(1)
+            .is_some_and(|e| e.is_html(Tag::Body))
+    }
+
+    fn is_in_scope_by(&self, scope: Scope, predicate: impl Fn(&OpenElement) -> bool) -> bool {
+        for node in self.open_elements.iter().rev() {
+            if predicate(node) {
+                return true;
+            }
+
+            if scope.is_boundary(node) {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    #[inline]
+    fn is_in_scope(&self, scope: Scope, tag: Tag) -> bool {
+        self.is_in_scope_by(scope, |e| e.is_html(tag))
+    }
+
+    fn generate_implied_end_tags(&mut self, thorough: bool, except: Option<&LocalName<'_>>) {
+        while let Some(node) = self.open_elements.last() {
+            let is_implied = if thorough {
+                node.is_in_thorough_implied_end_tag_set()
+            } else {
+                node.is_in_cursory_implied_end_tag_set()
+            };
+
+            if !is_implied || except.is_some_and(|name| node.is_html_named(name)) {
+                break;
+            }
+
+            self.pop();
+        }
+    }
+
+    fn close_p_element(&mut self) {
+        self.generate_implied_end_tags(false, Some(&LocalName::Hash(Tag::P.into())));
+        self.pop_until(|e| e.is_html(Tag::P));
+    }
+
+    #[inline]
+    fn close_p_element_in_button_scope(&mut self) {
+        if self.is_in_scope(Scope::Button, Tag::P) {
+            self.close_p_element();
+        }
+    }
+
+    fn close_the_cell(&mut self) {
+        self.generate_implied_end_tags(false, None);
+        self.pop_until(|e| e.is_html(Tag::Td) || e.is_html(Tag::Th));
+        self.clear_active_formatting_elements_to_marker();
+    }
+
+    fn clear_stack_back_to(&mut self, predicate: impl Fn(&OpenElement) -> bool) {
+        while self.open_elements.last().is_some_and(|e| !predicate(e)) {
+            self.pop();
+        }
+    }
+
+    #[inline]
+    fn clear_stack_back_to_table_context(&mut self) {
+        self.clear_stack_back_to(|e| {
+            e.ns == Namespace::Html && tag_is_one_of!(e.name, [Table, Template, Html])
+        });
+    }
+
+    #[inline]
+    fn clear_stack_back_to_table_body_context(&mut self) {
+        self.clear_stack_back_to(|e| {
+            e.ns == Namespace::Html && tag_is_one_of!(e.name, [Tbody, Tfoot, Thead, Template, Html])
+        });
+    }
+
+    #[inline]
+    fn clear_stack_back_to_table_row_context(&mut self) {
+        self.clear_stack_back_to(|e| {
+            e.ns == Namespace::Html && tag_is_one_of!(e.name, [Tr, Template, Html])
+        });
+    }
+
+    fn reset_insertion_mode(&mut self) {
+        use InsertionMode::*;
+
+        for (idx, node) in self.open_elements.iter().enumerate().rev() {
+            let last = idx == 0;
+
+            if node.ns != Namespace::Html {
+                continue;
+            }
+
+            let name = &node.name;
+
+            let mode = if tag_is_one_of!(*name, [Td, Th]) && !last {
+                InCell
+            } else if *name == Tag::Tr {
+                InRow
+            } else if tag_is_one_of!(*name, [Tbody, Thead, Tfoot]) {
+                InTableBody
+            } else if *name == Tag::Caption {
+                InCaption
+            } else if *name == Tag::Colgroup {
+                InColumnGroup
+            } else if *name == Tag::Table {
+                InTable
+            } else if *name == Tag::Template {
+                self.template_modes.last().copied().unwrap_or(InTemplate)
+            } else if *name == Tag::Head && !last {
+                InHead
+            } else if *name == Tag::Body {
+                InBody
+            } else if *name == Tag::Frameset {
+                InFrameset
+            } else if *name == Tag::Html {
+                if self.head_element.is_none() {
+                    BeforeHead
+                } else {
+                    AfterHead
+                }
+            } else {
+                continue;
+            };
+
+            self.mode = mode;
+            return;
+        }
+
+        self.mode = InBody;
+    }
+
+    // List of active formatting elements
+    fn insert_formatting_element_for(&mut self, token: &TagToken<'_, '_>) {
+        let mut element = self.insert_and_pop_html_element_for(token);
+
+        element.attributes_hash = token.attributes_hash(&self.attributes_hasher);
+        self.open_elements.push(element.clone());
+
+        let mut count = 0;
+        let mut identical_count = 0;
+        let mut earliest_identical_idx = None;
+
+        for (idx, entry) in self.active_formatting_elements.iter().enumerate().rev() {
+            match entry {
+                FormattingEntry::Marker => break,
+                FormattingEntry::Element(e) => {
+                    count += 1;
+
+                    if e.name == element.name && e.attributes_hash == element.attributes_hash {
+                        identical_count += 1;
+                        earliest_identical_idx = Some(idx);
+                    }
+                }
+            }
+        }
+
+        // NOTE: the "Noah's Ark" clause: there can be at most three elements with the same
+        // name and attributes after the last marker.
+        if let Some(idx) = earliest_identical_idx.filter(|_| identical_count >= 3) {
+            self.active_formatting_elements.remove(idx);
+        } else if count >= MAX_ACTIVE_FORMATTING_ELEMENTS {
+            let first_idx = self.active_formatting_elements.len() - count;
+
+            self.active_formatting_elements.remove(first_idx);
+        }
+
+        self.active_formatting_elements
+            .push(FormattingEntry::Element(element));
+    }
+
+    fn position_in_active_formatting_elements(&self, id: u64) -> Option<usize> {
+        self.active_formatting_elements
+            .iter()
+            .rposition(|entry| matches!(entry, FormattingEntry::Element(e) if e.id == id))
+    }
+
+    fn clear_active_formatting_elements_to_marker(&mut self) {
+        while let Some(entry) = self.active_formatting_elements.pop() {
+            if let FormattingEntry::Marker = entry {
+                break;
+            }
+        }
+    }
+
+    fn is_marker_or_open(&self, entry: &FormattingEntry) -> bool {
+        match entry {
+            FormattingEntry::Marker => true,
+            FormattingEntry::Element(e) => self.open_elements.iter().any(|o| o.id == e.id),
+        }
+    }
+
+    fn reconstruct_active_formatting_elements(&mut self) {
+        match self.active_formatting_elements.last() {
+            Some(entry) if !self.is_marker_or_open(entry) => (),
+            _ => return,
+        }
+
+        let mut idx = self.active_formatting_elements.len() - 1;
+
+        while idx > 0 {
+            if self.is_marker_or_open(&self.active_formatting_elements[idx - 1]) {
+                break;
+            }
+
+            idx -= 1;
+        }
+
+        for idx in idx..self.active_formatting_elements.len() {
+            if let FormattingEntry::Element(ref entry) = self.active_formatting_elements[idx] {
+                let entry = entry.clone();
+                let element = self.recreate_formatting_element(&entry);
+
+                self.open_elements.push(element.clone());
+                self.active_formatting_elements
+                    .replace(idx, FormattingEntry::Element(element));
+            }
+        }
+    }
+
+    fn find_formatting_element(&self, name: &LocalName<'_>) -> Option<(usize, OpenElement)> {
+        for (idx, entry) in self.active_formatting_elements.iter().enumerate().rev() {
+            match entry {
+                FormattingEntry::Marker => return None,
+                FormattingEntry::Element(e) if e.name == *name => return Some((idx, e.clone())),
+                FormattingEntry::Element(_) => (),
+            }
+        }
+
+        None
+    }
+
+    fn handle_misnested_a_tags(&mut self) {
+        let a = LocalName::Hash(Tag::A.into());
+
+        if let Some((_, element)) = self.find_formatting_element(&a) {
+            self.run_adoption_agency(&a);
+
+            if let Some(idx) = self.position_in_active_formatting_elements(element.id) {
+                self.active_formatting_elements.remove(idx);
+            }
+
+            self.remove_from_stack(element.id);
+        }
+    }
+
+    // NOTE: the adoption agency algorithm mostly deals with the reparenting of nodes in the
+    // DOM tree, here we keep only its effects on the stack of open elements and the list of
+    // active formatting elements.
+    fn run_adoption_agency(&mut self, subject: &LocalName<'_>) {
+        let is_current_node_not_formatting = self.open_elements.last().is_some_and(|current| {
+            current.is_html_named(subject)
+                && self
+                    .position_in_active_formatting_elements(current.id)
+                    .is_none()
+        });
+
+        if is_current_node_not_formatting {
+            self.pop();
+            return;
+        }
+
+        for _ in 0..8 {
+            let Some((formatting_idx, formatting_element)) = self.find_formatting_element(subject)
+            else {
+                self.process_any_other_end_tag_in_body(subject);
+                return;
+            };
+
+            let Some(formatting_stack_idx) = self
+                .open_elements
+                .iter()
+                .rposition(|e| e.id == formatting_element.id)
+            else {
+                self.active_formatting_elements.remove(formatting_idx);
+                return;
+            };
+
+            if !self.is_in_scope_by(Scope::Default, |e| e.id == formatting_element.id) {
+                return;
+            }
+
+            let Some(furthest_block_idx) = self
+                .open_elements
+                .iter()
+                .enumerate()
+                .skip(formatting_stack_idx + 1)
+                .find(|(_, e)| e.is_special())
+                .map(|(idx, _)| idx)
+            else {
+                self.truncate_stack(formatting_stack_idx);
+                self.active_formatting_elements.remove(formatting_idx);
+                return;
+            };
+
+            let furthest_block_id = self.open_elements[furthest_block_idx].id;
+            let mut bookmark = Bookmark::Replace(formatting_element.id);
+            let mut node_idx = furthest_block_idx;
+            let mut last_node_id = furthest_block_id;
+            let mut inner_loop_counter = 0;
+
+            loop {
+                inner_loop_counter += 1;
+                node_idx -= 1;
+
+                let node_id = self.open_elements[node_idx].id;
+
+                if node_id == formatting_element.id {
+                    break;
+                }
+
+                let node_formatting_idx = self.position_in_active_formatting_elements(node_id);
+
+                if let Some(idx) = node_formatting_idx.filter(|_| inner_loop_counter > 3) {
+                    self.active_formatting_elements.remove(idx);
+                    self.remove_from_stack_at(node_idx);
+                    continue;
+                }
+
+                let Some(node_formatting_idx) = node_formatting_idx else {
+                    self.remove_from_stack_at(node_idx);
+                    continue;
+                };
+
+                let node = self.open_elements[node_idx].clone();
+                let element = self.recreate_formatting_element(&node);
+
+                if last_node_id == furthest_block_id {
+                    bookmark = Bookmark::InsertAfter(element.id);
+                }
+
+                last_node_id = element.id;
+                let node = self.open_elements.replace(node_idx, element.clone());
+
+                self.close(node);
+                self.active_formatting_elements
+                    .replace(node_formatting_idx, FormattingEntry::Element(element));
+            }
+
+            let element = self.recreate_formatting_element(&formatting_element);
+
+            match bookmark {
+                Bookmark::Replace(id) => {
+                    if let Some(idx) = self.position_in_active_formatting_elements(id) {
+                        self.active_formatting_elements
+                            .replace(idx, FormattingEntry::Element(element.clone()));
+                    }
+                }
+                Bookmark::InsertAfter(id) => {
+                    if let Some(idx) = self.position_in_active_formatting_elements(id) {
+                        self.active_formatting_elements
+                            .insert(idx + 1, FormattingEntry::Element(element.clone()));
+                    }
+
+                    if let Some(idx) =
+                        self.position_in_active_formatting_elements(formatting_element.id)
+                    {
+                        self.active_formatting_elements.remove(idx);
+                    }
+                }
+            }
+
+            self.remove_from_stack(formatting_element.id);
+
+            if let Some(idx) = self
+                .open_elements
+                .iter()
+                .position(|e| e.id == furthest_block_id)
+            {
+                self.open_elements.insert(idx + 1, element);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::Range;
+    use crate::html::LocalNameHash;
+    use crate::parser::{AttributeBuffer, AttributeOutline};
+
+    fn new_tree_builder() -> TreeBuilder {
+        TreeBuilder::new(SharedMemoryLimiter::new(usize::MAX))
+    }
+
+    /// Processes the tags, an end tag is denoted with a leading `/`. A start tag can have
+    /// a single attribute, e.g. `b id=1`.
+    fn process_tags(tree_builder: &mut TreeBuilder, tags: &[&str]) {
+        for tag in tags {
+            process_tag(tree_builder, tag);
+
+            // NOTE: the lexer takes the change after each tag.
+            tree_builder.take_open_elements_change(|_, _| {});
+        }
+    }
+
+    fn process_tag(tree_builder: &mut TreeBuilder, tag: &str) {
+        let (is_start, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (false, tag),
+            None => (true, tag),
+        };
+
+        let name = tag.split(' ').next().unwrap_or_default();
+
+        let range = Range {
+            start: 0,
+            end: name.len(),
+        };
+
+        let name_hash = LocalNameHash::from(name);
+        let mut attributes = AttributeBuffer::default();
+
+        if let Some(attr) = tag.get(name.len() + 1..) {
+            let eq_pos = name.len() + 1 + attr.find('=').unwrap_or(attr.len());
+
+            attributes.push(AttributeOutline {
+                name: Range {
+                    start: name.len() + 1,
+                    end: eq_pos,
+                },
+                value: Range {
+                    start: (eq_pos + 1).min(tag.len()),
+                    end: tag.len(),
+                },
+                raw_range: Range {
+                    start: name.len() + 1,
+                    end: tag.len(),
+                },
+            });
+        }
+
+        let token_outline = if is_start {
+            TagTokenOutline::StartTag {
+                name: range,
+                name_hash,
+                ns: Namespace::Html,
+                attributes,
+                self_closing: false,
+            }
+        } else {
+            TagTokenOutline::EndTag {
+                name: range,
+                name_hash,
+            }
+        };
+
+        let lexeme = TagLexeme::new(0, Bytes::new(tag.as_bytes()), token_outline, range);
+
+        let _ = tree_builder.process_tag(&lexeme).unwrap();
+    }
+
+    fn push_element(tree_builder: &mut TreeBuilder, tag: Tag, ns: Namespace) {
+        let element = tree_builder.create_element(LocalName::Hash(tag.into()), ns);
+
+        tree_builder.open_elements.push(element);
+    }
+
+    fn assert_open_elements(tree_builder: &TreeBuilder, expected: &[Tag]) {
+        assert_eq!(tree_builder.open_elements.len(), expected.len());
+
+        for (element, &tag) in tree_builder.open_elements.iter().zip(expected) {
+            assert!(element.is_html(tag), "expected {tag:?}, got {element:?}");
+        }
+    }
+
+    #[test]
+    fn document_insertion_modes() {
+        use InsertionMode::*;
+
+        let mut tree_builder = new_tree_builder();
+
+        assert_eq!(tree_builder.mode, Initial);
+
+        tree_builder.process_doctype(Some(b"html"), false);
+        assert_eq!(tree_builder.mode, BeforeHtml);
+        assert!(!tree_builder.quirks_mode);
+
+        for (tag, mode) in [
+            ("html", BeforeHead),
+            ("head", InHead),
+            ("/head", AfterHead),
+            ("body", InBody),
+            ("textarea", Text),
+            ("/textarea", InBody),
+            ("/body", AfterBody),
+            ("/html", AfterAfterBody),
+        ] {
+            process_tags(&mut tree_builder, &[tag]);
+            assert_eq!(tree_builder.mode, mode, "after {tag}");
+        }
+    }
+
+    #[test]
+    fn implied_document_elements() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["div"]);
+
+        assert_eq!(tree_builder.mode, InsertionMode::InBody);
+        assert!(tree_builder.quirks_mode);
+        assert!(tree_builder.head_element.is_some());
+        assert_open_elements(&tree_builder, &[Tag::Html, Tag::Body, Tag::Div]);
+    }
+
+    #[test]
+    fn table_insertion_modes() {
+        use InsertionMode::*;
+
+        let mut tree_builder = new_tree_builder();
+
+        for (tag, mode) in [
+            ("table", InTable),
+            ("caption", InCaption),
+            ("/caption", InTable),
+            ("colgroup", InColumnGroup),
+            ("tr", InRow),
+            ("td", InCell),
+            ("th", InCell),
+            ("/tr", InTableBody),
+            ("/table", InBody),
+        ] {
+            process_tags(&mut tree_builder, &[tag]);
+            assert_eq!(tree_builder.mode, mode, "after {tag}");
+        }
+
+        assert_open_elements(&tree_builder, &[Tag::Html, Tag::Body]);
+    }
+
+    #[test]
+    fn template_insertion_modes() {
+        use InsertionMode::*;
+
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["body", "template"]);
+        assert_eq!(tree_builder.mode, InTemplate);
+
+        process_tags(&mut tree_builder, &["tr"]);
+        assert_eq!(tree_builder.mode, InRow);
+
+        process_tags(&mut tree_builder, &["/template"]);
+        assert_eq!(tree_builder.mode, InBody);
+        assert!(tree_builder.template_modes.is_empty());
+    }
+
+    #[test]
+    fn frameset_insertion_modes() {
+        use InsertionMode::*;
+
+        let mut tree_builder = new_tree_builder();
+
+        for (tag, mode) in [
+            ("frameset", InFrameset),
+            ("frame", InFrameset),
+            ("/frameset", AfterFrameset),
+            ("/html", AfterAfterFrameset),
+        ] {
+            process_tags(&mut tree_builder, &[tag]);
+            assert_eq!(tree_builder.mode, mode, "after {tag}");
+        }
+    }
+
+    #[test]
+    fn default_scope() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["div", "span"]);
+        assert!(tree_builder.is_in_scope(Scope::Default, Tag::Div));
+
+        process_tags(&mut tree_builder, &["object"]);
+        assert!(!tree_builder.is_in_scope(Scope::Default, Tag::Div));
+
+        // NOTE: an HTML `<title>` isn't a boundary, unlike the SVG one.
+        push_element(&mut tree_builder, Tag::P, Namespace::Html);
+        push_element(&mut tree_builder, Tag::Title, Namespace::Html);
+        assert!(tree_builder.is_in_scope(Scope::Default, Tag::P));
+
+        push_element(&mut tree_builder, Tag::Title, Namespace::Svg);
+        assert!(!tree_builder.is_in_scope(Scope::Default, Tag::P));
+    }
+
+    #[test]
+    fn button_scope() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["p", "span"]);
+        assert!(tree_builder.is_in_scope(Scope::Button, Tag::P));
+
+        process_tags(&mut tree_builder, &["button"]);
+        assert!(!tree_builder.is_in_scope(Scope::Button, Tag::P));
+        assert!(tree_builder.is_in_scope(Scope::Default, Tag::P));
+
+        // NOTE: a `<p>` start tag closes the `<p>` only if it's in the button scope.
+        process_tags(&mut tree_builder, &["p"]);
+        assert_open_elements(
+            &tree_builder,
+            &[Tag::Html, Tag::Body, Tag::P, Tag::Span, Tag::Button, Tag::P],
+        );
+    }
+
+    #[test]
+    fn list_item_scope() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["ul", "li", "div"]);
+        assert!(tree_builder.is_in_scope(Scope::ListItem, Tag::Li));
+
+        process_tags(&mut tree_builder, &["ol"]);
+        assert!(!tree_builder.is_in_scope(Scope::ListItem, Tag::Li));
+        assert!(tree_builder.is_in_scope(Scope::Default, Tag::Li));
+
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["ul", "li"]);
+        push_element(&mut tree_builder, Tag::Ul, Namespace::Svg);
+        assert!(tree_builder.is_in_scope(Scope::ListItem, Tag::Li));
+    }
+
+    #[test]
+    fn table_scope() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["table", "tr", "td", "div"]);
+        assert!(tree_builder.is_in_scope(Scope::Table, Tag::Tr));
+        assert!(tree_builder.is_in_scope(Scope::Table, Tag::Td));
+        assert!(!tree_builder.is_in_scope(Scope::Default, Tag::Tr));
+
+        process_tags(&mut tree_builder, &["table"]);
+        assert!(!tree_builder.is_in_scope(Scope::Table, Tag::Tr));
+    }
+
+    #[test]
+    fn implied_end_tags() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["ul", "li", "p", "li"]);
+        assert_open_elements(&tree_builder, &[Tag::Html, Tag::Body, Tag::Ul, Tag::Li]);
+
+        process_tags(&mut tree_builder, &["dd", "p", "dt"]);
+        assert_open_elements(
+            &tree_builder,
+            &[Tag::Html, Tag::Body, Tag::Ul, Tag::Li, Tag::Dt],
+        );
+
+        process_tags(&mut tree_builder, &["/ul"]);
+        assert_open_elements(&tree_builder, &[Tag::Html, Tag::Body]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut tree_builder = new_tree_builder();
+
+        tree_builder.process_doctype(Some(b"html"), false);
+        process_tags(&mut tree_builder, &["table", "tr"]);
+        tree_builder.reset();
+
+        assert_eq!(tree_builder.mode, InsertionMode::Initial);
+        assert!(tree_builder.quirks_mode);
+        assert!(tree_builder.open_elements.is_empty());
+        assert!(tree_builder.head_element.is_none());
+    }
+
+    fn formatting_element_count(tree_builder: &TreeBuilder) -> usize {
+        tree_builder
+            .active_formatting_elements
+            .iter()
+            .filter(|e| matches!(e, FormattingEntry::Element(_)))
+            .count()
+    }
+
+    #[test]
+    fn noahs_ark_clause() {
+        let mut tree_builder = new_tree_builder();
+
+        process_tags(&mut tree_builder, &["body"]);
+
+        for _ in 0..10 {
+            process_tags(&mut tree_builder, &["p", "b", "/p"]);
+            tree_builder.process_text(b"x").unwrap();
+        }
+
+        assert_eq!(formatting_element_count(&tree_builder), 3);
+
+        process_tags(&mut tree_builder, &["b id=1", "b ID=1", "b id=2", "b id=1"]);
+        assert_eq!(formatting_element_count(&tree_builder), 7);
+
+        process_tags(&mut tree_builder, &["b id=1"]);
+        assert_eq!(formatting_element_count(&tree_builder), 7);
+
+        // NOTE: the elements before a marker aren't taken into consideration.
+        process_tags(&mut tree_builder, &["object", "b", "b", "b", "b"]);
+        assert_eq!(formatting_element_count(&tree_builder), 10);
+    }
+
+    #[test]
+    fn active_formatting_elements_limit() {
+        let mut tree_builder = new_tree_builder();
+        let tags = (0..MAX_ACTIVE_FORMATTING_ELEMENTS + 10)
+            .map(|i| format!("b id={i}"))
+            .collect::<Vec<_>>();
+
+        process_tags(&mut tree_builder, &["body"]);
+
+        for tag in &tags {
+            process_tags(&mut tree_builder, &["p", tag, "/p"]);
+            tree_builder.process_text(b"x").unwrap();
+        }
+
+        assert_eq!(
+            formatting_element_count(&tree_builder),
+            MAX_ACTIVE_FORMATTING_ELEMENTS
+        );
+    }
+
+    #[test]
+    fn text_memory_usage() {
+        let memory_limiter = SharedMemoryLimiter::new(usize::MAX);
+        let mut tree_builder = TreeBuilder::new(memory_limiter.clone());
+
+        // NOTE: `<html>`, `<head>` and `<body>` are inserted before the text.
+        tree_builder.process_text(b"x").unwrap();
+        assert_open_elements(&tree_builder, &[Tag::Html, Tag::Body]);
+        assert!(memory_limiter.current_usage() > 0);
+    }
+
+    #[test]
+    fn element_name_memory_usage() {
+        let memory_limiter = SharedMemoryLimiter::new(usize::MAX);
+        let mut tree_builder = TreeBuilder::new(memory_limiter.clone());
+        let name = format!("custom-{}", "x".repeat(100));
+        let end_tag = format!("/{name}");
+
+        process_tags(&mut tree_builder, &["body"]);
+
+        let initial_usage = memory_limiter.current_usage();
+
+        process_tags(&mut tree_builder, &[name.as_str(); 10]);
+
+        let usage = memory_limiter.current_usage();
+
+        assert!(usage >= initial_usage + 10 * name.len());
+
+        process_tags(&mut tree_builder, &[end_tag.as_str(); 10]);
+
+        // NOTE: the names of the closed elements are charged until the next token.
+        tree_builder.process_text(b"").unwrap();
+
+        assert_eq!(memory_limiter.current_usage(), usage - 10 * name.len());
+    }
+
+    #[test]
+    fn closed_elements() {
+        use ElementCloseReason::*;
+
+        let mut tree_builder = new_tree_builder();
+
+        let take_change = |tree_builder: &mut TreeBuilder, tag| {
+            process_tag(tree_builder, tag);
+
+            tree_builder.take_open_elements_change(|closed, inserted_element_id| {
+                (closed.collect::<Vec<_>>(), inserted_element_id)
+            })
+        };
+
+        let current_node_id =
+            |tree_builder: &TreeBuilder| tree_builder.open_elements.last().unwrap().id;
+
+        process_tags(&mut tree_builder, &["body", "ul", "li"]);
+
+        let ul_id = tree_builder.open_elements[tree_builder.open_elements.len() - 2].id;
+        let li_id = current_node_id(&tree_builder);
+
+        // NOTE: the next `<li>` closes the previous one.
+        let (closed, inserted_element_id) = take_change(&mut tree_builder, "li");
+
+        assert_eq!(closed, [(li_id, ImpliedByStartTag)]);
+        assert_eq!(inserted_element_id, Some(current_node_id(&tree_builder)));
+
+        let li_id = current_node_id(&tree_builder);
+
+        assert_eq!(
+            take_change(&mut tree_builder, "/ul"),
+            (
+                vec![(li_id, ImpliedByAncestorEndTag), (ul_id, EndTag)],
+                None
+            )
+        );
+
+        process_tags(&mut tree_builder, &["h1"]);
+
+        let h1_id = current_node_id(&tree_builder);
+
+        assert_eq!(
+            take_change(&mut tree_builder, "/h2"),
+            (vec![(h1_id, EndTag)], None)
+        );
+
+        // NOTE: void elements aren't left open.
+        assert_eq!(take_change(&mut tree_builder, "img"), (vec![], None));
+
+        process_tags(&mut tree_builder, &["b", "div"]);
+
+        let b_id = tree_builder.open_elements[tree_builder.open_elements.len() - 2].id;
+        let b_clone_id = tree_builder.next_element_id + 1;
+
+        // NOTE: the adoption agency algorithm removes `<b>` from under the `<div>` and
+        // inserts a clone of it into the `<div>`, which is closed next.
+        assert_eq!(
+            take_change(&mut tree_builder, "/b"),
+            (
+                vec![(b_id, EndTag), (b_clone_id, ImpliedByAncestorEndTag)],
+                None
+            )
+        );
+    }
+}
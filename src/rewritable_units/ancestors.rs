@@ -0,0 +1,215 @@
+use crate::base::BytesCow;
+use crate::html::{LocalName, Namespace};
+use encoding_rs::Encoding;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+/// The values of the retained attributes of an open element, paired with the indices of
+/// their names in the list of the retained attribute names.
+pub(crate) type RetainedAttributeValues = Vec<(usize, BytesCow<'static>)>;
+
+/// An open element that contains a rewritable unit.
+///
+/// Ancestors are read-only: they describe the elements whose start tags have already been
+/// processed by the rewriter, so they can't be modified anymore.
+pub struct Ancestor {
+    name: LocalName<'static>,
+    ns: Namespace,
+    encoding: &'static Encoding,
+    depth: usize,
+    attribute_names: Arc<[String]>,
+    attributes: RetainedAttributeValues,
+    parent: Option<Arc<Ancestor>>,
+}
+
+impl Ancestor {
+    #[inline]
+    #[must_use]
+    pub(crate) const fn new(
+        name: LocalName<'static>,
+        ns: Namespace,
+        encoding: &'static Encoding,
+        depth: usize,
+        attribute_names: Arc<[String]>,
+        attributes: RetainedAttributeValues,
+        parent: Option<Arc<Self>>,
+    ) -> Self {
+        Self {
+            name,
+            ns,
+            encoding,
+            depth,
+            attribute_names,
+            attributes,
+            parent,
+        }
+    }
+
+    /// Returns the name of the element, always ASCII lowercased.
+    #[inline]
+    #[must_use]
+    pub fn tag_name(&self) -> String {
+        self.name.as_lowercase_string(self.encoding)
+    }
+
+    /// Returns `true` if the name of the element is `name`, compared ASCII case-insensitively.
+    ///
+    /// Unlike comparing the [`tag_name`] this doesn't allocate.
+    ///
+    /// [`tag_name`]: #method.tag_name
+    #[inline]
+    #[must_use]
+    pub fn has_tag_name(&self, name: &str) -> bool {
+        LocalName::from_str_without_replacements(name, self.encoding)
+            .is_ok_and(|name| name == self.name)
+    }
+
+    /// Returns the [namespace URI] of the element.
+    ///
+    /// [namespace URI]: https://developer.mozilla.org/en-US/docs/Web/API/Element/namespaceURI
+    #[inline]
+    #[must_use]
+    pub fn namespace_uri(&self) -> &'static str {
+        self.ns.uri()
+    }
+
+    /// Returns the value of the attribute with the `name`, compared ASCII case-insensitively.
+    ///
+    /// Only the attributes listed with [`Settings::append_retained_attribute`] are retained on
+    /// the open elements, so `None` is returned for any other attribute.
+    ///
+    /// [`Settings::append_retained_attribute`]: ../struct.Settings.html#method.append_retained_attribute
+    #[inline]
+    #[must_use]
+    pub fn get_attribute(&self, name: &str) -> Option<String> {
+        let index = self
+            .attribute_names
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))?;
+
+        self.attributes
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, value)| value.as_string(self.encoding))
+    }
+
+    /// Returns the number of open elements that contain the element, i.e. top level elements
+    /// have zero depth.
+    #[inline]
+    #[must_use]
+    pub const fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the element that contains this one, if any.
+    #[inline]
+    #[must_use]
+    pub fn parent(&self) -> Option<&Self> {
+        self.parent.as_deref()
+    }
+}
+
+// NOTE: the parent chain is unlinked iteratively, as the recursive drop of a
+// deeply nested chain can overflow the stack.
+impl Drop for Ancestor {
+    fn drop(&mut self) {
+        let mut parent = self.parent.take();
+
+        while let Some(mut ancestor) = parent.and_then(Arc::into_inner) {
+            parent = ancestor.parent.take();
+        }
+    }
+}
+
+impl Debug for Ancestor {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ancestor")
+            .field("tag_name", &self.tag_name())
+            .field("namespace_uri", &self.namespace_uri())
+            .field("depth", &self.depth)
+            .finish()
+    }
+}
+
+/// An iterator over the open elements that contain a rewritable unit, starting with the
+/// innermost one.
+///
+/// The open elements are only tracked if enabled with [`Settings::with_ancestor_tracking`],
+/// otherwise there are no ancestors. The ancestors are charged to the memory limit, see
+/// [`MemorySettings`].
+///
+/// [`Settings::with_ancestor_tracking`]: ../struct.Settings.html#method.with_ancestor_tracking
+/// [`MemorySettings`]: ../struct.MemorySettings.html
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, text, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     "<p>Run <code>ls</code> here</p>",
+///     RewriteStrSettings::new()
+///         .with_ancestor_tracking(true)
+///         .append_element_content_handler(text!("p", |t| {
+///             if !t.ancestors().any(|a| a.has_tag_name("code")) {
+///                 let text = t.as_str().to_uppercase();
+///
+///                 t.replace(&text, ContentType::Text);
+///             }
+///
+///             Ok(())
+///         })),
+/// )
+/// .unwrap();
+///
+/// assert_eq!(html, "<p>RUN <code>ls</code> HERE</p>");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Ancestors<'a> {
+    next: Option<&'a Ancestor>,
+}
+
+impl<'a> Ancestors<'a> {
+    #[inline]
+    #[must_use]
+    pub(crate) fn new(innermost: Option<&'a Ancestor>) -> Self {
+        Self { next: innermost }
+    }
+}
+
+impl Ancestors<'_> {
+    /// Returns the value of the retained attribute with the `name` of the innermost ancestor
+    /// that has it.
+    ///
+    /// See [`Ancestor::get_attribute`] for the details.
+    ///
+    /// [`Ancestor::get_attribute`]: struct.Ancestor.html#method.get_attribute
+    #[inline]
+    #[must_use]
+    pub fn nearest_attribute(mut self, name: &str) -> Option<String> {
+        self.find_map(|a| a.get_attribute(name))
+    }
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = &'a Ancestor;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a Ancestor> {
+        let ancestor = self.next?;
+
+        self.next = ancestor.parent();
+
+        Some(ancestor)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.next.map_or(0, |a| a.depth + 1);
+
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for Ancestors<'_> {}
@@ -1,7 +1,8 @@
 use super::mutations::MutationsInner;
 use super::{
-    Attribute, AttributeNameError, ContentType, EndTag, Mutations, StartTag, StreamingHandler,
-    StringChunk,
+    Ancestors, Attribute, AttributeNameError, AttributeQuote, AttributeRenameError,
+    AttributeTokenError, ContentType, EndTag, Mutations, RelocationTarget, Relocations, StartTag,
+    StreamingHandler, StringChunk, StyleDeclaration, StylePropertyError, style,
 };
 use crate::HandlerResult;
 use crate::base::{BytesCow, SourceLocation};
@@ -48,6 +49,9 @@ pub struct Element<'rewriter, 'input_token, H: HandlerTypes = LocalHandlerTypes>
     end_tag_handlers: Vec<H::EndTagHandler<'static>>,
     can_have_content: bool,
     should_remove_content: bool,
+    is_wrapped: bool,
+    relocations: &'rewriter Relocations,
+    relocation_target: Option<RelocationTarget>,
     encoding: &'static Encoding,
     user_data: Box<dyn Any>,
 }
@@ -58,6 +62,7 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
     pub(crate) fn new(
         start_tag: &'rewriter mut StartTag<'input_token>,
         can_have_content: bool,
+        relocations: &'rewriter Relocations,
     ) -> Self {
         let encoding = start_tag.encoding();
 
@@ -68,6 +73,9 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
             end_tag_handlers: Vec::new(),
             can_have_content,
             should_remove_content: false,
+            is_wrapped: false,
+            relocations,
+            relocation_target: None,
             encoding,
             user_data: Box::new(()),
         }
@@ -131,6 +139,12 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
     /// The new tag name must be in the same namespace, have the same content model, and be valid in its location.
     /// Otherwise change of the tag name may cause the resulting document to be parsed in an unexpected way,
     /// out of sync with this library.
+    ///
+    /// If the element is closed by the start tag that follows it, e.g. `<p>` in `<p>a<div>` in the
+    /// [full tree construction] mode, the renamed element gets an explicit end tag with the new
+    /// name.
+    ///
+    /// [full tree construction]: crate::Settings::with_full_tree_construction
     #[inline]
     pub fn set_tag_name(&mut self, name: &str) -> Result<(), TagNameError> {
         let name = self.tag_name_bytes_from_str(name)?;
@@ -165,8 +179,11 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
     ///
     /// Returns `true` if the element isn't a [void element in HTML][void],
     /// or is in **foreign content** and doesn't have a self-closing tag (eg, `<svg />`).
+    /// In the [full tree construction] mode, also returns `false` for the start tags the
+    /// parser ignores, e.g. `<td>` outside of a table.
     ///
     /// [void]: https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+    /// [full tree construction]: crate::Settings::with_full_tree_construction
     ///
     /// Note that the self-closing syntax has no effect in HTML content.
     #[inline]
@@ -184,6 +201,16 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
         self.start_tag.namespace_uri()
     }
 
+    /// Returns the open elements that contain the element, starting with its parent.
+    ///
+    /// See [`Ancestors`] for the details.
+    ///
+    /// [`Ancestors`]: struct.Ancestors.html
+    #[inline]
+    pub fn ancestors(&self) -> Ancestors<'_> {
+        Ancestors::new(self.start_tag.ancestor())
+    }
+
     /// Returns an immutable collection of element's attributes.
     ///
     /// `get_attribute` is faster if you only need to read few attributes.
@@ -193,6 +220,37 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
         self.start_tag.attributes()
     }
 
+    /// Returns all the occurrences of an attribute with the `name`, in source order.
+    ///
+    /// The element can have multiple occurrences of an attribute, e.g. `<a href=a href=b>`.
+    /// Browsers use the first one, and so do [`get_attribute`] and [`set_attribute`].
+    ///
+    /// [`get_attribute`]: #method.get_attribute
+    /// [`set_attribute`]: #method.set_attribute
+    #[inline]
+    #[must_use]
+    pub fn attribute_occurrences(&self, name: &str) -> Vec<&Attribute<'input_token>> {
+        self.start_tag.attribute_occurrences(name)
+    }
+
+    /// Returns the attributes that have the same name as one of the preceding attributes, i.e.
+    /// the ones that browsers ignore.
+    #[inline]
+    #[must_use]
+    pub fn duplicate_attributes(&self) -> Vec<&Attribute<'input_token>> {
+        self.start_tag.duplicate_attributes()
+    }
+
+    /// Removes the attributes that have the same name as one of the preceding attributes.
+    ///
+    /// See also [`Settings::with_remove_duplicate_attributes`].
+    ///
+    /// [`Settings::with_remove_duplicate_attributes`]: ../struct.Settings.html#method.with_remove_duplicate_attributes
+    #[inline]
+    pub fn remove_duplicate_attributes(&mut self) {
+        self.start_tag.remove_duplicate_attributes();
+    }
+
     /// Returns the value of an attribute with the `name`. The value may have HTML/XML entities.
     ///
     /// Returns `None` if the element doesn't have an attribute with the `name`.
@@ -211,21 +269,249 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
 
     /// Sets `value` of element's attribute with `name`. The value may have HTML/XML entities.
     ///
-    /// `"` will be entity-escaped if needed. `&` won't be escaped.
+    /// The quote that encloses the value will be entity-escaped if needed. `&` won't be escaped.
     ///
-    /// If element doesn't have an attribute with the `name`, method adds a new attribute
-    /// to the element with `name` and `value`.
+    /// The attribute keeps its position, and its value is enclosed in the [quotes] of the
+    /// attribute. If element doesn't have an attribute with the `name`, method adds a new attribute
+    /// to the end of the element with `name` and `value`, enclosed in double quotes.
+    ///
+    /// [quotes]: struct.Attribute.html#method.quote
     #[inline]
     pub fn set_attribute(&mut self, name: &str, value: &str) -> Result<(), AttributeNameError> {
         self.start_tag.set_attribute(name, value)
     }
 
+    /// Inserts an attribute with the `name` and the `value` at the `index` of the list of
+    /// element's [attributes], or at its end if the `index` is out of bounds.
+    ///
+    /// If element already has an attribute with the `name`, its value is replaced and it's moved
+    /// to the `index`. Otherwise the value is enclosed in double quotes.
+    ///
+    /// [attributes]: #method.attributes
+    #[inline]
+    pub fn insert_attribute(
+        &mut self,
+        index: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), AttributeNameError> {
+        self.start_tag.insert_attribute(index, name, value)
+    }
+
+    /// Renames element's attribute with the `old_name` to the `new_name` if it is present. The
+    /// attribute keeps its position, value and quotes, e.g. `<img src="a.png" alt>` becomes
+    /// `<img data-src="a.png" alt>` once `src` is renamed to `data-src`.
+    ///
+    /// Fails with [`AttributeRenameError::AlreadyExists`] if element already has an attribute
+    /// with the `new_name`, as the renamed attribute would be shadowed by it or shadow it. If
+    /// element has multiple occurrences of the attribute with the `old_name`, all of them are
+    /// renamed in place, so the first one still takes precedence (see
+    /// [`remove_duplicate_attributes`]).
+    ///
+    /// [`AttributeRenameError::AlreadyExists`]: ../errors/enum.AttributeRenameError.html#variant.AlreadyExists
+    /// [`remove_duplicate_attributes`]: #method.remove_duplicate_attributes
+    #[inline]
+    pub fn rename_attribute(
+        &mut self,
+        old_name: &str,
+        new_name: &str,
+    ) -> Result<(), AttributeRenameError> {
+        self.start_tag.rename_attribute(old_name, new_name)
+    }
+
+    /// Changes the quotes around the value of an attribute with the `name` if it is present.
+    ///
+    /// If the value can't be written without quotes (e.g. it's empty or contains whitespace),
+    /// [`AttributeQuote::Unquoted`] encloses it in double quotes.
+    ///
+    /// [`AttributeQuote::Unquoted`]: enum.AttributeQuote.html#variant.Unquoted
+    #[inline]
+    pub fn set_attribute_quote(&mut self, name: &str, quote: AttributeQuote) {
+        self.start_tag.set_attribute_quote(name, quote);
+    }
+
     /// Removes an attribute with the `name` if it is present.
     #[inline]
     pub fn remove_attribute(&mut self, name: &str) {
         self.start_tag.remove_attribute(name);
     }
 
+    /// Returns the tokens of an attribute with the `name` that holds a list of
+    /// whitespace-separated tokens, e.g. `rel` or `sandbox`.
+    ///
+    /// The tokens are separated the same way as for the `~=` attribute selector. Returns an empty
+    /// list if the element doesn't have an attribute with the `name`.
+    #[inline]
+    #[must_use]
+    pub fn attribute_tokens(&self, name: &str) -> Vec<String> {
+        self.start_tag.attribute_tokens(name)
+    }
+
+    /// Returns `true` if an attribute with the `name` contains the `token`.
+    ///
+    /// See [`attribute_tokens`] for the details.
+    ///
+    /// [`attribute_tokens`]: #method.attribute_tokens
+    #[inline]
+    #[must_use]
+    pub fn has_attribute_token(&self, name: &str, token: &str) -> bool {
+        self.start_tag.has_attribute_token(name, token)
+    }
+
+    /// Adds the `token` to an attribute with the `name` if the attribute doesn't contain it yet.
+    ///
+    /// If element doesn't have an attribute with the `name`, method adds a new attribute with
+    /// the `token` as its value. If the value changes, its tokens are re-joined with single
+    /// spaces.
+    #[inline]
+    pub fn add_attribute_token(
+        &mut self,
+        name: &str,
+        token: &str,
+    ) -> Result<(), AttributeTokenError> {
+        self.start_tag.set_attribute_token(name, token, true)?;
+
+        Ok(())
+    }
+
+    /// Removes all the occurrences of the `token` from an attribute with the `name`.
+    ///
+    /// The attribute is kept even if it has no tokens left. If the value changes, its tokens are
+    /// re-joined with single spaces.
+    #[inline]
+    pub fn remove_attribute_token(&mut self, name: &str, token: &str) {
+        // NOTE: invalid names and tokens can't be present in the attribute.
+        let _ = self.start_tag.set_attribute_token(name, token, false);
+    }
+
+    /// Removes the `token` from an attribute with the `name` if the attribute contains it, and
+    /// adds it otherwise. Returns `true` if the attribute contains the `token` afterwards.
+    ///
+    /// See [`add_attribute_token`] and [`remove_attribute_token`] for the details.
+    ///
+    /// [`add_attribute_token`]: #method.add_attribute_token
+    /// [`remove_attribute_token`]: #method.remove_attribute_token
+    #[inline]
+    pub fn toggle_attribute_token(
+        &mut self,
+        name: &str,
+        token: &str,
+    ) -> Result<bool, AttributeTokenError> {
+        let present = !self.has_attribute_token(name, token);
+
+        self.start_tag.set_attribute_token(name, token, present)?;
+
+        Ok(present)
+    }
+
+    /// Returns the classes of the element.
+    ///
+    /// See [`attribute_tokens`] for the details.
+    ///
+    /// [`attribute_tokens`]: #method.attribute_tokens
+    #[inline]
+    #[must_use]
+    pub fn class_list(&self) -> Vec<String> {
+        self.attribute_tokens("class")
+    }
+
+    /// Returns `true` if the element has the `class`.
+    #[inline]
+    #[must_use]
+    pub fn has_class(&self, class: &str) -> bool {
+        self.has_attribute_token("class", class)
+    }
+
+    /// Adds the `class` to the element if it doesn't have it yet.
+    ///
+    /// See [`add_attribute_token`] for the details.
+    ///
+    /// [`add_attribute_token`]: #method.add_attribute_token
+    #[inline]
+    pub fn add_class(&mut self, class: &str) -> Result<(), AttributeTokenError> {
+        self.add_attribute_token("class", class)
+    }
+
+    /// Removes the `class` from the element.
+    ///
+    /// See [`remove_attribute_token`] for the details.
+    ///
+    /// [`remove_attribute_token`]: #method.remove_attribute_token
+    #[inline]
+    pub fn remove_class(&mut self, class: &str) {
+        self.remove_attribute_token("class", class);
+    }
+
+    /// Removes the `class` from the element if it has it, and adds it otherwise. Returns `true`
+    /// if the element has the `class` afterwards.
+    #[inline]
+    pub fn toggle_class(&mut self, class: &str) -> Result<bool, AttributeTokenError> {
+        self.toggle_attribute_token("class", class)
+    }
+
+    /// Returns the valid declarations of the `style` attribute of the element.
+    ///
+    /// The attribute value is parsed as is, so HTML/XML entities in it are not decoded.
+    #[inline]
+    #[must_use]
+    pub fn style_declarations(&self) -> Vec<StyleDeclaration> {
+        self.get_attribute("style")
+            .map(|style| {
+                style::parse_style(&style)
+                    .into_iter()
+                    .map(|(d, _)| d)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of the `style` attribute property with the `name`, without `!important`.
+    ///
+    /// If the property is declared more than once, the value of the declaration that applies is
+    /// returned, i.e. of the last one, with the `!important` declarations taking precedence.
+    #[inline]
+    #[must_use]
+    pub fn get_style_property(&self, name: &str) -> Option<String> {
+        style::get_property(&self.get_attribute("style")?, name)
+    }
+
+    /// Sets the `value` of the `style` attribute property with the `name`, adding `!important` to
+    /// it if `important` is `true`.
+    ///
+    /// The first declaration of the property is replaced in place and its other declarations are
+    /// removed. If there are none, the declaration is appended to the attribute, which is added
+    /// if it's missing. Other declarations, comments and formatting are preserved.
+    #[inline]
+    pub fn set_style_property(
+        &mut self,
+        name: &str,
+        value: &str,
+        important: bool,
+    ) -> Result<(), StylePropertyError> {
+        let style = self.get_attribute("style").unwrap_or_default();
+        let style = style::set_property(&style, name, value, important)?;
+
+        self.set_attribute("style", &style)
+            .unwrap_or_else(|_| unreachable!("the attribute name is valid"));
+
+        Ok(())
+    }
+
+    /// Removes all the declarations of the `style` attribute property with the `name`.
+    ///
+    /// The attribute is kept even if it has no declarations left.
+    #[inline]
+    pub fn remove_style_property(&mut self, name: &str) {
+        let style = self
+            .get_attribute("style")
+            .and_then(|style| style::remove_property(&style, name));
+
+        if let Some(style) = style {
+            self.set_attribute("style", &style)
+                .unwrap_or_else(|_| unreachable!("the attribute name is valid"));
+        }
+    }
+
     /// Inserts `content` before the element.
     ///
     /// Consequent calls to the method append `content` to the previously inserted content.
@@ -318,6 +604,70 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
         self.after_chunk(StringChunk::stream(string_writer));
     }
 
+    /// Wraps the element with `open_html` and `close_html`, i.e. inserts them as HTML before and
+    /// after the element.
+    ///
+    /// Unlike inserting `close_html` from an [end tag handler], this works for the elements
+    /// that don't have end tags: `close_html` goes right after the start tag of an
+    /// [empty element], and before the next token if the end tag is implied (e.g. `<li>a<li>b`
+    /// in the [full tree construction] mode).
+    ///
+    /// Consequent calls to the method wrap the element with the wrappers inside the previously
+    /// inserted ones. Use [`remove_and_keep_content`] to unwrap the element instead.
+    ///
+    /// # Misnested markup
+    ///
+    /// The wrappers only balance if the element is properly nested. If the element is closed
+    /// while the elements opened inside it are still open (e.g. `<a>1<div>2</a>3</div>`),
+    /// `close_html` ends up inside them, where the parser closes them early or ignores it.
+    ///
+    /// In the [full tree construction] mode such elements, and the elements left open inside
+    /// of them, are detected, and rewriting fails with
+    /// [`RewritingError::ContentHandlerError`] at their end tags. Otherwise, elements are
+    /// matched to the end tags by name and misnesting isn't detected.
+    ///
+    /// [`RewritingError::ContentHandlerError`]: crate::errors::RewritingError::ContentHandlerError
+    /// [end tag handler]: #method.on_end_tag
+    /// [empty element]: https://developer.mozilla.org/en-US/docs/Glossary/Empty_element
+    /// [full tree construction]: crate::Settings::with_full_tree_construction
+    /// [`remove_and_keep_content`]: #method.remove_and_keep_content
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, element, RewriteStrSettings};
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<div><p>foo<p><img src="bar.png"></div>"#,
+    ///     RewriteStrSettings::new()
+    ///         .with_full_tree_construction(true)
+    ///         .append_element_content_handler(element!("p", |el| {
+    ///             el.wrap("<section>", "</section>");
+    ///
+    ///             Ok(())
+    ///         }))
+    ///         .append_element_content_handler(element!("img", |el| {
+    ///             el.wrap(r#"<a href="bar.png">"#, "</a>");
+    ///
+    ///             Ok(())
+    ///         }))
+    /// ).unwrap();
+    ///
+    /// assert_eq!(
+    ///     html,
+    ///     concat!(
+    ///         r#"<div><section><p>foo</section><section><p>"#,
+    ///         r#"<a href="bar.png"><img src="bar.png"></a></section></div>"#
+    ///     )
+    /// );
+    /// ```
+    #[inline]
+    pub fn wrap(&mut self, open_html: &str, close_html: &str) {
+        self.is_wrapped |= self.can_have_content;
+        self.before(open_html, ContentType::Html);
+        self.after(close_html, ContentType::Html);
+    }
+
     /// Prepends `content` to the element's inner content, i.e. inserts content right after
     /// the element's start tag.
     ///
@@ -435,6 +785,46 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
         self.append_chunk(StringChunk::stream(string_writer));
     }
 
+    /// Wraps the element's inner content with `open_html` and `close_html`, i.e. inserts them as
+    /// HTML right after the element's start tag and right before its end tag, even if the latter
+    /// is implied in the [full tree construction] mode.
+    ///
+    /// Consequent calls to the method wrap the inner content with the wrappers outside the
+    /// previously inserted ones. A call to the method doesn't make any effect if the element is
+    /// an [empty element]. Like with [`wrap`], the wrappers only balance if the element is
+    /// properly nested, and misnesting is rejected in the full tree construction mode.
+    ///
+    /// [`wrap`]: #method.wrap
+    /// [empty element]: https://developer.mozilla.org/en-US/docs/Glossary/Empty_element
+    /// [full tree construction]: crate::Settings::with_full_tree_construction
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, element, RewriteStrSettings};
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<p>foo<p>bar<img>"#,
+    ///     RewriteStrSettings::new()
+    ///         .with_full_tree_construction(true)
+    ///         .append_element_content_handler(element!("p, img", |el| {
+    ///             el.wrap_inner("<b>", "</b>");
+    ///
+    ///             Ok(())
+    ///         }))
+    /// ).unwrap();
+    ///
+    /// assert_eq!(html, r#"<p><b>foo</b><p><b>bar<img></b>"#);
+    /// ```
+    #[inline]
+    pub fn wrap_inner(&mut self, open_html: &str, close_html: &str) {
+        if self.can_have_content {
+            self.is_wrapped = true;
+            self.prepend(open_html, ContentType::Html);
+            self.append(close_html, ContentType::Html);
+        }
+    }
+
     /// Replaces inner content of the element with `content`.
     ///
     /// Consequent calls to the method overwrite previously inserted content.
@@ -582,6 +972,107 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
         }
     }
 
+    /// Moves the element to the `anchor`, i.e. removes it from its current location and inserts
+    /// its outer HTML where the anchor is [appended] later in the document.
+    ///
+    /// The moved HTML includes the content inserted around the element and the changes made
+    /// to it by the content handlers. It's kept in memory until the anchor is reached, so it's
+    /// subject to the [memory limit]. If the anchor is not reached, the HTML is inserted at the
+    /// end of the document.
+    ///
+    /// The element can be moved or copied to multiple anchors, in which case each of them gets
+    /// the HTML.
+    ///
+    /// [appended]: #method.append_anchor
+    /// [memory limit]: ../struct.MemorySettings.html#method.with_max_allowed_memory_usage
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, element, RewriteStrSettings};
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<body><script src="a.js"></script><p>foo</p></body>"#,
+    ///     RewriteStrSettings::new()
+    ///         .append_element_content_handler(element!("body", |el| {
+    ///             el.append_anchor("scripts");
+    ///
+    ///             Ok(())
+    ///         }))
+    ///         .append_element_content_handler(element!("script", |el| {
+    ///             el.move_to_anchor("scripts");
+    ///
+    ///             Ok(())
+    ///         }))
+    /// ).unwrap();
+    ///
+    /// assert_eq!(html, r#"<body><p>foo</p><script src="a.js"></script></body>"#);
+    /// ```
+    #[inline]
+    pub fn move_to_anchor(&mut self, anchor: &str) {
+        self.relocate(anchor, false);
+    }
+
+    /// Copies the element to the `anchor`, i.e. inserts its outer HTML where the anchor is
+    /// [appended] later in the document, keeping the element in its current location.
+    ///
+    /// See [`move_to_anchor`] for the details.
+    ///
+    /// [appended]: #method.append_anchor
+    /// [`move_to_anchor`]: #method.move_to_anchor
+    #[inline]
+    pub fn copy_to_anchor(&mut self, anchor: &str) {
+        self.relocate(anchor, true);
+    }
+
+    fn relocate(&mut self, anchor: &str, keep_original: bool) {
+        self.relocation_target
+            .get_or_insert_with(|| RelocationTarget::new(true))
+            .add_anchor(anchor, keep_original);
+    }
+
+    /// Appends the `anchor` to the element's inner content, i.e. inserts the elements moved or
+    /// copied to the `anchor` so far right before the element's end tag.
+    ///
+    /// The elements are inserted in the document order. The ones that are moved or copied to
+    /// the `anchor` after it's reached go to the next element with the same anchor, or to the
+    /// end of the document. A call to the method doesn't make any effect if the element is an
+    /// [empty element].
+    ///
+    /// [empty element]: https://developer.mozilla.org/en-US/docs/Glossary/Empty_element
+    #[inline]
+    pub fn append_anchor(&mut self, anchor: &str) {
+        let chunk = self.relocations.anchor_content(anchor);
+
+        self.append_chunk(chunk);
+    }
+
+    /// Marks the boundaries of the element's output for the anchors it's moved or copied to.
+    ///
+    /// Must be called once all the content handlers have processed the element, so that the
+    /// boundaries enclose all the content inserted around the element.
+    pub(crate) fn finish_relocation(&mut self) {
+        let Some(target) = self.relocation_target.take() else {
+            return;
+        };
+
+        let start = self.relocations.capture_start(target);
+        let end = self.relocations.capture_end();
+
+        self.start_tag
+            .mutations
+            .mutate()
+            .content_before
+            .push_front(start);
+
+        if self.can_have_content {
+            &mut self.end_tag_mutations_mut().content_after
+        } else {
+            &mut self.start_tag.mutations.mutate().content_after
+        }
+        .push_back(end);
+    }
+
     /// Returns `true` if the element has been removed or replaced with some content.
     #[inline]
     #[must_use]
@@ -602,7 +1093,8 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
 
     /// Returns the handlers that will run when the end tag is reached.
     ///
-    /// The handlers may not run if there is no explicit end tag.
+    /// If the end tag has been omitted, the handlers run when the element gets implicitly
+    /// closed. See [`EndTag::close_reason()`].
     ///
     /// You can use this to add your "on end tag" handlers.
     ///
@@ -658,10 +1150,15 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
 
     /// Adds a handler to run when the end tag is reached. Returns `Err` when `element.can_have_content()` is `false`.
     ///
-    /// Note: currently end tag handlers are not invoked for implicitly-closed elements.
+    /// The handler is also invoked for implicitly-closed elements, e.g. an `<li>` followed by `</ul>`
+    /// or, in the [full tree construction] mode, a `<p>` followed by a `<div>`, with an implied
+    /// [`EndTag`] that doesn't appear in the output. See [`EndTag::close_reason()`].
+    /// Only the element closed by its own end tag receives the end tag token from the source.
     ///
     /// Use [`end_tag!`](crate::end_tag!) macro to provide type hint for the closure's argument.
     ///
+    /// [full tree construction]: crate::Settings::with_full_tree_construction
+    ///
     /// # Example
     ///
     /// ```
@@ -697,6 +1194,7 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
     pub(crate) fn into_end_tag_handler(self) -> Option<H::EndTagHandler<'static>> {
         let end_tag_mutations = self.end_tag_mutations;
         let modified_end_tag_name = self.modified_end_tag_name;
+        let is_wrapped = self.is_wrapped;
         let mut end_tag_handlers = self.end_tag_handlers;
 
         if end_tag_mutations.is_some()
@@ -705,7 +1203,15 @@ impl<'rewriter, 'input_token, H: HandlerTypes> Element<'rewriter, 'input_token,
         {
             end_tag_handlers.insert(
                 0,
-                H::new_end_tag_handler(|end_tag: &mut EndTag<'_>| {
+                H::new_end_tag_handler(move |end_tag: &mut EndTag<'_>| {
+                    if is_wrapped && end_tag.is_misnested() {
+                        return Err(format!(
+                            "{} is misnested, so its wrappers can't be balanced",
+                            end_tag.name()
+                        )
+                        .into());
+                    }
+
                     if let Some(name) = modified_end_tag_name {
                         end_tag.set_name_raw(Cow::from(name.into_vec()).into());
                     }
@@ -760,6 +1266,7 @@ mod tests {
     use crate::*;
     use encoding_rs::{EUC_JP, Encoding, UTF_8};
     use rewritable_units::StreamingHandlerSink;
+    use std::borrow::Cow;
 
     fn rewrite_element(
         html: &[u8],
@@ -798,6 +1305,23 @@ mod tests {
         output
     }
 
+    fn rewrite_element_preserving_formatting(
+        html: &str,
+        selector: &str,
+        mut handler: impl FnMut(&mut Element<'_, '_>),
+    ) -> String {
+        rewrite_str(
+            html,
+            RewriteStrSettings::new()
+                .with_preserve_attribute_formatting(true)
+                .append_element_content_handler(element!(selector, |el| {
+                    handler(el);
+                    Ok(())
+                })),
+        )
+        .unwrap()
+    }
+
     #[test]
     fn many_end_tags() {
         let output = rewrite_html(
@@ -851,29 +1375,351 @@ mod tests {
         );
 
         assert_eq!(
-            "*div@div<div>*div.div@div<div class=c>…*unclosed.unclosed<unclosed class=c>*unclosed+.unclosed+*div+.div+div+</div>/div/.div/*div/.unclosed/*unclosed *unclosed.unclosed<unclosed class=c>*unclosed+.unclosed+*div+div+</div>/div/*div/.unclosed/*unclosed",
+            "*div@div<div>*div.div@div<div class=c>…*unclosed.unclosed<unclosed class=c>*unclosed+.unclosed+/.unclosed/*unclosed*div+.div+div+</div>/div/.div/*div *unclosed.unclosed<unclosed class=c>*unclosed+.unclosed+/.unclosed/*unclosed*div+div+</div>/div/*div",
             output
         );
     }
 
-    #[test]
-    fn empty_tag_name() {
-        rewrite_element(b"<div>", UTF_8, "div", |el| {
-            let err = el.set_tag_name("").unwrap_err();
+    fn rewrite_with_full_tree_construction(
+        html: &str,
+        element_content_handlers: Vec<(Cow<'_, Selector>, ElementContentHandlers<'_>)>,
+    ) -> String {
+        let mut output = Vec::new();
 
-            assert_eq!(err, TagNameError::Empty);
-        });
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers,
+                ..Settings::new()
+            }
+            .with_full_tree_construction(true),
+            |c: &[u8]| output.extend_from_slice(c),
+        );
+
+        for ch in html.as_bytes().chunks(15) {
+            rewriter.write(ch).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    fn rewrite_with_close_reasons(html: &str, selector: &str) -> String {
+        rewrite_with_full_tree_construction(
+            html,
+            vec![element!(selector, |el| {
+                let tag_name = el.tag_name();
+                el.on_end_tag(end_tag!(move |end| {
+                    end.before(
+                        &format!("[{tag_name}:{:?}]", end.close_reason()),
+                        ContentType::Text,
+                    );
+                    Ok(())
+                }))
+            })],
+        )
     }
 
     #[test]
-    fn sanitizer_bypass1() {
-        let out = rewrite_element(
-            b"<math><style><img></style></math>
-             <textarea><!--</textarea><img>--></textarea>
-             <div><style><img></style></div>",
-            UTF_8,
-            "img",
-            |el| el.set_tag_name("TROUBLE").unwrap(),
+    fn implied_end_tags() {
+        assert_eq!(
+            rewrite_with_close_reasons("<p>a<p>b<div>c</div>", "p"),
+            "<p>a[p:ImpliedByStartTag]<p>b[p:ImpliedByStartTag]<div>c</div>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<ul><li>a<li>b<ul><li>c</ul></ul>", "li"),
+            "<ul><li>a[li:ImpliedByStartTag]<li>b<ul><li>c[li:ImpliedByAncestorEndTag]</ul>\
+             [li:ImpliedByAncestorEndTag]</ul>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<dl><dt>a<dd>b</dd><dt>c", "dt,dd"),
+            "<dl><dt>a[dt:ImpliedByStartTag]<dd>b[dd:EndTag]</dd><dt>c[dt:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<table><tr><td>a<td>b<tr><th>c</table>", "tr,td,th"),
+            "<table><tr><td>a[td:ImpliedByStartTag]<td>b[td:ImpliedByStartTag]\
+             [tr:ImpliedByStartTag]<tr><th>c[th:ImpliedByAncestorEndTag]\
+             [tr:ImpliedByAncestorEndTag]</table>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<ul><li>a<li>b</ul>", "ul,li"),
+            "<ul><li>a[li:ImpliedByStartTag]<li>b[li:ImpliedByAncestorEndTag][ul:EndTag]</ul>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<select><option>a<option>b</select>", "option"),
+            "<select><option>a[option:ImpliedByStartTag]<option>b\
+             [option:ImpliedByAncestorEndTag]</select>"
+        );
+    }
+
+    #[test]
+    fn implied_end_tags_respect_scope() {
+        assert_eq!(
+            rewrite_with_close_reasons("<p><button><div>a</div></button>b</p>", "p"),
+            "<p><button><div>a</div></button>b[p:EndTag]</p>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<li><div><span><li>", "li"),
+            "<li><div><span>[li:ImpliedByStartTag]<li>[li:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<li><section><li>", "li"),
+            "<li><section><li>[li:EndOfDocument][li:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<p>a<span>b</span>c", "p"),
+            "<p>a<span>b</span>c[p:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<li><dd><li>", "li"),
+            "<li><dd><li>[li:EndOfDocument][li:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<li><dt><li>", "li"),
+            "<li><dt><li>[li:EndOfDocument][li:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<p>a<title>b</title><div>", "p"),
+            "<p>a<title>b</title>[p:ImpliedByStartTag]<div>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<p><svg><title>a</title></svg><div>", "p"),
+            "<p><svg><title>a</title></svg>[p:ImpliedByStartTag]<div>"
+        );
+    }
+
+    #[test]
+    fn implied_end_tags_close_elements_for_selectors() {
+        let rewrite = |html: &str, selector: &str| {
+            rewrite_with_full_tree_construction(
+                html,
+                vec![element!(selector, |el| {
+                    el.set_attribute("matched", "")?;
+                    Ok(())
+                })],
+            )
+        };
+
+        assert_eq!(
+            rewrite("<ul><li>a<li>b</ul>", "li li"),
+            "<ul><li>a<li>b</ul>"
+        );
+        assert_eq!(
+            rewrite("<ul><li>a<li>b</ul>", "li > li"),
+            "<ul><li>a<li>b</ul>"
+        );
+        assert_eq!(
+            rewrite("<ul><li>a<li>b</ul>", "ul > li"),
+            "<ul><li matched=\"\">a<li matched=\"\">b</ul>"
+        );
+
+        assert_eq!(rewrite("<p>a<div>b</div>", "p div"), "<p>a<div>b</div>");
+        assert_eq!(
+            rewrite("<body><p>a<div>b</div>", "body > div"),
+            "<body><p>a<div matched=\"\">b</div>"
+        );
+
+        assert_eq!(
+            rewrite("<p>a<span>b</span>", "p span"),
+            "<p>a<span matched=\"\">b</span>"
+        );
+    }
+
+    #[test]
+    fn implied_end_tags_of_formatting_elements() {
+        assert_eq!(
+            rewrite_with_close_reasons("<a>1<a>2</a>", "a"),
+            "<a>1[a:ImpliedByStartTag]<a>2[a:EndTag]</a>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<nobr>1<nobr>2", "nobr"),
+            "<nobr>1[nobr:ImpliedByStartTag]<nobr>2[nobr:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<button>1<button>2", "button"),
+            "<button>1[button:ImpliedByStartTag]<button>2[button:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<form><div>1</form>2</div>", "form, div"),
+            "<form><div>1[form:EndTag]</form>2[div:EndTag]</div>"
+        );
+
+        // NOTE: the adoption agency algorithm closes `<b>`, while `<p>` stays open.
+        assert_eq!(
+            rewrite_with_close_reasons("<b>1<p>2</b>3</p>", "b, p"),
+            "<b>1<p>2[b:EndTag]</b>3[p:EndTag]</p>"
+        );
+    }
+
+    #[test]
+    fn implied_end_tags_of_ignored_start_tags() {
+        let output = rewrite_with_full_tree_construction(
+            "<div><td>a</td></div>",
+            vec![element!("td", |el| {
+                assert!(!el.can_have_content());
+                Ok(())
+            })],
+        );
+
+        assert_eq!(output, "<div><td>a</td></div>");
+    }
+
+    #[test]
+    fn implied_end_tags_without_full_tree_construction() {
+        let output = rewrite_html(
+            b"<ul><li>a<li>b</ul><p>c<div>d</div>",
+            UTF_8,
+            vec![
+                element!("li li, p div", |el| {
+                    el.set_attribute("matched", "")?;
+                    Ok(())
+                }),
+                element!("li, p", |el| {
+                    let tag_name = el.tag_name();
+
+                    el.on_end_tag(end_tag!(move |end| {
+                        end.before(
+                            &format!("[{tag_name}:{:?}]", end.close_reason()),
+                            ContentType::Text,
+                        );
+                        Ok(())
+                    }))
+                }),
+            ],
+            vec![],
+        );
+
+        assert_eq!(
+            output,
+            "<ul><li>a<li matched=\"\">b[li:ImpliedByAncestorEndTag]\
+             [li:ImpliedByAncestorEndTag]</ul><p>c<div matched=\"\">d</div>[p:EndOfDocument]"
+        );
+    }
+
+    #[test]
+    fn implied_end_tags_in_quirks_mode() {
+        assert_eq!(
+            rewrite_with_close_reasons("<p>a<table></table>", "p"),
+            "<p>a<table></table>[p:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<!DOCTYPE html><p>a<table></table>", "p"),
+            "<!DOCTYPE html><p>a[p:ImpliedByStartTag]<table></table>"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<!DOCTYPE foo><p>a<table></table>", "p"),
+            "<!DOCTYPE foo><p>a<table></table>[p:EndOfDocument]"
+        );
+
+        assert_eq!(
+            rewrite_with_close_reasons("<!DOCTYPE><p>a<table></table>", "p"),
+            "<!DOCTYPE><p>a<table></table>[p:EndOfDocument]"
+        );
+    }
+
+    #[test]
+    fn implied_end_tag_mutations() {
+        let output = rewrite_with_full_tree_construction(
+            "<p>a<p>b<p>c<h1>d</h1>",
+            vec![element!("p", |el| {
+                el.append("!", ContentType::Text);
+                el.after("<hr>", ContentType::Html);
+                el.set_tag_name("div")?;
+                Ok(())
+            })],
+        );
+
+        assert_eq!(
+            output,
+            "<div>a!</div><hr><div>b!</div><hr><div>c!</div><hr><h1>d</h1>"
+        );
+
+        let output = rewrite_with_full_tree_construction(
+            "<ul><li class=x>a<b>b</b><li>c<li class=x>d</ul>",
+            vec![element!(".x", |el| {
+                el.set_inner_content("removed", ContentType::Text);
+                Ok(())
+            })],
+        );
+
+        assert_eq!(
+            output,
+            "<ul><li class=x>removed<li>c<li class=x>removed</ul>"
+        );
+
+        let output = rewrite_with_full_tree_construction(
+            "<ul><li>a<li>b</ul><p>c",
+            vec![element!("li, p", |el| {
+                el.set_tag_name("span")?;
+                Ok(())
+            })],
+        );
+
+        assert_eq!(output, "<ul><span>a</span><span>b</ul><span>c");
+    }
+
+    #[test]
+    fn implied_end_tag_handler_error() {
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![element!("li", |el| {
+                    el.on_end_tag(end_tag!(|end| {
+                        if end.is_implied() {
+                            Err("implied".into())
+                        } else {
+                            Ok(())
+                        }
+                    }))
+                })],
+                ..Settings::new()
+            }
+            .with_full_tree_construction(true),
+            |_: &[u8]| {},
+        );
+
+        assert!(rewriter.write(b"<ul><li>a</li>").is_ok());
+
+        assert!(matches!(
+            rewriter.write(b"<li>b<li>c"),
+            Err(RewritingError::ContentHandlerError(e)) if e.to_string() == "implied"
+        ));
+    }
+
+    #[test]
+    fn empty_tag_name() {
+        rewrite_element(b"<div>", UTF_8, "div", |el| {
+            let err = el.set_tag_name("").unwrap_err();
+
+            assert_eq!(err, TagNameError::Empty);
+        });
+    }
+
+    #[test]
+    fn sanitizer_bypass1() {
+        let out = rewrite_element(
+            b"<math><style><img></style></math>
+             <textarea><!--</textarea><img>--></textarea>
+             <div><style><img></style></div>",
+            UTF_8,
+            "img",
+            |el| el.set_tag_name("TROUBLE").unwrap(),
         );
         assert_eq!(
             out,
@@ -1033,7 +1879,7 @@ mod tests {
         );
         assert_eq!(
             out,
-            r#"<svg><A><foreignobject><A><table><A></A><style><!--</style></A><A id="-><img>">"#
+            r#"<svg><A><foreignobject><A><table><A></table><style><!--</style></svg><A id="-><img>">"#
         );
     }
 
@@ -1292,6 +2138,243 @@ mod tests {
         }
     }
 
+    #[test]
+    fn class_list() {
+        for (html, enc) in encoded("<div class=\"  fooѦ\tbar\x0Cfoo\n\rbaz fooѦ \"><span>") {
+            let output = rewrite_element(&html, enc, "div", |el| {
+                assert_eq!(el.class_list(), ["fooѦ", "bar", "foo", "baz", "fooѦ"]);
+                assert!(el.has_class("bar"), "Encoding: {}", enc.name());
+                assert!(!el.has_class("ba"), "Encoding: {}", enc.name());
+                assert!(!el.has_class("bar baz"), "Encoding: {}", enc.name());
+                assert!(!el.has_class(""), "Encoding: {}", enc.name());
+
+                // NOTE: the value is left as is if it doesn't change.
+                el.add_class("bar").unwrap();
+                el.remove_class("qux");
+                el.remove_class("");
+
+                assert_eq!(
+                    el.get_attribute("class").unwrap(),
+                    "  fooѦ\tbar\x0Cfoo\n\rbaz fooѦ ",
+                    "Encoding: {}",
+                    enc.name()
+                );
+
+                el.remove_class("fooѦ");
+
+                assert_eq!(el.get_attribute("class").unwrap(), "bar foo baz");
+
+                el.add_class("quxѤ").unwrap();
+
+                assert!(!el.toggle_class("foo").unwrap());
+                assert!(el.toggle_class("new").unwrap());
+                assert_eq!(el.class_list(), ["bar", "baz", "quxѤ", "new"]);
+
+                assert_eq!(el.add_class(""), Err(AttributeTokenError::Empty));
+                assert_eq!(
+                    el.toggle_class("a b"),
+                    Err(AttributeTokenError::ForbiddenWhitespace)
+                );
+            });
+
+            assert_eq!(output, "<div class=\"bar baz quxѤ new\"><span>");
+        }
+    }
+
+    #[test]
+    fn attribute_tokens() {
+        let output = rewrite_element(b"<p rel><a rel='noopener'>", UTF_8, "a", |el| {
+            assert_eq!(el.attribute_tokens("sandbox"), Vec::<String>::new());
+            assert!(!el.has_attribute_token("sandbox", "allow-forms"));
+
+            el.remove_attribute_token("sandbox", "allow-forms");
+
+            assert!(!el.has_attribute("sandbox"));
+
+            el.add_attribute_token("SANDBOX", "allow-forms").unwrap();
+            el.add_attribute_token("sandbox", "allow-scripts").unwrap();
+            el.add_attribute_token("REL", "nofollow").unwrap();
+
+            assert!(el.has_attribute_token("Rel", "noopener"));
+            assert_eq!(el.attribute_tokens("rel"), ["noopener", "nofollow"]);
+
+            el.remove_attribute_token("rel", "noopener");
+            el.remove_attribute_token("rel", "nofollow");
+
+            assert!(el.has_attribute("rel"));
+
+            assert_eq!(
+                el.add_attribute_token("", "foo"),
+                Err(AttributeTokenError::InvalidAttributeName(
+                    AttributeNameError::Empty
+                ))
+            );
+        });
+
+        assert_eq!(
+            output,
+            r#"<p rel><a rel="" sandbox="allow-forms allow-scripts">"#
+        );
+    }
+
+    #[test]
+    fn duplicate_attributes() {
+        let html = "<a href=/ id=x HREF='/evil' Href id=y>";
+
+        for (html, enc) in encoded(html) {
+            let output = rewrite_element(&html, enc, "a", |el| {
+                let values = |attrs: Vec<&Attribute<'_>>| {
+                    attrs.iter().map(|a| a.value()).collect::<Vec<_>>()
+                };
+
+                assert_eq!(values(el.attribute_occurrences("Href")), ["/", "/evil", ""]);
+                assert!(el.attribute_occurrences("rel").is_empty());
+                assert_eq!(values(el.duplicate_attributes()), ["/evil", "", "y"]);
+
+                el.set_attribute("href", "/home").unwrap();
+
+                assert_eq!(
+                    values(el.attribute_occurrences("href")),
+                    ["/home", "/evil", ""]
+                );
+
+                el.remove_duplicate_attributes();
+
+                assert!(el.duplicate_attributes().is_empty());
+            });
+
+            assert_eq!(output, "<a href=\"/home\" id=x>");
+        }
+
+        let output = rewrite_element(b"<a href=/ id=x>", UTF_8, "a", |el| {
+            el.remove_duplicate_attributes();
+        });
+
+        assert_eq!(output, "<a href=/ id=x>");
+    }
+
+    #[test]
+    fn many_duplicate_attributes() {
+        const COUNT: usize = 50_000;
+
+        let attrs: String = (0..COUNT)
+            .map(|i| {
+                let prefix = if (i / 100) % 2 == 0 { "a" } else { "A" };
+
+                format!(" {prefix}{}=v{i}", i % 100)
+            })
+            .collect();
+
+        // NOTE: written in a single chunk, the tag is lexed only once.
+        let output = rewrite_str(
+            &format!("<div{attrs}>"),
+            RewriteStrSettings::new().append_element_content_handler(element!("div", |el| {
+                assert_eq!(el.duplicate_attributes().len(), COUNT - 100);
+
+                el.remove_duplicate_attributes();
+
+                assert_eq!(el.attributes().len(), 100);
+
+                Ok(())
+            })),
+        )
+        .unwrap();
+
+        let expected: String = (0..100).map(|i| format!(" a{i}=v{i}")).collect();
+
+        assert_eq!(output, format!("<div{expected}>"));
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let html = "<A  HREF = \"/\"\tdisabled title='' id=x/>";
+
+        for (html, enc) in encoded(html) {
+            let output = rewrite_element(&html, enc, "a", |el| {
+                el.set_attribute("href", "/home").unwrap();
+                el.set_attribute("rel", "nofollow").unwrap();
+
+                let raw = el
+                    .attributes()
+                    .iter()
+                    .map(|a| {
+                        (
+                            a.raw_bytes()
+                                .map(|b| String::from_utf8(b.to_vec()).unwrap()),
+                            a.raw_quote(),
+                            a.has_raw_value(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                assert_eq!(
+                    raw,
+                    [
+                        (
+                            Some("HREF = \"/\"".into()),
+                            Some(AttributeQuote::Double),
+                            true
+                        ),
+                        (Some("disabled".into()), None, false),
+                        (Some("title=''".into()), Some(AttributeQuote::Single), true),
+                        (Some("id=x/".into()), Some(AttributeQuote::Unquoted), true),
+                        (None, None, false),
+                    ]
+                );
+
+                assert_eq!(el.start_tag().raw_bytes(), &*html);
+            });
+
+            assert_eq!(
+                output,
+                "<A HREF=\"/home\" disabled title='' id=x/ rel=\"nofollow\">"
+            );
+        }
+    }
+
+    #[test]
+    fn style_properties() {
+        let html = r#"<div style="color: red; /* keep */ background: URL('a.png')!important">"#;
+
+        let output = rewrite_element(html.as_bytes(), UTF_8, "div", |el| {
+            let declarations = el.style_declarations();
+
+            assert_eq!(declarations.len(), 2);
+            assert_eq!(declarations[1].name(), "background");
+            assert_eq!(declarations[1].value(), "URL('a.png')");
+            assert!(declarations[1].is_important());
+
+            assert_eq!(el.get_style_property("COLOR").unwrap(), "red");
+            assert_eq!(el.get_style_property("display"), None);
+
+            el.set_style_property("display", "none", true).unwrap();
+            el.set_style_property("color", "\"blue\"", false).unwrap();
+            el.remove_style_property("background");
+
+            assert_eq!(
+                el.set_style_property("color", "red; display: block", false),
+                Err(StylePropertyError::InvalidValue)
+            );
+        });
+
+        assert_eq!(
+            output,
+            r#"<div style="color: &quot;blue&quot;; /* keep */ display: none !important">"#
+        );
+
+        let output = rewrite_element(b"<div><span>", UTF_8, "div", |el| {
+            assert!(el.style_declarations().is_empty());
+
+            el.remove_style_property("color");
+
+            assert!(!el.has_attribute("style"));
+
+            el.set_style_property("color", "red", false).unwrap();
+        });
+
+        assert_eq!(output, r#"<div style="color: red"><span>"#);
+    }
+
     #[test]
     fn insert_content_before() {
         for (html, enc) in encoded("<div><span>ĥi</span></div>") {
@@ -1539,6 +2622,442 @@ mod tests {
         assert_eq!(res, "<div>fooXYZ</div>");
     }
 
+    #[test]
+    fn wrap() {
+        let wrap = |html: &str, selector| {
+            rewrite_element(html.as_bytes(), UTF_8, selector, |el| {
+                el.wrap("<div>", "</div>");
+            })
+        };
+
+        let wrap_with_full_tree_construction = |html: &str, selector: &str| {
+            rewrite_with_full_tree_construction(
+                html,
+                vec![element!(selector, |el| {
+                    el.wrap("<div>", "</div>");
+                    Ok(())
+                })],
+            )
+        };
+
+        assert_eq!(wrap("<p>foo</p>", "p"), "<div><p>foo</p></div>");
+        assert_eq!(
+            wrap("<br><img/>", "br, img"),
+            "<div><br></div><div><img/></div>"
+        );
+        assert_eq!(
+            wrap("<svg><path/></svg>", "path"),
+            "<svg><div><path/></div></svg>"
+        );
+
+        assert_eq!(
+            wrap_with_full_tree_construction("<p>foo<p>bar<div>baz</div>", "p"),
+            "<div><p>foo</div><div><p>bar</div><div>baz</div>"
+        );
+
+        assert_eq!(
+            wrap_with_full_tree_construction("<ul><li>foo<li>bar</ul>", "li"),
+            "<ul><div><li>foo</div><div><li>bar</div></ul>"
+        );
+
+        assert_eq!(
+            wrap("<ul><li>foo<ul><li>bar</ul></ul>", "ul, li"),
+            "<div><ul><div><li>foo<div><ul><div><li>bar</div></ul></div></div></ul></div>"
+        );
+
+        assert_eq!(wrap("<p>foo", "p"), "<div><p>foo</div>");
+
+        // NOTE: `</a>` closes the element while `<div>` is still open, so the wrapper can't
+        // balance. It's only detected in the full tree construction mode.
+        assert_eq!(
+            wrap("<a>1<div>2</a>3</div>", "a"),
+            "<div><a>1<div>2</a></div>3</div>"
+        );
+    }
+
+    #[test]
+    fn wrap_inner() {
+        let wrap_inner = |html: &str, selector, content: Option<&str>| {
+            rewrite_element(html.as_bytes(), UTF_8, selector, |el| {
+                if let Some(content) = content {
+                    el.set_inner_content(content, ContentType::Text);
+                }
+
+                el.wrap_inner("<b>", "</b>");
+            })
+        };
+
+        assert_eq!(wrap_inner("<p>foo</p>", "p", None), "<p><b>foo</b></p>");
+        assert_eq!(
+            rewrite_with_full_tree_construction(
+                "<p>foo<p>bar",
+                vec![element!("p", |el| {
+                    el.wrap_inner("<b>", "</b>");
+                    Ok(())
+                })],
+            ),
+            "<p><b>foo</b><p><b>bar</b>"
+        );
+        assert_eq!(wrap_inner("<br><img/>", "br, img", None), "<br><img/>");
+        assert_eq!(
+            wrap_inner("<ul><li>foo</ul>", "li", None),
+            "<ul><li><b>foo</b></ul>"
+        );
+        assert_eq!(
+            wrap_inner("<p>foo</p>", "p", Some("bar")),
+            "<p><b>bar</b></p>"
+        );
+    }
+
+    #[test]
+    fn wrap_misnested() {
+        let wrap = |html: &str, selector: &str, inner: bool| {
+            rewrite_str(
+                html,
+                RewriteStrSettings::new()
+                    .with_full_tree_construction(true)
+                    .append_element_content_handler(element!(selector, move |el| {
+                        if inner {
+                            el.wrap_inner("<b>", "</b>");
+                        } else {
+                            el.wrap("<div>", "</div>");
+                        }
+
+                        Ok(())
+                    })),
+            )
+            .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(
+            wrap("<a>1<div>2</a>3</div>", "a", false),
+            Err("a is misnested, so its wrappers can't be balanced".into())
+        );
+
+        assert_eq!(
+            wrap("<form><div>1</form>2</div>", "form", true),
+            Err("form is misnested, so its wrappers can't be balanced".into())
+        );
+
+        // NOTE: `<p>` is left open when the adoption agency algorithm closes `<b>`.
+        assert_eq!(
+            wrap("<b>1<p>2</b>3</p>", "p", false),
+            Err("p is misnested, so its wrappers can't be balanced".into())
+        );
+
+        // NOTE: `<i>` is closed before `<b>`, so the wrapper balances.
+        assert_eq!(
+            wrap("<b>1<i>2</b>3", "i", false),
+            Ok("<b>1<div><i>2</div></b>3".into())
+        );
+
+        assert_eq!(
+            wrap("<ul><li>1<li>2</ul>", "li", true),
+            Ok("<ul><li><b>1</b><li><b>2</b></ul>".into())
+        );
+    }
+
+    fn relocate(html: &str, handlers: &[(&str, &str, &str)]) -> String {
+        rewrite_html(
+            html.as_bytes(),
+            UTF_8,
+            handlers
+                .iter()
+                .map(|&(selector, action, anchor)| {
+                    element!(selector, move |el| {
+                        match action {
+                            "move" => el.move_to_anchor(anchor),
+                            "copy" => el.copy_to_anchor(anchor),
+                            _ => el.append_anchor(anchor),
+                        }
+
+                        Ok(())
+                    })
+                })
+                .collect(),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn move_to_anchor() {
+        assert_eq!(
+            relocate(
+                "<body><script>a</script><p>b<script>c</script></p></body>",
+                &[("script", "move", "s"), ("body", "anchor", "s")]
+            ),
+            "<body><p>b</p><script>a</script><script>c</script></body>"
+        );
+
+        // NOTE: the anchor gets only the elements that have been moved before it's reached.
+        assert_eq!(
+            relocate(
+                "<div><img id=a></div><div><img id=b></div><img id=c>",
+                &[("img", "move", "i"), ("div", "anchor", "i")]
+            ),
+            "<div><img id=a></div><div><img id=b></div><img id=c>"
+        );
+
+        assert_eq!(
+            relocate(
+                "<img id=a><div></div><img id=b><div><img id=c></div>",
+                &[("img", "move", "i"), ("div", "anchor", "i")]
+            ),
+            "<div><img id=a></div><div><img id=b><img id=c></div>"
+        );
+
+        // NOTE: the elements are inserted at the end of the document if the anchor is not reached.
+        assert_eq!(
+            relocate(
+                "<ul><li>a</li><li>b</li></ul><p>c",
+                &[("li", "move", "x"), ("p", "anchor", "y")]
+            ),
+            "<ul></ul><p>c<li>a</li><li>b</li>"
+        );
+
+        assert_eq!(
+            relocate(
+                "<div>a<b>b</b><i>c</i></div><p></p>",
+                &[
+                    ("div", "move", "d"),
+                    ("b", "move", "b"),
+                    ("p", "anchor", "d")
+                ]
+            ),
+            "<p><div>a<i>c</i></div></p><b>b</b>"
+        );
+    }
+
+    #[test]
+    fn copy_to_anchor() {
+        assert_eq!(
+            relocate(
+                "<head><link rel=preload></head><body><p>a</p></body>",
+                &[
+                    ("link", "copy", "l"),
+                    ("link", "copy", "m"),
+                    ("body", "anchor", "l"),
+                    ("p", "anchor", "m")
+                ]
+            ),
+            "<head><link rel=preload></head><body><p>a<link rel=preload></p>\
+             <link rel=preload></body>"
+        );
+
+        assert_eq!(
+            relocate(
+                "<div>a<b>b</b></div><p></p>",
+                &[
+                    ("div", "copy", "d"),
+                    ("b", "move", "b"),
+                    ("p", "anchor", "d")
+                ]
+            ),
+            "<div>a</div><p><div>a</div></p><b>b</b>"
+        );
+
+        assert_eq!(
+            relocate(
+                "<b>a</b><p></p>",
+                &[("b", "copy", "x"), ("b", "move", "y"), ("p", "anchor", "x")]
+            ),
+            "<p><b>a</b></p><b>a</b>"
+        );
+    }
+
+    #[test]
+    fn relocation_with_content_mutations() {
+        let output = rewrite_html(
+            b"<div><p>a</p></div><footer></footer>",
+            UTF_8,
+            vec![
+                element!("p", |el| {
+                    el.move_to_anchor("f");
+                    el.wrap("<section>", "</section>");
+                    el.set_tag_name("h1")?;
+                    el.on_end_tag(end_tag!(|end| {
+                        end.after("<!--p-->", ContentType::Html);
+                        Ok(())
+                    }))?;
+
+                    Ok(())
+                }),
+                element!("footer", |el| {
+                    el.prepend("[", ContentType::Text);
+                    el.append_anchor("f");
+                    el.append("]", ContentType::Text);
+
+                    Ok(())
+                }),
+            ],
+            vec![],
+        );
+
+        assert_eq!(
+            output,
+            "<div></div><footer>[<section><h1>a</h1><!--p--></section>]</footer>"
+        );
+    }
+
+    #[test]
+    fn ancestors() {
+        const HTML: &str = "http://www.w3.org/1999/xhtml";
+        const SVG: &str = "http://www.w3.org/2000/svg";
+
+        let ancestors_with = |html: &str, selector: &str, full_tree_construction: bool| {
+            let mut ancestors = Vec::new();
+
+            rewrite_str(
+                html,
+                RewriteStrSettings::new()
+                    .with_ancestor_tracking(true)
+                    .with_full_tree_construction(full_tree_construction)
+                    .append_element_content_handler(element!(selector, |el| {
+                        ancestors = el
+                            .ancestors()
+                            .map(|a| (a.tag_name(), a.namespace_uri(), a.depth()))
+                            .collect::<Vec<_>>();
+
+                        assert_eq!(el.ancestors().len(), ancestors.len());
+
+                        Ok(())
+                    })),
+            )
+            .unwrap();
+
+            ancestors
+        };
+
+        let ancestors = |html: &str, selector: &str| ancestors_with(html, selector, false);
+
+        assert_eq!(ancestors("<div>Hi</div>", "div"), []);
+
+        assert_eq!(
+            ancestors("<Div><p><SVG><g><rect/></g></svg></p></div>", "rect"),
+            [
+                ("g".into(), SVG, 3),
+                ("svg".into(), SVG, 2),
+                ("p".into(), HTML, 1),
+                ("div".into(), HTML, 0),
+            ]
+        );
+
+        assert_eq!(
+            ancestors("<section><img><span></span></section>", "span"),
+            [("section".into(), HTML, 0)]
+        );
+
+        assert_eq!(
+            ancestors("<main><p><div></div></main>", "div"),
+            [("p".into(), HTML, 1), ("main".into(), HTML, 0)]
+        );
+
+        // NOTE: the `<p>` element is implicitly closed by the `<div>` in the full tree
+        // construction mode.
+        assert_eq!(
+            ancestors_with("<main><p><div></div></main>", "div", true),
+            [("main".into(), HTML, 0)]
+        );
+
+        rewrite_str(
+            "<ul><li><My-Widget>",
+            RewriteStrSettings::new()
+                .with_ancestor_tracking(true)
+                .append_element_content_handler(element!("my-widget", |el| {
+                    let parent = el.ancestors().next().unwrap();
+
+                    assert!(parent.has_tag_name("LI"));
+                    assert!(!parent.has_tag_name("ul"));
+                    assert!(parent.parent().unwrap().has_tag_name("ul"));
+
+                    Ok(())
+                })),
+        )
+        .unwrap();
+
+        // NOTE: the ancestors are empty unless the tracking is enabled.
+        rewrite_element(b"<div><span></span></div>", UTF_8, "span", |el| {
+            assert_eq!(el.ancestors().len(), 0);
+        });
+    }
+
+    #[test]
+    fn retained_ancestor_attributes() {
+        let html = concat!(
+            r#"<div LANG="fr" dir=rtl class=c><section data-x>"#,
+            r#"<svg lang="en"><g><text>Hi</text></g></svg>"#,
+            r#"<p translate=no><span>Salut</span></p></section></div>"#
+        );
+
+        let mut spans = Vec::new();
+        let mut texts = Vec::new();
+
+        rewrite_str(
+            html,
+            RewriteStrSettings::new()
+                .append_retained_attribute("lang")
+                .append_retained_attribute("Dir")
+                .append_retained_attribute("translate")
+                .append_element_content_handler(element!("span, [data-x]", |el| {
+                    let ancestors = el.ancestors();
+
+                    spans.push((
+                        ancestors.clone().nearest_attribute("lang"),
+                        ancestors.clone().nearest_attribute("DIR"),
+                        ancestors.clone().nearest_attribute("translate"),
+                        ancestors.clone().nearest_attribute("class"),
+                    ));
+
+                    Ok(())
+                }))
+                .append_document_content_handler(doc_text!(|t| {
+                    if !t.as_str().is_empty() {
+                        let ancestors = t.ancestors();
+
+                        texts.push(ancestors.clone().nearest_attribute("lang"));
+                        assert_eq!(
+                            ancestors.last().unwrap().get_attribute("dir").unwrap(),
+                            "rtl"
+                        );
+                    }
+
+                    Ok(())
+                })),
+        )
+        .unwrap();
+
+        assert_eq!(
+            spans,
+            [
+                (Some("fr".into()), Some("rtl".into()), None, None),
+                (
+                    Some("fr".into()),
+                    Some("rtl".into()),
+                    Some("no".into()),
+                    None
+                ),
+            ]
+        );
+
+        assert_eq!(texts, [Some("en".into()), Some("fr".into())]);
+
+        // NOTE: open elements are tracked with retained attributes even without element handlers.
+        let mut langs = Vec::new();
+
+        rewrite_str(
+            r#"<html lang=de><body><!--comment--></body></html>"#,
+            RewriteStrSettings::new()
+                .append_retained_attribute("lang")
+                .append_document_content_handler(doc_comments!(|c| {
+                    langs.push(c.ancestors().nearest_attribute("lang"));
+                    Ok(())
+                })),
+        )
+        .unwrap();
+
+        assert_eq!(langs, [Some("de".into())]);
+    }
+
     mod serialization {
         use super::*;
 
@@ -1603,6 +3122,15 @@ mod tests {
                 },
                 r#"<a a1='foo " baré " baz' a2="foo ' bar ' baz" a3="foo/bar42" a4></a>"#
             );
+
+            let output = rewrite_element_preserving_formatting(HTML, SELECTOR, |el| {
+                el.set_attribute("a3", "foo/bar42").unwrap();
+            });
+
+            assert_eq!(
+                output,
+                r#"<a a1='foo " baré " baz' a2="foo ' bar ' baz" a3=foo/bar42 a4></a>"#
+            );
         }
 
         #[test]
@@ -1625,6 +3153,163 @@ mod tests {
             );
         }
 
+        #[test]
+        fn modified_attr_quotes_escaping() {
+            test!(
+                |el| {
+                    el.set_attribute("a1", r#"it's "42""#).unwrap();
+                    el.set_attribute("a3", "foo bar").unwrap();
+                },
+                r#"<a a1="it's &quot;42&quot;" a2="foo ' bar ' baz" a3="foo bar" a4></a>"#
+            );
+
+            let output = rewrite_element_preserving_formatting(HTML, SELECTOR, |el| {
+                el.set_attribute("a1", r#"it's "42""#).unwrap();
+                el.set_attribute("a3", "foo bar").unwrap();
+            });
+
+            assert_eq!(
+                output,
+                r#"<a a1='it&#39;s "42"' a2="foo ' bar ' baz" a3="foo bar" a4></a>"#
+            );
+        }
+
+        #[test]
+        fn insert_attr() {
+            test!(
+                |el| {
+                    el.insert_attribute(0, "a0", "42").unwrap();
+                    el.insert_attribute(2, "a3", "foo/bar42").unwrap();
+                    el.insert_attribute(42, "a5", "").unwrap();
+                },
+                r#"<a a0="42" a1='foo " baré " baz' a3="foo/bar42" a2="foo ' bar ' baz" a4 a5=""></a>"#
+            );
+
+            let output = rewrite_element(b"<a\n  href=/\tid='x'>", UTF_8, "a", |el| {
+                el.insert_attribute(0, "class", "foo").unwrap();
+
+                assert_eq!(
+                    el.insert_attribute(1, "", "foo"),
+                    Err(AttributeNameError::Empty)
+                );
+            });
+
+            assert_eq!(output, "<a class=\"foo\" href=/ id='x'>");
+
+            let output =
+                rewrite_element_preserving_formatting("<a\n  href=/\tid='x'>", "a", |el| {
+                    el.insert_attribute(0, "class", "foo").unwrap();
+                    el.set_attribute("href", "/home").unwrap();
+                });
+
+            assert_eq!(output, "<a\n  class=\"foo\" href=/home\tid='x'>");
+        }
+
+        #[test]
+        fn rename_attr() {
+            test!(
+                |el| {
+                    el.rename_attribute("A1", "DATA-a1").unwrap();
+                    el.rename_attribute("a3", "a5").unwrap();
+                    el.rename_attribute("a4", "a6").unwrap();
+                    el.rename_attribute("a7", "a8").unwrap();
+                    el.rename_attribute("a5", "A5").unwrap();
+
+                    assert_eq!(
+                        el.rename_attribute("a6", "a2"),
+                        Err(AttributeRenameError::AlreadyExists)
+                    );
+
+                    assert_eq!(
+                        el.rename_attribute("a5", "a=5"),
+                        Err(AttributeRenameError::InvalidAttributeName(
+                            AttributeNameError::ForbiddenCharacter('=')
+                        ))
+                    );
+
+                    assert_eq!(el.get_attribute("data-a1").unwrap(), "foo \" baré \" baz");
+                    assert!(!el.has_attribute("a8"));
+                },
+                r#"<a data-a1="foo &quot; baré &quot; baz" a2="foo ' bar ' baz" a5="foo/bar" a6></a>"#
+            );
+
+            let output = rewrite_element(b"<img\nsrc=a.png data-src=b.png>", UTF_8, "img", |el| {
+                assert_eq!(
+                    el.rename_attribute("src", "data-src"),
+                    Err(AttributeRenameError::AlreadyExists)
+                );
+
+                el.remove_attribute("data-src");
+                el.rename_attribute("src", "data-src").unwrap();
+                el.set_attribute("src", "c.png").unwrap();
+            });
+
+            assert_eq!(output, "<img data-src=\"a.png\" src=\"c.png\">");
+
+            let output = rewrite_element(b"<img src=a alt SRC='b' src>", UTF_8, "img", |el| {
+                el.rename_attribute("src", "data-src").unwrap();
+
+                assert_eq!(el.get_attribute("data-src").unwrap(), "a");
+                assert!(!el.has_attribute("src"));
+            });
+
+            assert_eq!(output, "<img data-src=\"a\" alt data-src=\"b\" data-src>");
+        }
+
+        #[test]
+        fn set_attr_quote() {
+            test!(
+                |el| {
+                    el.set_attribute_quote("a1", AttributeQuote::Double);
+                    el.set_attribute_quote("a2", AttributeQuote::Double);
+                    el.set_attribute_quote("a3", AttributeQuote::Single);
+                    el.set_attribute_quote("a4", AttributeQuote::Unquoted);
+                    el.set_attribute_quote("a5", AttributeQuote::Single);
+
+                    assert_eq!(el.attributes()[0].quote(), AttributeQuote::Double);
+                },
+                r#"<a a1="foo &quot; baré &quot; baz" a2="foo ' bar ' baz" a3='foo/bar' a4=""></a>"#
+            );
+        }
+
+        #[test]
+        fn modified_attr_whitespace() {
+            let html = "<a\n  href='/'\tid=x\rclass  >";
+
+            let output = rewrite_element(html.as_bytes(), UTF_8, "a", |el| {
+                assert_eq!(el.attributes()[0].quote(), AttributeQuote::Double);
+
+                el.set_attribute("href", "/home").unwrap();
+                el.set_attribute("id", "y").unwrap();
+            });
+
+            assert_eq!(output, "<a href=\"/home\" id=\"y\" class>");
+
+            let output = rewrite_element_preserving_formatting(html, "a", |el| {
+                assert_eq!(el.attributes()[0].quote(), AttributeQuote::Single);
+                assert_eq!(el.attributes()[1].quote(), AttributeQuote::Unquoted);
+                assert_eq!(el.attributes()[2].quote(), AttributeQuote::Double);
+
+                el.set_attribute("href", "/home").unwrap();
+                el.set_attribute("id", "y").unwrap();
+            });
+
+            assert_eq!(output, "<a\n  href='/home'\tid=y\rclass  >");
+
+            let output =
+                rewrite_element_preserving_formatting("<img\nsrc=a.png\n/>", "img", |el| {
+                    el.set_attribute("src", "b.png").unwrap();
+                });
+
+            assert_eq!(output, "<img\nsrc=b.png\n/>");
+
+            let output = rewrite_element_preserving_formatting("<img src=a.png />", "img", |el| {
+                el.remove_attribute("src");
+            });
+
+            assert_eq!(output, "<img />");
+        }
+
         #[test]
         fn self_closing_flag() {
             // NOTE: we should add space between valueless attr and self-closing slash
@@ -1804,6 +3489,11 @@ mod tests {
                     el.set_attribute("look", &raw_input[loc.bytes()]).unwrap();
                     if el.can_have_content() {
                         el.on_end_tag(Box::new(|end| {
+                            if end.is_implied() {
+                                assert_eq!(end.source_location().bytes(), 138..138);
+                                return Ok(());
+                            }
+
                             let tag = &raw_input[end.source_location().bytes()];
                             assert_eq!("</", &tag[0..2]);
                             assert_eq!(b'>', *tag.as_bytes().last().unwrap());
@@ -1,14 +1,18 @@
 use std::any::Any;
 
 pub(crate) use self::mutations::{Mutations, StringChunk};
+pub(crate) use self::relocations::{RelocationTarget, Relocations};
 pub(crate) use self::text_decoder::TextDecoder;
 pub(crate) use self::text_encoder::{IncompleteUtf8Resync, TextEncoder};
 
+pub(crate) use self::ancestors::RetainedAttributeValues;
+pub use self::ancestors::{Ancestor, Ancestors};
 pub use self::bail_out::*;
 pub use self::document_end::*;
 pub use self::element::*;
 pub use self::mutations::{ContentType, StreamingHandler};
 pub use self::streaming_sink::StreamingHandlerSink;
+pub use self::style::{StyleDeclaration, StylePropertyError};
 pub use self::text_encoder::Utf8Error;
 pub use self::tokens::*;
 
@@ -84,10 +88,13 @@ macro_rules! impl_user_data {
 #[macro_use]
 mod mutations;
 
+mod ancestors;
 mod bail_out;
 mod document_end;
 mod element;
+mod relocations;
 mod streaming_sink;
+mod style;
 mod text_decoder;
 mod text_encoder;
 mod tokens;
@@ -83,6 +83,11 @@ impl Mutations {
     pub fn removed(&self) -> bool {
         self.inner.as_ref().is_some_and(|inner| inner.removed)
     }
+
+    #[inline]
+    pub const fn is_mutated(&self) -> bool {
+        self.inner.is_some()
+    }
 }
 
 /// Part of [`DynamicString`]
@@ -0,0 +1,237 @@
+use super::{StreamingHandlerSink, StringChunk};
+use crate::memory::{MemoryLimitExceededError, SharedMemoryLimiter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// The anchors an element is moved or copied to.
+pub(crate) struct RelocationTarget {
+    anchors: Vec<Box<str>>,
+    keep_original: bool,
+}
+
+impl RelocationTarget {
+    #[inline]
+    pub fn new(keep_original: bool) -> Self {
+        Self {
+            anchors: Vec::new(),
+            keep_original,
+        }
+    }
+
+    #[inline]
+    pub fn add_anchor(&mut self, anchor: &str, keep_original: bool) {
+        self.anchors.push(anchor.into());
+        self.keep_original &= keep_original;
+    }
+}
+
+struct Capture {
+    target: RelocationTarget,
+    content: Vec<u8>,
+}
+
+#[derive(Default)]
+struct RelocationsInner {
+    /// Captures of the elements that are being serialized, the innermost one is the last.
+    captures: Vec<Capture>,
+    /// Captured content waiting for its anchor, in the document order.
+    pending: Vec<(Box<str>, Vec<u8>)>,
+    /// The size of the captured content charged to the memory limiter.
+    used_memory: usize,
+    error: Option<MemoryLimitExceededError>,
+}
+
+impl RelocationsInner {
+    fn charge(&mut self, memory_limiter: &SharedMemoryLimiter, byte_count: usize) -> bool {
+        match memory_limiter.increase_usage(byte_count) {
+            Ok(()) => {
+                self.used_memory += byte_count;
+                true
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+                false
+            }
+        }
+    }
+
+    fn release(&mut self, memory_limiter: &SharedMemoryLimiter, byte_count: usize) {
+        memory_limiter.decrease_usage(byte_count);
+        self.used_memory -= byte_count;
+    }
+}
+
+/// Keeps the output of the elements that are moved or copied to anchors until the anchors
+/// are reached.
+///
+/// The capture boundaries are marked with the content chunks inserted around the elements,
+/// so they match the serialized output exactly.
+#[derive(Clone)]
+pub(crate) struct Relocations {
+    inner: Arc<Mutex<RelocationsInner>>,
+    /// Set once an element is relocated, so that the documents without relocations don't take
+    /// the lock for every output chunk.
+    active: Arc<AtomicBool>,
+    memory_limiter: SharedMemoryLimiter,
+}
+
+impl Relocations {
+    #[inline]
+    #[must_use]
+    pub fn new(memory_limiter: SharedMemoryLimiter) -> Self {
+        Self {
+            inner: Arc::default(),
+            active: Arc::default(),
+            memory_limiter,
+        }
+    }
+
+    #[inline]
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, RelocationsInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Passes the output `chunk` to the open captures, starting with the innermost one, and
+    /// then to the `output_handler`, unless one of the captures moves its element.
+    pub fn write(&self, chunk: &[u8], output_handler: &mut dyn FnMut(&[u8])) {
+        if !self.is_active() {
+            output_handler(chunk);
+            return;
+        }
+
+        let mut inner = self.lock();
+        let mut pass_through = true;
+
+        for idx in (0..inner.captures.len()).rev() {
+            if inner.charge(&self.memory_limiter, chunk.len()) {
+                inner.captures[idx].content.extend_from_slice(chunk);
+            }
+
+            if !inner.captures[idx].target.keep_original {
+                pass_through = false;
+                break;
+            }
+        }
+
+        drop(inner);
+
+        if pass_through {
+            output_handler(chunk);
+        }
+    }
+
+    /// Returns the chunk that starts capturing the output for the `target`.
+    pub fn capture_start(&self, target: RelocationTarget) -> StringChunk {
+        let relocations = self.clone();
+
+        self.active.store(true, Ordering::Relaxed);
+
+        StringChunk::stream(Box::new(move |_: &mut StreamingHandlerSink<'_>| {
+            relocations.lock().captures.push(Capture {
+                target,
+                content: Vec::new(),
+            });
+
+            Ok(())
+        }))
+    }
+
+    /// Returns the chunk that finishes the innermost capture, making its content pending for
+    /// the anchors.
+    pub fn capture_end(&self) -> StringChunk {
+        let relocations = self.clone();
+
+        StringChunk::stream(Box::new(move |_: &mut StreamingHandlerSink<'_>| {
+            let mut inner = relocations.lock();
+
+            if let Some(Capture { target, content }) = inner.captures.pop() {
+                let mut anchors = target.anchors.into_iter();
+
+                // NOTE: the first anchor takes the content that has already been charged,
+                // the rest get copies of it.
+                if let Some(anchor) = anchors.next() {
+                    for anchor in anchors {
+                        if inner.charge(&relocations.memory_limiter, content.len()) {
+                            inner.pending.push((anchor, content.clone()));
+                        }
+                    }
+
+                    inner.pending.push((anchor, content));
+                }
+            }
+
+            Ok(())
+        }))
+    }
+
+    /// Returns the chunk that writes the content pending for the `anchor`.
+    pub fn anchor_content(&self, anchor: &str) -> StringChunk {
+        let relocations = self.clone();
+        let anchor = Box::<str>::from(anchor);
+
+        StringChunk::stream(Box::new(move |sink: &mut StreamingHandlerSink<'_>| {
+            let content = relocations.take_pending(|a| *a == *anchor);
+
+            if !content.is_empty() {
+                (sink.output_handler())(&content);
+            }
+
+            Ok(())
+        }))
+    }
+
+    /// Removes the pending content of the anchors that match the `filter` and returns it
+    /// concatenated in the document order.
+    pub fn take_pending(&self, filter: impl Fn(&str) -> bool) -> Vec<u8> {
+        if !self.is_active() {
+            return Vec::new();
+        }
+
+        let mut inner = self.lock();
+        let mut taken = Vec::new();
+
+        inner.pending.retain_mut(|(anchor, content)| {
+            if filter(anchor) {
+                taken.push(std::mem::take(content));
+                false
+            } else {
+                true
+            }
+        });
+
+        let content = taken.concat();
+
+        inner.release(&self.memory_limiter, content.len());
+
+        content
+    }
+
+    #[inline]
+    pub fn take_error(&self) -> Option<MemoryLimitExceededError> {
+        if !self.is_active() {
+            return None;
+        }
+
+        self.lock().error.take()
+    }
+
+    /// Drops the captured content of the previous document.
+    pub fn reset(&self) {
+        if !self.active.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let mut inner = self.lock();
+        let used_memory = inner.used_memory;
+
+        inner.release(&self.memory_limiter, used_memory);
+        inner.captures.clear();
+        inner.pending.clear();
+        inner.error = None;
+    }
+}
@@ -0,0 +1,591 @@
+use cssparser::{
+    AtRuleParser, CowRcStr, DeclarationParser, ParseError, Parser, ParserInput, ParserState,
+    QualifiedRuleParser, RuleBodyItemParser, RuleBodyParser, Token, parse_important,
+};
+use std::ops::Range;
+use thiserror::Error;
+
+/// An error that occurs when an invalid property is provided for the `style` attribute.
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum StylePropertyError {
+    /// The provided property name is not a CSS identifier.
+    #[error("The style property name is not a CSS identifier.")]
+    InvalidName,
+
+    /// The provided value is empty, or it can't be used as a single declaration value without
+    /// affecting the other declarations (e.g. it contains `;` or an unclosed block).
+    ///
+    /// Use the `important` flag instead of adding `!important` to the value.
+    #[error("The style property value is not a single CSS declaration value.")]
+    InvalidValue,
+
+    /// The declaration can't be appended to the `style` attribute, because the end of the
+    /// attribute value would swallow it.
+    #[error("The declaration can't be appended to the style attribute.")]
+    InvalidStyle,
+}
+
+/// A declaration of the `style` attribute of an [`Element`].
+///
+/// [`Element`]: struct.Element.html
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StyleDeclaration {
+    name: String,
+    value: String,
+    important: bool,
+}
+
+impl StyleDeclaration {
+    /// Returns the name of the property as it's written in the attribute.
+    #[inline]
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the value of the property, without `!important`.
+    #[inline]
+    #[must_use]
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Returns `true` if the declaration has `!important`.
+    #[inline]
+    #[must_use]
+    pub const fn is_important(&self) -> bool {
+        self.important
+    }
+
+    /// Returns `true` if the declaration is for the property with the `name`.
+    ///
+    /// Custom property names are case-sensitive, others are ASCII case-insensitive.
+    #[inline]
+    fn is_for(&self, name: &str) -> bool {
+        if name.starts_with("--") {
+            self.name == name
+        } else {
+            self.name.eq_ignore_ascii_case(name)
+        }
+    }
+}
+
+/// Collects the declarations of the `style` attribute along with their source ranges, skipping
+/// the invalid ones.
+struct StyleParser;
+
+impl<'i> DeclarationParser<'i> for StyleParser {
+    type Declaration = (StyleDeclaration, Range<usize>);
+    type Error = ();
+
+    fn parse_value<'t>(
+        &mut self,
+        name: CowRcStr<'i>,
+        input: &mut Parser<'i, 't>,
+        declaration_start: &ParserState,
+    ) -> Result<Self::Declaration, ParseError<'i, ()>> {
+        input.skip_whitespace();
+
+        let value_start = input.position();
+        let mut value_end = value_start;
+        let mut end = value_start;
+        let mut important = false;
+
+        loop {
+            if input
+                .try_parse(|input| {
+                    parse_important(input)?;
+                    input.expect_exhausted()
+                })
+                .is_ok()
+            {
+                important = true;
+                end = input.position();
+                break;
+            }
+
+            // NOTE: the value is trimmed, so the trailing whitespace and comments are skipped.
+            match input.next_including_whitespace_and_comments() {
+                Ok(Token::WhiteSpace(_) | Token::Comment(_)) => continue,
+                Ok(
+                    Token::Function(_)
+                    | Token::ParenthesisBlock
+                    | Token::SquareBracketBlock
+                    | Token::CurlyBracketBlock,
+                ) => {
+                    input.parse_nested_block(|input| {
+                        while input.next_including_whitespace_and_comments().is_ok() {}
+                        Ok::<_, ParseError<'i, ()>>(())
+                    })?;
+                }
+                Ok(_) => (),
+                Err(_) => break,
+            }
+
+            value_end = input.position();
+            end = value_end;
+        }
+
+        if value_end == value_start {
+            return Err(input.new_custom_error(()));
+        }
+
+        let declaration = StyleDeclaration {
+            name: name.to_string(),
+            value: input.slice(value_start..value_end).to_owned(),
+            important,
+        };
+
+        Ok((
+            declaration,
+            declaration_start.position().byte_index()..end.byte_index(),
+        ))
+    }
+}
+
+impl<'i> AtRuleParser<'i> for StyleParser {
+    type Prelude = ();
+    type AtRule = (StyleDeclaration, Range<usize>);
+    type Error = ();
+}
+
+impl<'i> QualifiedRuleParser<'i> for StyleParser {
+    type Prelude = ();
+    type QualifiedRule = (StyleDeclaration, Range<usize>);
+    type Error = ();
+}
+
+impl<'i> RuleBodyItemParser<'i, (StyleDeclaration, Range<usize>), ()> for StyleParser {
+    #[inline]
+    fn parse_declarations(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn parse_qualified(&self) -> bool {
+        false
+    }
+}
+
+/// Parses the value of the `style` attribute into the declarations with their source ranges.
+pub(crate) fn parse_style(style: &str) -> Vec<(StyleDeclaration, Range<usize>)> {
+    let mut input = ParserInput::new(style);
+    let mut input = Parser::new(&mut input);
+
+    RuleBodyParser::new(&mut input, &mut StyleParser)
+        .filter_map(Result::ok)
+        .collect()
+}
+
+/// Returns the value of the declaration that applies for the property with the `name`, i.e. the
+/// last one, with the important declarations taking precedence.
+pub(crate) fn get_property(style: &str, name: &str) -> Option<String> {
+    parse_style(style)
+        .into_iter()
+        .map(|(d, _)| d)
+        .filter(|d| d.is_for(name))
+        .fold(None, |applied: Option<StyleDeclaration>, d| match applied {
+            Some(applied) if applied.important && !d.important => Some(applied),
+            _ => Some(d),
+        })
+        .map(|d| d.value)
+}
+
+/// Returns the unescaped property name, or an error if the `name` is not a CSS identifier.
+fn parse_name(name: &str) -> Result<String, StylePropertyError> {
+    let mut input = ParserInput::new(name);
+    let mut input = Parser::new(&mut input);
+
+    input
+        .parse_entirely(|input| {
+            input
+                .expect_ident()
+                .map(ToString::to_string)
+                .map_err(Into::<ParseError<'_, ()>>::into)
+        })
+        .map_err(|_| StylePropertyError::InvalidName)
+}
+
+/// Checks that the `value` ends where the declaration ends, so that it can't affect the
+/// declarations that follow it.
+fn validate_value(value: &str) -> Result<(), StylePropertyError> {
+    let probe = format!("a:{value};b:c");
+    let declarations = parse_style(&probe);
+
+    match &*declarations {
+        [(value, _), (next, _)] if !value.important && next.name == "b" && next.value == "c" => {
+            Ok(())
+        }
+        _ => Err(StylePropertyError::InvalidValue),
+    }
+}
+
+/// Removes the text of the declaration in the `range` along with the `;` and the whitespace
+/// that follow it. The preceding whitespace is removed too if the declaration is the last one.
+fn remove_declaration(style: &mut String, range: Range<usize>) {
+    let rest = &style[range.end..];
+    let mut end = range.end + (rest.len() - rest.trim_start().len());
+
+    if style[end..].starts_with(';') {
+        end += 1;
+        end += style[end..].len() - style[end..].trim_start().len();
+    }
+
+    let start = if end == style.len() {
+        style[..range.start].trim_end().len()
+    } else {
+        range.start
+    };
+
+    style.replace_range(start..end, "");
+}
+
+/// Returns `true` if the `c` can be a part of a CSS identifier.
+#[inline]
+const fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_') || !c.is_ascii()
+}
+
+/// Terminates the comment, the string, the `url()` and the blocks that are left open at the end
+/// of the `style`, so that the text appended to it is parsed on its own. A trailing escape is
+/// removed, as it would escape the appended text otherwise.
+fn terminate_style(style: &mut String) {
+    let mut closers = Vec::new();
+    let mut quote = None;
+    let mut in_comment = false;
+    let mut in_url = false;
+    let mut trailing_escape = None;
+    let mut chars = style.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if in_comment {
+            in_comment = !(c == '*' && chars.next_if(|&(_, c)| c == '/').is_some());
+
+            continue;
+        }
+
+        if c == '\\' {
+            if chars.next().is_none() {
+                trailing_escape = Some(i);
+            }
+
+            continue;
+        }
+
+        if let Some(q) = quote {
+            if c == q || c == '\n' {
+                quote = None;
+            }
+
+            continue;
+        }
+
+        if in_url {
+            in_url = c != ')';
+
+            continue;
+        }
+
+        match c {
+            '/' if chars.next_if(|&(_, c)| c == '*').is_some() => in_comment = true,
+            '"' | '\'' => quote = Some(c),
+            '(' => {
+                let is_url = style[..i]
+                    .get(i.saturating_sub(3)..)
+                    .is_some_and(|f| f.eq_ignore_ascii_case("url"))
+                    && !style[..i - 3].ends_with(is_ident_char);
+
+                let is_unquoted_url = is_url
+                    && !matches!(style[i + 1..].trim_start().chars().next(), Some('"' | '\''));
+
+                if is_unquoted_url {
+                    in_url = true;
+                } else {
+                    closers.push(')');
+                }
+            }
+            '[' => closers.push(']'),
+            '{' => closers.push('}'),
+            ')' | ']' | '}' if closers.last() == Some(&c) => {
+                closers.pop();
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(i) = trailing_escape {
+        style.truncate(i);
+    }
+
+    if in_comment {
+        style.push_str("*/");
+    }
+
+    style.extend(quote);
+
+    if in_url {
+        style.push(')');
+    }
+
+    style.extend(closers.into_iter().rev());
+}
+
+/// Sets the property with the `name` in the `style`. The first declaration of the property is
+/// replaced in place and the others are removed. If there are none, the declaration is appended
+/// after terminating the end of the `style`.
+pub(crate) fn set_property(
+    style: &str,
+    name: &str,
+    value: &str,
+    important: bool,
+) -> Result<String, StylePropertyError> {
+    let unescaped_name = parse_name(name)?;
+    let value = value.trim();
+
+    if value.is_empty() {
+        return Err(StylePropertyError::InvalidValue);
+    }
+
+    validate_value(value)?;
+
+    let mut declaration = format!("{name}: {value}");
+
+    if important {
+        declaration.push_str(" !important");
+    }
+
+    let ranges = parse_style(style)
+        .into_iter()
+        .filter(|(d, _)| d.is_for(&unescaped_name))
+        .map(|(_, range)| range)
+        .collect::<Vec<_>>();
+
+    let mut style = style.to_owned();
+
+    let Some((first, others)) = ranges.split_first() else {
+        terminate_style(&mut style);
+
+        let trimmed_len = style.trim_end().len();
+
+        style.truncate(trimmed_len);
+
+        if !style.is_empty() {
+            if !style.ends_with(';') {
+                style.push(';');
+            }
+
+            style.push(' ');
+        }
+
+        style.push_str(&declaration);
+
+        // NOTE: make sure that nothing at the end of the original style swallows the declaration.
+        return match parse_style(&style).last() {
+            Some((d, _)) if d.name == name && d.value == value && d.important == important => {
+                Ok(style)
+            }
+            _ => Err(StylePropertyError::InvalidStyle),
+        };
+    };
+
+    // NOTE: the declarations are removed from the end, so that the ranges stay valid.
+    for range in others.iter().rev() {
+        remove_declaration(&mut style, range.clone());
+    }
+
+    style.replace_range(first.clone(), &declaration);
+
+    Ok(style)
+}
+
+/// Removes all the declarations of the property with the `name` from the `style`. Returns `None`
+/// if there are none.
+pub(crate) fn remove_property(style: &str, name: &str) -> Option<String> {
+    let name = parse_name(name).ok()?;
+
+    let ranges = parse_style(style)
+        .into_iter()
+        .filter(|(d, _)| d.is_for(&name))
+        .map(|(_, range)| range)
+        .collect::<Vec<_>>();
+
+    if ranges.is_empty() {
+        return None;
+    }
+
+    let mut style = style.to_owned();
+
+    for range in ranges.into_iter().rev() {
+        remove_declaration(&mut style, range);
+    }
+
+    Some(style)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let declarations = parse_style(
+            "color: red; ; background: url( a.png ) no-repeat /* comment */ ;\
+             invalid; margin:0!IMPORTANT; --Custom: { a: b }; width: calc(1px + (2px))",
+        )
+        .into_iter()
+        .map(|(d, _)| (d.name, d.value, d.important))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            declarations,
+            [
+                ("color".into(), "red".into(), false),
+                ("background".into(), "url( a.png ) no-repeat".into(), false),
+                ("margin".into(), "0".into(), true),
+                ("--Custom".into(), "{ a: b }".into(), false),
+                ("width".into(), "calc(1px + (2px))".into(), false),
+            ] as [(String, String, bool); 5]
+        );
+    }
+
+    #[test]
+    fn get() {
+        let style = "COLOR: red; color: blue !important; Color: green; --x: 1; --X: 2";
+
+        assert_eq!(get_property(style, "color").as_deref(), Some("blue"));
+        assert_eq!(get_property(style, "--x").as_deref(), Some("1"));
+        assert_eq!(get_property(style, "--X").as_deref(), Some("2"));
+        assert_eq!(get_property(style, "display"), None);
+        assert_eq!(
+            get_property("color: red; color: green", "color").as_deref(),
+            Some("green")
+        );
+    }
+
+    #[test]
+    fn set() {
+        let set = |style, name, value, important| set_property(style, name, value, important);
+
+        assert_eq!(
+            set(
+                "color: red;  display:block ; margin: 0",
+                "DISPLAY",
+                "none",
+                true
+            )
+            .unwrap(),
+            "color: red;  DISPLAY: none !important ; margin: 0"
+        );
+
+        assert_eq!(
+            set(
+                "display: block; color: red; display: flex;",
+                "display",
+                "none",
+                false
+            )
+            .unwrap(),
+            "display: none; color: red;"
+        );
+
+        assert_eq!(set("", "color", "red", false).unwrap(), "color: red");
+        assert_eq!(set("  ", "color", "red", false).unwrap(), "color: red");
+
+        assert_eq!(
+            set("margin: 0 ", "color", "red", false).unwrap(),
+            "margin: 0; color: red"
+        );
+
+        assert_eq!(
+            set("margin: 0; ", "color", "red", false).unwrap(),
+            "margin: 0; color: red"
+        );
+
+        assert_eq!(
+            set(
+                "color: red",
+                "background-image",
+                " url(\"a;b.png\") ",
+                false
+            )
+            .unwrap(),
+            "color: red; background-image: url(\"a;b.png\")"
+        );
+
+        for value in [
+            "",
+            " ",
+            "red; display: none",
+            "calc(1px",
+            "\"red",
+            "red !important",
+        ] {
+            assert_eq!(
+                set("color: red", "color", value, false),
+                Err(StylePropertyError::InvalidValue),
+                "Value: {value}"
+            );
+        }
+
+        for (style, expected) in [
+            (
+                "background:url(x",
+                "background:url(x); color: red !important",
+            ),
+            (
+                "background: URL( x ",
+                "background: URL( x ); color: red !important",
+            ),
+            (
+                "background:url('x",
+                "background:url('x'); color: red !important",
+            ),
+            ("margin:0; /* c", "margin:0; /* c*/; color: red !important"),
+            ("a:b; c:{", "a:b; c:{}; color: red !important"),
+            ("a:b; c:{[(", "a:b; c:{[()]}; color: red !important"),
+            ("content:'x", "content:'x'; color: red !important"),
+            ("content:'x ", "content:'x '; color: red !important"),
+            ("content:\"a)\\", "content:\"a)\"; color: red !important"),
+            ("a:b\\", "a:b; color: red !important"),
+            (
+                "a:b; --x: { \"}\" /* } */ (",
+                "a:b; --x: { \"}\" /* } */ ()}; color: red !important",
+            ),
+        ] {
+            let style = set(style, "color", "red", true).unwrap();
+
+            assert_eq!(style, expected);
+            assert_eq!(get_property(&style, "color").as_deref(), Some("red"));
+        }
+
+        assert_eq!(
+            set("a:b\\ ", "color", "red", false),
+            Err(StylePropertyError::InvalidStyle)
+        );
+
+        for name in ["", "1px", "col or", "color:"] {
+            assert_eq!(
+                set("color: red", name, "red", false),
+                Err(StylePropertyError::InvalidName),
+                "Name: {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn remove() {
+        assert_eq!(
+            remove_property("color: red; display: none ;margin: 0", "Display").unwrap(),
+            "color: red; margin: 0"
+        );
+
+        assert_eq!(
+            remove_property("color: red; margin: 0; color: blue", "color").unwrap(),
+            "margin: 0;"
+        );
+
+        assert_eq!(remove_property("color: red", "color").unwrap(), "");
+        assert_eq!(remove_property("color: red", "display"), None);
+        assert_eq!(remove_property("color: red", "1px"), None);
+    }
+}
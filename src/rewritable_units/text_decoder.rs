@@ -29,6 +29,13 @@ impl TextDecoder {
         }
     }
 
+    /// Drops the pending text for a new document, keeping the text buffer.
+    pub fn reset(&mut self, encoding: AsciiCompatibleEncoding) {
+        self.encoding = encoding;
+        self.pending_source_location_bytes_start = 0;
+        self.pending_text_streaming_decoder = None;
+    }
+
     /// Unflushed text will use its old encoding
     pub fn set_encoding(&mut self, encoding: AsciiCompatibleEncoding) {
         self.encoding = encoding;
@@ -1,10 +1,14 @@
-use crate::base::{Bytes, BytesCow, SourceLocation, eq_case_insensitive};
+use crate::base::{Bytes, BytesCow, Range, SourceLocation, eq_case_insensitive};
 use crate::errors::RewritingError;
-use crate::html::escape_double_quotes_only;
+use crate::html::{
+    attr_value_tokens, escape_quotes_only, is_attr_whitespace, is_valid_unquoted_attr_value,
+};
 use crate::parser::AttributeBuffer;
 use crate::rewritable_units::Serialize;
 use encoding_rs::Encoding;
+use std::borrow::Cow;
 use std::cell::OnceCell;
+use std::collections::HashSet;
 use std::fmt::{self, Debug};
 use std::num::NonZero;
 use thiserror::Error;
@@ -31,6 +35,67 @@ pub enum AttributeNameError {
     UnencodableCharacter,
 }
 
+/// An error that occurs when an invalid token is provided for an attribute that holds a list of
+/// whitespace-separated tokens (e.g. `class`).
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AttributeTokenError {
+    /// The provided token is empty.
+    #[error("Attribute token can't be empty.")]
+    Empty,
+
+    /// The provided token contains whitespace, which separates the tokens in the attribute value.
+    #[error("Attribute token can't contain whitespace.")]
+    ForbiddenWhitespace,
+
+    /// The provided attribute name is invalid.
+    #[error("{0}")]
+    InvalidAttributeName(#[from] AttributeNameError),
+}
+
+/// An error that occurs when an attribute of an [`Element`] can't be renamed.
+///
+/// [`Element`]: struct.Element.html
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AttributeRenameError {
+    /// The element already has an attribute with the new name.
+    #[error("An attribute with the new name already exists.")]
+    AlreadyExists,
+
+    /// The provided new name is invalid.
+    #[error("{0}")]
+    InvalidAttributeName(#[from] AttributeNameError),
+}
+
+/// The quotes around the value of an attribute of an [`Element`].
+///
+/// [`Element`]: struct.Element.html
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub enum AttributeQuote {
+    /// The value is enclosed in `"`, e.g. `name="value"`.
+    #[default]
+    Double,
+
+    /// The value is enclosed in `'`, e.g. `name='value'`.
+    Single,
+
+    /// The value is not enclosed in quotes, e.g. `name=value`.
+    ///
+    /// Values that can't be written without quotes (e.g. empty values or values with
+    /// whitespace) are enclosed in `"` instead.
+    Unquoted,
+}
+
+impl AttributeQuote {
+    #[inline]
+    const fn from_closing_quote(quote: Option<u8>) -> Self {
+        match quote {
+            Some(b'\'') => Self::Single,
+            Some(b'"') => Self::Double,
+            _ => Self::Unquoted,
+        }
+    }
+}
+
 /// An attribute of an [`Element`].
 ///
 /// This is an immutable representation of an attribute. To modify element's attributes use
@@ -40,7 +105,19 @@ pub enum AttributeNameError {
 pub struct Attribute<'i> {
     name: BytesCow<'i>,
     value: BytesCow<'i>,
+    /// bytes in the source document, kept after modifications
     raw: Option<Bytes<'i>>,
+    /// quotes around the value in the source document, if it has a value
+    raw_quote: Option<AttributeQuote>,
+    /// unset for attributes without a value until a value or quotes are set
+    has_value: bool,
+    modified: bool,
+    /// quotes set explicitly to serialize the value with
+    quote: Option<AttributeQuote>,
+    /// keeps the quotes of the source document once the attribute is modified
+    preserve_formatting: bool,
+    /// whitespace that precedes the attribute in the original tag
+    separator: Option<Bytes<'i>>,
     encoding: &'static Encoding,
     /// absolute document position of attribute name and attribute value
     name_value_start: Option<(usize, NonZero<usize>)>,
@@ -53,6 +130,8 @@ impl<'i> Attribute<'i> {
         name: BytesCow<'i>,
         value: BytesCow<'i>,
         raw: Bytes<'i>,
+        raw_quote: Option<AttributeQuote>,
+        separator: Option<Bytes<'i>>,
         encoding: &'static Encoding,
         name_value_start: Option<(usize, NonZero<usize>)>,
     ) -> Self {
@@ -60,11 +139,39 @@ impl<'i> Attribute<'i> {
             name,
             value,
             raw: Some(raw),
+            raw_quote,
+            has_value: raw_quote.is_some(),
+            modified: false,
+            quote: None,
+            preserve_formatting: false,
+            separator,
             encoding,
             name_value_start,
         }
     }
 
+    #[inline]
+    #[must_use]
+    const fn new_modified(
+        name: BytesCow<'i>,
+        value: BytesCow<'i>,
+        encoding: &'static Encoding,
+    ) -> Self {
+        Attribute {
+            name,
+            value,
+            raw: None,
+            raw_quote: None,
+            has_value: true,
+            modified: true,
+            quote: None,
+            preserve_formatting: false,
+            separator: None,
+            encoding,
+            name_value_start: None,
+        }
+    }
+
     fn name_from_string(
         name: String,
         encoding: &'static Encoding,
@@ -134,21 +241,124 @@ impl<'i> Attribute<'i> {
     #[inline]
     fn set_value(&mut self, value: &str) {
         self.value = BytesCow::owned_from_str(value, self.encoding);
-        self.raw = None;
+        self.has_value = true;
+        self.modified = true;
         self.name_value_start = None;
     }
+
+    #[inline]
+    fn set_value_bytes(&mut self, value: Vec<u8>) {
+        self.value = BytesCow::from(Cow::Owned(value));
+        self.has_value = true;
+        self.modified = true;
+        self.name_value_start = None;
+    }
+
+    /// Returns the bytes of the attribute in the source document, before any rewriting. They
+    /// span from the start of the name to the end of the value, including the closing quote.
+    ///
+    /// The bytes are in the document's character encoding. Returns `None` for attributes that
+    /// were added.
+    #[inline]
+    #[must_use]
+    pub fn raw_bytes(&self) -> Option<&[u8]> {
+        self.raw.as_deref()
+    }
+
+    /// Returns the quotes around the value of the attribute in the source document.
+    ///
+    /// Returns `None` for attributes that were added and for attributes without a value, e.g.
+    /// `disabled` in `<input disabled>`.
+    #[inline]
+    #[must_use]
+    pub const fn raw_quote(&self) -> Option<AttributeQuote> {
+        self.raw_quote
+    }
+
+    /// Returns `true` if the attribute has a value in the source document, even an empty one
+    /// (e.g. `title=""`).
+    ///
+    /// Returns `false` for attributes that were added and for attributes without a value, e.g.
+    /// `disabled` in `<input disabled>`.
+    #[inline]
+    #[must_use]
+    pub const fn has_raw_value(&self) -> bool {
+        self.raw_quote.is_some()
+    }
+
+    /// Returns the quotes that are used around the value when the modified attribute is
+    /// serialized.
+    ///
+    /// Modified attributes are enclosed in double quotes, unless their quotes are set with
+    /// [`Element::set_attribute_quote`], or the quotes of the original document are kept with
+    /// [`Settings::with_preserve_attribute_formatting`].
+    ///
+    /// [`Element::set_attribute_quote`]: struct.Element.html#method.set_attribute_quote
+    /// [`Settings::with_preserve_attribute_formatting`]: ../struct.Settings.html#method.with_preserve_attribute_formatting
+    #[inline]
+    #[must_use]
+    pub const fn quote(&self) -> AttributeQuote {
+        match (self.quote, self.raw_quote) {
+            (Some(quote), _) => quote,
+            // NOTE: attributes without values are quoted once a value is set.
+            (None, Some(raw_quote)) if self.preserve_formatting => raw_quote,
+            (None, _) => AttributeQuote::Double,
+        }
+    }
+
+    #[inline]
+    fn set_quote(&mut self, quote: AttributeQuote) -> bool {
+        let current_quote = match (self.has_value, self.modified) {
+            (false, _) => None,
+            (true, true) => Some(self.quote()),
+            (true, false) => self.raw_quote,
+        };
+
+        if current_quote == Some(quote) {
+            return false;
+        }
+
+        self.quote = Some(quote);
+        self.has_value = true;
+        self.modified = true;
+        self.name_value_start = None;
+
+        true
+    }
+
+    #[inline]
+    fn has_token(&self, token: &[u8]) -> bool {
+        attr_value_tokens(&self.value).any(|t| t == token)
+    }
 }
 
 impl Serialize for &Attribute<'_> {
     #[inline]
     fn into_bytes(self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
-        if let Some(raw) = self.raw.as_ref() {
+        if let (Some(raw), false) = (self.raw.as_ref(), self.modified) {
             output_handler(raw);
         } else {
             output_handler(&self.name);
-            output_handler(b"=\"");
-            escape_double_quotes_only(self.value.as_ref(), output_handler);
-            output_handler(b"\"");
+
+            // NOTE: attributes without values keep them omitted, e.g. once they are renamed.
+            if !self.has_value {
+                return Ok(());
+            }
+
+            let quote = match self.quote() {
+                AttributeQuote::Unquoted if is_valid_unquoted_attr_value(&self.value) => {
+                    output_handler(b"=");
+                    output_handler(&self.value);
+
+                    return Ok(());
+                }
+                AttributeQuote::Single => b'\'',
+                AttributeQuote::Double | AttributeQuote::Unquoted => b'"',
+            };
+
+            output_handler(&[b'=', quote]);
+            escape_quotes_only(self.value.as_ref(), quote, output_handler);
+            output_handler(&[quote]);
         }
         Ok(())
     }
@@ -170,6 +380,7 @@ pub(crate) struct Attributes<'i> {
     items: OnceCell<Vec<Attribute<'i>>>,
     pub(crate) encoding: &'static Encoding,
     source_byte_offset: usize,
+    preserve_formatting: bool,
 }
 
 impl<'i> Attributes<'i> {
@@ -187,9 +398,27 @@ impl<'i> Attributes<'i> {
             items: OnceCell::default(),
             encoding,
             source_byte_offset,
+            preserve_formatting: false,
         }
     }
 
+    /// Keeps the quotes of the modified attributes and the whitespace between the attributes
+    /// of the original tag.
+    pub(crate) fn preserve_formatting(&mut self) {
+        self.preserve_formatting = true;
+
+        if let Some(items) = self.items.get_mut() {
+            for attr in items {
+                attr.preserve_formatting = true;
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) const fn preserves_formatting(&self) -> bool {
+        self.preserve_formatting
+    }
+
     pub(crate) fn map_attribute<R>(
         &self,
         name: &str,
@@ -236,19 +465,227 @@ impl<'i> Attributes<'i> {
         {
             Some(attr) => attr.set_value(value),
             None => {
-                items.push(Attribute {
+                items.push(Attribute::new_modified(
                     name,
-                    value: BytesCow::owned_from_str(value, encoding),
-                    raw: None,
+                    BytesCow::owned_from_str(value, encoding),
                     encoding,
-                    name_value_start: None,
-                });
+                ));
             }
         }
 
         Ok(())
     }
 
+    /// Inserts the attribute at the `index`, or at the end if the `index` is out of bounds.
+    ///
+    /// If the attribute is already present, its value is replaced and it's moved to the
+    /// `index`.
+    pub fn insert_attribute(
+        &mut self,
+        index: usize,
+        name: &str,
+        value: &str,
+        encoding: &'static Encoding,
+    ) -> Result<(), AttributeNameError> {
+        let name = Attribute::name_from_string(name.to_ascii_lowercase(), encoding)?;
+        let items = self.as_mut_vec();
+
+        let mut attr = match items
+            .iter()
+            .position(|attr| eq_case_insensitive(&attr.name.as_ref(), &name.as_ref()))
+        {
+            Some(existing) => {
+                let mut attr = items.remove(existing);
+
+                attr.set_value(value);
+                attr.separator = None;
+                attr
+            }
+            None => {
+                Attribute::new_modified(name, BytesCow::owned_from_str(value, encoding), encoding)
+            }
+        };
+
+        // NOTE: the whitespace of the original tag is kept in place.
+        if let Some(next) = items.get_mut(index) {
+            attr.separator = next.separator.take();
+        }
+
+        items.insert(index.min(items.len()), attr);
+
+        Ok(())
+    }
+
+    /// Renames every occurrence of the attribute, keeping their positions, values and quotes.
+    /// Returns `true` if the attribute is present.
+    pub fn rename_attribute(
+        &mut self,
+        old_name: &str,
+        new_name: &str,
+        encoding: &'static Encoding,
+    ) -> Result<bool, AttributeRenameError> {
+        let new_name = Attribute::name_from_string(new_name.to_ascii_lowercase(), encoding)?;
+        let Ok(old_name) = Attribute::name_from_string(old_name.to_ascii_lowercase(), encoding)
+        else {
+            return Ok(false);
+        };
+
+        let items = self.as_mut_vec();
+        let has_name = |attr: &Attribute<'_>, name: &[u8]| eq_case_insensitive(&attr.name, name);
+
+        if !items.iter().any(|attr| has_name(attr, &old_name)) {
+            return Ok(false);
+        }
+
+        if *old_name == *new_name {
+            return Ok(true);
+        }
+
+        if items.iter().any(|attr| has_name(attr, &new_name)) {
+            return Err(AttributeRenameError::AlreadyExists);
+        }
+
+        for attr in items.iter_mut().filter(|attr| has_name(attr, &old_name)) {
+            attr.name = new_name.clone();
+            attr.modified = true;
+            attr.name_value_start = None;
+        }
+
+        Ok(true)
+    }
+
+    /// Changes the quotes of the attribute's value. Returns `true` if they change.
+    pub fn set_attribute_quote(&mut self, name: &str, quote: AttributeQuote) -> bool {
+        let Ok(name) = Attribute::name_from_string(name.to_ascii_lowercase(), self.encoding) else {
+            return false;
+        };
+
+        self.as_mut_vec()
+            .iter_mut()
+            .find(|attr| eq_case_insensitive(&attr.name.as_ref(), &name.as_ref()))
+            .is_some_and(|attr| attr.set_quote(quote))
+    }
+
+    #[inline(never)]
+    pub(crate) fn attribute_tokens(&self, name: &str) -> Vec<String> {
+        self.map_attribute(name, |attr| {
+            attr_value_tokens(&attr.value)
+                .map(|token| Bytes::new(token).as_string(attr.encoding))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    #[inline(never)]
+    pub(crate) fn has_attribute_token(&self, name: &str, token: &str) -> bool {
+        let Ok(token) = Self::token_from_str(token, self.encoding) else {
+            return false;
+        };
+
+        self.map_attribute(name, |attr| attr.has_token(&token))
+            .unwrap_or(false)
+    }
+
+    fn token_from_str(
+        token: &str,
+        encoding: &'static Encoding,
+    ) -> Result<BytesCow<'static>, AttributeTokenError> {
+        if token.is_empty() {
+            Err(AttributeTokenError::Empty)
+        } else if token.bytes().any(is_attr_whitespace) {
+            Err(AttributeTokenError::ForbiddenWhitespace)
+        } else {
+            Ok(BytesCow::owned_from_str(token, encoding))
+        }
+    }
+
+    /// Adds the `token` to the attribute if `present` is `true`, or removes all of its
+    /// occurrences otherwise. The attribute is added if it's missing.
+    ///
+    /// The tokens are re-joined with single spaces if the value changes. Returns `true` if it
+    /// does.
+    pub fn set_attribute_token(
+        &mut self,
+        name: &str,
+        token: &str,
+        present: bool,
+    ) -> Result<bool, AttributeTokenError> {
+        let encoding = self.encoding;
+        let token = Self::token_from_str(token, encoding)?;
+        let name = Attribute::name_from_string(name.to_ascii_lowercase(), encoding)?;
+        let items = self.as_mut_vec();
+
+        let Some(attr) = items
+            .iter_mut()
+            .find(|attr| eq_case_insensitive(&attr.name.as_ref(), &name.as_ref()))
+        else {
+            if present {
+                items.push(Attribute::new_modified(name, token, encoding));
+            }
+
+            return Ok(present);
+        };
+
+        if attr.has_token(&token) == present {
+            return Ok(false);
+        }
+
+        let mut value = Vec::with_capacity(attr.value.len() + token.len() + 1);
+
+        for existing in attr_value_tokens(&attr.value).filter(|&t| t != &*token) {
+            if !value.is_empty() {
+                value.push(b' ');
+            }
+
+            value.extend_from_slice(existing);
+        }
+
+        if present {
+            if !value.is_empty() {
+                value.push(b' ');
+            }
+
+            value.extend_from_slice(&token);
+        }
+
+        attr.set_value_bytes(value);
+
+        Ok(true)
+    }
+
+    pub(crate) fn attribute_occurrences(&self, name: &str) -> Vec<&Attribute<'i>> {
+        let Ok(name) = Attribute::name_from_string(name.to_ascii_lowercase(), self.encoding) else {
+            return Vec::new();
+        };
+
+        self.to_slice()
+            .iter()
+            .filter(|attr| eq_case_insensitive(&attr.name.as_ref(), &name.as_ref()))
+            .collect()
+    }
+
+    /// Returns the attributes that have the same name as one of the preceding attributes.
+    pub(crate) fn duplicate_attributes(&self) -> Vec<&Attribute<'i>> {
+        let mut seen = HashSet::new();
+
+        self.to_slice()
+            .iter()
+            .filter(|attr| !seen.insert(attr.name.to_ascii_lowercase()))
+            .collect()
+    }
+
+    /// Removes the attributes that have the same name as one of the preceding attributes.
+    /// Returns `true` if there are any.
+    pub fn remove_duplicate_attributes(&mut self) -> bool {
+        let items = self.as_mut_vec();
+        let len_before = items.len();
+        let mut seen = HashSet::new();
+
+        items.retain(|attr| seen.insert(attr.name.to_ascii_lowercase()));
+
+        len_before != items.len()
+    }
+
     pub fn remove_attribute(&mut self, name: &str) -> bool {
         let Ok(name) = Attribute::name_from_string(name.to_ascii_lowercase(), self.encoding) else {
             return false;
@@ -274,7 +711,33 @@ impl<'i> Attributes<'i> {
         };
         let base = self.source_byte_offset;
         self.attribute_buffer.iter().map(move |a| {
-            Attribute::new(
+            let raw_quote = if a.raw_range.end == a.name.end {
+                None
+            } else if a.raw_range.end > a.value.end {
+                Some(AttributeQuote::from_closing_quote(
+                    self.input.get(a.value.end).copied(),
+                ))
+            } else {
+                Some(AttributeQuote::Unquoted)
+            };
+
+            let separator_len = self
+                .input
+                .get(..a.raw_range.start)
+                .unwrap_or_default()
+                .iter()
+                .rev()
+                .take_while(|&&b| is_attr_whitespace(b))
+                .count();
+
+            let separator = (separator_len > 0).then(|| {
+                self.input.slice(Range {
+                    start: a.raw_range.start - separator_len,
+                    end: a.raw_range.start,
+                })
+            });
+
+            let attr = Attribute::new(
                 self.input
                     .opt_slice(Some(a.name))
                     .unwrap_or_else(cant_fail)
@@ -286,9 +749,16 @@ impl<'i> Attributes<'i> {
                 self.input
                     .opt_slice(Some(a.raw_range))
                     .unwrap_or_else(cant_fail),
+                raw_quote,
+                separator,
                 self.encoding,
                 NonZero::new(base + a.value.start).map(|val| (base + a.name.start, val)),
-            )
+            );
+
+            Attribute {
+                preserve_formatting: self.preserve_formatting,
+                ..attr
+            }
         })
     }
 
@@ -321,8 +791,12 @@ impl<'i> Attributes<'i> {
 impl Serialize for &mut Attributes<'_> {
     #[inline]
     fn into_bytes(self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
+        let preserve_formatting = self.preserve_formatting;
+
         for attr in self.as_mut_vec() {
-            output_handler(b" ");
+            let separator = attr.separator.as_deref().filter(|_| preserve_formatting);
+
+            output_handler(separator.unwrap_or(b" "));
             attr.into_bytes(output_handler)?;
         }
         Ok(())
@@ -3,10 +3,11 @@ use crate::base::{Bytes, BytesCow};
 use crate::base::{SourceLocation, SpannedRawBytes};
 use crate::errors::RewritingError;
 use crate::html_content::{StreamingHandler, StreamingHandlerSink};
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{Ancestor, Ancestors, StringChunk};
 use encoding_rs::Encoding;
 use std::any::Any;
 use std::fmt::{self, Debug};
+use std::sync::Arc;
 use thiserror::Error;
 
 /// An error that occurs when invalid value is provided for the HTML comment text.
@@ -35,6 +36,7 @@ pub struct Comment<'i> {
     encoding: &'static Encoding,
     mutations: Mutations,
     user_data: Box<dyn Any>,
+    ancestor: Option<Arc<Ancestor>>,
 }
 
 /// Returns `true` if `text`, when emitted between `<!--` and `-->`, would let a
@@ -65,6 +67,7 @@ impl<'i> Comment<'i> {
             encoding,
             mutations: Mutations::new(),
             user_data: Box::new(()),
+            ancestor: None,
         })
     }
 
@@ -80,6 +83,21 @@ impl<'i> Comment<'i> {
         self.encoding
     }
 
+    /// Returns the open elements that contain the comment, starting with the innermost one.
+    ///
+    /// See [`Ancestors`] for the details.
+    ///
+    /// [`Ancestors`]: struct.Ancestors.html
+    #[inline]
+    pub fn ancestors(&self) -> Ancestors<'_> {
+        Ancestors::new(self.ancestor.as_deref())
+    }
+
+    #[inline]
+    pub(crate) fn set_ancestor(&mut self, ancestor: Option<Arc<Ancestor>>) {
+        self.ancestor = ancestor;
+    }
+
     /// Sets the text of the comment.
     #[inline]
     pub fn set_text(&mut self, text: &str) -> Result<(), CommentTextError> {
@@ -242,6 +260,11 @@ impl<'i> Comment<'i> {
         self.mutations.removed()
     }
 
+    #[inline]
+    pub(super) fn is_modified(&self) -> bool {
+        self.mutations.is_mutated() || self.raw.original().is_none()
+    }
+
     #[inline]
     fn serialize_self(&self, sink: &mut StreamingHandlerSink<'_>) -> Result<(), RewritingError> {
         let output_handler = sink.output_handler();
@@ -281,6 +304,7 @@ mod tests {
     use crate::errors::*;
     use crate::html_content::*;
     use crate::rewritable_units::test_utils::*;
+    use crate::{RewriteStrSettings, rewrite_str};
     use encoding_rs::{EUC_JP, Encoding, UTF_8};
 
     fn rewrite_comment(
@@ -367,6 +391,31 @@ mod tests {
         });
     }
 
+    #[test]
+    fn ancestors() {
+        let mut ancestors = Vec::new();
+
+        rewrite_str(
+            "<!--a--><div><!--b--><p><!--c--></p></div>",
+            RewriteStrSettings::new()
+                .with_ancestor_tracking(true)
+                .append_element_content_handler(comments!("div", |c| {
+                    ancestors.push(c.ancestors().map(|a| a.tag_name()).collect::<Vec<_>>());
+                    Ok(())
+                }))
+                .append_document_content_handler(doc_comments!(|c| {
+                    if c.text() == "a" {
+                        assert_eq!(c.ancestors().len(), 0);
+                    }
+
+                    Ok(())
+                })),
+        )
+        .unwrap();
+
+        assert_eq!(ancestors, [vec!["div"], vec!["p", "div"]]);
+    }
+
     mod serialization {
         use super::*;
 
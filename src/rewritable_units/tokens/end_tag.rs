@@ -1,19 +1,51 @@
 use super::{Mutations, Token};
 use crate::base::{Bytes, BytesCow};
-use crate::base::{SourceLocation, SpannedRawBytes};
+use crate::base::{SourceLocation, Spanned, SpannedRawBytes};
 use crate::errors::RewritingError;
 use crate::html_content::{ContentType, StreamingHandler, StreamingHandlerSink};
 use crate::rewritable_units::StringChunk;
 use encoding_rs::Encoding;
 use std::fmt::{self, Debug};
 
+/// The reason an element has been closed.
+///
+/// HTML allows end tags of many elements to be omitted, e.g. `<p>` is closed by the
+/// start of a following block element and `<li>` by the next `<li>`. End tag handlers
+/// run for implicitly closed elements too. Elements are closed by start tags and
+/// misnested end tags only in the [full tree construction] mode, otherwise an element
+/// is closed by its own end tag or the end tag of one of its ancestors.
+///
+/// [full tree construction]: crate::Settings::with_full_tree_construction
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ElementCloseReason {
+    /// The element has been closed by its own end tag.
+    EndTag,
+    /// The end tag has been omitted and the element has been closed by the start tag
+    /// that follows it, e.g. `<p>` followed by `<div>`, or by text it can't contain,
+    /// e.g. `<head>` followed by text. Only in the full tree construction mode.
+    ImpliedByStartTag,
+    /// The end tag has been omitted and the element has been closed by the end tag
+    /// of one of its ancestors, e.g. `<li>` followed by `</ul>`.
+    ImpliedByAncestorEndTag,
+    /// The element has been left open until the end of the document.
+    EndOfDocument,
+}
+
 /// An HTML end tag rewritable unit.
 ///
 /// Exposes API for examination and modification of a parsed HTML end tag.
+///
+/// End tag handlers are also invoked for elements whose end tag has been omitted from
+/// the source. In that case the unit represents the implied end tag: it serializes to
+/// nothing, but content can still be inserted around it. Use
+/// [`close_reason()`][EndTag::close_reason] to tell implied end tags apart.
 pub struct EndTag<'i> {
     name: BytesCow<'i>,
     raw: SpannedRawBytes<'i>,
     encoding: &'static Encoding,
+    close_reason: ElementCloseReason,
+    is_misnested: bool,
     pub(crate) mutations: Mutations,
 }
 
@@ -29,10 +61,33 @@ impl<'i> EndTag<'i> {
             name: name.into(),
             raw,
             encoding,
+            close_reason: ElementCloseReason::EndTag,
+            is_misnested: false,
             mutations: Mutations::new(),
         })
     }
 
+    /// Creates an end tag for an element which has been closed without an explicit end tag
+    /// in the source. `source_location_byte_start` is the position at which the element has
+    /// been closed.
+    #[inline]
+    #[must_use]
+    pub(crate) fn new_implied(
+        name: BytesCow<'i>,
+        close_reason: ElementCloseReason,
+        source_location_byte_start: usize,
+        encoding: &'static Encoding,
+    ) -> Self {
+        EndTag {
+            name,
+            raw: Spanned::new(source_location_byte_start, Bytes::default()).into(),
+            encoding,
+            close_reason,
+            is_misnested: false,
+            mutations: Mutations::new(),
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn encoding(&self) -> &'static Encoding {
         self.encoding
@@ -52,6 +107,36 @@ impl<'i> EndTag<'i> {
         self.name.as_string(self.encoding)
     }
 
+    /// Returns the reason the element has been closed.
+    ///
+    /// Elements closed by an ancestor's end tag, e.g. an `<li>` closed by `</ul>`, get their own
+    /// implied end tags, which are handled right before the ancestor's end tag token.
+    #[inline]
+    #[must_use]
+    pub fn close_reason(&self) -> ElementCloseReason {
+        self.close_reason
+    }
+
+    /// Returns `true` if the end tag is not present in the source and has been implied by
+    /// the parser.
+    #[inline]
+    #[must_use]
+    pub fn is_implied(&self) -> bool {
+        self.close_reason != ElementCloseReason::EndTag
+    }
+
+    /// Returns `true` if the element is closed while the elements inside of it are still open,
+    /// or is inside of such an element. Only in the full tree construction mode.
+    #[inline]
+    pub(crate) fn is_misnested(&self) -> bool {
+        self.is_misnested
+    }
+
+    #[inline]
+    pub(crate) fn set_misnested(&mut self, is_misnested: bool) {
+        self.is_misnested = is_misnested;
+    }
+
     /// Sets the name of the tag.
     pub(crate) fn set_name_raw(&mut self, name: BytesCow<'static>) {
         self.name = name;
@@ -164,16 +249,31 @@ impl<'i> EndTag<'i> {
         self.mutations.removed()
     }
 
+    #[inline]
+    pub(super) fn is_modified(&self) -> bool {
+        self.mutations.is_mutated() || self.raw.original().is_none()
+    }
+
     #[inline]
     fn serialize_self(&self, sink: &mut StreamingHandlerSink<'_>) -> Result<(), RewritingError> {
         let output_handler = sink.output_handler();
 
-        if let Some(raw) = self.raw.original() {
-            output_handler(raw);
-        } else {
-            output_handler(b"</");
-            output_handler(&self.name);
-            output_handler(b">");
+        match self.raw.original() {
+            Some(raw) => output_handler(raw),
+            // NOTE: the ancestor's end tag and the end of the document close
+            // the element regardless of its name, so the implied end tag can
+            // stay implied after renaming.
+            None if matches!(
+                self.close_reason,
+                ElementCloseReason::ImpliedByAncestorEndTag | ElementCloseReason::EndOfDocument
+            ) => {}
+            // NOTE: the renamed element may not be closed by the following
+            // start tag anymore, so the implied end tag has to be written out.
+            None => {
+                output_handler(b"</");
+                output_handler(&self.name);
+                output_handler(b">");
+            }
         }
         Ok(())
     }
@@ -193,6 +293,7 @@ impl Debug for EndTag<'_> {
         f.debug_struct("EndTag")
             .field("name", &self.name())
             .field("at", &self.source_location())
+            .field("close_reason", &self.close_reason)
             .finish()
     }
 }
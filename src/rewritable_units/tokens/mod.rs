@@ -5,7 +5,9 @@ use super::Mutations;
 use crate::errors::RewritingError;
 
 pub(super) use self::attributes::Attributes;
-pub use self::attributes::{Attribute, AttributeNameError};
+pub use self::attributes::{
+    Attribute, AttributeNameError, AttributeQuote, AttributeRenameError, AttributeTokenError,
+};
 pub use self::capturer::*;
 
 // Pub only for integration tests
@@ -61,7 +63,7 @@ mod text_chunk;
 
 pub use self::comment::{Comment, CommentTextError};
 pub use self::doctype::Doctype;
-pub use self::end_tag::EndTag;
+pub use self::end_tag::{ElementCloseReason, EndTag};
 pub use self::start_tag::StartTag;
 pub use self::text_chunk::TextChunk;
 
@@ -75,6 +77,20 @@ pub enum Token<'i> {
     Doctype(Doctype<'i>),
 }
 
+impl Token<'_> {
+    /// Returns `true` if the token has been modified by content handlers.
+    #[inline]
+    pub(crate) fn is_modified(&self) -> bool {
+        match self {
+            Token::TextChunk(t) => t.is_modified(),
+            Token::StartTag(t) => t.is_modified(),
+            Token::EndTag(t) => t.is_modified(),
+            Token::Comment(t) => t.is_modified(),
+            Token::Doctype(t) => t.removed(),
+        }
+    }
+}
+
 impl Serialize for Token<'_> {
     #[inline]
     fn into_bytes(self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
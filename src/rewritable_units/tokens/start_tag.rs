@@ -1,14 +1,18 @@
-use super::{Attribute, AttributeNameError, Attributes};
+use super::{
+    Attribute, AttributeNameError, AttributeQuote, AttributeRenameError, AttributeTokenError,
+    Attributes,
+};
 use super::{Mutations, Serialize, Token};
 use crate::base::SourceLocation;
 use crate::base::SpannedRawBytes;
 use crate::base::{Bytes, BytesCow};
 use crate::errors::RewritingError;
-use crate::html::Namespace;
+use crate::html::{Namespace, is_attr_whitespace};
 use crate::html_content::{ContentType, StreamingHandler, StreamingHandlerSink};
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{Ancestor, StringChunk};
 use encoding_rs::Encoding;
 use std::fmt::{self, Debug};
+use std::sync::Arc;
 
 /// An HTML start tag rewritable unit.
 ///
@@ -20,6 +24,7 @@ pub struct StartTag<'input_token> {
     self_closing: bool,
     raw: SpannedRawBytes<'input_token>,
     pub(crate) mutations: Mutations,
+    ancestor: Option<Arc<Ancestor>>,
 }
 
 impl<'input_token> StartTag<'input_token> {
@@ -40,6 +45,7 @@ impl<'input_token> StartTag<'input_token> {
             self_closing,
             raw,
             mutations: Mutations::new(),
+            ancestor: None,
         })
     }
 
@@ -49,6 +55,40 @@ impl<'input_token> StartTag<'input_token> {
         self.attributes.encoding
     }
 
+    /// Returns the innermost open element that contains the tag's element.
+    #[inline]
+    pub(crate) fn ancestor(&self) -> Option<&Ancestor> {
+        self.ancestor.as_deref()
+    }
+
+    #[inline]
+    pub(crate) fn set_ancestor(&mut self, ancestor: Option<Arc<Ancestor>>) {
+        self.ancestor = ancestor;
+    }
+
+    /// Keeps the quotes of the attributes and the whitespace of the original tag once it's
+    /// modified.
+    #[inline]
+    pub(crate) fn preserve_formatting(&mut self) {
+        self.attributes.preserve_formatting();
+    }
+
+    /// Returns the whitespace before the closing `>` or `/>` of the original tag.
+    fn trailing_whitespace(&self) -> &'input_token [u8] {
+        let raw = self.raw.source();
+        let end = raw
+            .len()
+            .saturating_sub(if self.self_closing { 2 } else { 1 });
+        let before_end = raw.get(..end).unwrap_or_default();
+        let len = before_end
+            .iter()
+            .rev()
+            .take_while(|&&b| is_attr_whitespace(b))
+            .count();
+
+        &before_end[before_end.len() - len..]
+    }
+
     /// Returns the name of the tag, always ASCII lowercased.
     #[inline]
     pub fn name(&self) -> String {
@@ -61,6 +101,11 @@ impl<'input_token> StartTag<'input_token> {
         self.name.as_string(self.attributes.encoding)
     }
 
+    #[inline]
+    pub(crate) fn name_raw(&self) -> &BytesCow<'input_token> {
+        &self.name
+    }
+
     /// Sets the name of the tag.
     #[inline]
     pub(crate) fn set_name_raw(&mut self, name: BytesCow<'static>) {
@@ -111,12 +156,45 @@ impl<'input_token> StartTag<'input_token> {
         self.attributes.to_slice()
     }
 
+    /// Returns all the occurrences of an attribute with the `name` in the tag, in source order.
+    ///
+    /// The tag can have multiple occurrences of an attribute, e.g. `<a href=a href=b>`. Browsers
+    /// use the first one, and so do [`get_attribute`] and [`set_attribute`].
+    ///
+    /// [`get_attribute`]: #method.get_attribute
+    /// [`set_attribute`]: #method.set_attribute
+    #[inline]
+    #[must_use]
+    pub fn attribute_occurrences(&self, name: &str) -> Vec<&Attribute<'input_token>> {
+        self.attributes.attribute_occurrences(name)
+    }
+
+    /// Returns the attributes that have the same name as one of the preceding attributes, i.e.
+    /// the ones that browsers ignore.
+    #[inline]
+    #[must_use]
+    pub fn duplicate_attributes(&self) -> Vec<&Attribute<'input_token>> {
+        self.attributes.duplicate_attributes()
+    }
+
+    /// Removes the attributes that have the same name as one of the preceding attributes, so
+    /// that every consumer of the output sees the same attributes as browsers.
+    #[inline]
+    pub fn remove_duplicate_attributes(&mut self) {
+        if self.attributes.remove_duplicate_attributes() {
+            self.raw.set_modified();
+        }
+    }
+
     /// Sets `value` of tag's attribute with `name`. The value may have HTML/XML entities.
     ///
-    /// `"` will be entity-escaped if needed. `&` won't be escaped.
+    /// The quote that encloses the value will be entity-escaped if needed. `&` won't be escaped.
     ///
-    /// If tag doesn't have an attribute with the `name`, method adds a new attribute
-    /// to the tag with `name` and `value`.
+    /// The attribute keeps its position, and its value is enclosed in the [quotes] of the
+    /// attribute. If tag doesn't have an attribute with the `name`, method adds a new attribute
+    /// to the end of the tag with `name` and `value`, enclosed in double quotes.
+    ///
+    /// [quotes]: struct.Attribute.html#method.quote
     #[inline]
     pub fn set_attribute(&mut self, name: &str, value: &str) -> Result<(), AttributeNameError> {
         self.attributes
@@ -126,6 +204,50 @@ impl<'input_token> StartTag<'input_token> {
         Ok(())
     }
 
+    /// Inserts an attribute with the `name` and the `value` at the `index` of the list of
+    /// attributes, or at its end if the `index` is out of bounds.
+    ///
+    /// If the attribute is already present, its value is replaced and it's moved to the `index`.
+    pub fn insert_attribute(
+        &mut self,
+        index: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), AttributeNameError> {
+        self.attributes
+            .insert_attribute(index, name, value, self.attributes.encoding)?;
+        self.raw.set_modified();
+
+        Ok(())
+    }
+
+    /// Renames every occurrence of an attribute with the `old_name` to the `new_name` if it is
+    /// present, keeping their positions, values and quotes.
+    ///
+    /// Fails if the start tag already has an attribute with the `new_name`.
+    pub fn rename_attribute(
+        &mut self,
+        old_name: &str,
+        new_name: &str,
+    ) -> Result<(), AttributeRenameError> {
+        if self
+            .attributes
+            .rename_attribute(old_name, new_name, self.attributes.encoding)?
+        {
+            self.raw.set_modified();
+        }
+
+        Ok(())
+    }
+
+    /// Changes the quotes around the value of an attribute with the `name` if it is present.
+    #[inline]
+    pub fn set_attribute_quote(&mut self, name: &str, quote: AttributeQuote) {
+        if self.attributes.set_attribute_quote(name, quote) {
+            self.raw.set_modified();
+        }
+    }
+
     /// Removes an attribute with the `name` if it is present.
     #[inline]
     pub fn remove_attribute(&mut self, name: &str) {
@@ -134,6 +256,31 @@ impl<'input_token> StartTag<'input_token> {
         }
     }
 
+    #[inline]
+    pub(crate) fn attribute_tokens(&self, name: &str) -> Vec<String> {
+        self.attributes.attribute_tokens(name)
+    }
+
+    #[inline]
+    pub(crate) fn has_attribute_token(&self, name: &str, token: &str) -> bool {
+        self.attributes.has_attribute_token(name, token)
+    }
+
+    pub(crate) fn set_attribute_token(
+        &mut self,
+        name: &str,
+        token: &str,
+        present: bool,
+    ) -> Result<bool, AttributeTokenError> {
+        let changed = self.attributes.set_attribute_token(name, token, present)?;
+
+        if changed {
+            self.raw.set_modified();
+        }
+
+        Ok(changed)
+    }
+
     /// Whether the tag syntactically ends with `/>`. In HTML content this is purely a decorative, unnecessary, and has no effect of any kind.
     ///
     /// The `/>` syntax only affects parsing of elements in foreign content (SVG and MathML).
@@ -229,6 +376,11 @@ impl<'input_token> StartTag<'input_token> {
         self.mutations.mutate().remove();
     }
 
+    #[inline]
+    pub(super) fn is_modified(&self) -> bool {
+        self.mutations.is_mutated() || self.raw.original().is_none()
+    }
+
     fn serialize_self(
         &mut self,
         sink: &mut StreamingHandlerSink<'_>,
@@ -243,6 +395,12 @@ impl<'input_token> StartTag<'input_token> {
         output_handler(b"<");
         output_handler(&self.name);
 
+        let trailing_whitespace = if self.attributes.preserves_formatting() {
+            self.trailing_whitespace()
+        } else {
+            b""
+        };
+
         if !self.attributes.is_empty() {
             self.attributes.into_bytes(output_handler)?;
 
@@ -251,11 +409,15 @@ impl<'input_token> StartTag<'input_token> {
             // add extra space before the `/`, because otherwise
             // it will be treated as a part of such an unquoted
             // attribute value.
-            if self.self_closing {
+            if self.self_closing && trailing_whitespace.is_empty() {
                 output_handler(b" ");
             }
         }
 
+        if !trailing_whitespace.is_empty() {
+            output_handler(trailing_whitespace);
+        }
+
         if self.self_closing {
             output_handler(b"/>");
         } else {
@@ -279,6 +441,15 @@ impl<'input_token> StartTag<'input_token> {
         self.raw.source_location()
     }
 
+    /// Bytes of this tag in the source document, before any rewriting
+    ///
+    /// The bytes are in the document's character encoding, from `<` to `>` inclusive.
+    #[inline]
+    #[must_use]
+    pub fn raw_bytes(&self) -> &'input_token [u8] {
+        self.raw.source()
+    }
+
     /// [Self::namespace_uri], but as a `CStr`
     #[inline]
     #[must_use]
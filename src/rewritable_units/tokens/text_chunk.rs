@@ -3,11 +3,12 @@ use crate::base::SourceLocation;
 use crate::errors::RewritingError;
 use crate::html::TextType;
 use crate::html_content::{ContentType, StreamingHandler, StreamingHandlerSink};
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{Ancestor, Ancestors, StringChunk};
 use encoding_rs::Encoding;
 use std::any::Any;
 use std::borrow::Cow;
 use std::fmt::{self, Debug};
+use std::sync::Arc;
 
 /// A fragment of an HTML text node. Beware: this is tricky to use.
 ///
@@ -74,6 +75,7 @@ pub struct TextChunk<'i> {
     encoding: &'static Encoding,
     mutations: Mutations,
     user_data: Box<dyn Any>,
+    ancestor: Option<Arc<Ancestor>>,
     source_location: SourceLocation,
 }
 
@@ -94,6 +96,7 @@ impl<'i> TextChunk<'i> {
             encoding,
             mutations: Mutations::new(),
             user_data: Box::new(()),
+            ancestor: None,
             source_location,
         }
     }
@@ -137,6 +140,21 @@ impl<'i> TextChunk<'i> {
         self.text = Cow::Owned(text);
     }
 
+    /// Returns the open elements that contain the text chunk, starting with the innermost one.
+    ///
+    /// See [`Ancestors`] for the details.
+    ///
+    /// [`Ancestors`]: struct.Ancestors.html
+    #[inline]
+    pub fn ancestors(&self) -> Ancestors<'_> {
+        Ancestors::new(self.ancestor.as_deref())
+    }
+
+    #[inline]
+    pub(crate) fn set_ancestor(&mut self, ancestor: Option<Arc<Ancestor>>) {
+        self.ancestor = ancestor;
+    }
+
     /// Returns the type of the text in the chunk.
     ///
     /// The type of the text depends on the surrounding context of the text. E.g. regular visible
@@ -328,6 +346,11 @@ impl<'i> TextChunk<'i> {
         self.mutations.removed()
     }
 
+    #[inline]
+    pub(super) const fn is_modified(&self) -> bool {
+        self.mutations.is_mutated()
+    }
+
     #[inline]
     fn serialize_self(&self, sink: &mut StreamingHandlerSink<'_>) -> Result<(), RewritingError> {
         if !self.text.is_empty() {
@@ -362,6 +385,7 @@ impl Debug for TextChunk<'_> {
 mod tests {
     use crate::html_content::*;
     use crate::rewritable_units::test_utils::*;
+    use crate::{RewriteStrSettings, rewrite_str};
     use encoding_rs::{Encoding, UTF_8};
 
     fn rewrite_text_chunk(
@@ -418,6 +442,67 @@ mod tests {
         assert_eq!(chunk.as_str(), "hello world!");
     }
 
+    #[test]
+    fn ancestors() {
+        // NOTE: open elements are tracked for the document content handlers too.
+        rewrite_str(
+            "<div>Hi</div>",
+            RewriteStrSettings::new()
+                .with_ancestor_tracking(true)
+                .append_document_content_handler(doc_text!(|c| {
+                    if !c.as_str().is_empty() {
+                        assert_eq!(
+                            c.ancestors().map(Ancestor::tag_name).collect::<Vec<_>>(),
+                            ["div"]
+                        );
+                    }
+
+                    Ok(())
+                })),
+        )
+        .unwrap();
+
+        // NOTE: the ancestors are empty unless the tracking is enabled.
+        rewrite_text_chunk(b"<div>Hi</div>", UTF_8, |c| {
+            assert_eq!(c.ancestors().len(), 0);
+        });
+
+        let mut ancestors = Vec::new();
+
+        rewrite_str(
+            "<div>a<p>b<code>c</code></p>d</div>e",
+            RewriteStrSettings::new()
+                .with_ancestor_tracking(true)
+                .append_element_content_handler(text!("div", |c| {
+                    if !c.as_str().is_empty() {
+                        let names = c.ancestors().map(|a| a.tag_name()).collect::<Vec<_>>();
+
+                        ancestors.push((c.as_str().to_owned(), names.join(" < ")));
+                    }
+
+                    Ok(())
+                }))
+                .append_document_content_handler(doc_text!(|c| {
+                    if c.as_str() == "e" {
+                        assert_eq!(c.ancestors().len(), 0);
+                    }
+
+                    Ok(())
+                })),
+        )
+        .unwrap();
+
+        assert_eq!(
+            ancestors,
+            [
+                ("a".into(), "div".into()),
+                ("b".into(), "p < div".into()),
+                ("c".into(), "code < p < div".into()),
+                ("d".into(), "div".into()),
+            ] as [(String, String); 4]
+        );
+    }
+
     mod serialization {
         use super::*;
 
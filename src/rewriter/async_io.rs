@@ -0,0 +1,615 @@
+use super::io_adapters::{ReadSink, ReadState, poisoned_error};
+use super::{HandlerTypes, HtmlRewriter, LocalHandlerTypes, RewritingError, Settings};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+#[cold]
+fn closed_error() -> io::Error {
+    io::Error::other("Attempt to use the HtmlRewriter after it has been closed.")
+}
+
+/// An [`AsyncWrite`] adapter for the [`HtmlRewriter`] that writes the rewritten output into
+/// another [`AsyncWrite`].
+///
+/// The output of a written chunk is buffered until it's written into the underlying writer,
+/// which happens before the next chunk is accepted, so the adapter doesn't accept the input
+/// faster than the underlying writer accepts the output.
+///
+/// [`poll_close`] must be called once all the input is written, otherwise the end of the
+/// output is lost. Errors of the rewriter are converted into [`io::Error`]s. The output
+/// produced before an error (e.g. on a [graceful bail-out]) is written first, and the writer
+/// can't be used after the error.
+///
+/// The underlying writer must be [`Unpin`]; use [`Box::pin`] for writers that are not.
+///
+/// *Requires the `stream` feature.*
+///
+/// # Example
+/// ```
+/// use futures_io::AsyncWrite;
+/// use lol_html::{element, AsyncRewritingWriter, Settings};
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll, Waker};
+///
+/// let mut writer = AsyncRewritingWriter::new(
+///     Settings::new().append_element_content_handler(element!("a[href]", |el| {
+///         el.set_attribute("rel", "nofollow")?;
+///         Ok(())
+///     })),
+///     Vec::new(),
+/// );
+///
+/// let mut cx = Context::from_waker(Waker::noop());
+///
+/// for chunk in [&b"<a href='/'>"[..], b"Home</a>"] {
+///     let Poll::Ready(Ok(_)) = Pin::new(&mut writer).poll_write(&mut cx, chunk) else {
+///         panic!("Vec<u8> is always ready.");
+///     };
+/// }
+///
+/// let Poll::Ready(Ok(())) = Pin::new(&mut writer).poll_close(&mut cx) else {
+///     panic!("Vec<u8> is always ready.");
+/// };
+///
+/// assert_eq!(writer.into_inner(), br#"<a href='/' rel="nofollow">Home</a>"#);
+/// ```
+///
+/// [`HtmlRewriter`]: struct.HtmlRewriter.html
+/// [`poll_close`]: https://docs.rs/futures-io/latest/futures_io/trait.AsyncWrite.html#tymethod.poll_close
+/// [graceful bail-out]: struct.MemorySettings.html#method.with_graceful_bail_out_on_memory_limit_exceeded
+pub struct AsyncRewritingWriter<'h, W, H: HandlerTypes = LocalHandlerTypes> {
+    writer: W,
+    /// `None` once the rewriting has finished.
+    rewriter: Option<HtmlRewriter<'h, ReadSink, H>>,
+    output: Vec<u8>,
+    output_pos: usize,
+    error: Option<io::Error>,
+    poisoned: bool,
+}
+
+impl<'h, W, H: HandlerTypes> AsyncRewritingWriter<'h, W, H> {
+    /// Constructs a new writer with the provided `settings` that writes the rewritten output
+    /// into the `writer`.
+    #[inline]
+    pub fn new<'s>(settings: Settings<'h, 's, H>, writer: W) -> Self {
+        Self {
+            writer,
+            rewriter: Some(HtmlRewriter::new(settings, ReadSink(Vec::new()))),
+            output: Vec::new(),
+            output_pos: 0,
+            error: None,
+            poisoned: false,
+        }
+    }
+
+    /// Returns the underlying writer.
+    ///
+    /// The output that hasn't been written yet is lost.
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn take_output(&mut self, res: Result<(), RewritingError>) {
+        if let Some(rewriter) = &mut self.rewriter {
+            std::mem::swap(&mut self.output, &mut rewriter.output_sink_mut().0);
+        }
+
+        // NOTE: the output produced before the error is written first.
+        if let Err(e) = res {
+            self.rewriter = None;
+            self.error = Some(e.into());
+        }
+    }
+
+    /// Returns the error of the rewriter once the output produced before it is written.
+    fn check(&mut self) -> io::Result<&mut HtmlRewriter<'h, ReadSink, H>> {
+        if let Some(e) = self.error.take() {
+            self.poisoned = true;
+
+            return Err(e);
+        }
+
+        match &mut self.rewriter {
+            Some(rewriter) => Ok(rewriter),
+            None if self.poisoned => Err(poisoned_error()),
+            None => Err(closed_error()),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin, H: HandlerTypes> AsyncRewritingWriter<'_, W, H> {
+    /// Writes the buffered output into the underlying writer.
+    fn poll_write_output(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.output_pos < self.output.len() {
+            let res =
+                ready!(Pin::new(&mut self.writer).poll_write(cx, &self.output[self.output_pos..]));
+
+            match res {
+                Ok(0) => {
+                    self.poisoned = true;
+
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                Ok(count) => self.output_pos += count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    // NOTE: the rest of the output is dropped after an error, since
+                    // it wouldn't be possible to resume the output where it failed.
+                    self.poisoned = true;
+                    self.rewriter = None;
+                    self.error = None;
+
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+
+        self.output.clear();
+        self.output_pos = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W, H> AsyncWrite for AsyncRewritingWriter<'_, W, H>
+where
+    Self: Unpin,
+    W: AsyncWrite + Unpin,
+    H: HandlerTypes,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_output(cx))?;
+
+        let res = this.check()?.write(buf);
+
+        this.take_output(res);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// [Flushes] the rewriter and then the underlying writer.
+    ///
+    /// [Flushes]: struct.HtmlRewriter.html#method.flush
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_output(cx))?;
+
+        let res = this.check()?.flush();
+
+        this.take_output(res);
+
+        ready!(this.poll_write_output(cx))?;
+        this.check()?;
+
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    /// Finalizes the rewriting and closes the underlying writer.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_output(cx))?;
+
+        if this.rewriter.is_some() || this.error.is_some() {
+            this.check()?;
+
+            if let Some((sink, res)) = this.rewriter.take().map(HtmlRewriter::end_into_output_sink)
+            {
+                this.output = sink.0;
+
+                if let Err(e) = res {
+                    this.error = Some(e.into());
+                }
+            }
+
+            ready!(this.poll_write_output(cx))?;
+
+            if let Some(e) = this.error.take() {
+                this.poisoned = true;
+
+                return Poll::Ready(Err(e));
+            }
+        } else if this.poisoned {
+            return Poll::Ready(Err(poisoned_error()));
+        }
+
+        Pin::new(&mut this.writer).poll_close(cx)
+    }
+}
+
+/// An [`AsyncRead`] adapter for the [`HtmlRewriter`] that reads the input from another
+/// [`AsyncRead`] and produces the rewritten output.
+///
+/// The input is read only when the rewritten output of the previous input chunk has been
+/// consumed, so the adapter doesn't read ahead of its consumer.
+///
+/// Errors of the rewriter are converted into [`io::Error`]s. The output produced before an
+/// error (e.g. on a [graceful bail-out]) is read first, and the reader can't be used after
+/// the error.
+///
+/// The underlying reader must be [`Unpin`]; use [`Box::pin`] for readers that are not.
+///
+/// *Requires the `stream` feature.*
+///
+/// # Example
+/// ```
+/// use futures_io::AsyncRead;
+/// use lol_html::{element, AsyncRewritingReader, Settings};
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll, Waker};
+///
+/// let mut reader = AsyncRewritingReader::new(
+///     Settings::new().append_element_content_handler(element!("a[href]", |el| {
+///         el.set_attribute("rel", "nofollow")?;
+///         Ok(())
+///     })),
+///     &b"<a href='/'>Home</a>"[..],
+/// );
+///
+/// let mut cx = Context::from_waker(Waker::noop());
+/// let mut buf = [0; 64];
+/// let mut output = Vec::new();
+///
+/// while let Poll::Ready(Ok(count @ 1..)) = Pin::new(&mut reader).poll_read(&mut cx, &mut buf) {
+///     output.extend_from_slice(&buf[..count]);
+/// }
+///
+/// assert_eq!(output, br#"<a href='/' rel="nofollow">Home</a>"#);
+/// ```
+///
+/// [`HtmlRewriter`]: struct.HtmlRewriter.html
+/// [graceful bail-out]: struct.MemorySettings.html#method.with_graceful_bail_out_on_memory_limit_exceeded
+pub struct AsyncRewritingReader<'h, R, H: HandlerTypes = LocalHandlerTypes> {
+    source: R,
+    state: ReadState<'h, H>,
+}
+
+impl<'h, R, H: HandlerTypes> AsyncRewritingReader<'h, R, H> {
+    /// Constructs a new reader with the provided `settings` that rewrites the input read from
+    /// the `source`.
+    #[inline]
+    pub fn new<'s>(settings: Settings<'h, 's, H>, source: R) -> Self {
+        Self {
+            source,
+            state: ReadState::new(settings),
+        }
+    }
+
+    /// Returns the underlying reader.
+    ///
+    /// The output that hasn't been read yet is lost.
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+}
+
+impl<R: AsyncRead + Unpin, H: HandlerTypes> AsyncRewritingReader<'_, R, H> {
+    /// Feeds the next chunk of input to the rewriter. Returns `false` if there is no more output.
+    fn poll_fill_output(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        if !self.state.needs_input()? {
+            return Poll::Ready(Ok(false));
+        }
+
+        let read_byte_count =
+            match ready!(Pin::new(&mut self.source).poll_read(cx, &mut self.state.input)) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Poll::Ready(Ok(true)),
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+
+        self.state.rewrite_input(read_byte_count);
+
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R, H> AsyncRead for AsyncRewritingReader<'_, R, H>
+where
+    Self: Unpin,
+    R: AsyncRead + Unpin,
+    H: HandlerTypes,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        while !this.state.has_output() {
+            if !ready!(this.poll_fill_output(cx))? {
+                return Poll::Ready(Ok(0));
+            }
+        }
+
+        Poll::Ready(Ok(this.state.read_output(buf)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_content::ContentType;
+    use crate::{element, end, send, text};
+    use std::task::Waker;
+
+    fn settings() -> send::Settings<'static, 'static> {
+        send::Settings::new_send()
+            .append_element_content_handler(element!("p", |el| {
+                el.prepend("<b>", ContentType::Html);
+                el.append("</b>", ContentType::Html);
+                Ok(())
+            }))
+            .append_element_content_handler(text!("p", |t| {
+                if t.as_str() == "fail" {
+                    return Err("Text handler failed.".into());
+                }
+
+                Ok(())
+            }))
+            .append_document_content_handler(end!(|end| {
+                end.append("<!--end-->", ContentType::Html);
+                Ok(())
+            }))
+    }
+
+    /// Returns `Pending` before each call that makes progress, and reads or writes at most
+    /// `max_chunk_size` bytes at a time.
+    struct TestIo {
+        input: &'static [u8],
+        output: Vec<u8>,
+        max_chunk_size: usize,
+        pending: bool,
+        polls: usize,
+    }
+
+    impl TestIo {
+        fn new(input: &'static [u8], max_chunk_size: usize) -> Self {
+            Self {
+                input,
+                output: Vec::new(),
+                max_chunk_size,
+                pending: false,
+                polls: 0,
+            }
+        }
+
+        fn is_pending(&mut self) -> bool {
+            self.polls += 1;
+            self.pending = !self.pending;
+            self.pending
+        }
+    }
+
+    impl AsyncRead for TestIo {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.is_pending() {
+                return Poll::Pending;
+            }
+
+            let count = self.input.len().min(buf.len()).min(self.max_chunk_size);
+
+            buf[..count].copy_from_slice(&self.input[..count]);
+            self.input = &self.input[count..];
+
+            Poll::Ready(Ok(count))
+        }
+    }
+
+    impl AsyncWrite for TestIo {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.is_pending() {
+                return Poll::Pending;
+            }
+
+            let count = buf.len().min(self.max_chunk_size);
+
+            self.output.extend_from_slice(&buf[..count]);
+
+            Poll::Ready(Ok(count))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn block_on<T>(mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>) -> T {
+        let mut cx = Context::from_waker(Waker::noop());
+
+        loop {
+            if let Poll::Ready(res) = poll(&mut cx) {
+                return res;
+            }
+        }
+    }
+
+    fn write_all<W: AsyncWrite + Unpin>(writer: &mut W, mut input: &[u8]) -> io::Result<()> {
+        while !input.is_empty() {
+            let count = block_on(|cx| Pin::new(&mut *writer).poll_write(cx, input))?;
+
+            input = &input[count..];
+        }
+
+        Ok(())
+    }
+
+    fn read_to_end<R: AsyncRead + Unpin>(reader: &mut R, output: &mut Vec<u8>) -> io::Result<()> {
+        let mut buf = [0; 7];
+
+        loop {
+            match block_on(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf))? {
+                0 => return Ok(()),
+                count => output.extend_from_slice(&buf[..count]),
+            }
+        }
+    }
+
+    #[test]
+    fn writer() {
+        for max_chunk_size in [1, 3, usize::MAX] {
+            let mut writer: send::AsyncRewritingWriter<'_, _> =
+                AsyncRewritingWriter::new(settings(), TestIo::new(b"", max_chunk_size));
+
+            for chunk in b"<p>Hello</p><p>world</p>".chunks(5) {
+                write_all(&mut writer, chunk).unwrap();
+            }
+
+            block_on(|cx| Pin::new(&mut writer).poll_flush(cx)).unwrap();
+            block_on(|cx| Pin::new(&mut writer).poll_close(cx)).unwrap();
+
+            assert_eq!(
+                String::from_utf8(writer.into_inner().output).unwrap(),
+                "<p><b>Hello</b></p><p><b>world</b></p><!--end-->"
+            );
+        }
+    }
+
+    #[test]
+    fn writer_errors() {
+        let mut writer = AsyncRewritingWriter::new(settings(), TestIo::new(b"", 1));
+
+        write_all(&mut writer, b"<div></div><p>fail</p>").unwrap();
+
+        // NOTE: the output produced before the error is written first.
+        let err = write_all(&mut writer, b"<p>").unwrap_err();
+
+        assert_eq!(err.to_string(), "Text handler failed.");
+        assert!(write_all(&mut writer, b"<p>").is_err());
+        assert!(block_on(|cx| Pin::new(&mut writer).poll_close(cx)).is_err());
+        assert_eq!(writer.into_inner().output, b"<div></div><p><b>");
+
+        // NOTE: the unfinished tag is flushed as-is on the bail-out at the end of the input.
+        let bail_out_settings = Settings::new()
+            .with_graceful_bail_out_on_content_handler_error(true)
+            .append_document_content_handler(end!(|_| Err("End handler failed.".into())));
+
+        let mut writer = AsyncRewritingWriter::new(bail_out_settings, TestIo::new(b"", 3));
+
+        write_all(&mut writer, b"<div></div><p").unwrap();
+
+        let err = block_on(|cx| Pin::new(&mut writer).poll_close(cx)).unwrap_err();
+
+        assert_eq!(err.to_string(), "End handler failed.");
+        assert!(write_all(&mut writer, b"<p>").is_err());
+        assert_eq!(writer.into_inner().output, b"<div></div><p");
+
+        let mut writer = AsyncRewritingWriter::new(settings(), TestIo::new(b"", 3));
+
+        block_on(|cx| Pin::new(&mut writer).poll_close(cx)).unwrap();
+
+        let err = write_all(&mut writer, b"<p>").unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Attempt to use the HtmlRewriter after it has been closed."
+        );
+    }
+
+    #[test]
+    fn reader() {
+        for max_chunk_size in [1, 3, usize::MAX] {
+            let mut reader: send::AsyncRewritingReader<'_, _> = AsyncRewritingReader::new(
+                settings(),
+                TestIo::new(b"<p>Hello</p><p>world</p>", max_chunk_size),
+            );
+
+            let mut output = Vec::new();
+
+            read_to_end(&mut reader, &mut output).unwrap();
+
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "<p><b>Hello</b></p><p><b>world</b></p><!--end-->"
+            );
+        }
+    }
+
+    #[test]
+    fn reader_backpressure() {
+        let mut reader =
+            AsyncRewritingReader::new(settings(), TestIo::new(b"<p>Hello</p>", usize::MAX));
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut buf = [0; 4];
+
+        assert!(
+            Pin::new(&mut reader)
+                .poll_read(&mut cx, &mut buf)
+                .is_pending()
+        );
+
+        let Poll::Ready(Ok(count)) = Pin::new(&mut reader).poll_read(&mut cx, &mut buf) else {
+            panic!("Expected output.");
+        };
+
+        assert_eq!(&buf[..count], b"<p><");
+
+        // NOTE: the rest of the output is read without polling the input again.
+        let mut output = buf[..count].to_vec();
+
+        while output.len() < "<p><b>Hello</b></p>".len() {
+            let Poll::Ready(Ok(count)) = Pin::new(&mut reader).poll_read(&mut cx, &mut buf) else {
+                panic!("Expected output.");
+            };
+
+            output.extend_from_slice(&buf[..count]);
+        }
+
+        assert_eq!(output, b"<p><b>Hello</b></p>");
+        assert_eq!(reader.into_inner().polls, 2);
+    }
+
+    #[test]
+    fn reader_errors() {
+        let mut reader = AsyncRewritingReader::new(
+            settings(),
+            TestIo::new(b"<div></div><p>fail</p>", usize::MAX),
+        );
+
+        let mut output = Vec::new();
+
+        let err = read_to_end(&mut reader, &mut output).unwrap_err();
+
+        assert_eq!(err.to_string(), "Text handler failed.");
+        assert_eq!(output, b"<div></div><p><b>");
+        assert!(read_to_end(&mut reader, &mut output).is_err());
+    }
+
+    #[test]
+    fn adapters_are_send() {
+        static_assertions::assert_impl_all!(
+            send::AsyncRewritingWriter<'static, TestIo>: Send, AsyncWrite
+        );
+        static_assertions::assert_impl_all!(
+            send::AsyncRewritingReader<'static, TestIo>: Send, AsyncRead
+        );
+    }
+}
@@ -1,7 +1,12 @@
-use super::ElementDescriptor;
 use super::settings::*;
-use crate::rewritable_units::{DocumentEnd, Element, StartTag, Token, TokenCaptureFlags};
+use super::{ElementDescriptor, RewritingError};
+use crate::base::BytesCow;
+use crate::rewritable_units::{
+    DocumentEnd, Element, ElementCloseReason, EndTag, Relocations, StartTag, Token,
+    TokenCaptureFlags,
+};
 use crate::selectors_vm::{MatchId, MatchInfo};
+use encoding_rs::Encoding;
 use std::num::NonZero;
 
 #[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Hash)]
@@ -21,6 +26,18 @@ fn locator_to_idx(locator: Locator) -> usize {
 struct HandlerVecItem<H> {
     handler: H,
     user_count: u32,
+    always_active: bool,
+}
+
+struct EndTagHandlerItem<H> {
+    /// Taken once the handler is invoked.
+    handler: Option<H>,
+    /// Name of the element, used for its implied end tag
+    name: BytesCow<'static>,
+    close_reason: ElementCloseReason,
+    /// The element is closed while the elements inside of it are still open, or is inside
+    /// of such an element.
+    is_misnested: bool,
 }
 
 struct HandlerVec<H> {
@@ -43,6 +60,7 @@ impl<H> HandlerVec<H> {
         let item = HandlerVecItem {
             handler,
             user_count: u32::from(always_active),
+            always_active,
         };
 
         self.user_count += item.user_count;
@@ -53,6 +71,22 @@ impl<H> HandlerVec<H> {
         locator
     }
 
+    /// Deactivates all the handlers, except for the ones that are always active.
+    pub fn reset_user_counts(&mut self) {
+        self.user_count = 0;
+
+        for item in &mut self.items {
+            item.user_count = u32::from(item.always_active);
+            self.user_count += item.user_count;
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.user_count = 0;
+    }
+
     #[inline]
     pub fn inc_user_count(&mut self, idx: Locator) {
         let Some(item) = self.items.get_mut(locator_to_idx(idx)) else {
@@ -75,6 +109,13 @@ impl<H> HandlerVec<H> {
         self.user_count -= 1;
     }
 
+    #[inline]
+    pub fn get_mut(&mut self, idx: Locator) -> Option<&mut H> {
+        self.items
+            .get_mut(locator_to_idx(idx))
+            .map(|item| &mut item.handler)
+    }
+
     #[inline]
     pub const fn has_active(&self) -> bool {
         self.user_count > 0
@@ -110,10 +151,10 @@ impl<H> HandlerVec<H> {
         Ok(())
     }
 
-    pub fn do_for_each_active_and_remove_tail(
+    pub fn do_for_each_active_and_remove_tail<E>(
         &mut self,
-        mut cb: impl FnMut(H) -> HandlerResult,
-    ) -> HandlerResult {
+        mut cb: impl FnMut(H) -> Result<(), E>,
+    ) -> Result<(), E> {
         // already-handled end tag handlers may be first, and they must not be removed
         if let Some(first) = self.items.iter().position(|item| item.user_count > 0) {
             // Must drop everything after, as remove() would change indexes anyway, breaking locators.
@@ -130,21 +171,59 @@ impl<H> HandlerVec<H> {
     }
 }
 
+impl<H> HandlerVec<EndTagHandlerItem<H>> {
+    /// Invokes the active handlers from the last one, except for the ones for which `defer`
+    /// returns `true`, which are kept active.
+    ///
+    /// NOTE: an element can be closed while the elements opened inside of it are still open,
+    /// e.g. by `</form>` in the full tree construction mode. So, the items of the invoked
+    /// handlers are only removed from the end of the list, keeping the locators of the open
+    /// elements' handlers valid.
+    pub fn do_for_each_active_and_remove_handled_except<E>(
+        &mut self,
+        defer: impl Fn(&EndTagHandlerItem<H>) -> bool,
+        mut cb: impl FnMut(H, &mut EndTagHandlerItem<H>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for item in self.items.iter_mut().rev() {
+            if item.user_count > 0 && !defer(&item.handler) {
+                self.user_count -= item.user_count;
+                item.user_count = 0;
+
+                if let Some(handler) = item.handler.handler.take() {
+                    cb(handler, &mut item.handler)?;
+                }
+            }
+        }
+
+        while self
+            .items
+            .last()
+            .is_some_and(|item| item.handler.handler.is_none())
+        {
+            self.items.pop();
+        }
+
+        Ok(())
+    }
+}
+
 pub(crate) struct ContentHandlersDispatcher<'h, H: HandlerTypes> {
     doctype_handlers: HandlerVec<H::DoctypeHandler<'h>>,
     comment_handlers: HandlerVec<H::CommentHandler<'h>>,
     text_handlers: HandlerVec<H::TextHandler<'h>>,
-    end_tag_handlers: HandlerVec<H::EndTagHandler<'static>>,
+    end_tag_handlers: HandlerVec<EndTagHandlerItem<H::EndTagHandler<'static>>>,
     element_handlers: HandlerVec<H::ElementHandler<'h>>,
     end_handlers: HandlerVec<H::EndHandler<'h>>,
     next_element_can_have_content: bool,
     matched_elements_with_removed_content: usize,
+    has_implied_end_tags: bool,
+    relocations: Relocations,
     /// Dense index by match_id
     locators: Vec<SelectorHandlersLocator>,
 }
 
-impl<H: HandlerTypes> Default for ContentHandlersDispatcher<'_, H> {
-    fn default() -> Self {
+impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
+    pub fn new(relocations: Relocations) -> Self {
         ContentHandlersDispatcher {
             doctype_handlers: Default::default(),
             comment_handlers: Default::default(),
@@ -154,12 +233,33 @@ impl<H: HandlerTypes> Default for ContentHandlersDispatcher<'_, H> {
             end_handlers: Default::default(),
             next_element_can_have_content: false,
             matched_elements_with_removed_content: 0,
+            has_implied_end_tags: false,
+            relocations,
             locators: Vec::new(),
         }
     }
-}
 
-impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
+    /// Restores the initial state for a new document.
+    ///
+    /// Document end handlers that have already been invoked are not restored.
+    pub fn reset(&mut self) {
+        self.doctype_handlers.reset_user_counts();
+        self.comment_handlers.reset_user_counts();
+        self.text_handlers.reset_user_counts();
+        self.end_tag_handlers.clear();
+        self.element_handlers.reset_user_counts();
+        self.end_handlers.reset_user_counts();
+        self.next_element_can_have_content = false;
+        self.matched_elements_with_removed_content = 0;
+        self.has_implied_end_tags = false;
+        self.relocations.reset();
+    }
+
+    #[inline]
+    pub const fn relocations(&self) -> &Relocations {
+        &self.relocations
+    }
+
     #[inline]
     pub fn add_document_content_handlers(&mut self, handlers: DocumentContentHandlers<'h, H>) {
         if let Some(handler) = handlers.doctype {
@@ -228,8 +328,32 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
         self.next_element_can_have_content = match_info.with_content;
     }
 
+    /// Returns `true` if the last matched element has been pushed to the stack of open elements.
     #[inline]
-    pub fn stop_matching(&mut self, elem_desc: ElementDescriptor) {
+    pub const fn next_element_can_have_content(&self) -> bool {
+        self.next_element_can_have_content
+    }
+
+    #[inline]
+    pub const fn has_implied_end_tags(&self) -> bool {
+        self.has_implied_end_tags
+    }
+
+    #[inline]
+    /// Marks the element as misnested, see [`Stack::close`].
+    ///
+    /// [`Stack::close`]: crate::selectors_vm::Stack::close
+    pub fn mark_misnested(&mut self, end_tag_handler_idx: Locator) {
+        if let Some(item) = self.end_tag_handlers.get_mut(end_tag_handler_idx) {
+            item.is_misnested = true;
+        }
+    }
+
+    pub fn stop_matching(
+        &mut self,
+        elem_desc: ElementDescriptor,
+        close_reason: ElementCloseReason,
+    ) {
         for match_id in elem_desc.matched_content_handlers.iter() {
             let Some(locator) = self.locators.get(match_id as usize) else {
                 debug_assert!(false);
@@ -246,7 +370,22 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
         }
 
         if let Some(idx) = elem_desc.end_tag_handler_idx {
+            if let Some(item) = self.end_tag_handlers.get_mut(idx) {
+                item.close_reason = close_reason;
+            }
+
             self.end_tag_handlers.inc_user_count(idx);
+
+            // NOTE: there is no end tag token for these, so the end tag
+            // handlers need to be invoked separately.
+            if matches!(
+                close_reason,
+                ElementCloseReason::ImpliedByStartTag
+                    | ElementCloseReason::ImpliedByAncestorEndTag
+                    | ElementCloseReason::EndOfDocument
+            ) {
+                self.has_implied_end_tags = true;
+            }
         }
 
         if elem_desc.remove_content {
@@ -263,11 +402,18 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
             start_tag.remove();
         }
 
-        let mut element = Element::new(start_tag, self.next_element_can_have_content);
+        let name = start_tag.name_raw().clone();
+        let mut element = Element::new(
+            start_tag,
+            self.next_element_can_have_content,
+            &self.relocations,
+        );
 
         self.element_handlers
             .do_for_each_active_and_deactivate(|h| h(&mut element))?;
 
+        element.finish_relocation();
+
         if self.next_element_can_have_content {
             if let Some(elem_desc) = current_element_data {
                 if element.should_remove_content() {
@@ -277,7 +423,14 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
 
                 debug_assert!(element.can_have_content());
                 if let Some(handler) = element.into_end_tag_handler() {
-                    elem_desc.end_tag_handler_idx = self.end_tag_handlers.push(handler, false);
+                    let item = EndTagHandlerItem {
+                        handler: Some(handler),
+                        name: name.into_owned(),
+                        close_reason: ElementCloseReason::EndTag,
+                        is_misnested: false,
+                    };
+
+                    elem_desc.end_tag_handler_idx = self.end_tag_handlers.push(item, false);
                 }
             }
         }
@@ -293,14 +446,61 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
         match token {
             Token::Doctype(doctype) => self.doctype_handlers.for_each_active(|h| h(doctype)),
             Token::StartTag(start_tag) => self.handle_start_tag(start_tag, current_element_data),
-            Token::EndTag(end_tag) => self
-                .end_tag_handlers
-                .do_for_each_active_and_remove_tail(|h| h(end_tag)),
+            Token::EndTag(end_tag) => {
+                // NOTE: elements implicitly closed by an ancestor's end tag have already been
+                // handled with their own implied end tags, before this one.
+                self.end_tag_handlers
+                    .do_for_each_active_and_remove_handled_except(
+                        |_| false,
+                        |handler, item| {
+                            end_tag.set_misnested(item.is_misnested);
+
+                            handler(end_tag)
+                        },
+                    )
+            }
             Token::TextChunk(text) => self.text_handlers.for_each_active(|h| h(text)),
             Token::Comment(comment) => self.comment_handlers.for_each_active(|h| h(comment)),
         }
     }
 
+    /// Invokes end tag handlers of the elements that have been closed without an end tag
+    /// token, and passes their implied end tags to `emit_token`.
+    pub fn handle_implied_end_tags(
+        &mut self,
+        source_location_byte_start: usize,
+        encoding: &'static Encoding,
+        emit_token: &mut dyn FnMut(Token<'_>) -> Result<(), RewritingError>,
+    ) -> Result<(), RewritingError> {
+        self.has_implied_end_tags = false;
+
+        // NOTE: the handler of the element closed by the end tag token (if any) is
+        // invoked later with the token itself.
+        self.end_tag_handlers
+            .do_for_each_active_and_remove_handled_except(
+                |item| item.close_reason == ElementCloseReason::EndTag,
+                |handler, item| {
+                    let mut end_tag = EndTag::new_implied(
+                        std::mem::replace(&mut item.name, BytesCow::from(&[][..])),
+                        item.close_reason,
+                        source_location_byte_start,
+                        encoding,
+                    );
+
+                    end_tag.set_misnested(item.is_misnested);
+
+                    handler(&mut end_tag).map_err(RewritingError::ContentHandlerError)?;
+
+                    emit_token(Token::EndTag(end_tag))
+                },
+            )?;
+
+        // NOTE: an end tag token closes a single element, so at most one handler is deferred.
+        debug_assert!(self.end_tag_handlers.user_count <= 1);
+
+        Ok(())
+    }
+
     pub fn handle_end(&mut self, document_end: &mut DocumentEnd<'_>) -> HandlerResult {
         self.end_handlers
             .do_for_each_active_and_remove_tail(|h| h(document_end))
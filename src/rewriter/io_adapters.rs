@@ -0,0 +1,476 @@
+use super::{HandlerTypes, HtmlRewriter, LocalHandlerTypes, RewritingError, Settings};
+use crate::OutputSink;
+use std::io;
+
+const INPUT_BUFFER_SIZE: usize = 8 * 1024;
+
+impl From<RewritingError> for io::Error {
+    #[inline]
+    fn from(err: RewritingError) -> Self {
+        Self::other(err)
+    }
+}
+
+#[cold]
+pub(super) fn poisoned_error() -> io::Error {
+    io::Error::other("Attempt to use the HtmlRewriter after a fatal error.")
+}
+
+/// Writes the output into an [`io::Write`], keeping the first error.
+struct WriteSink<W> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> OutputSink for WriteSink<W> {
+    #[inline]
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        // NOTE: the rest of the output is dropped after an error, since
+        // it wouldn't be possible to resume the output where it failed.
+        if self.error.is_none() && !chunk.is_empty() {
+            if let Err(e) = self.writer.write_all(chunk) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+/// An [`io::Write`] adapter for the [`HtmlRewriter`] that writes the rewritten output into
+/// another [`io::Write`].
+///
+/// [`finish`] must be called once all the input is written, otherwise the end of the output
+/// is lost. Errors of the rewriter are converted into [`io::Error`]s, and the writer can't be
+/// used after an error.
+///
+/// # Example
+/// ```
+/// use lol_html::{element, RewritingWriter, Settings};
+/// use std::io::{self, Write};
+///
+/// let mut writer = RewritingWriter::new(
+///     Settings::new().append_element_content_handler(element!("a[href]", |el| {
+///         el.set_attribute("rel", "nofollow")?;
+///         Ok(())
+///     })),
+///     Vec::new(),
+/// );
+///
+/// io::copy(&mut &b"<a href='/'>Home</a>"[..], &mut writer).unwrap();
+///
+/// let output = writer.finish().unwrap();
+///
+/// assert_eq!(output, br#"<a href='/' rel="nofollow">Home</a>"#);
+/// ```
+///
+/// [`HtmlRewriter`]: struct.HtmlRewriter.html
+/// [`finish`]: #method.finish
+pub struct RewritingWriter<'h, W: io::Write, H: HandlerTypes = LocalHandlerTypes> {
+    rewriter: HtmlRewriter<'h, WriteSink<W>, H>,
+    poisoned: bool,
+}
+
+impl<'h, W: io::Write, H: HandlerTypes> RewritingWriter<'h, W, H> {
+    /// Constructs a new writer with the provided `settings` that writes the rewritten output
+    /// into the `writer`.
+    #[inline]
+    pub fn new<'s>(settings: Settings<'h, 's, H>, writer: W) -> Self {
+        Self {
+            rewriter: HtmlRewriter::new(
+                settings,
+                WriteSink {
+                    writer,
+                    error: None,
+                },
+            ),
+            poisoned: false,
+        }
+    }
+
+    /// Finalizes the rewriting, flushes the underlying writer and returns it.
+    ///
+    /// # Errors
+    /// Returns an error if the rewriter or the underlying writer fail.
+    pub fn finish(self) -> io::Result<W> {
+        if self.poisoned {
+            return Err(poisoned_error());
+        }
+
+        let (mut sink, res) = self.rewriter.end_into_output_sink();
+
+        res?;
+
+        if let Some(e) = sink.error {
+            return Err(e);
+        }
+
+        sink.writer.flush()?;
+
+        Ok(sink.writer)
+    }
+
+    fn check(&mut self, res: Result<(), RewritingError>) -> io::Result<()> {
+        let sink_error = self.rewriter.output_sink_mut().error.take();
+
+        if res.is_err() || sink_error.is_some() {
+            self.poisoned = true;
+        }
+
+        res?;
+
+        sink_error.map_or(Ok(()), Err)
+    }
+}
+
+impl<W: io::Write, H: HandlerTypes> io::Write for RewritingWriter<'_, W, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.poisoned {
+            return Err(poisoned_error());
+        }
+
+        let res = self.rewriter.write(buf);
+
+        self.check(res)?;
+
+        Ok(buf.len())
+    }
+
+    /// [Flushes] the rewriter and then the underlying writer.
+    ///
+    /// [Flushes]: struct.HtmlRewriter.html#method.flush
+    fn flush(&mut self) -> io::Result<()> {
+        if self.poisoned {
+            return Err(poisoned_error());
+        }
+
+        let res = self.rewriter.flush();
+
+        self.check(res)?;
+        self.rewriter.output_sink_mut().writer.flush()
+    }
+}
+
+/// Collects the output until it's read.
+pub(super) struct ReadSink(pub(super) Vec<u8>);
+
+impl OutputSink for ReadSink {
+    #[inline]
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0.extend_from_slice(chunk);
+    }
+}
+
+/// The rewriting state of the readers, which only differ in the way they read the input.
+pub(super) struct ReadState<'h, H: HandlerTypes> {
+    /// `None` once the rewriting has finished.
+    rewriter: Option<HtmlRewriter<'h, ReadSink, H>>,
+    pub(super) input: Box<[u8]>,
+    output: Vec<u8>,
+    output_pos: usize,
+    error: Option<io::Error>,
+    poisoned: bool,
+}
+
+impl<'h, H: HandlerTypes> ReadState<'h, H> {
+    #[inline]
+    pub(super) fn new<'s>(settings: Settings<'h, 's, H>) -> Self {
+        Self {
+            rewriter: Some(HtmlRewriter::new(settings, ReadSink(Vec::new()))),
+            input: vec![0; INPUT_BUFFER_SIZE].into_boxed_slice(),
+            output: Vec::new(),
+            output_pos: 0,
+            error: None,
+            poisoned: false,
+        }
+    }
+
+    /// Drops the output that has been read. Returns `false` if the rewriting has finished and
+    /// no more input is needed.
+    pub(super) fn needs_input(&mut self) -> io::Result<bool> {
+        self.output.clear();
+        self.output_pos = 0;
+
+        if self.rewriter.is_some() {
+            return Ok(true);
+        }
+
+        if let Some(e) = self.error.take() {
+            self.poisoned = true;
+
+            return Err(e);
+        }
+
+        if self.poisoned {
+            Err(poisoned_error())
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Feeds the first `read_byte_count` bytes of the input buffer to the rewriter, or ends
+    /// the rewriting if there are none.
+    pub(super) fn rewrite_input(&mut self, read_byte_count: usize) {
+        let res = if read_byte_count == 0 {
+            match self.rewriter.take().map(HtmlRewriter::end_into_output_sink) {
+                Some((sink, res)) => {
+                    self.output = sink.0;
+
+                    res
+                }
+                None => Ok(()),
+            }
+        } else if let Some(rewriter) = &mut self.rewriter {
+            let res = rewriter.write(&self.input[..read_byte_count]);
+
+            std::mem::swap(&mut self.output, &mut rewriter.output_sink_mut().0);
+
+            res
+        } else {
+            Ok(())
+        };
+
+        // NOTE: the output produced before the error is read first.
+        if let Err(e) = res {
+            self.rewriter = None;
+            self.error = Some(e.into());
+        }
+    }
+
+    #[inline]
+    pub(super) fn has_output(&self) -> bool {
+        self.output_pos < self.output.len()
+    }
+
+    /// Copies the pending output into the `buf`, returning the number of copied bytes.
+    pub(super) fn read_output(&mut self, buf: &mut [u8]) -> usize {
+        let pending = &self.output[self.output_pos..];
+        let count = pending.len().min(buf.len());
+
+        buf[..count].copy_from_slice(&pending[..count]);
+        self.output_pos += count;
+
+        count
+    }
+}
+
+/// An [`io::Read`] adapter for the [`HtmlRewriter`] that reads the input from another
+/// [`io::Read`] and produces the rewritten output.
+///
+/// Errors of the rewriter are converted into [`io::Error`]s. The output produced before an
+/// error (e.g. on a [graceful bail-out]) is read first, and the reader can't be used after
+/// the error.
+///
+/// # Example
+/// ```
+/// use lol_html::{element, RewritingReader, Settings};
+/// use std::io::Read;
+///
+/// let mut reader = RewritingReader::new(
+///     Settings::new().append_element_content_handler(element!("a[href]", |el| {
+///         el.set_attribute("rel", "nofollow")?;
+///         Ok(())
+///     })),
+///     &b"<a href='/'>Home</a>"[..],
+/// );
+///
+/// let mut output = String::new();
+///
+/// reader.read_to_string(&mut output).unwrap();
+///
+/// assert_eq!(output, r#"<a href='/' rel="nofollow">Home</a>"#);
+/// ```
+///
+/// [`HtmlRewriter`]: struct.HtmlRewriter.html
+/// [graceful bail-out]: struct.MemorySettings.html#method.with_graceful_bail_out_on_memory_limit_exceeded
+pub struct RewritingReader<'h, R: io::Read, H: HandlerTypes = LocalHandlerTypes> {
+    source: R,
+    state: ReadState<'h, H>,
+}
+
+impl<'h, R: io::Read, H: HandlerTypes> RewritingReader<'h, R, H> {
+    /// Constructs a new reader with the provided `settings` that rewrites the input read from
+    /// the `source`.
+    #[inline]
+    pub fn new<'s>(settings: Settings<'h, 's, H>, source: R) -> Self {
+        Self {
+            source,
+            state: ReadState::new(settings),
+        }
+    }
+
+    /// Returns the underlying reader.
+    ///
+    /// The output that hasn't been read yet is lost.
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Feeds the next chunk of input to the rewriter. Returns `false` if there is no more output.
+    fn fill_output(&mut self) -> io::Result<bool> {
+        if !self.state.needs_input()? {
+            return Ok(false);
+        }
+
+        let read_byte_count = match self.source.read(&mut self.state.input) {
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e),
+        };
+
+        self.state.rewrite_input(read_byte_count);
+
+        Ok(true)
+    }
+}
+
+impl<R: io::Read, H: HandlerTypes> io::Read for RewritingReader<'_, R, H> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while !self.state.has_output() {
+            if !self.fill_output()? {
+                return Ok(0);
+            }
+        }
+
+        Ok(self.state.read_output(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_content::ContentType;
+    use crate::{element, end, text};
+    use std::io::{Read, Write};
+
+    fn settings() -> Settings<'static, 'static> {
+        Settings::new()
+            .append_element_content_handler(element!("p", |el| {
+                el.prepend("<b>", ContentType::Html);
+                el.append("</b>", ContentType::Html);
+                Ok(())
+            }))
+            .append_element_content_handler(text!("p", |t| {
+                if t.as_str() == "fail" {
+                    return Err("Text handler failed.".into());
+                }
+
+                Ok(())
+            }))
+            .append_document_content_handler(end!(|end| {
+                end.append("<!--end-->", ContentType::Html);
+                Ok(())
+            }))
+    }
+
+    /// Reads at most `max_chunk_size` bytes at a time.
+    struct ChunkedReader<'i> {
+        input: &'i [u8],
+        max_chunk_size: usize,
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.input.len().min(buf.len()).min(self.max_chunk_size);
+
+            buf[..count].copy_from_slice(&self.input[..count]);
+            self.input = &self.input[count..];
+
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn writer() {
+        let mut writer = RewritingWriter::new(settings(), Vec::new());
+
+        for chunk in b"<p>Hello</p><p>world</p>".chunks(3) {
+            writer.write_all(chunk).unwrap();
+        }
+
+        writer.flush().unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.finish().unwrap()).unwrap(),
+            "<p><b>Hello</b></p><p><b>world</b></p><!--end-->"
+        );
+    }
+
+    #[test]
+    fn writer_errors() {
+        let mut writer = RewritingWriter::new(settings(), Vec::new());
+
+        let err = writer.write_all(b"<p>fail</p>").unwrap_err();
+
+        assert_eq!(err.to_string(), "Text handler failed.");
+        assert!(writer.write_all(b"<p>").is_err());
+        assert!(writer.finish().is_err());
+
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("Write failed."))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = RewritingWriter::new(settings(), FailingWriter);
+
+        let err = writer.write_all(b"<p>Hello</p>").unwrap_err();
+
+        assert_eq!(err.to_string(), "Write failed.");
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn reader() {
+        for max_chunk_size in [1, 3, usize::MAX] {
+            let mut reader = RewritingReader::new(
+                settings(),
+                ChunkedReader {
+                    input: b"<p>Hello</p><p>world</p>",
+                    max_chunk_size,
+                },
+            );
+
+            let mut output = String::new();
+
+            reader.read_to_string(&mut output).unwrap();
+
+            assert_eq!(output, "<p><b>Hello</b></p><p><b>world</b></p><!--end-->");
+        }
+    }
+
+    #[test]
+    fn reader_errors() {
+        let mut reader = RewritingReader::new(settings(), &b"<div></div><p>fail</p>"[..]);
+        let mut output = Vec::new();
+
+        let err = reader.read_to_end(&mut output).unwrap_err();
+
+        assert_eq!(err.to_string(), "Text handler failed.");
+        assert_eq!(output, b"<div></div><p><b>");
+        assert!(reader.read(&mut [0; 16]).is_err());
+
+        // NOTE: the unfinished tag is flushed as-is on the bail-out at the end of the input.
+        let settings = Settings::new()
+            .with_graceful_bail_out_on_content_handler_error(true)
+            .append_document_content_handler(end!(|_| Err("End handler failed.".into())));
+
+        let mut reader = RewritingReader::new(settings, &b"<div>Hello</div><p"[..]);
+        let mut output = Vec::new();
+
+        let err = reader.read_to_end(&mut output).unwrap_err();
+
+        assert_eq!(err.to_string(), "End handler failed.");
+        assert_eq!(output, b"<div>Hello</div><p");
+        assert!(reader.read(&mut [0; 16]).is_err());
+    }
+}
@@ -1,14 +1,24 @@
+#[cfg(feature = "stream")]
+mod async_io;
 mod handlers_dispatcher;
+mod io_adapters;
 mod rewrite_controller;
+#[cfg(feature = "stream")]
+mod stream;
 
 #[macro_use]
 pub(crate) mod settings;
 
+#[cfg(feature = "stream")]
+pub use self::async_io::{AsyncRewritingReader, AsyncRewritingWriter};
+pub use self::io_adapters::{RewritingReader, RewritingWriter};
 use self::rewrite_controller::{ElementDescriptor, HtmlRewriteController};
 pub use self::settings::*;
+#[cfg(feature = "stream")]
+pub use self::stream::RewritingStream;
 use crate::base::SharedEncoding;
-use crate::memory::{MemoryLimitExceededError, SharedMemoryLimiter};
-use crate::parser::ParsingAmbiguityError;
+use crate::memory::{MaxNestingDepthExceededError, MemoryLimitExceededError, SharedMemoryLimiter};
+use crate::parser::{ParsingAmbiguityError, TagLimitExceededError};
 use crate::rewritable_units::{Element, IncompleteUtf8Resync};
 use crate::transform_stream::*;
 use encoding_rs::Encoding;
@@ -16,12 +26,14 @@ use mime::Mime;
 use std::borrow::Cow;
 use std::error::Error as StdError;
 use std::fmt::{self, Debug};
+use std::ops::Range;
 use thiserror::Error;
 
 /// This is an encoding known to be ASCII-compatible.
 ///
 /// Non-ASCII-compatible encodings (`UTF-16LE`, `UTF-16BE`, `ISO-2022-JP` and
-/// `replacement`) are not supported by `lol_html`.
+/// `replacement`) can't be parsed by `lol_html` directly, but `UTF-16LE`, `UTF-16BE` and
+/// `ISO-2022-JP` input can be transcoded with [`Settings::with_transcoded_encoding`].
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct AsciiCompatibleEncoding(&'static Encoding);
 
@@ -63,17 +75,49 @@ impl TryFrom<&'static Encoding> for AsciiCompatibleEncoding {
     }
 }
 
+/// This is a non-ASCII-compatible encoding that can be transcoded with
+/// [`Settings::with_transcoded_encoding`], i.e. `UTF-16LE`, `UTF-16BE` or `ISO-2022-JP`.
+///
+/// The `replacement` encoding can't be transcoded, as it decodes any input into U+FFFD.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TranscodedEncoding(&'static Encoding);
+
+impl TranscodedEncoding {
+    /// Returns `Some` if `Encoding` is neither ascii-compatible nor `replacement`, or `None`
+    /// otherwise.
+    #[must_use]
+    pub fn new(encoding: &'static Encoding) -> Option<Self> {
+        (!encoding.is_ascii_compatible() && encoding != encoding_rs::REPLACEMENT)
+            .then_some(Self(encoding))
+    }
+}
+
+impl From<TranscodedEncoding> for &'static Encoding {
+    fn from(transcoded_enc: TranscodedEncoding) -> &'static Encoding {
+        transcoded_enc.0
+    }
+}
+
+impl TryFrom<&'static Encoding> for TranscodedEncoding {
+    type Error = ();
+
+    fn try_from(enc: &'static Encoding) -> Result<Self, ()> {
+        Self::new(enc).ok_or(())
+    }
+}
+
 /// A compound error type that can be returned by [`write`] and [`end`] methods of the rewriter.
 ///
 /// # Note
 /// This error is unrecoverable. The rewriter instance will panic on attempt to use it after such an
-/// error.
+/// error, unless it's [`reset`] for the next document.
 ///
 /// This enum is marked `#[non_exhaustive]` so that future variants can be added in minor
 /// releases. External `match` expressions on `RewritingError` must include a wildcard arm.
 ///
 /// [`write`]: ../struct.HtmlRewriter.html#method.write
 /// [`end`]: ../struct.HtmlRewriter.html#method.end
+/// [`reset`]: ../struct.HtmlRewriter.html#method.reset
 #[non_exhaustive]
 #[derive(Error, Debug)]
 pub enum RewritingError {
@@ -83,6 +127,18 @@ pub enum RewritingError {
     #[error("{0}")]
     MemoryLimitExceeded(MemoryLimitExceededError),
 
+    /// See [`MaxNestingDepthExceededError`].
+    ///
+    /// [`MaxNestingDepthExceededError`]: struct.MaxNestingDepthExceededError.html
+    #[error("{0}")]
+    MaxNestingDepthExceeded(MaxNestingDepthExceededError),
+
+    /// See [`TagLimitExceededError`].
+    ///
+    /// [`TagLimitExceededError`]: enum.TagLimitExceededError.html
+    #[error("{0}")]
+    TagLimitExceeded(TagLimitExceededError),
+
     /// See [`ParsingAmbiguityError`].
     ///
     /// [`ParsingAmbiguityError`]: struct.ParsingAmbiguityError.html
@@ -92,6 +148,20 @@ pub enum RewritingError {
     /// An error that was propagated from one of the content handlers.
     #[error("{0}")]
     ContentHandlerError(Box<dyn StdError + Send + Sync + 'static>),
+
+    /// A content handler has modified the content in the [read-only mode].
+    ///
+    /// [read-only mode]: ../struct.Settings.html#method.with_read_only
+    #[error("Content can't be modified in the read-only mode.")]
+    ModifiedInReadOnlyMode,
+
+    /// The rewriter with [document end handlers] can't be [finalized and reset], because the
+    /// handlers are called only once.
+    ///
+    /// [document end handlers]: ../struct.Settings.html#method.append_document_content_handler
+    /// [finalized and reset]: ../struct.HtmlRewriter.html#method.end_and_reset
+    #[error("The rewriter with document end handlers can't be finalized and reset.")]
+    EndHandlersNotReusable,
 }
 
 /// A streaming HTML rewriter.
@@ -131,6 +201,7 @@ pub enum RewritingError {
 /// ```
 pub struct HtmlRewriter<'h, O: OutputSink, H: HandlerTypes = LocalHandlerTypes> {
     stream: TransformStream<HtmlRewriteController<'h, H>, O>,
+    has_end_handlers: bool,
     poisoned: bool,
 }
 
@@ -169,10 +240,24 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
         let graceful_bail_out_on_content_handler_error =
             settings.graceful_bail_out_on_content_handler_error;
         let strict = settings.strict;
+        let full_tree_construction = settings.full_tree_construction;
+        let max_attributes_per_tag = settings.memory_settings.max_attributes_per_tag;
+        let max_start_tag_size = settings.memory_settings.max_start_tag_size;
 
         let encoding = settings.encoding;
+        let transcoded_encoding = settings.transcoded_encoding;
+        let encoding_sniffing = settings.encoding_sniffing;
+        let output_encoding = settings.output_encoding;
+        let read_only = settings.read_only;
+        let min_output_chunk_size = settings.min_output_chunk_size;
+        let max_output_chunk_size = settings.max_output_chunk_size;
         let next_encoding = SharedEncoding::default();
 
+        let has_end_handlers = settings
+            .document_content_handlers
+            .iter()
+            .any(|handlers| handlers.end.is_some());
+
         let memory_limiter =
             SharedMemoryLimiter::new(settings.memory_settings.max_allowed_memory_usage);
 
@@ -186,14 +271,24 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
             preallocated_parsing_buffer_size,
             memory_limiter,
             encoding,
+            transcoded_encoding,
+            output_encoding,
+            encoding_sniffing,
+            read_only,
+            min_output_chunk_size,
+            max_output_chunk_size,
             next_encoding,
             strict,
+            full_tree_construction,
+            max_attributes_per_tag,
+            max_start_tag_size,
             graceful_bail_out_on_memory_limit_exceeded,
             graceful_bail_out_on_content_handler_error,
         });
 
         HtmlRewriter {
             stream,
+            has_end_handlers,
             poisoned: false,
         }
     }
@@ -211,6 +306,32 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
         guarded!(self, self.stream.write(data))
     }
 
+    /// Passes everything the rewriter can safely output so far to the output sink, without
+    /// finalizing the rewriting process.
+    ///
+    /// This includes the output coalesced because of [the minimal output chunk size] and the
+    /// input buffered for [encoding sniffing]: the encoding is detected from the input written so
+    /// far. The rest of the output is already passed to the output sink as soon as possible,
+    /// except for the parts of the input that can't be processed until more input is written:
+    /// characters split between input chunks, and tags, comments and other lexemes that the
+    /// content handlers need to see in full (e.g. `<` at the end of the input can be the start of
+    /// a tag).
+    ///
+    /// Use it to reduce the latency of streaming the output when the input is written slowly.
+    ///
+    /// # Panics
+    ///  * If previous invocation of [`write`] returned a [`RewritingError`] (these errors
+    ///    are unrecoverable).
+    ///
+    /// [the minimal output chunk size]: struct.Settings.html#method.with_min_output_chunk_size
+    /// [encoding sniffing]: struct.Settings.html#method.with_encoding_sniffing
+    /// [`RewritingError`]: errors/enum.RewritingError.html
+    /// [`write`]: struct.HtmlRewriter.html#method.write
+    #[inline]
+    pub fn flush(&mut self) -> Result<(), RewritingError> {
+        guarded!(self, self.stream.flush())
+    }
+
     /// Finalizes the rewriting process.
     ///
     /// Should be called once the last chunk of the input is written.
@@ -225,6 +346,120 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
     pub fn end(mut self) -> Result<(), RewritingError> {
         guarded!(self, self.stream.end())
     }
+
+    /// Same as [`end`](Self::end), but returns the output sink, even if the rewriting fails.
+    #[inline]
+    pub(crate) fn end_into_output_sink(mut self) -> (O, Result<(), RewritingError>) {
+        let res = guarded!(self, self.stream.end());
+
+        (self.stream.into_output_sink(), res)
+    }
+
+    /// Finalizes the rewriting process like [`end`] does, and then [resets] the rewriter for
+    /// the next document that is written into `output_sink`. Returns the output sink of the
+    /// finalized document.
+    ///
+    /// If the rewriting fails, the rewriter is not reset, and [`reset`] must be called before
+    /// it's used for the next document.
+    ///
+    /// [Document end handlers] are called only once, so the rewriter that has them can't be
+    /// reused for the next document: [`RewritingError::EndHandlersNotReusable`] is returned
+    /// without finalizing the document, which can still be finalized with [`end`].
+    ///
+    /// # Panics
+    ///  * If previous invocation of [`write`] returned a [`RewritingError`] (these errors
+    ///    are unrecoverable).
+    ///
+    /// [`end`]: struct.HtmlRewriter.html#method.end
+    /// [resets]: struct.HtmlRewriter.html#method.reset
+    /// [`reset`]: struct.HtmlRewriter.html#method.reset
+    /// [Document end handlers]: struct.Settings.html#method.append_document_content_handler
+    /// [`RewritingError::EndHandlersNotReusable`]: errors/enum.RewritingError.html#variant.EndHandlersNotReusable
+    /// [`RewritingError`]: errors/enum.RewritingError.html
+    /// [`write`]: struct.HtmlRewriter.html#method.write
+    pub fn end_and_reset(&mut self, output_sink: O) -> Result<O, RewritingError> {
+        if self.has_end_handlers {
+            return Err(RewritingError::EndHandlersNotReusable);
+        }
+
+        guarded!(self, self.stream.end())?;
+
+        Ok(self.reset(output_sink))
+    }
+
+    /// Prepares the rewriter for a new document that is written into `output_sink`, discarding
+    /// the rest of the current document. Returns the previous output sink.
+    ///
+    /// The parser state, the stack of open elements and the memory usage are cleared, while
+    /// the compiled selectors, content handlers and allocated buffers are kept, which makes
+    /// rewriting of many small documents cheaper than constructing a new rewriter for each.
+    /// The rewriter can be reset after a [`RewritingError`] as well.
+    ///
+    /// # Note
+    /// Content handlers are not reset, so handlers that keep their own state need to be reset
+    /// by the caller. [Document end handlers] are called only once, so the rewriter that has
+    /// them can't be finalized with [`end_and_reset`].
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::html_content::ContentType;
+    /// use lol_html::{element, HtmlRewriter, OutputSink, Settings};
+    ///
+    /// #[derive(Default)]
+    /// struct Output(Vec<u8>);
+    ///
+    /// impl OutputSink for Output {
+    ///     fn handle_chunk(&mut self, chunk: &[u8]) {
+    ///         self.0.extend_from_slice(chunk);
+    ///     }
+    /// }
+    ///
+    /// let mut rewriter = HtmlRewriter::new(
+    ///     Settings::new().append_element_content_handler(element!("p", |el| {
+    ///         el.prepend("> ", ContentType::Text);
+    ///         Ok(())
+    ///     })),
+    ///     Output::default(),
+    /// );
+    ///
+    /// rewriter.write(b"<p>Hello</p>").unwrap();
+    ///
+    /// let first = rewriter.end_and_reset(Output::default()).unwrap();
+    ///
+    /// rewriter.write(b"<div><p>world</p>").unwrap();
+    ///
+    /// let second = rewriter.end_and_reset(Output::default()).unwrap();
+    ///
+    /// assert_eq!(first.0, b"<p>&gt; Hello</p>");
+    /// assert_eq!(second.0, b"<div><p>&gt; world</p>");
+    /// ```
+    ///
+    /// [`RewritingError`]: errors/enum.RewritingError.html
+    /// [Document end handlers]: struct.Settings.html#method.append_document_content_handler
+    /// [`end_and_reset`]: struct.HtmlRewriter.html#method.end_and_reset
+    pub fn reset(&mut self, output_sink: O) -> O {
+        self.poisoned = false;
+
+        self.stream.reset(output_sink)
+    }
+
+    #[inline]
+    pub(crate) fn output_sink_mut(&mut self) -> &mut O {
+        self.stream.output_sink_mut()
+    }
+
+    /// Returns the encoding detected from the start of the input and the confidence of the
+    /// detection.
+    ///
+    /// Returns `None` if [encoding sniffing] is disabled or if not enough input has been
+    /// written to detect the encoding yet.
+    ///
+    /// [encoding sniffing]: struct.Settings.html#method.with_encoding_sniffing
+    #[inline]
+    #[must_use]
+    pub fn detected_encoding(&self) -> Option<DetectedEncoding> {
+        self.stream.detected_encoding()
+    }
 }
 
 // NOTE: this opaque Debug implementation is required to make
@@ -240,11 +475,9 @@ impl<O: OutputSink, H: HandlerTypes> Debug for HtmlRewriter<'_, O, H> {
 fn handler_adjust_charset_on_meta_tag<'h, H: HandlerTypes>(
     encoding: SharedEncoding,
 ) -> (Cow<'h, crate::Selector>, ElementContentHandlers<'h, H>) {
-    // HTML5 allows encoding to be set only once
-    let mut found = false;
-
     let handler = move |el: &mut Element<'_, '_, H>| {
-        if found {
+        // HTML5 allows encoding to be set only once
+        if encoding.get().is_some() {
             return Ok(());
         }
 
@@ -263,8 +496,59 @@ fn handler_adjust_charset_on_meta_tag<'h, H: HandlerTypes>(
         });
 
         if let Some(charset) = charset {
-            found = true;
-            let _ = encoding.set(charset);
+            encoding.set(charset);
+        }
+
+        Ok(())
+    };
+
+    let content_handlers = ElementContentHandlers {
+        element: Some(H::new_element_handler(handler)),
+        comments: None,
+        text: None,
+    };
+
+    (Cow::Owned("meta".parse().unwrap()), content_handlers)
+}
+
+fn handler_remove_duplicate_attributes<'h, H: HandlerTypes>()
+-> (Cow<'h, crate::Selector>, ElementContentHandlers<'h, H>) {
+    let handler = |el: &mut Element<'_, '_, H>| {
+        el.remove_duplicate_attributes();
+
+        Ok(())
+    };
+
+    let content_handlers = ElementContentHandlers {
+        element: Some(H::new_element_handler(handler)),
+        comments: None,
+        text: None,
+    };
+
+    (Cow::Owned("*".parse().unwrap()), content_handlers)
+}
+
+fn handler_rewrite_charset_on_meta_tag<'h, H: HandlerTypes>(
+    output_encoding: &'static Encoding,
+) -> (Cow<'h, crate::Selector>, ElementContentHandlers<'h, H>) {
+    let charset = output_encoding.name();
+
+    let handler = move |el: &mut Element<'_, '_, H>| {
+        if el.has_attribute("charset") {
+            el.set_attribute("charset", charset)?;
+        }
+
+        let content = el
+            .get_attribute("http-equiv")
+            .filter(|http_equiv| http_equiv.eq_ignore_ascii_case("Content-Type"))
+            .and_then(|_| el.get_attribute("content"));
+
+        // NOTE: only the charset is replaced, the rest of the content is kept as it is.
+        if let Some(mut content) = content {
+            if let Some(range) = meta_content_charset_range(&content) {
+                content.replace_range(range, charset);
+                el.set_attribute("content", &content)?;
+            }
         }
 
         Ok(())
@@ -279,6 +563,54 @@ fn handler_adjust_charset_on_meta_tag<'h, H: HandlerTypes>(
     (Cow::Owned("meta".parse().unwrap()), content_handlers)
 }
 
+/// Returns the range of the encoding label in the `content` attribute of `<meta>`, found the
+/// way browsers [extract] it.
+///
+/// [extract]: https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn meta_content_charset_range(content: &str) -> Option<Range<usize>> {
+    const CHARSET: &[u8] = b"charset";
+
+    let bytes = content.as_bytes();
+    let skip_whitespace = |mut pos: usize| {
+        while bytes.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+
+        pos
+    };
+
+    let mut pos = 0;
+
+    loop {
+        pos += bytes[pos..]
+            .windows(CHARSET.len())
+            .position(|w| w.eq_ignore_ascii_case(CHARSET))?;
+        pos = skip_whitespace(pos + CHARSET.len());
+
+        if bytes.get(pos) == Some(&b'=') {
+            break;
+        }
+    }
+
+    let start = skip_whitespace(pos + 1);
+
+    match bytes.get(start) {
+        Some(&quote @ (b'"' | b'\'')) => {
+            let len = bytes[start + 1..].iter().position(|&b| b == quote)?;
+
+            Some(start + 1..start + 1 + len)
+        }
+        _ => {
+            let len = bytes[start..]
+                .iter()
+                .position(|&b| b.is_ascii_whitespace() || b == b';')
+                .unwrap_or(bytes.len() - start);
+
+            (len > 0).then_some(start..start + len)
+        }
+    }
+}
+
 /// Rewrites given `html` string with the provided `settings`.
 ///
 /// # Example
@@ -343,11 +675,14 @@ fn rewrite_str_utf8<H: HandlerTypes>(
 mod tests {
     use super::*;
     use crate::html::TextType;
-    use crate::html_content::ContentType;
+    use crate::html_content::{ContentType, EndTag};
     use crate::test_utils::{ASCII_COMPATIBLE_ENCODINGS, NON_ASCII_COMPATIBLE_ENCODINGS, Output};
-    use encoding_rs::{Encoding, WINDOWS_1252};
+    use encoding_rs::{
+        Encoding, ISO_2022_JP, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1251, WINDOWS_1252,
+    };
     use itertools::Itertools;
     use static_assertions::assert_impl_all;
+    use std::cell::RefCell;
     use std::convert::TryInto;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
@@ -452,37 +787,861 @@ mod tests {
         )
         .unwrap();
 
-        assert_eq!(
-            res,
-            r#"<title s="y" c="y">…</title><div s="y" c="y">…</div><style s="y" c="y">…</style><script s="y" c="y">…</script>
-            <br s="y" c="n" /><br s="n" c="n"><embed s="y" c="n" /><embed s="n" c="n"> <svg><a s="y" c="n" /><path s="y" c="n" /><path s="n" c="y">…</path></svg>"#
-        );
-    }
+        assert_eq!(
+            res,
+            r#"<title s="y" c="y">…</title><div s="y" c="y">…</div><style s="y" c="y">…</style><script s="y" c="y">…</script>
+            <br s="y" c="n" /><br s="n" c="n"><embed s="y" c="n" /><embed s="n" c="n"> <svg><a s="y" c="n" /><path s="y" c="n" /><path s="n" c="y">…</path></svg>"#
+        );
+    }
+
+    #[test]
+    fn rewrite_arbitrary_settings() {
+        let res = rewrite_str("<span>Some text</span>", Settings::new()).unwrap();
+        assert_eq!(res, "<span>Some text</span>");
+    }
+
+    #[test]
+    fn rewrite_non_utf8() {
+        let text = "前<meta charset=latin1><span>中</span><!-- 後 -->";
+        let rewritten = rewrite_str(
+            text,
+            Settings::new()
+                .with_encoding(encoding_rs::BIG5.try_into().unwrap())
+                .with_adjust_charset_on_meta_tag(true),
+        )
+        .unwrap();
+        assert_eq!(rewritten, text);
+    }
+
+    #[test]
+    fn non_ascii_compatible_encoding() {
+        for encoding in &NON_ASCII_COMPATIBLE_ENCODINGS {
+            assert_eq!(AsciiCompatibleEncoding::new(encoding), None);
+        }
+    }
+
+    #[test]
+    fn transcoded_encoding() {
+        fn encode(encoding: &'static Encoding, s: &str) -> Vec<u8> {
+            match encoding.name() {
+                "UTF-16LE" => s.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+                "UTF-16BE" => s.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+                _ => encoding.encode(s).0.into_owned(),
+            }
+        }
+
+        let html = "\u{FEFF}<div title=\"日本\">日本語</div><!--コメント--><p>x</p>";
+        let expected = concat!(
+            "\u{FEFF}<div title=\"日本\" lang=\"ja\">日本語</div>テスト",
+            "<!--コメント--><p>x</p><!--終わり-->"
+        );
+
+        for encoding in [UTF_16LE, UTF_16BE, ISO_2022_JP] {
+            let input = encode(encoding, html);
+            let mut texts = String::new();
+            let mut output = Vec::new();
+
+            {
+                let mut rewriter = HtmlRewriter::new(
+                    Settings::new()
+                        .with_transcoded_encoding(TranscodedEncoding::new(encoding).unwrap())
+                        .with_adjust_charset_on_meta_tag(true)
+                        .append_element_content_handler(element!("div", |el| {
+                            assert_eq!(el.get_attribute("title").unwrap(), "日本");
+                            el.set_attribute("lang", "ja").unwrap();
+                            el.after("テスト", ContentType::Text);
+                            Ok(())
+                        }))
+                        .append_element_content_handler(text!("div", |t| {
+                            texts.push_str(t.as_str());
+                            Ok(())
+                        }))
+                        .append_document_content_handler(end!(|end| {
+                            end.append("<!--終わり-->", ContentType::Html);
+                            Ok(())
+                        })),
+                    |c: &[u8]| output.extend_from_slice(c),
+                );
+
+                // NOTE: feed the input byte by byte to split characters between chunks.
+                for byte in &input {
+                    rewriter.write(std::slice::from_ref(byte)).unwrap();
+                }
+
+                rewriter.end().unwrap();
+            }
+
+            assert_eq!(texts, "日本語", "{}", encoding.name());
+            assert_eq!(output, encode(encoding, expected), "{}", encoding.name());
+        }
+    }
+
+    #[test]
+    fn transcoded_encoding_validation() {
+        for encoding in [UTF_16LE, UTF_16BE, ISO_2022_JP] {
+            assert!(TranscodedEncoding::new(encoding).is_some());
+        }
+
+        for encoding in [UTF_8, WINDOWS_1251, encoding_rs::REPLACEMENT] {
+            assert!(TranscodedEncoding::new(encoding).is_none());
+        }
+    }
+
+    #[test]
+    fn transcoded_encoding_with_empty_output_chunks() {
+        let (input, ..) = ISO_2022_JP.encode("<p>日本<!--語-->語</p>");
+        let mut output = Vec::new();
+
+        {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_transcoded_encoding(TranscodedEncoding::new(ISO_2022_JP).unwrap())
+                    .append_document_content_handler(doc_comments!(|c| {
+                        // NOTE: the empty comment text is passed to the output sink as an empty
+                        // chunk, which is not the end of the output.
+                        c.set_text("").unwrap();
+                        Ok(())
+                    })),
+                |c: &[u8]| output.extend_from_slice(c),
+            );
+
+            for byte in &*input {
+                rewriter.write(std::slice::from_ref(byte)).unwrap();
+            }
+
+            rewriter.end().unwrap();
+        }
+
+        assert_eq!(output, &*ISO_2022_JP.encode("<p>日本<!---->語</p>").0);
+    }
+
+    #[test]
+    fn transcoded_encoding_ignores_meta_charset() {
+        let html = "<meta charset=windows-1251><p>é</p>";
+        let input: Vec<u8> = html.encode_utf16().flat_map(u16::to_le_bytes).collect();
+
+        let output = rewrite_html_bytes(
+            &input,
+            Settings::new()
+                .with_transcoded_encoding(TranscodedEncoding::new(UTF_16LE).unwrap())
+                .with_adjust_charset_on_meta_tag(true)
+                .append_element_content_handler(element!("p", |el| {
+                    el.prepend("à", ContentType::Text);
+                    Ok(())
+                })),
+        );
+
+        let expected: Vec<u8> = "<meta charset=windows-1251><p>àé</p>"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn output_encoding() {
+        let html = concat!(
+            r#"<meta charset="windows-1251">"#,
+            r#"<meta http-equiv="Content-Type" content="text/html; charset=windows-1251">"#,
+            "<p>Привет</p>"
+        );
+        let (input, ..) = WINDOWS_1251.encode(html);
+        let mut texts = String::new();
+        let mut output = Vec::new();
+
+        {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_encoding(WINDOWS_1251.try_into().unwrap())
+                    .with_output_encoding(UTF_8)
+                    .append_element_content_handler(element!("p", |el| {
+                        el.append("мир", ContentType::Text);
+                        Ok(())
+                    }))
+                    .append_element_content_handler(text!("p", |t| {
+                        texts.push_str(t.as_str());
+                        Ok(())
+                    })),
+                |c: &[u8]| output.extend_from_slice(c),
+            );
+
+            for byte in &*input {
+                rewriter.write(std::slice::from_ref(byte)).unwrap();
+            }
+
+            rewriter.end().unwrap();
+        }
+
+        assert_eq!(texts, "Привет");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"<meta charset="UTF-8">"#,
+                r#"<meta http-equiv="Content-Type" content="text/html; charset=UTF-8">"#,
+                "<p>Приветмир</p>"
+            )
+        );
+    }
+
+    #[test]
+    fn output_encoding_numeric_character_references() {
+        let output = rewrite_html_bytes(
+            "<p title=\"日本\">Ж日本</p>".as_bytes(),
+            Settings::new()
+                .with_output_encoding(WINDOWS_1251)
+                .append_element_content_handler(element!("p", |el| {
+                    el.append("ё😀", ContentType::Text);
+                    Ok(())
+                })),
+        );
+
+        let (expected, ..) =
+            WINDOWS_1251.encode("<p title=\"&#26085;&#26412;\">Ж&#26085;&#26412;ё&#128512;</p>");
+
+        assert_eq!(output, &*expected);
+    }
+
+    #[test]
+    fn output_encoding_escapes_in_raw_text_and_comments() {
+        let html = concat!(
+            r#"<p title="日">日</p><script>"日😀"</script><style>a::after{content:"日"}</style>"#,
+            "<!--日--><textarea>日</textarea><xmp>日</xmp>日"
+        );
+
+        let expected = concat!(
+            r#"<p title="&#26085;">&#26085;</p><script>"\u65E5\uD83D\uDE00"</script>"#,
+            r#"<style>a::after{content:"\65E5 "}</style><!--?--><textarea>&#26085;</textarea>"#,
+            "<xmp>?</xmp>&#26085;"
+        );
+
+        let output = rewrite_html_bytes(
+            html.as_bytes(),
+            Settings::new().with_output_encoding(WINDOWS_1251),
+        );
+
+        assert_eq!(output, expected.as_bytes());
+
+        // NOTE: ISO-2022-JP switches to ASCII before the escape.
+        let output = rewrite_html_bytes(
+            "<script>'日é'</script>".as_bytes(),
+            Settings::new().with_output_encoding(ISO_2022_JP),
+        );
+
+        assert_eq!(
+            output,
+            &*ISO_2022_JP.encode(r"<script>'日\u00E9'</script>").0
+        );
+    }
+
+    #[test]
+    fn output_encoding_escapes_follow_parser_context() {
+        let output = rewrite_html_bytes(
+            r#"<div title=x"y><script>var s="日"</script>"#.as_bytes(),
+            Settings::new().with_output_encoding(WINDOWS_1251),
+        );
+
+        assert_eq!(output, br#"<div title=x"y><script>var s="\u65E5"</script>"#);
+
+        // NOTE: in foreign content `<style>` is parsed as markup, so references are decoded.
+        let output = rewrite_html_bytes(
+            "<svg><style>日</style></svg><style>日</style>".as_bytes(),
+            Settings::new().with_output_encoding(WINDOWS_1251),
+        );
+
+        assert_eq!(
+            output,
+            br"<svg><style>&#26085;</style></svg><style>\65E5 </style>"
+        );
+    }
+
+    #[test]
+    fn output_encoding_utf_16_bom() {
+        let encode = |s: &str| -> Vec<u8> { s.encode_utf16().flat_map(u16::to_be_bytes).collect() };
+
+        let output = rewrite_html_bytes(
+            "<meta charset=utf-8><p>é</p>".as_bytes(),
+            Settings::new()
+                .with_adjust_charset_on_meta_tag(true)
+                .with_output_encoding(UTF_16BE),
+        );
+
+        assert_eq!(
+            output,
+            encode("\u{FEFF}<meta charset=\"UTF-16BE\"><p>é</p>")
+        );
+
+        let output = rewrite_html_bytes(
+            "\u{FEFF}<p>é</p>".as_bytes(),
+            Settings::new().with_output_encoding(UTF_16BE),
+        );
+
+        assert_eq!(output, encode("\u{FEFF}<p>é</p>"));
+    }
+
+    #[test]
+    fn output_encoding_follows_meta_charset() {
+        let (input, ..) = WINDOWS_1251.encode("<meta charset=windows-1251><p>Привет</p>");
+
+        let output = rewrite_html_bytes(
+            &input,
+            Settings::new()
+                .with_encoding(WINDOWS_1252.try_into().unwrap())
+                .with_adjust_charset_on_meta_tag(true)
+                .with_output_encoding(UTF_8),
+        );
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"<meta charset="UTF-8"><p>Привет</p>"#
+        );
+    }
+
+    #[test]
+    fn output_encoding_keeps_content_type_parameters() {
+        let html = concat!(
+            r#"<meta http-equiv="Content-Type" "#,
+            r#"content='text/html; foo=bar; charset=windows-1251; baz="a b"'>"#,
+            r#"<meta http-equiv="content-type" "#,
+            r#"content='text/html; foo="a\"b"; Charset = "windows-1251"; baz="a b"'>"#,
+        );
+
+        let output = rewrite_html_bytes(
+            html.as_bytes(),
+            Settings::new()
+                .with_encoding(WINDOWS_1251.try_into().unwrap())
+                .with_output_encoding(UTF_8)
+                .with_preserve_attribute_formatting(true),
+        );
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"<meta http-equiv="Content-Type" "#,
+                r#"content='text/html; foo=bar; charset=UTF-8; baz="a b"'>"#,
+                r#"<meta http-equiv="content-type" "#,
+                r#"content='text/html; foo="a\"b"; Charset = "UTF-8"; baz="a b"'>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn remove_duplicate_attributes() {
+        let html = b"<a href=/ HREF='/evil' id=x><svg viewBox=1 viewbox=2 id=y id=z></svg></a><p>";
+        let mut duplicates = Vec::new();
+
+        let output = rewrite_html_bytes(
+            html,
+            Settings::new()
+                .with_remove_duplicate_attributes(true)
+                .append_element_content_handler(element!("a", |el| {
+                    duplicates.extend(el.duplicate_attributes().iter().map(|a| a.value()));
+                    Ok(())
+                })),
+        );
+
+        assert_eq!(duplicates, ["/evil"]);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "<a href=/ id=x><svg viewBox=1 id=y></svg></a><p>"
+        );
+
+        let output = rewrite_html_bytes(
+            html,
+            Settings::new()
+                .with_remove_duplicate_attributes(true)
+                .with_read_only(true),
+        );
+
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn read_only() {
+        let mut links = Vec::new();
+        let mut texts = String::new();
+        let mut comments = Vec::new();
+        let mut ended = false;
+
+        {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_read_only(true)
+                    .with_output_encoding(WINDOWS_1252)
+                    .append_element_content_handler(element!("a[href]", |el| {
+                        links.push(el.get_attribute("href").unwrap());
+                        Ok(())
+                    }))
+                    .append_element_content_handler(text!("a", |t| {
+                        texts.push_str(t.as_str());
+                        Ok(())
+                    }))
+                    .append_document_content_handler(doc_comments!(|c| {
+                        comments.push(c.text());
+                        Ok(())
+                    }))
+                    .append_document_content_handler(end!(|_| {
+                        ended = true;
+                        Ok(())
+                    })),
+                |_: &[u8]| panic!("Output sink shouldn't be called in the read-only mode."),
+            );
+
+            for chunk in r#"<meta charset=utf-8><a href="/x">foo</a><!--c--><p><a href=/y>bar"#
+                .as_bytes()
+                .chunks(3)
+            {
+                rewriter.write(chunk).unwrap();
+            }
+
+            rewriter.end().unwrap();
+        }
+
+        assert_eq!(links, ["/x", "/y"]);
+        assert_eq!(texts, "foobar");
+        assert_eq!(comments, ["c"]);
+        assert!(ended);
+    }
+
+    #[test]
+    fn read_only_modifications() {
+        fn assert_modification_error(settings: Settings<'_, '_>) {
+            let mut rewriter = HtmlRewriter::new(settings.with_read_only(true), |_: &[u8]| {});
+
+            let res = match rewriter.write(b"<div><!--c-->text</div>") {
+                Ok(()) => rewriter.end(),
+                Err(e) => Err(e),
+            };
+
+            assert!(matches!(res, Err(RewritingError::ModifiedInReadOnlyMode)));
+        }
+
+        assert_modification_error(Settings::new().append_element_content_handler(element!(
+            "div",
+            |el| {
+                el.set_attribute("foo", "bar")?;
+                Ok(())
+            }
+        )));
+
+        assert_modification_error(Settings::new().append_element_content_handler(element!(
+            "div",
+            |el| {
+                el.append("foo", ContentType::Text);
+                Ok(())
+            }
+        )));
+
+        assert_modification_error(Settings::new().append_element_content_handler(text!(
+            "div",
+            |t| {
+                t.remove();
+                Ok(())
+            }
+        )));
+
+        assert_modification_error(
+            Settings::new().append_document_content_handler(doc_comments!(|c| {
+                c.set_text("foo")?;
+                Ok(())
+            })),
+        );
+
+        assert_modification_error(Settings::new().append_document_content_handler(end!(|end| {
+            end.append("foo", ContentType::Html);
+            Ok(())
+        })));
+
+        // NOTE: the implied end tag of `p` is produced by the start tag of `div`.
+        let mut rewriter = HtmlRewriter::new(
+            Settings::new()
+                .with_read_only(true)
+                .with_full_tree_construction(true)
+                .append_element_content_handler(element!("p", |el| {
+                    if let Some(handlers) = el.end_tag_handlers() {
+                        handlers.push(Box::new(|end: &mut EndTag<'_>| {
+                            end.after("foo", ContentType::Text);
+                            Ok(())
+                        }) as _);
+                    }
+
+                    Ok(())
+                })),
+            |_: &[u8]| {},
+        );
+
+        assert!(matches!(
+            rewriter.write(b"<p>foo<div></div>"),
+            Err(RewritingError::ModifiedInReadOnlyMode)
+        ));
+    }
+
+    #[test]
+    fn output_chunk_sizes() {
+        let mut chunks = Vec::new();
+
+        {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_min_output_chunk_size(8)
+                    .with_max_output_chunk_size(16)
+                    .append_element_content_handler(element!("a", |el| {
+                        el.set_attribute("rel", "nofollow")?;
+                        Ok(())
+                    })),
+                |c: &[u8]| chunks.push(String::from_utf8(c.to_vec()).unwrap()),
+            );
+
+            for chunk in [
+                "<a",
+                " href=/>",
+                "a",
+                "</a>",
+                "<p>",
+                "long text in a paragraph",
+            ] {
+                rewriter.write(chunk.as_bytes()).unwrap();
+            }
+
+            rewriter.flush().unwrap();
+            rewriter.write(b"</p>").unwrap();
+            rewriter.end().unwrap();
+        }
+
+        assert!(
+            chunks
+                .iter()
+                .rev()
+                .skip(3)
+                .all(|c| (8..=16).contains(&c.len()))
+        );
+        assert_eq!(chunks.last().unwrap(), "");
+        assert_eq!(
+            chunks.concat(),
+            r#"<a href=/ rel="nofollow">a</a><p>long text in a paragraph</p>"#
+        );
+    }
+
+    #[test]
+    fn flush() {
+        let output = RefCell::new(Vec::new());
+        let mut texts = Vec::new();
+
+        {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_encoding_sniffing(true)
+                    .with_min_output_chunk_size(1024)
+                    .append_element_content_handler(text!("p", |t| {
+                        texts.push(t.as_str().to_owned());
+                        Ok(())
+                    })),
+                |c: &[u8]| output.borrow_mut().extend_from_slice(c),
+            );
+
+            rewriter.flush().unwrap();
+
+            assert_eq!(rewriter.detected_encoding(), None);
+
+            rewriter.write(b"<p>caf\xC3").unwrap();
+
+            assert!(output.borrow().is_empty());
+
+            rewriter.flush().unwrap();
+
+            assert_eq!(
+                rewriter.detected_encoding(),
+                Some(DetectedEncoding::new(UTF_8, EncodingConfidence::Tentative))
+            );
+
+            // NOTE: the incomplete character is held back.
+            assert_eq!(*output.borrow(), b"<p>caf");
+
+            rewriter.write(b"\xA9 <").unwrap();
+            rewriter.flush().unwrap();
+
+            // NOTE: `<` can be the start of a tag.
+            assert_eq!(*output.borrow(), "<p>café ".as_bytes());
+
+            rewriter.write(b"/p>").unwrap();
+            rewriter.end().unwrap();
+        }
+
+        assert_eq!(*output.borrow(), "<p>café </p>".as_bytes());
+        assert_eq!(texts, ["caf", "é ", ""]);
+    }
+
+    #[derive(Default)]
+    struct VecSink(Vec<u8>);
+
+    impl OutputSink for VecSink {
+        fn handle_chunk(&mut self, chunk: &[u8]) {
+            self.0.extend_from_slice(chunk);
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let texts = RefCell::new(Vec::new());
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings::new()
+                .with_adjust_charset_on_meta_tag(true)
+                .append_element_content_handler(element!("div", |el| {
+                    el.set_inner_content("removed", ContentType::Text);
+                    el.on_end_tag(end_tag!(|t| {
+                        t.after("<!--div-->", ContentType::Html);
+                        Ok(())
+                    }))?;
+                    Ok(())
+                }))
+                .append_element_content_handler(text!("p", |t| {
+                    texts.borrow_mut().push(t.as_str().to_owned());
+                    Ok(())
+                })),
+            VecSink::default(),
+        );
+
+        rewriter
+            .write(b"<meta charset=windows-1251><div><p>\xF0 <scr")
+            .unwrap();
+
+        // NOTE: the content removal, the open elements, the encoding and the buffered input
+        // of the abandoned document don't affect the next one.
+        let first = rewriter.reset(VecSink::default());
+
+        assert_eq!(first.0, b"<meta charset=windows-1251><div>removed");
+        assert_eq!(*texts.borrow(), ["\u{440} "]);
+
+        texts.borrow_mut().clear();
+        rewriter.write("<p>é</p><div></div>".as_bytes()).unwrap();
+
+        let second = rewriter.end_and_reset(VecSink::default()).unwrap();
+
+        assert_eq!(second.0, "<p>é</p><div>removed</div><!--div-->".as_bytes());
+        assert_eq!(*texts.borrow(), ["é", ""]);
+
+        rewriter.write(b"<p>a</p>").unwrap();
+        rewriter.end().unwrap();
+    }
+
+    #[test]
+    fn end_and_reset_with_end_handlers() {
+        let mut ends = 0;
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings::new().append_document_content_handler(end!(|end| {
+                ends += 1;
+                end.append("<!--end-->", ContentType::Html);
+                Ok(())
+            })),
+            VecSink::default(),
+        );
+
+        rewriter.write(b"<p>a</p>").unwrap();
+
+        assert!(matches!(
+            rewriter.end_and_reset(VecSink::default()),
+            Err(RewritingError::EndHandlersNotReusable)
+        ));
+
+        // NOTE: the document isn't finalized, so it can still be reset or ended.
+        let output = rewriter.reset(VecSink::default());
+
+        assert_eq!(output.0, b"<p>a</p>");
+
+        rewriter.write(b"<p>b</p>").unwrap();
+
+        let (output, res) = rewriter.end_into_output_sink();
+
+        res.unwrap();
+
+        assert_eq!(output.0, b"<p>b</p><!--end-->");
+        assert_eq!(ends, 1);
+    }
+
+    #[test]
+    fn reset_after_error() {
+        let mut rewriter = HtmlRewriter::new(
+            Settings::new()
+                .with_memory_settings(MemorySettings {
+                    max_allowed_memory_usage: 4096,
+                    preallocated_parsing_buffer_size: 0,
+                    ..MemorySettings::new()
+                })
+                .append_element_content_handler(text!("p", |t| {
+                    if t.as_str() == "fail" {
+                        return Err("Text handler failed.".into());
+                    }
+
+                    Ok(())
+                })),
+            VecSink::default(),
+        );
+
+        assert!(rewriter.write(b"<p>fail</p>").is_err());
+
+        rewriter.reset(VecSink::default());
+        rewriter.write(b"<p><!--").unwrap();
+
+        assert!(matches!(
+            rewriter.write(&[b'x'; 4096]),
+            Err(RewritingError::MemoryLimitExceeded(_))
+        ));
+
+        // NOTE: the memory charged before the error is released by the reset.
+        for _ in 0..10 {
+            rewriter.reset(VecSink::default());
+            rewriter.write(b"<p><!--").unwrap();
+            rewriter.write(&[b'x'; 2048]).unwrap();
+            rewriter.write(b"--></p>").unwrap();
+
+            let output = rewriter.end_and_reset(VecSink::default()).unwrap();
+
+            assert_eq!(output.0.len(), 2062);
+        }
+    }
+
+    #[test]
+    fn encoding_sniffing() {
+        fn sniff(input: &[u8], chunk_size: usize) -> (Option<DetectedEncoding>, String, Vec<u8>) {
+            let mut texts = String::new();
+            let mut output = Vec::new();
+            let detected;
+
+            {
+                let mut rewriter = HtmlRewriter::new(
+                    Settings::new()
+                        .with_encoding_sniffing(true)
+                        .with_adjust_charset_on_meta_tag(true)
+                        .append_element_content_handler(text!("p", |t| {
+                            texts.push_str(t.as_str());
+                            Ok(())
+                        })),
+                    |c: &[u8]| output.extend_from_slice(c),
+                );
+
+                for chunk in input.chunks(chunk_size) {
+                    rewriter.write(chunk).unwrap();
+                }
+
+                detected = rewriter.detected_encoding();
+                rewriter.end().unwrap();
+            }
 
-    #[test]
-    fn rewrite_arbitrary_settings() {
-        let res = rewrite_str("<span>Some text</span>", Settings::new()).unwrap();
-        assert_eq!(res, "<span>Some text</span>");
+            (detected, texts, output)
+        }
+
+        let padding = format!("<!--{}-->", "x".repeat(1024));
+
+        // BOM
+        let input = format!("\u{FEFF}<meta charset=windows-1251><p>é</p>{padding}");
+        let (detected, texts, output) = sniff(input.as_bytes(), 7);
+        let detected = detected.unwrap();
+
+        assert_eq!(detected.encoding(), UTF_8);
+        assert_eq!(detected.confidence(), EncodingConfidence::Certain);
+        assert_eq!(texts, "é");
+        assert_eq!(output, input.as_bytes());
+
+        // <meta> prescan
+        let html = format!("<meta charset=windows-1251><p>Привет</p>{padding}");
+        let (input, ..) = WINDOWS_1251.encode(&html);
+        let (detected, texts, output) = sniff(&input, 3);
+        let detected = detected.unwrap();
+
+        assert_eq!(detected.encoding(), WINDOWS_1251);
+        assert_eq!(detected.confidence(), EncodingConfidence::Tentative);
+        assert_eq!(texts, "Привет");
+        assert_eq!(output, &*input);
+
+        // Fallback
+        let (detected, texts, _) = sniff(format!("<p>abc</p>{padding}").as_bytes(), 1);
+        let detected = detected.unwrap();
+
+        assert_eq!(detected.encoding(), UTF_8);
+        assert_eq!(detected.confidence(), EncodingConfidence::Tentative);
+        assert_eq!(texts, "abc");
+
+        // UTF-16 BOM
+        let html = format!("\u{FEFF}<p>日本語</p>{padding}");
+        let input: Vec<u8> = html.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let (detected, texts, output) = sniff(&input, 5);
+        let detected = detected.unwrap();
+
+        assert_eq!(detected.encoding(), UTF_16LE);
+        assert_eq!(detected.confidence(), EncodingConfidence::Certain);
+        assert_eq!(texts, "日本語");
+        assert_eq!(output, input);
     }
 
     #[test]
-    fn rewrite_non_utf8() {
-        let text = "前<meta charset=latin1><span>中</span><!-- 後 -->";
-        let rewritten = rewrite_str(
-            text,
-            Settings::new()
-                .with_encoding(encoding_rs::BIG5.try_into().unwrap())
-                .with_adjust_charset_on_meta_tag(true),
-        )
-        .unwrap();
-        assert_eq!(rewritten, text);
+    fn encoding_sniffing_buffers_prescanned_bytes() {
+        let mut handled = false;
+        let mut output = Vec::new();
+
+        {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_encoding_sniffing(true)
+                    .append_element_content_handler(element!("p", |_| {
+                        handled = true;
+                        Ok(())
+                    })),
+                |c: &[u8]| output.extend_from_slice(c),
+            );
+
+            rewriter.write(b"<p>").unwrap();
+            assert!(rewriter.detected_encoding().is_none());
+
+            rewriter.write(&[b' '; 1024]).unwrap();
+            assert!(rewriter.detected_encoding().is_some());
+
+            rewriter.end().unwrap();
+        }
+
+        assert!(handled);
+        assert_eq!(output.len(), 1027);
     }
 
     #[test]
-    fn non_ascii_compatible_encoding() {
-        for encoding in &NON_ASCII_COMPATIBLE_ENCODINGS {
-            assert_eq!(AsciiCompatibleEncoding::new(encoding), None);
-        }
+    fn encoding_sniffing_charges_prescanned_bytes() {
+        let create_rewriter = |max_allowed_memory_usage| {
+            HtmlRewriter::new(
+                Settings::new()
+                    .with_encoding_sniffing(true)
+                    .with_memory_settings(
+                        MemorySettings::new()
+                            .with_max_allowed_memory_usage(max_allowed_memory_usage)
+                            .with_preallocated_parsing_buffer_size(0),
+                    ),
+                VecSink::default(),
+            )
+        };
+
+        let large_chunk = vec![b'x'; 1024 * 1024];
+
+        // NOTE: only the bytes up to the prescan size are buffered and charged.
+        let mut rewriter = create_rewriter(4096);
+
+        rewriter.write(b"<p>").unwrap();
+        rewriter.write(&large_chunk).unwrap();
+
+        let (output, res) = rewriter.end_into_output_sink();
+
+        res.unwrap();
+
+        assert_eq!(output.0.len(), 3 + large_chunk.len());
+
+        let mut rewriter = create_rewriter(512);
+
+        rewriter.write(b"<p>").unwrap();
+
+        assert!(matches!(
+            rewriter.write(&large_chunk),
+            Err(RewritingError::MemoryLimitExceeded(_))
+        ));
     }
 
     #[test]
@@ -707,6 +1866,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn rewrite_text_types_with_full_tree_construction() {
+        let text_types = |html: &str| {
+            let mut text_types = vec![];
+            let mut text = String::new();
+
+            rewrite_str::<LocalHandlerTypes>(
+                html,
+                RewriteStrSettings::new()
+                    .with_full_tree_construction(true)
+                    .append_document_content_handler(doc_text!(|c| {
+                        text += c.as_str();
+
+                        if c.last_in_text_node() {
+                            text_types.push((std::mem::take(&mut text), c.text_type()));
+                        }
+
+                        Ok(())
+                    })),
+            )
+            .unwrap();
+
+            text_types
+        };
+
+        // NOTE: ambiguous for the feedback simulation in the strict mode.
+        assert_eq!(
+            text_types(r#"<select><xmp><script>"use strict";</script></select>"#),
+            [(
+                r#"<script>"use strict";</script></select>"#.into(),
+                TextType::RawText
+            )]
+        );
+
+        assert_eq!(
+            text_types("<table><td><style>s</style></table><textarea>t</textarea>"),
+            [
+                ("s".into(), TextType::RawText),
+                ("t".into(), TextType::RCData)
+            ]
+        );
+
+        assert_eq!(
+            text_types("<math><mi><title>a</title></mi><title>b</title></math><title>c</title>"),
+            [
+                ("a".into(), TextType::RCData),
+                ("b".into(), TextType::Data),
+                ("c".into(), TextType::RCData)
+            ]
+        );
+
+        assert_eq!(
+            text_types("<svg><foreignObject><svg><![CDATA[a]]></svg><p><![CDATA[b]]>"),
+            [("a".into(), TextType::CDataSection)]
+        );
+    }
+
     #[test]
     fn handler_invocation_order() {
         let handlers_executed = Arc::new(Mutex::new(Vec::default()));
@@ -969,6 +2185,320 @@ mod tests {
             }
         }
 
+        #[test]
+        fn relocation_limit() {
+            const MAX: usize = 4096;
+
+            let rewrite = |html: &str| {
+                let mut output = Vec::new();
+                let mut rewriter = HtmlRewriter::new(
+                    Settings::new()
+                        .with_memory_settings(
+                            MemorySettings::new()
+                                .with_max_allowed_memory_usage(MAX)
+                                .with_preallocated_parsing_buffer_size(0),
+                        )
+                        .append_element_content_handler(element!("p", |el| {
+                            el.move_to_anchor("p");
+                            Ok(())
+                        }))
+                        .append_element_content_handler(element!("footer", |el| {
+                            el.append_anchor("p");
+                            Ok(())
+                        })),
+                    |c: &[u8]| output.extend_from_slice(c),
+                );
+
+                let res = html
+                    .as_bytes()
+                    .chunks(64)
+                    .try_for_each(|chunk| rewriter.write(chunk))
+                    .and_then(|()| rewriter.end());
+
+                res.map(|()| String::from_utf8(output).unwrap())
+            };
+
+            // NOTE: the memory is released once the moved elements are inserted at the anchor.
+            let html = format!("<p>{}</p><footer></footer>", "a".repeat(MAX / 2)).repeat(4);
+
+            assert_eq!(
+                rewrite(&html).unwrap(),
+                format!("<footer><p>{}</p></footer>", "a".repeat(MAX / 2)).repeat(4)
+            );
+
+            let html = format!("<p>{}</p>", "a".repeat(MAX / 2)).repeat(4);
+
+            match rewrite(&html).unwrap_err() {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                err => panic!("{}", err),
+            }
+        }
+
+        #[test]
+        fn ancestor_limit() {
+            const MAX: usize = 12 * 1024;
+
+            let rewrite = |html: &str| {
+                let mut rewriter = HtmlRewriter::new(
+                    Settings::new()
+                        .with_memory_settings(
+                            MemorySettings::new()
+                                .with_max_allowed_memory_usage(MAX)
+                                .with_preallocated_parsing_buffer_size(0),
+                        )
+                        .with_ancestor_tracking(true)
+                        .append_document_content_handler(doc_text!(|_| Ok(()))),
+                    |_: &[u8]| {},
+                );
+
+                rewriter.write(html.as_bytes())?;
+                rewriter.end()
+            };
+
+            // NOTE: the memory is released once the elements are closed.
+            rewrite(&"<div>a</div>".repeat(1000)).unwrap();
+            rewrite(&"<div>".repeat(60)).unwrap();
+
+            match rewrite(&"<div>a".repeat(60)).unwrap_err() {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                err => panic!("{}", err),
+            }
+        }
+
+        #[test]
+        fn retained_attribute_limit() {
+            const MAX: usize = 12 * 1024;
+
+            let rewrite = |html: &str| {
+                let mut rewriter = HtmlRewriter::new(
+                    Settings::new()
+                        .with_memory_settings(
+                            MemorySettings::new()
+                                .with_max_allowed_memory_usage(MAX)
+                                .with_preallocated_parsing_buffer_size(0),
+                        )
+                        .append_retained_attribute("lang"),
+                    |_: &[u8]| {},
+                );
+
+                rewriter.write(html.as_bytes())?;
+                rewriter.end()
+            };
+
+            let lang = "a".repeat(MAX / 16);
+
+            // NOTE: the memory is released once the elements are closed.
+            rewrite(&format!("<div lang={lang}></div>").repeat(100)).unwrap();
+            rewrite(&format!("<div title={lang}>").repeat(16)).unwrap();
+
+            match rewrite(&format!("<div lang={lang}>").repeat(16)).unwrap_err() {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                err => panic!("{}", err),
+            }
+        }
+
+        fn rewrite_without_selectors(
+            html: &str,
+            max_allowed_memory_usage: usize,
+            full_tree_construction: bool,
+        ) -> Result<(), RewritingError> {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_memory_settings(
+                        MemorySettings::new()
+                            .with_max_allowed_memory_usage(max_allowed_memory_usage)
+                            .with_preallocated_parsing_buffer_size(0),
+                    )
+                    .with_full_tree_construction(full_tree_construction),
+                |_: &[u8]| {},
+            );
+
+            rewriter.write(html.as_bytes())?;
+            rewriter.end()
+        }
+
+        #[test]
+        fn namespace_stack_limit() {
+            const MAX: usize = 1000;
+
+            let html = "<svg><foreignObject>".repeat(100);
+
+            rewrite_without_selectors(&html, MAX, false).unwrap();
+
+            let html = "<svg><foreignObject>".repeat(300);
+
+            match rewrite_without_selectors(&html, MAX, false).unwrap_err() {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                err => panic!("{}", err),
+            }
+        }
+
+        #[test]
+        fn tree_builder_stacks_limit() {
+            const MAX: usize = 12 * 1024;
+
+            let html = "<b>".repeat(100);
+
+            rewrite_without_selectors(&html, MAX, true).unwrap();
+
+            let html = "<b>".repeat(5000);
+
+            match rewrite_without_selectors(&html, MAX, true).unwrap_err() {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                err => panic!("{}", err),
+            }
+        }
+
+        fn rewrite_with_max_nesting_depth(
+            html: &str,
+            max_nesting_depth: usize,
+            graceful_bail_out: bool,
+        ) -> (Result<(), RewritingError>, String) {
+            let mut output = Vec::new();
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_memory_settings(
+                        MemorySettings::new()
+                            .with_max_nesting_depth(max_nesting_depth)
+                            .with_graceful_bail_out_on_memory_limit_exceeded(graceful_bail_out),
+                    )
+                    .append_element_content_handler(element!("*", |el| {
+                        el.set_attribute("seen", "1").unwrap();
+                        Ok(())
+                    })),
+                |c: &[u8]| output.extend_from_slice(c),
+            );
+
+            let res = match rewriter.write(html.as_bytes()) {
+                Ok(()) => rewriter.end(),
+                Err(e) => Err(e),
+            };
+
+            (res, String::from_utf8(output).unwrap())
+        }
+
+        #[test]
+        fn max_nesting_depth() {
+            // NOTE: void elements and closed siblings don't count towards the depth.
+            let html = "<div><div><br><img><div></div><div>x</div></div></div>";
+
+            let (res, output) = rewrite_with_max_nesting_depth(html, 3, false);
+
+            res.unwrap();
+            assert_eq!(
+                output,
+                concat!(
+                    r#"<div seen="1"><div seen="1"><br seen="1"><img seen="1">"#,
+                    r#"<div seen="1"></div><div seen="1">x</div></div></div>"#
+                )
+            );
+
+            let html = "<div>".repeat(4);
+
+            match rewrite_with_max_nesting_depth(&html, 3, false)
+                .0
+                .unwrap_err()
+            {
+                RewritingError::MaxNestingDepthExceeded(e) => {
+                    assert_eq!(e, MaxNestingDepthExceededError);
+                }
+                err => panic!("{}", err),
+            }
+        }
+
+        #[test]
+        fn max_nesting_depth_graceful_bail_out() {
+            let html = "<div><p>1</p><div><div><span>2</span></div></div></div>";
+
+            let (res, output) = rewrite_with_max_nesting_depth(html, 2, true);
+
+            assert!(matches!(
+                res.unwrap_err(),
+                RewritingError::MaxNestingDepthExceeded(_)
+            ));
+            assert_eq!(
+                output,
+                r#"<div seen="1"><p seen="1">1</p><div seen="1"><div><span>2</span></div></div></div>"#
+            );
+        }
+
+        fn rewrite_with_tag_limits(
+            chunks: &[&str],
+            max_attributes_per_tag: usize,
+            max_start_tag_size: usize,
+        ) -> Result<(), RewritingError> {
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_memory_settings(
+                        MemorySettings::new()
+                            .with_max_attributes_per_tag(max_attributes_per_tag)
+                            .with_max_start_tag_size(max_start_tag_size),
+                    )
+                    .append_element_content_handler(element!("div", |_| Ok(()))),
+                |_: &[u8]| {},
+            );
+
+            for chunk in chunks {
+                rewriter.write(chunk.as_bytes())?;
+            }
+
+            rewriter.end()
+        }
+
+        #[test]
+        fn max_attributes_per_tag() {
+            rewrite_with_tag_limits(&["<div a b c></div><div a b=1 c='2'>"], 3, usize::MAX)
+                .unwrap();
+
+            // NOTE: tags that aren't matched by any selector are only scanned for names, except
+            // for the first one, which is lexed along with the doctype that may precede it.
+            rewrite_with_tag_limits(&["<p></p><span a b c d></span>"], 3, usize::MAX).unwrap();
+
+            match rewrite_with_tag_limits(&["<div a b", " c d></div>"], 3, usize::MAX).unwrap_err()
+            {
+                RewritingError::TagLimitExceeded(e) => {
+                    assert_eq!(e, TagLimitExceededError::TooManyAttributes { limit: 3 });
+                }
+                err => panic!("{}", err),
+            }
+        }
+
+        #[test]
+        fn max_start_tag_size() {
+            let tag = r#"<div class="x">"#;
+
+            rewrite_with_tag_limits(&[tag, "</div>"], usize::MAX, tag.len()).unwrap();
+
+            match rewrite_with_tag_limits(&[tag], usize::MAX, tag.len() - 1).unwrap_err() {
+                RewritingError::TagLimitExceeded(e) => {
+                    assert_eq!(
+                        e,
+                        TagLimitExceededError::StartTagTooLarge {
+                            limit: tag.len() - 1
+                        }
+                    );
+                }
+                err => panic!("{}", err),
+            }
+
+            // NOTE: the tag is rejected before it gets buffered.
+            let mut rewriter = HtmlRewriter::new(
+                Settings::new()
+                    .with_memory_settings(MemorySettings::new().with_max_start_tag_size(50))
+                    .append_element_content_handler(element!("div", |_| Ok(()))),
+                |_: &[u8]| {},
+            );
+
+            let chunk = format!(r#"<div class="{}"#, "x".repeat(100));
+
+            assert!(matches!(
+                rewriter.write(chunk.as_bytes()).unwrap_err(),
+                RewritingError::TagLimitExceeded(TagLimitExceededError::StartTagTooLarge {
+                    limit: 50
+                })
+            ));
+        }
+
         #[test]
         #[should_panic(expected = "Attempt to use the HtmlRewriter after a fatal error.")]
         fn poisoning_after_fatal_error() {
@@ -1317,6 +2847,31 @@ mod tests {
             );
         }
 
+        /// The output coalesced because of the minimal output chunk size must be passed to the
+        /// sink before the error.
+        #[test]
+        fn test_bail_out_reconstruct_with_coalesced_output() {
+            let html = format!(
+                "<p>Hello</p><img src=\"data:image/png;base64,{}\"><p>World</p>",
+                "A".repeat(16384),
+            );
+
+            let reconstructed = reconstruct_response_on_oom(
+                html.as_bytes(),
+                512,
+                Settings::new()
+                    .with_min_output_chunk_size(4096)
+                    .with_memory_settings(bail_out_settings(8192))
+                    .append_element_content_handler(element!("*", |_| Ok(()))),
+            );
+
+            assert_eq!(
+                reconstructed,
+                html.as_bytes(),
+                "response with coalesced output must be reconstructable",
+            );
+        }
+
         /// Huge HTML comment (`<!-- ... -->`). The lexer buffers from `<!--` to `-->`, so a
         /// comment body larger than the limit overflows the Arena the same way a huge tag does.
         /// The comment handler puts the parser in lex mode for comments inside the outer
@@ -4,11 +4,14 @@ use crate::base::SharedEncoding;
 use crate::html::{LocalName, Namespace};
 use crate::memory::SharedMemoryLimiter;
 use crate::parser::ActionError;
-use crate::rewritable_units::{BailOut, DocumentEnd, Token, TokenCaptureFlags};
+use crate::rewritable_units::{
+    BailOut, DocumentEnd, ElementCloseReason, Relocations, Token, TokenCaptureFlags,
+};
 use crate::selectors_vm::{
     Ast, AuxStartTagInfoRequest, DenseHashSet, ElementData, SelectorMatchingVm, VmError,
 };
 use crate::transform_stream::{DispatcherError, StartTagHandlingResult, TransformController};
+use encoding_rs::Encoding;
 
 pub(crate) struct ElementDescriptor {
     pub matched_content_handlers: DenseHashSet,
@@ -36,6 +39,10 @@ pub(crate) struct HtmlRewriteController<'h, H: HandlerTypes> {
     handlers_dispatcher: ContentHandlersDispatcher<'h, H>,
     selector_matching_vm: Option<SelectorMatchingVm<ElementDescriptor>>,
     bail_out_handlers: Vec<H::BailOutHandler<'h>>,
+    /// If not set, the rewritable units are passed to the handlers without ancestors.
+    ancestor_tracking: bool,
+    /// If set, the modified start tags keep the quotes and the whitespace of the original ones.
+    preserve_attribute_formatting: bool,
 }
 
 impl<'h, H: HandlerTypes> HtmlRewriteController<'h, H> {
@@ -48,20 +55,52 @@ impl<'h, H: HandlerTypes> HtmlRewriteController<'h, H> {
         encoding: &SharedEncoding,
     ) -> Self {
         let mut selectors_ast = Ast::default();
-        let mut dispatcher = ContentHandlersDispatcher::<H>::default();
-        let has_selectors =
-            !settings.element_content_handlers.is_empty() || settings.adjust_charset_on_meta_tag;
+        let mut dispatcher =
+            ContentHandlersDispatcher::<H>::new(Relocations::new(memory_limiter.clone()));
+        // NOTE: the encoding of the transcoded input can't be changed by the content.
+        let adjust_charset_on_meta_tag =
+            settings.adjust_charset_on_meta_tag && settings.transcoded_encoding.is_none();
+        // NOTE: there is no output to rewrite the charset in the read-only mode.
+        let output_encoding = settings.output_encoding.filter(|_| !settings.read_only);
+        // NOTE: there is no output to normalize in the read-only mode either.
+        let remove_duplicate_attributes =
+            settings.remove_duplicate_attributes && !settings.read_only;
+        let ancestor_tracking =
+            settings.ancestor_tracking || !settings.retained_attributes.is_empty();
+        // NOTE: the open elements are tracked for the ancestors of the text chunks and
+        // comments passed to the document content handlers too.
+        let has_document_units_with_ancestors = ancestor_tracking
+            && settings
+                .document_content_handlers
+                .iter()
+                .any(|handlers| handlers.text.is_some() || handlers.comments.is_some());
+        let has_selectors = !settings.element_content_handlers.is_empty()
+            || !settings.retained_attributes.is_empty()
+            || has_document_units_with_ancestors
+            || adjust_charset_on_meta_tag
+            || output_encoding.is_some()
+            || remove_duplicate_attributes;
 
-        let charset_adjust_handler = if settings.adjust_charset_on_meta_tag {
+        let charset_adjust_handler = if adjust_charset_on_meta_tag {
             let encoding = SharedEncoding::clone(encoding);
             Some(super::handler_adjust_charset_on_meta_tag(encoding))
         } else {
             None
         };
 
+        // NOTE: runs after the charset adjustment, so that the latter sees the original charset.
+        let charset_rewrite_handler =
+            output_encoding.map(super::handler_rewrite_charset_on_meta_tag);
+
+        // NOTE: runs after the other handlers, so that they see the duplicates.
+        let duplicate_attributes_handler =
+            remove_duplicate_attributes.then(super::handler_remove_duplicate_attributes);
+
         let element_content_handlers = charset_adjust_handler
             .into_iter()
-            .chain(settings.element_content_handlers);
+            .chain(charset_rewrite_handler)
+            .chain(settings.element_content_handlers)
+            .chain(duplicate_attributes_handler);
 
         for (selector, handlers) in element_content_handlers {
             let match_id = dispatcher.add_selector_associated_handlers(handlers);
@@ -78,13 +117,22 @@ impl<'h, H: HandlerTypes> HtmlRewriteController<'h, H> {
                 selectors_ast,
                 settings.encoding.into(),
                 memory_limiter.clone(),
+                settings.memory_settings.max_nesting_depth,
                 settings.enable_esi_tags,
+                settings.full_tree_construction,
+                settings.retained_attributes,
             ))
         } else {
             None
         };
 
-        Self::new(dispatcher, selector_matching_vm, settings.bail_out_handlers)
+        Self::new(
+            dispatcher,
+            selector_matching_vm,
+            settings.bail_out_handlers,
+            ancestor_tracking,
+            settings.preserve_attribute_formatting,
+        )
     }
 
     #[inline]
@@ -92,11 +140,15 @@ impl<'h, H: HandlerTypes> HtmlRewriteController<'h, H> {
         handlers_dispatcher: ContentHandlersDispatcher<'h, H>,
         selector_matching_vm: Option<SelectorMatchingVm<ElementDescriptor>>,
         bail_out_handlers: Vec<H::BailOutHandler<'h>>,
+        ancestor_tracking: bool,
+        preserve_attribute_formatting: bool,
     ) -> Self {
         HtmlRewriteController {
             handlers_dispatcher,
             selector_matching_vm,
             bail_out_handlers,
+            ancestor_tracking,
+            preserve_attribute_formatting,
         }
     }
 }
@@ -114,8 +166,7 @@ impl<H: HandlerTypes> HtmlRewriteController<'_, H> {
                 };
                 let mut match_handler = |m| this.handlers_dispatcher.start_matching(&m);
 
-                aux_info_req(vm, aux_info, &mut match_handler)
-                    .map_err(RewritingError::MemoryLimitExceeded)?;
+                aux_info_req(vm, aux_info, &mut match_handler)?;
 
                 Ok(this.get_capture_flags())
             },
@@ -146,9 +197,7 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
                 match vm.exec_for_start_tag(local_name, ns, &mut match_handler) {
                     Ok(()) => Ok(self.get_capture_flags()),
                     Err(VmError::InfoRequest(req)) => Self::respond_to_aux_info_request(req),
-                    Err(VmError::MemoryLimitExceeded(e)) => Err(DispatcherError::RewritingError(
-                        RewritingError::MemoryLimitExceeded(e),
-                    )),
+                    Err(VmError::RewritingError(e)) => Err(DispatcherError::RewritingError(e)),
                 }
             }
             // NOTE: fast path - we can skip executing selector matching VM completely
@@ -159,16 +208,73 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
 
     fn handle_end_tag(&mut self, local_name: LocalName<'_>) -> TokenCaptureFlags {
         if let Some(ref mut vm) = self.selector_matching_vm {
+            // NOTE: the first popped element is the one the end tag belongs to,
+            // the rest have been left unclosed inside of it.
+            let mut close_reason = ElementCloseReason::EndTag;
+
             vm.exec_for_end_tag(local_name, |elem_desc| {
-                self.handlers_dispatcher.stop_matching(elem_desc);
+                self.handlers_dispatcher
+                    .stop_matching(elem_desc, close_reason);
+                close_reason = ElementCloseReason::ImpliedByAncestorEndTag;
             });
         }
 
         self.get_capture_flags()
     }
 
+    fn handle_open_elements_change(
+        &mut self,
+        closed_elements: &mut dyn Iterator<Item = (u64, ElementCloseReason)>,
+        inserted_element_id: Option<u64>,
+    ) {
+        if let Some(ref mut vm) = self.selector_matching_vm {
+            let mut misnested_end_tag_handlers = Vec::new();
+
+            for (id, close_reason) in closed_elements {
+                vm.exec_for_closed_element(
+                    id,
+                    |elem_desc| misnested_end_tag_handlers.extend(elem_desc.end_tag_handler_idx),
+                    |elem_desc| {
+                        self.handlers_dispatcher
+                            .stop_matching(elem_desc, close_reason);
+                    },
+                );
+            }
+
+            for idx in misnested_end_tag_handlers {
+                self.handlers_dispatcher.mark_misnested(idx);
+            }
+
+            vm.set_next_element_id(inserted_element_id);
+        }
+    }
+
     #[inline]
     fn handle_token(&mut self, token: &mut Token<'_>) -> Result<(), RewritingError> {
+        if let (Token::StartTag(t), true) = (&mut *token, self.preserve_attribute_formatting) {
+            t.preserve_formatting();
+        }
+
+        if let (Some(vm), true) = (&mut self.selector_matching_vm, self.ancestor_tracking) {
+            let count = vm.open_element_count();
+
+            match token {
+                Token::StartTag(t) => {
+                    // NOTE: the element itself is already on the stack if it can have content.
+                    let count = if self.handlers_dispatcher.next_element_can_have_content() {
+                        count.saturating_sub(1)
+                    } else {
+                        count
+                    };
+
+                    t.set_ancestor(vm.ancestor(count, t.encoding())?);
+                }
+                Token::TextChunk(t) => t.set_ancestor(vm.ancestor(count, t.encoding())?),
+                Token::Comment(t) => t.set_ancestor(vm.ancestor(count, t.encoding())?),
+                _ => (),
+            }
+        }
+
         let current_element_data = self
             .selector_matching_vm
             .as_mut()
@@ -179,6 +285,33 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
             .map_err(RewritingError::ContentHandlerError)
     }
 
+    #[inline]
+    fn has_implied_end_tags(&self) -> bool {
+        self.handlers_dispatcher.has_implied_end_tags()
+    }
+
+    fn handle_implied_end_tags(
+        &mut self,
+        source_location_byte_start: usize,
+        encoding: &'static Encoding,
+        emit_token: &mut dyn FnMut(Token<'_>) -> Result<(), RewritingError>,
+    ) -> Result<(), RewritingError> {
+        self.handlers_dispatcher.handle_implied_end_tags(
+            source_location_byte_start,
+            encoding,
+            emit_token,
+        )
+    }
+
+    fn close_open_elements(&mut self) {
+        if let Some(ref mut vm) = self.selector_matching_vm {
+            vm.exec_for_end_of_document(|elem_desc| {
+                self.handlers_dispatcher
+                    .stop_matching(elem_desc, ElementCloseReason::EndOfDocument);
+            });
+        }
+    }
+
     fn handle_end(&mut self, document_end: &mut DocumentEnd<'_>) -> Result<(), RewritingError> {
         self.handlers_dispatcher
             .handle_end(document_end)
@@ -197,4 +330,17 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
             handler(error, bail_out);
         }
     }
+
+    #[inline]
+    fn relocations(&self) -> Option<Relocations> {
+        Some(self.handlers_dispatcher.relocations().clone())
+    }
+
+    fn reset(&mut self) {
+        self.handlers_dispatcher.reset();
+
+        if let Some(ref mut vm) = self.selector_matching_vm {
+            vm.reset();
+        }
+    }
 }
@@ -1,7 +1,8 @@
 use crate::rewritable_units::{BailOut, Comment, Doctype, DocumentEnd, Element, EndTag, TextChunk};
 use crate::selectors_vm::Selector;
 // N.B. `use crate::` will break this because the constructor is not public, only the struct itself
-use super::{AsciiCompatibleEncoding, RewritingError};
+use super::{AsciiCompatibleEncoding, RewritingError, TranscodedEncoding};
+use encoding_rs::Encoding;
 use std::borrow::Cow;
 use std::error::Error;
 
@@ -528,7 +529,7 @@ macro_rules! text {
         #[inline(always)]
         fn type_hint<T>(h: T) -> T
         where
-            T: FnMut(&mut $crate::html_content::TextChunk) -> $crate::HandlerResult,
+            T: FnMut(&mut $crate::html_content::TextChunk<'_>) -> $crate::HandlerResult,
         {
             h
         }
@@ -824,11 +825,13 @@ macro_rules! bail_out {
 /// individual values via the `with_*` builder methods.
 ///
 /// [`HtmlRewriter`]: struct.HtmlRewriter.html
-// NOTE: exposed in C API as well, thus repr(C).
-#[repr(C)]
+// NOTE: the C API has its own frozen copy of this struct, so new fields don't break its ABI.
 pub struct MemorySettings {
     pub(crate) preallocated_parsing_buffer_size: usize,
     pub(crate) max_allowed_memory_usage: usize,
+    pub(crate) max_nesting_depth: usize,
+    pub(crate) max_attributes_per_tag: usize,
+    pub(crate) max_start_tag_size: usize,
     pub(crate) graceful_bail_out_on_memory_limit_exceeded: bool,
 }
 
@@ -847,6 +850,9 @@ impl MemorySettings {
         Self {
             preallocated_parsing_buffer_size: 1024,
             max_allowed_memory_usage: usize::MAX,
+            max_nesting_depth: usize::MAX,
+            max_attributes_per_tag: usize::MAX,
+            max_start_tag_size: usize::MAX,
             graceful_bail_out_on_memory_limit_exceeded: false,
         }
     }
@@ -900,6 +906,92 @@ impl MemorySettings {
         self
     }
 
+    /// Sets a limit on the number of simultaneously open elements the rewriter tracks for
+    /// selector matching.
+    ///
+    /// Rewriter's [`write`] and [`end`] methods will error with [`MaxNestingDepthExceededError`]
+    /// if a start tag would open an element deeper than this limit. Unlike [the memory limit],
+    /// the limit doesn't depend on the length of tag names, so it's a more predictable way to
+    /// reject pathologically nested documents.
+    ///
+    /// The limit only applies if the rewriter has element content handlers, otherwise open
+    /// elements aren't tracked at all. Exceeding the limit is treated as exceeding the memory
+    /// limit by [`with_graceful_bail_out_on_memory_limit_exceeded`].
+    ///
+    /// ### Default
+    ///
+    /// [`usize::MAX`].
+    ///
+    /// [`usize::MAX`]: https://doc.rust-lang.org/std/usize/constant.MAX.html
+    /// [`write`]: struct.HtmlRewriter.html#method.write
+    /// [`end`]: struct.HtmlRewriter.html#method.end
+    /// [`MaxNestingDepthExceededError`]: errors/struct.MaxNestingDepthExceededError.html
+    /// [the memory limit]: #method.with_max_allowed_memory_usage
+    /// [`with_graceful_bail_out_on_memory_limit_exceeded`]: #method.with_graceful_bail_out_on_memory_limit_exceeded
+    #[inline]
+    #[must_use]
+    pub const fn with_max_nesting_depth(mut self, depth: usize) -> Self {
+        self.max_nesting_depth = depth;
+        self
+    }
+
+    /// Sets a limit on the number of attributes in a single start tag.
+    ///
+    /// Rewriter's [`write`] and [`end`] methods will error with
+    /// [`TagLimitExceededError::TooManyAttributes`] if a start tag the rewriter parses has more
+    /// attributes than this limit. Attributes past the limit are never buffered.
+    ///
+    /// Start tags that don't need to be examined by any of the content handlers are only scanned
+    /// for their names, so their attributes aren't counted.
+    ///
+    /// Exceeding the limit is treated as exceeding the memory limit by
+    /// [`with_graceful_bail_out_on_memory_limit_exceeded`].
+    ///
+    /// ### Default
+    ///
+    /// [`usize::MAX`].
+    ///
+    /// [`usize::MAX`]: https://doc.rust-lang.org/std/usize/constant.MAX.html
+    /// [`write`]: struct.HtmlRewriter.html#method.write
+    /// [`end`]: struct.HtmlRewriter.html#method.end
+    /// [`TagLimitExceededError::TooManyAttributes`]: errors/enum.TagLimitExceededError.html#variant.TooManyAttributes
+    /// [`with_graceful_bail_out_on_memory_limit_exceeded`]: #method.with_graceful_bail_out_on_memory_limit_exceeded
+    #[inline]
+    #[must_use]
+    pub const fn with_max_attributes_per_tag(mut self, count: usize) -> Self {
+        self.max_attributes_per_tag = count;
+        self
+    }
+
+    /// Sets a limit in bytes on the length of a single start tag, from `<` to `>` inclusive.
+    ///
+    /// Rewriter's [`write`] and [`end`] methods will error with
+    /// [`TagLimitExceededError::StartTagTooLarge`] if a start tag the rewriter parses is longer
+    /// than this limit. A start tag that is split between input chunks is rejected as soon as its
+    /// part that would need to be buffered exceeds the limit.
+    ///
+    /// Start tags that don't need to be examined by any of the content handlers are only scanned
+    /// for their names, so only their names count towards the limit.
+    ///
+    /// Exceeding the limit is treated as exceeding the memory limit by
+    /// [`with_graceful_bail_out_on_memory_limit_exceeded`].
+    ///
+    /// ### Default
+    ///
+    /// [`usize::MAX`].
+    ///
+    /// [`usize::MAX`]: https://doc.rust-lang.org/std/usize/constant.MAX.html
+    /// [`write`]: struct.HtmlRewriter.html#method.write
+    /// [`end`]: struct.HtmlRewriter.html#method.end
+    /// [`TagLimitExceededError::StartTagTooLarge`]: errors/enum.TagLimitExceededError.html#variant.StartTagTooLarge
+    /// [`with_graceful_bail_out_on_memory_limit_exceeded`]: #method.with_graceful_bail_out_on_memory_limit_exceeded
+    #[inline]
+    #[must_use]
+    pub const fn with_max_start_tag_size(mut self, bytes: usize) -> Self {
+        self.max_start_tag_size = bytes;
+        self
+    }
+
     /// Controls how the rewriter recovers when [the memory limit] is exceeded.
     ///
     /// When `false` (the default), the rewriter aborts processing the response, returns
@@ -974,10 +1066,21 @@ pub struct Settings<'handlers, 'selectors, H: HandlerTypes = LocalHandlerTypes>
     )>,
     pub(crate) document_content_handlers: Vec<DocumentContentHandlers<'handlers, H>>,
     pub(crate) bail_out_handlers: Vec<H::BailOutHandler<'handlers>>,
+    pub(crate) retained_attributes: Vec<String>,
+    pub(crate) ancestor_tracking: bool,
     pub(crate) encoding: AsciiCompatibleEncoding,
+    pub(crate) transcoded_encoding: Option<&'static Encoding>,
+    pub(crate) output_encoding: Option<&'static Encoding>,
+    pub(crate) encoding_sniffing: bool,
+    pub(crate) read_only: bool,
+    pub(crate) min_output_chunk_size: usize,
+    pub(crate) max_output_chunk_size: usize,
     pub(crate) memory_settings: MemorySettings,
     pub(crate) strict: bool,
+    pub(crate) full_tree_construction: bool,
     pub(crate) enable_esi_tags: bool,
+    pub(crate) remove_duplicate_attributes: bool,
+    pub(crate) preserve_attribute_formatting: bool,
     pub(crate) adjust_charset_on_meta_tag: bool,
     pub(crate) graceful_bail_out_on_content_handler_error: bool,
 }
@@ -1016,10 +1119,21 @@ impl<'handlers, 'selectors, H: HandlerTypes> Settings<'handlers, 'selectors, H>
             element_content_handlers: vec![],
             document_content_handlers: vec![],
             bail_out_handlers: vec![],
+            retained_attributes: vec![],
+            ancestor_tracking: false,
             encoding: AsciiCompatibleEncoding(encoding_rs::UTF_8),
+            transcoded_encoding: None,
+            output_encoding: None,
+            encoding_sniffing: false,
+            read_only: false,
+            min_output_chunk_size: 0,
+            max_output_chunk_size: usize::MAX,
             memory_settings: MemorySettings::new(),
             strict: true,
+            full_tree_construction: false,
             enable_esi_tags: false,
+            remove_duplicate_attributes: false,
+            preserve_attribute_formatting: false,
             adjust_charset_on_meta_tag: false,
             graceful_bail_out_on_content_handler_error: false,
         }
@@ -1137,12 +1251,83 @@ impl<'handlers, 'selectors, H: HandlerTypes> Settings<'handlers, 'selectors, H>
         self
     }
 
+    /// Appends the name of an attribute to retain on the open elements.
+    ///
+    /// The values of the retained attributes are kept for as long as their elements are open,
+    /// so that content handlers can query them on the [ancestors] of the rewritable units, e.g.
+    /// to find the language of a text chunk. Only the listed attributes are retained, and their
+    /// values are charged to the [memory limit](MemorySettings::with_max_allowed_memory_usage).
+    ///
+    /// Retaining attributes makes the rewriter parse the attributes of every start tag, so it
+    /// can slow down the rewriting of documents that are otherwise only scanned for tag names.
+    /// It also enables the [ancestor tracking](Self::with_ancestor_tracking).
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, text, RewriteStrSettings};
+    /// use lol_html::html_content::ContentType;
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<div lang="fr"><p>Bonjour</p><p lang="en">Hello</p></div>"#,
+    ///     RewriteStrSettings::new()
+    ///         .append_retained_attribute("lang")
+    ///         .append_element_content_handler(text!("p", |t| {
+    ///             if t.as_str().is_empty() {
+    ///                 return Ok(());
+    ///             }
+    ///
+    ///             if let Some(lang) = t.ancestors().nearest_attribute("lang") {
+    ///                 t.before(&format!("[{lang}] "), ContentType::Text);
+    ///             }
+    ///
+    ///             Ok(())
+    ///         })),
+    /// )
+    /// .unwrap();
+    ///
+    /// assert_eq!(html, r#"<div lang="fr"><p>[fr] Bonjour</p><p lang="en">[en] Hello</p></div>"#);
+    /// ```
+    ///
+    /// [ancestors]: html_content/struct.Ancestors.html
+    #[inline]
+    #[must_use]
+    pub fn append_retained_attribute(mut self, name: impl Into<String>) -> Self {
+        self.retained_attributes.push(name.into());
+        self
+    }
+
+    /// If enabled the rewriter tracks the open elements, so that content handlers can query
+    /// the [ancestors] of the rewritable units. Otherwise, the ancestors are always empty.
+    ///
+    /// The tracking slows down the rewriting, as the ancestors are created for every rewritable
+    /// unit passed to the content handlers, and the start and end tags of all the elements are
+    /// processed even if there are only document content handlers. Appending a
+    /// [retained attribute](Self::append_retained_attribute) enables the tracking too.
+    ///
+    /// ### Default
+    ///
+    /// `false`.
+    ///
+    /// [ancestors]: html_content/struct.Ancestors.html
+    #[inline]
+    #[must_use]
+    pub const fn with_ancestor_tracking(mut self, track: bool) -> Self {
+        self.ancestor_tracking = track;
+        self
+    }
+
     /// Sets the [character encoding] for the input and the output of the rewriter.
     ///
     /// Can be a [label] for any of the web-compatible encodings with an exception for `UTF-16LE`,
     /// `UTF-16BE`, `ISO-2022-JP` and `replacement` (these non-ASCII-compatible encodings
-    /// are not supported).
+    /// are not supported directly, use [`with_transcoded_encoding`] for them).
+    ///
+    /// Overrides the encoding previously set with [`with_transcoded_encoding`]. The output can be
+    /// encoded differently with [`with_output_encoding`].
     ///
+    /// [`with_transcoded_encoding`]: #method.with_transcoded_encoding
+    /// [`with_output_encoding`]: #method.with_output_encoding
     /// [character encoding]: https://developer.mozilla.org/en-US/docs/Glossary/character_encoding
     /// [label]: https://encoding.spec.whatwg.org/#names-and-labels
     ///
@@ -1153,6 +1338,176 @@ impl<'handlers, 'selectors, H: HandlerTypes> Settings<'handlers, 'selectors, H>
     #[must_use]
     pub const fn with_encoding(mut self, encoding: AsciiCompatibleEncoding) -> Self {
         self.encoding = encoding;
+        self.transcoded_encoding = None;
+        self
+    }
+
+    /// Sets a [character encoding] for the input and the output of the rewriter that the
+    /// rewriter can't parse directly, e.g. `UTF-16LE`, `UTF-16BE` or `ISO-2022-JP`.
+    ///
+    /// The input is transcoded into UTF-8 before parsing, and the output is encoded back into
+    /// `encoding`. Content handlers operate on UTF-8, and a byte order mark, if present, is
+    /// preserved as a part of the content. Transcoding adds an overhead of copying the whole
+    /// input and output, so [`with_encoding`] should be preferred for ASCII-compatible
+    /// encodings.
+    ///
+    /// Since the encoding of the output is fixed, [`with_adjust_charset_on_meta_tag`] has no
+    /// effect with a transcoded encoding.
+    ///
+    /// Overrides the encoding previously set with [`with_encoding`].
+    ///
+    /// See [`TranscodedEncoding`] for the encodings that can be transcoded.
+    ///
+    /// [character encoding]: https://developer.mozilla.org/en-US/docs/Glossary/character_encoding
+    /// [`with_encoding`]: #method.with_encoding
+    /// [`with_adjust_charset_on_meta_tag`]: #method.with_adjust_charset_on_meta_tag
+    /// [`TranscodedEncoding`]: struct.TranscodedEncoding.html
+    #[inline]
+    #[must_use]
+    pub const fn with_transcoded_encoding(mut self, encoding: TranscodedEncoding) -> Self {
+        self.encoding = AsciiCompatibleEncoding(encoding_rs::UTF_8);
+        self.transcoded_encoding = Some(encoding.0);
+        self
+    }
+
+    /// Sets the [character encoding] of the rewriter's output, independently of the input's
+    /// encoding.
+    ///
+    /// Every output chunk is converted from the encoding of the input into `encoding` (e.g. to
+    /// serve a `Shift_JIS` or `windows-1251` document as `UTF-8`). Characters that can't be
+    /// represented in `encoding` are replaced with numeric character references, such as
+    /// `&#26085;`. Since the references are not decoded in `<script>` and `<style>`, the
+    /// characters are replaced with JavaScript (`\u65E5`) and CSS (`\65E5 `) escapes there.
+    /// In comments and other raw text elements, such as `<xmp>`, nothing is decoded, so the
+    /// characters are replaced with `?`. The escape follows the parser's context, so the input
+    /// is always fully lexed in this case; content inserted by handlers is escaped like the
+    /// adjacent input.
+    ///
+    /// The `UTF-16LE` and `UTF-16BE` output starts with a byte order mark, as browsers don't
+    /// recognize these encodings declared with `<meta>`.
+    ///
+    /// The `charset` attribute and the `charset` parameter of `<meta http-equiv="Content-Type">`
+    /// are rewritten to declare `encoding`, before content handlers are invoked for the `<meta>`
+    /// element. Content handlers still operate on the input's encoding.
+    ///
+    /// [character encoding]: https://developer.mozilla.org/en-US/docs/Glossary/character_encoding
+    ///
+    /// ### Default
+    ///
+    /// `None`, the output has the same encoding as the input.
+    #[inline]
+    #[must_use]
+    pub const fn with_output_encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.output_encoding = Some(encoding);
+        self
+    }
+
+    /// Enables detection of the input's encoding before rewriting starts.
+    ///
+    /// The rewriter buffers the first 1024 bytes of the input (or all of it, if it's shorter),
+    /// checks them for a [byte order mark] and then [prescans] them for a `<meta>` tag that
+    /// declares the encoding, the same way browsers do. The encoding set with [`with_encoding`]
+    /// is used as a fallback if neither is found. No content handlers are invoked and no output
+    /// is produced until the encoding is detected, unless the rewriter is [flushed] earlier.
+    ///
+    /// Input with a `UTF-16LE` or `UTF-16BE` byte order mark, or with a `<meta>` tag that declares
+    /// `ISO-2022-JP`, is [transcoded]. Unless the fallback encoding is used, the detected
+    /// encoding can't be changed later on by [`with_adjust_charset_on_meta_tag`].
+    ///
+    /// The detected encoding and its confidence are reported by
+    /// [`HtmlRewriter::detected_encoding`]. Selectors with non-ASCII characters are matched
+    /// using the fallback encoding.
+    ///
+    /// Has no effect if [`with_transcoded_encoding`] is set.
+    ///
+    /// [byte order mark]: https://encoding.spec.whatwg.org/#bom-sniff
+    /// [prescans]: https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+    /// [`with_encoding`]: #method.with_encoding
+    /// [transcoded]: #method.with_transcoded_encoding
+    /// [`with_transcoded_encoding`]: #method.with_transcoded_encoding
+    /// [`with_adjust_charset_on_meta_tag`]: #method.with_adjust_charset_on_meta_tag
+    /// [`HtmlRewriter::detected_encoding`]: struct.HtmlRewriter.html#method.detected_encoding
+    /// [flushed]: struct.HtmlRewriter.html#method.flush
+    ///
+    /// ### Default
+    ///
+    /// `false`.
+    #[inline]
+    #[must_use]
+    pub const fn with_encoding_sniffing(mut self, sniff: bool) -> Self {
+        self.encoding_sniffing = sniff;
+        self
+    }
+
+    /// Enables the read-only mode, for extracting data from the document with content handlers
+    /// without rewriting it.
+    ///
+    /// In the read-only mode the rewriter never calls the output sink and content handlers are
+    /// forbidden from modifying the content: if they do, [`RewritingError::ModifiedInReadOnlyMode`]
+    /// is returned once the modified content would have been written to the output.
+    ///
+    /// The document is still parsed and the matched content is still passed to the handlers as
+    /// in the regular mode, so the read-only mode isn't expected to be noticeably faster.
+    ///
+    /// [`with_output_encoding`] has no effect in the read-only mode.
+    ///
+    /// [`RewritingError::ModifiedInReadOnlyMode`]: errors/enum.RewritingError.html#variant.ModifiedInReadOnlyMode
+    /// [`with_output_encoding`]: #method.with_output_encoding
+    ///
+    /// ### Default
+    ///
+    /// `false`.
+    #[inline]
+    #[must_use]
+    pub const fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the minimal size in bytes of the chunks passed to the output sink.
+    ///
+    /// The rewriter produces many small output chunks, often just a few bytes long (e.g. parts
+    /// of tags and rewritten attributes). With this setting the output is coalesced until at
+    /// least `bytes` bytes are available, which reduces the number of calls to the output sink
+    /// at the cost of holding the output back for longer. Chunks smaller than `bytes` are
+    /// only passed to the sink on [`end`], on [`flush`], and before a [`RewritingError`] is
+    /// returned.
+    ///
+    /// The coalesced output takes up to `bytes` bytes of memory, which count toward the
+    /// [memory limit]. If the limit doesn't allow buffering more output, it's passed to the sink
+    /// without coalescing.
+    ///
+    /// ### Default
+    ///
+    /// `0`, the output isn't coalesced.
+    ///
+    /// [`end`]: struct.HtmlRewriter.html#method.end
+    /// [`flush`]: struct.HtmlRewriter.html#method.flush
+    /// [`RewritingError`]: errors/enum.RewritingError.html
+    /// [memory limit]: struct.MemorySettings.html#method.with_max_allowed_memory_usage
+    #[inline]
+    #[must_use]
+    pub const fn with_min_output_chunk_size(mut self, bytes: usize) -> Self {
+        self.min_output_chunk_size = bytes;
+        self
+    }
+
+    /// Sets the maximal size in bytes of the chunks passed to the output sink.
+    ///
+    /// Larger output chunks (e.g. long ranges of unmodified input) are split. Note that the
+    /// chunks are split at arbitrary byte boundaries, which can be within a character or a tag.
+    /// If the limit is less than [the minimal chunk size], the latter is lowered to the limit.
+    ///
+    /// ### Default
+    ///
+    /// [`usize::MAX`].
+    ///
+    /// [`usize::MAX`]: https://doc.rust-lang.org/std/usize/constant.MAX.html
+    /// [the minimal chunk size]: #method.with_min_output_chunk_size
+    #[inline]
+    #[must_use]
+    pub const fn with_max_output_chunk_size(mut self, bytes: usize) -> Self {
+        self.max_output_chunk_size = bytes;
         self
     }
 
@@ -1203,6 +1558,34 @@ impl<'handlers, 'selectors, H: HandlerTypes> Settings<'handlers, 'selectors, H>
         self
     }
 
+    /// If set to `true` the rewriter maintains the full state of the HTML parser's tree
+    /// construction stage (the stack of open elements, the list of active formatting elements
+    /// and the insertion mode) instead of simulating its feedback.
+    ///
+    /// This allows the rewriter to correctly parse markup that is otherwise considered
+    /// ambiguous (see [`with_strict`](#method.with_strict)), so the rewriter never bails out with
+    /// [`RewritingError::ParsingAmbiguity`](crate::errors::RewritingError::ParsingAmbiguity) and
+    /// the value of the `strict` setting has no effect.
+    ///
+    /// Selectors are matched against the elements as the tree builder sees them: an element
+    /// is closed when the parser closes it, e.g. `<p>` by the following `<div>` or `<li>` by
+    /// the next `<li>`, and the start tags the parser ignores don't create elements. The end
+    /// tag handlers of the implicitly closed elements get an implied end tag with the
+    /// [`ElementCloseReason`](crate::html_content::ElementCloseReason).
+    ///
+    /// Note that the full tree construction requires every token to be lexed, which makes the
+    /// rewriting slower, especially if there are only a few content handlers.
+    ///
+    /// ### Default
+    ///
+    /// `false`.
+    #[inline]
+    #[must_use]
+    pub const fn with_full_tree_construction(mut self, full_tree_construction: bool) -> Self {
+        self.full_tree_construction = full_tree_construction;
+        self
+    }
+
     /// If enabled the rewriter enables support for [Edge Side Includes] tags, treating them as
     /// [void elements] and allowing them to be replaced with desired content.
     ///
@@ -1219,6 +1602,51 @@ impl<'handlers, 'selectors, H: HandlerTypes> Settings<'handlers, 'selectors, H>
         self
     }
 
+    /// If enabled the rewriter removes the attributes that have the same name as one of the
+    /// preceding attributes of the tag, e.g. `<a href=a href=b>` is rewritten as `<a href=a>`.
+    ///
+    /// Browsers ignore such duplicates, but other consumers of the output may not, which can be
+    /// used to confuse the filters that rely on them. Element content handlers see the
+    /// duplicates (see [`Element::duplicate_attributes`]) before they are removed.
+    ///
+    /// The setting has no effect in the [read-only] mode.
+    ///
+    /// ### Default
+    ///
+    /// `false`.
+    ///
+    /// [`Element::duplicate_attributes`]: html_content/struct.Element.html#method.duplicate_attributes
+    /// [read-only]: #method.with_read_only
+    #[inline]
+    #[must_use]
+    pub const fn with_remove_duplicate_attributes(mut self, remove: bool) -> Self {
+        self.remove_duplicate_attributes = remove;
+        self
+    }
+
+    /// If enabled the modified start tags keep the formatting of the original tags: modified
+    /// attributes keep their quotes (or lack of them), and the whitespace between the
+    /// attributes and before the closing `>` is kept. E.g. setting `href` of
+    /// `<a\n  href=/ >` outputs `<a\n  href=https://example.com >` rather than
+    /// `<a href="https://example.com">`.
+    ///
+    /// This keeps the changes to the markup minimal, e.g. for diffing or byte-exact caching.
+    /// An unquoted value that can't be written without quotes is enclosed in double quotes, and
+    /// added attributes are always enclosed in double quotes. The quotes of an attribute can be
+    /// chosen explicitly with [`Element::set_attribute_quote`] regardless of this setting.
+    ///
+    /// ### Default
+    ///
+    /// `false`.
+    ///
+    /// [`Element::set_attribute_quote`]: html_content/struct.Element.html#method.set_attribute_quote
+    #[inline]
+    #[must_use]
+    pub const fn with_preserve_attribute_formatting(mut self, preserve: bool) -> Self {
+        self.preserve_attribute_formatting = preserve;
+        self
+    }
+
     /// If enabled the rewriter will dynamically change the charset when it encounters a `meta` tag
     /// that specifies the charset.
     ///
@@ -1311,8 +1739,13 @@ impl<'h, 's, H: HandlerTypes> From<RewriteStrSettings<'h, 's, H>> for Settings<'
             element_content_handlers: settings.element_content_handlers,
             document_content_handlers: settings.document_content_handlers,
             bail_out_handlers: settings.bail_out_handlers,
+            retained_attributes: settings.retained_attributes,
+            ancestor_tracking: settings.ancestor_tracking,
             strict: settings.strict,
+            full_tree_construction: settings.full_tree_construction,
             enable_esi_tags: settings.enable_esi_tags,
+            remove_duplicate_attributes: settings.remove_duplicate_attributes,
+            preserve_attribute_formatting: settings.preserve_attribute_formatting,
             ..Settings::new_for_handler_types()
         }
     }
@@ -1348,8 +1781,13 @@ pub struct RewriteStrSettings<'handlers, 'selectors, H: HandlerTypes = LocalHand
     )>,
     pub(crate) document_content_handlers: Vec<DocumentContentHandlers<'handlers, H>>,
     pub(crate) bail_out_handlers: Vec<H::BailOutHandler<'handlers>>,
+    pub(crate) retained_attributes: Vec<String>,
+    pub(crate) ancestor_tracking: bool,
     pub(crate) strict: bool,
+    pub(crate) full_tree_construction: bool,
     pub(crate) enable_esi_tags: bool,
+    pub(crate) remove_duplicate_attributes: bool,
+    pub(crate) preserve_attribute_formatting: bool,
 }
 
 impl Default for RewriteStrSettings<'_, '_, LocalHandlerTypes> {
@@ -1386,8 +1824,13 @@ impl<'handlers, 'selectors, H: HandlerTypes> RewriteStrSettings<'handlers, 'sele
             element_content_handlers: vec![],
             document_content_handlers: vec![],
             bail_out_handlers: vec![],
+            retained_attributes: vec![],
+            ancestor_tracking: false,
             strict: true,
+            full_tree_construction: false,
             enable_esi_tags: true,
+            remove_duplicate_attributes: false,
+            preserve_attribute_formatting: false,
         }
     }
 
@@ -1465,6 +1908,26 @@ impl<'handlers, 'selectors, H: HandlerTypes> RewriteStrSettings<'handlers, 'sele
         self
     }
 
+    /// Appends the name of an attribute to retain on the open elements.
+    ///
+    /// See [`Settings::append_retained_attribute()`] for full semantics.
+    #[inline]
+    #[must_use]
+    pub fn append_retained_attribute(mut self, name: impl Into<String>) -> Self {
+        self.retained_attributes.push(name.into());
+        self
+    }
+
+    /// Enables the tracking of the open elements for the ancestors of the rewritable units.
+    ///
+    /// See [`Settings::with_ancestor_tracking()`] for full semantics.
+    #[inline]
+    #[must_use]
+    pub const fn with_ancestor_tracking(mut self, track: bool) -> Self {
+        self.ancestor_tracking = track;
+        self
+    }
+
     /// If set to `true` the rewriter bails out if it encounters markup that drives the HTML parser
     /// into ambiguous state.
     ///
@@ -1504,6 +1967,16 @@ impl<'handlers, 'selectors, H: HandlerTypes> RewriteStrSettings<'handlers, 'sele
         self
     }
 
+    /// Enables the full tree construction stage of the HTML parser.
+    ///
+    /// See [`Settings::with_full_tree_construction()`] for full semantics.
+    #[inline]
+    #[must_use]
+    pub const fn with_full_tree_construction(mut self, full_tree_construction: bool) -> Self {
+        self.full_tree_construction = full_tree_construction;
+        self
+    }
+
     /// If enabled the rewriter enables support for [Edge Side Includes] tags, treating them as
     /// [void elements] and allowing them to be replaced with desired content.
     ///
@@ -1519,4 +1992,24 @@ impl<'handlers, 'selectors, H: HandlerTypes> RewriteStrSettings<'handlers, 'sele
         self.enable_esi_tags = enable;
         self
     }
+
+    /// Enables the removal of the duplicate attributes of the tags.
+    ///
+    /// See [`Settings::with_remove_duplicate_attributes()`] for full semantics.
+    #[inline]
+    #[must_use]
+    pub const fn with_remove_duplicate_attributes(mut self, remove: bool) -> Self {
+        self.remove_duplicate_attributes = remove;
+        self
+    }
+
+    /// Enables keeping the quotes and the whitespace of the modified start tags.
+    ///
+    /// See [`Settings::with_preserve_attribute_formatting()`] for full semantics.
+    #[inline]
+    #[must_use]
+    pub const fn with_preserve_attribute_formatting(mut self, preserve: bool) -> Self {
+        self.preserve_attribute_formatting = preserve;
+        self
+    }
 }
@@ -0,0 +1,308 @@
+use super::{HandlerTypes, HtmlRewriter, LocalHandlerTypes, RewritingError, Settings};
+use crate::OutputSink;
+use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Collects the output until the stream yields it.
+struct StreamSink(BytesMut);
+
+impl OutputSink for StreamSink {
+    #[inline]
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0.extend_from_slice(chunk);
+    }
+}
+
+/// A [`Stream`] adapter for the [`HtmlRewriter`] that rewrites a stream of input chunks into
+/// a stream of output chunks.
+///
+/// The input stream is polled only when the rewritten output of the previous input chunk has
+/// been consumed, so the adapter doesn't read ahead of its consumer. Input chunks that don't
+/// produce any output (e.g. a part of a tag) are not yielded as empty output chunks.
+///
+/// If the rewriter fails, the output produced before the error (e.g. on a [graceful bail-out])
+/// is yielded first, followed by the error. The stream ends after the error.
+///
+/// The input stream must be [`Unpin`]; use [`Box::pin`] for streams that are not.
+///
+/// *Requires the `stream` feature.*
+///
+/// # Example
+/// ```
+/// use bytes::Bytes;
+/// use futures_core::Stream;
+/// use lol_html::{element, RewritingStream, Settings};
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll, Waker};
+///
+/// struct Chunks(Vec<Bytes>);
+///
+/// impl Stream for Chunks {
+///     type Item = Bytes;
+///
+///     fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Bytes>> {
+///         Poll::Ready((!self.0.is_empty()).then(|| self.0.remove(0)))
+///     }
+/// }
+///
+/// let input = Chunks(vec![Bytes::from("<a href='/'>"), Bytes::from("Home</a>")]);
+///
+/// let mut stream = RewritingStream::new(
+///     Settings::new().append_element_content_handler(element!("a[href]", |el| {
+///         el.set_attribute("rel", "nofollow")?;
+///         Ok(())
+///     })),
+///     input,
+/// );
+///
+/// let mut cx = Context::from_waker(Waker::noop());
+/// let mut output = Vec::new();
+///
+/// while let Poll::Ready(Some(chunk)) = Pin::new(&mut stream).poll_next(&mut cx) {
+///     output.extend_from_slice(&chunk.unwrap());
+/// }
+///
+/// assert_eq!(output, br#"<a href='/' rel="nofollow">Home</a>"#);
+/// ```
+///
+/// [`HtmlRewriter`]: struct.HtmlRewriter.html
+/// [graceful bail-out]: struct.MemorySettings.html#method.with_graceful_bail_out_on_memory_limit_exceeded
+pub struct RewritingStream<'h, S, H: HandlerTypes = LocalHandlerTypes> {
+    input: S,
+    /// `None` once the rewriting has finished.
+    rewriter: Option<HtmlRewriter<'h, StreamSink, H>>,
+    error: Option<RewritingError>,
+}
+
+impl<'h, S, H: HandlerTypes> RewritingStream<'h, S, H> {
+    /// Constructs a new stream with the provided `settings` that rewrites the chunks of the
+    /// `input` stream.
+    #[inline]
+    pub fn new<'s>(settings: Settings<'h, 's, H>, input: S) -> Self {
+        Self {
+            input,
+            rewriter: Some(HtmlRewriter::new(settings, StreamSink(BytesMut::new()))),
+            error: None,
+        }
+    }
+
+    /// Returns the input stream.
+    ///
+    /// The output that hasn't been yielded yet is lost.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.input
+    }
+}
+
+impl<S, H> Stream for RewritingStream<'_, S, H>
+where
+    Self: Unpin,
+    S: Stream + Unpin,
+    S::Item: AsRef<[u8]>,
+    H: HandlerTypes,
+{
+    type Item = Result<Bytes, RewritingError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            if let Some(err) = this.error.take() {
+                return Poll::Ready(Some(Err(err)));
+            }
+
+            let Some(rewriter) = &mut this.rewriter else {
+                return Poll::Ready(None);
+            };
+
+            let (output, res) = match Pin::new(&mut this.input).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(chunk)) => {
+                    let res = rewriter.write(chunk.as_ref());
+
+                    (rewriter.output_sink_mut().0.split(), res)
+                }
+                Poll::Ready(None) => match this.rewriter.take().map(|r| r.end_into_output_sink()) {
+                    Some((sink, res)) => (sink.0, res),
+                    None => (BytesMut::new(), Ok(())),
+                },
+            };
+
+            if let Err(err) = res {
+                this.rewriter = None;
+                this.error = Some(err);
+            }
+
+            if !output.is_empty() {
+                return Poll::Ready(Some(Ok(output.freeze())));
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.rewriter.is_none() {
+            (
+                usize::from(self.error.is_some()),
+                Some(usize::from(self.error.is_some())),
+            )
+        } else {
+            (0, None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_content::ContentType;
+    use crate::{element, end, send, text};
+    use std::collections::VecDeque;
+    use std::task::Waker;
+
+    /// Yields `Pending` before each chunk, counting the polls.
+    struct TestInput {
+        chunks: VecDeque<&'static [u8]>,
+        pending: bool,
+        polls: usize,
+    }
+
+    impl TestInput {
+        fn new(chunks: &[&'static [u8]]) -> Self {
+            Self {
+                chunks: chunks.iter().copied().collect(),
+                pending: false,
+                polls: 0,
+            }
+        }
+    }
+
+    impl Stream for TestInput {
+        type Item = &'static [u8];
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.polls += 1;
+            self.pending = !self.pending;
+
+            if self.pending {
+                Poll::Pending
+            } else {
+                Poll::Ready(self.chunks.pop_front())
+            }
+        }
+    }
+
+    fn settings() -> send::Settings<'static, 'static> {
+        send::Settings::new_send()
+            .append_element_content_handler(element!("p", |el| {
+                el.prepend("<b>", ContentType::Html);
+                el.append("</b>", ContentType::Html);
+                Ok(())
+            }))
+            .append_element_content_handler(text!("p", |t| {
+                if t.as_str() == "fail" {
+                    return Err("Text handler failed.".into());
+                }
+
+                Ok(())
+            }))
+            .append_document_content_handler(end!(|end| {
+                end.append("<!--end-->", ContentType::Html);
+                Ok(())
+            }))
+    }
+
+    fn collect<S: Stream + Unpin>(stream: &mut S) -> Vec<S::Item> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut items = Vec::new();
+
+        loop {
+            match Pin::new(&mut *stream).poll_next(&mut cx) {
+                Poll::Ready(Some(item)) => items.push(item),
+                Poll::Ready(None) => return items,
+                Poll::Pending => (),
+            }
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let mut stream: send::RewritingStream<'_, _> =
+            RewritingStream::new(settings(), TestInput::new(&[b"<p>He", b"llo</p>", b"<p"]));
+
+        let chunks = collect(&mut stream)
+            .into_iter()
+            .map(|c| String::from_utf8(c.unwrap().to_vec()).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(chunks, ["<p><b>He", "llo</b></p>", "<p<!--end-->"]);
+        assert_eq!(stream.size_hint(), (0, Some(0)));
+    }
+
+    #[test]
+    fn stream_backpressure() {
+        let mut stream = RewritingStream::new(settings(), TestInput::new(&[b"<p", b">", b"a"]));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+
+        // NOTE: the first chunk doesn't produce any output, so the input is polled again.
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+
+        let Poll::Ready(Some(Ok(chunk))) = Pin::new(&mut stream).poll_next(&mut cx) else {
+            panic!("Expected an output chunk.");
+        };
+
+        assert_eq!(chunk, "<p><b>");
+
+        let input = stream.into_inner();
+
+        assert_eq!(input.polls, 4);
+        assert_eq!(input.chunks, [b"a"]);
+    }
+
+    #[test]
+    fn stream_errors() {
+        let mut stream = RewritingStream::new(
+            settings(),
+            TestInput::new(&[b"<div></div>", b"<p>fail</p>", b"<p>"]),
+        );
+
+        let items = collect(&mut stream);
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap(), "<div></div>");
+        assert_eq!(items[1].as_ref().unwrap(), "<p><b>");
+        assert_eq!(
+            items[2].as_ref().unwrap_err().to_string(),
+            "Text handler failed."
+        );
+
+        // NOTE: the unfinished tag is flushed as-is on the bail-out at the end of the input.
+        let settings = send::Settings::new_send()
+            .with_graceful_bail_out_on_content_handler_error(true)
+            .append_document_content_handler(end!(|_| Err("End handler failed.".into())));
+
+        let mut stream = RewritingStream::new(settings, TestInput::new(&[b"<div></div><p"]));
+
+        let items = collect(&mut stream);
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap(), "<div></div>");
+        assert_eq!(items[1].as_ref().unwrap(), "<p");
+        assert_eq!(
+            items[2].as_ref().unwrap_err().to_string(),
+            "End handler failed."
+        );
+    }
+
+    #[test]
+    fn stream_is_send() {
+        static_assertions::assert_impl_all!(
+            send::RewritingStream<'static, TestInput>: Send, Stream
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use super::compiler::AttrExprOperands;
 use crate::base::Bytes;
-use crate::html::Namespace;
+use crate::html::{Namespace, is_attr_whitespace};
 use crate::parser::{AttributeBuffer, AttributeOutline};
 use memchr::{memchr, memchr2};
 use selectors::attr::{CaseSensitivity, ParsedCaseSensitivity};
@@ -9,11 +9,6 @@ use std::cell::OnceCell;
 const ID_ATTR: &[u8] = b"id";
 const CLASS_ATTR: &[u8] = b"class";
 
-#[inline]
-const fn is_attr_whitespace(b: u8) -> bool {
-    b == b' ' || b == b'\n' || b == b'\r' || b == b'\t' || b == b'\x0c'
-}
-
 #[inline]
 fn to_unconditional(
     parsed: ParsedCaseSensitivity,
@@ -77,7 +72,8 @@ impl<'i> AttributeMatcher<'i> {
     }
 
     #[inline]
-    fn get_value(&self, lowercased_name: &[u8]) -> Option<&'i [u8]> {
+    #[must_use]
+    pub fn get_value(&self, lowercased_name: &[u8]) -> Option<&'i [u8]> {
         self.find(lowercased_name)
             .map(|a| self.input.slice(a.value).as_slice())
     }
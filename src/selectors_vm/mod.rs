@@ -11,10 +11,14 @@ mod stack;
 
 use self::program::AddressRange;
 use self::stack::StackDirective;
+use crate::base::BytesCow;
 use crate::html::{LocalName, Namespace};
-use crate::memory::{MemoryLimitExceededError, SharedMemoryLimiter};
+use crate::memory::SharedMemoryLimiter;
+use crate::rewritable_units::Ancestor;
+use crate::rewriter::RewritingError;
 use crate::transform_stream::AuxStartTagInfo;
 use encoding_rs::Encoding;
+use std::sync::Arc;
 
 pub use self::ast::*;
 pub(crate) use self::attribute_matcher::AttributeMatcher;
@@ -30,13 +34,13 @@ pub(crate) type AuxStartTagInfoRequest<E> = Box<
             &mut SelectorMatchingVm<E>,
             AuxStartTagInfo<'_>,
             &mut dyn FnMut(MatchInfo),
-        ) -> Result<(), MemoryLimitExceededError>
+        ) -> Result<(), RewritingError>
         + Send,
 >;
 
 pub(crate) enum VmError<E: ElementData> {
     InfoRequest(AuxStartTagInfoRequest<E>),
-    MemoryLimitExceeded(MemoryLimitExceededError),
+    RewritingError(RewritingError),
 }
 
 type RecoveryPointHandler<T, E> =
@@ -68,7 +72,6 @@ pub(crate) struct SelectorState<'i> {
 struct ExecutionCtx<'i, E: ElementData> {
     stack_item: StackItem<'i, E>,
     with_content: bool,
-    ns: Namespace,
     enable_esi_tags: bool,
 }
 
@@ -76,9 +79,8 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
     #[inline]
     pub fn new(local_name: LocalName<'i>, ns: Namespace, enable_esi_tags: bool) -> Self {
         ExecutionCtx {
-            stack_item: StackItem::new(local_name),
+            stack_item: StackItem::new(local_name, ns),
             with_content: true,
-            ns,
             enable_esi_tags,
         }
     }
@@ -118,7 +120,6 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
         ExecutionCtx {
             stack_item: self.stack_item.into_owned(),
             with_content: self.with_content,
-            ns: self.ns,
             enable_esi_tags: self.enable_esi_tags,
         }
     }
@@ -134,6 +135,14 @@ pub(crate) struct SelectorMatchingVm<E: ElementData> {
     program: Program,
     stack: Stack<E>,
     enable_esi_tags: bool,
+    /// If set, the tree builder closes the elements instead of their end tags.
+    full_tree_construction: bool,
+    /// Tree builder id of the element created for the next start tag.
+    next_element_id: Option<u64>,
+    /// Lowercased names of the attributes retained on the open elements.
+    retained_attribute_names: Arc<[String]>,
+    /// Encoded retained attribute names, paired with their indices in `retained_attribute_names`.
+    encoded_retained_attribute_names: Vec<(usize, BytesCow<'static>)>,
 }
 
 impl<E> SelectorMatchingVm<E>
@@ -146,17 +155,52 @@ where
         ast: Ast,
         encoding: &'static Encoding,
         memory_limiter: SharedMemoryLimiter,
+        max_nesting_depth: usize,
         enable_esi_tags: bool,
+        full_tree_construction: bool,
+        retained_attribute_names: Vec<String>,
     ) -> Self {
         let program = Compiler::new(encoding).compile(ast);
 
+        let retained_attribute_names: Arc<[String]> = retained_attribute_names
+            .into_iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+
+        // NOTE: attributes which names can't be represented in the document's encoding can't be
+        // present in the document either.
+        let encoded_retained_attribute_names = retained_attribute_names
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                BytesCow::owned_from_str_without_replacements(name.as_str(), encoding)
+                    .ok()
+                    .map(|name| (i, name))
+            })
+            .collect();
+
         Self {
-            stack: Stack::new(memory_limiter, program.enable_nth_of_type),
+            stack: Stack::new(
+                memory_limiter,
+                max_nesting_depth,
+                program.enable_nth_of_type,
+            ),
             program,
             enable_esi_tags,
+            full_tree_construction,
+            next_element_id: None,
+            retained_attribute_names,
+            encoded_retained_attribute_names,
         }
     }
 
+    /// Clears the stack of open elements for a new document. The compiled program is kept.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.stack.reset();
+        self.next_element_id = None;
+    }
+
     pub fn exec_for_start_tag(
         &mut self,
         local_name: LocalName<'_>,
@@ -168,8 +212,22 @@ where
         self.stack.add_child(&local_name);
 
         let mut ctx = ExecutionCtx::new(local_name, ns, self.enable_esi_tags);
+        let mut stack_directive =
+            Stack::get_stack_directive(&ctx.stack_item, ctx.stack_item.ns, ctx.enable_esi_tags);
+
+        if self.full_tree_construction {
+            ctx.stack_item.tree_builder_id = self.next_element_id.take();
+
+            // NOTE: the tree builder has already taken the self-closing flag into account,
+            // and the start tags it ignores don't create elements.
+            stack_directive = match (ctx.stack_item.tree_builder_id, stack_directive) {
+                (Some(_), PushIfNotSelfClosing) => Push,
+                (Some(_), directive) => directive,
+                (None, _) => PopImmediately,
+            };
+        }
 
-        match Stack::get_stack_directive(&ctx.stack_item, ctx.ns, ctx.enable_esi_tags) {
+        match stack_directive {
             PopImmediately => {
                 ctx.with_content = false;
                 self.exec_without_attrs(ctx, match_handler)
@@ -190,8 +248,38 @@ where
         local_name: LocalName<'_>,
         unmatched_element_data_handler: impl FnMut(E),
     ) {
-        self.stack
-            .pop_up_to(local_name, unmatched_element_data_handler);
+        // NOTE: the tree builder closes the elements in the full tree construction mode.
+        if !self.full_tree_construction {
+            self.stack
+                .pop_up_to(local_name, unmatched_element_data_handler);
+        }
+    }
+
+    /// Closes the element with the given tree builder id, in the full tree construction mode.
+    #[inline]
+    pub fn exec_for_closed_element(
+        &mut self,
+        tree_builder_id: u64,
+        misnested_element_data_handler: impl FnMut(&E),
+        closed_element_data_handler: impl FnMut(E),
+    ) {
+        self.stack.close(
+            tree_builder_id,
+            misnested_element_data_handler,
+            closed_element_data_handler,
+        );
+    }
+
+    /// Sets the tree builder id of the element created for the next start tag, in the full
+    /// tree construction mode. `None` if the tree builder doesn't leave the element open.
+    #[inline]
+    pub fn set_next_element_id(&mut self, tree_builder_id: Option<u64>) {
+        self.next_element_id = tree_builder_id;
+    }
+
+    #[inline]
+    pub fn exec_for_end_of_document(&mut self, unclosed_element_data_handler: impl FnMut(E)) {
+        self.stack.pop_all(unclosed_element_data_handler);
     }
 
     #[inline]
@@ -199,13 +287,55 @@ where
         self.stack.current_element_data_mut()
     }
 
+    #[inline]
+    #[must_use]
+    pub fn open_element_count(&self) -> usize {
+        self.stack.items().len()
+    }
+
+    /// Returns the innermost of the first `count` open elements as an ancestor.
+    #[inline]
+    pub fn ancestor(
+        &mut self,
+        count: usize,
+        encoding: &'static Encoding,
+    ) -> Result<Option<Arc<Ancestor>>, RewritingError> {
+        self.stack
+            .ancestor(count, encoding, &self.retained_attribute_names)
+            .map_err(RewritingError::MemoryLimitExceeded)
+    }
+
+    #[inline]
+    fn retain_attributes(
+        &self,
+        ctx: &mut ExecutionCtx<'_, E>,
+        attr_matcher: &AttributeMatcher<'_>,
+    ) -> Result<(), RewritingError> {
+        for (i, name) in &self.encoded_retained_attribute_names {
+            if let Some(value) = attr_matcher.get_value(name) {
+                let byte_count = size_of::<(usize, BytesCow<'static>)>() + value.len();
+
+                self.stack
+                    .charge_item_memory(&mut ctx.stack_item, byte_count)
+                    .map_err(RewritingError::MemoryLimitExceeded)?;
+
+                ctx.stack_item
+                    .retained_attributes
+                    .push((*i, BytesCow::from(value).into_owned()));
+            }
+        }
+
+        Ok(())
+    }
+
     fn exec_after_immediate_aux_info_request(
         &mut self,
         mut ctx: ExecutionCtx<'static, E>,
         aux_info: AuxStartTagInfo<'_>,
         match_handler: &mut dyn FnMut(MatchInfo),
-    ) -> Result<(), MemoryLimitExceededError> {
-        let attr_matcher = AttributeMatcher::new(*aux_info.input, aux_info.attr_buffer, ctx.ns);
+    ) -> Result<(), RewritingError> {
+        let attr_matcher =
+            AttributeMatcher::new(*aux_info.input, aux_info.attr_buffer, ctx.stack_item.ns);
 
         ctx.with_content = !aux_info.self_closing;
 
@@ -222,6 +352,7 @@ where
         ctx.handle_matched_ids(match_handler);
 
         if ctx.with_content {
+            self.retain_attributes(&mut ctx, &attr_matcher)?;
             self.stack.push_item(ctx.stack_item)?;
         }
 
@@ -236,7 +367,8 @@ where
         let mut ctx = ctx.into_owned();
 
         aux_info_request!(move |this, aux_info, match_handler| {
-            let attr_matcher = AttributeMatcher::new(*aux_info.input, aux_info.attr_buffer, ctx.ns);
+            let attr_matcher =
+                AttributeMatcher::new(*aux_info.input, aux_info.attr_buffer, ctx.stack_item.ns);
 
             this.complete_instr_execution_with_attrs(bailout.at_addr, &attr_matcher, &mut ctx);
 
@@ -245,6 +377,7 @@ where
             ctx.handle_matched_ids(match_handler);
 
             if ctx.with_content {
+                this.retain_attributes(&mut ctx, &attr_matcher)?;
                 this.stack.push_item(ctx.stack_item)?;
             }
 
@@ -310,12 +443,27 @@ where
             return Self::bailout(ctx, b, Self::recover_after_bailout_in_hereditary_jumps);
         }
 
+        if ctx.with_content && !self.encoded_retained_attribute_names.is_empty() {
+            let mut ctx = ctx.into_owned();
+
+            return aux_info_request!(move |this, aux_info, match_handler| {
+                let attr_matcher =
+                    AttributeMatcher::new(*aux_info.input, aux_info.attr_buffer, ctx.stack_item.ns);
+
+                ctx.handle_matched_ids(match_handler);
+                this.retain_attributes(&mut ctx, &attr_matcher)?;
+                this.stack.push_item(ctx.stack_item)?;
+
+                Ok(())
+            });
+        }
+
         ctx.handle_matched_ids(match_handler);
 
         if ctx.with_content {
             self.stack
                 .push_item(ctx.stack_item.into_owned())
-                .map_err(VmError::MemoryLimitExceeded)
+                .map_err(VmError::RewritingError)
         } else {
             Ok(())
         }
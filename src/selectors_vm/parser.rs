@@ -278,6 +278,9 @@ impl<'i> Parser<'i> for SelectorsParser {
 /// `E F`                          | an `F` element descendant of an `E` element                                                                                 |
 /// `E > F`                        | an `F` element child of an `E` element                                                                                      |
 ///
+/// Elements whose end tags are omitted are closed where the HTML parser would close them, e.g.
+/// in `<p>a<div>b</div>` the `<div>` is a sibling of the `<p>`, so `p div` doesn't match it.
+///
 /// [`str`]: https://doc.rust-lang.org/std/primitive.str.html
 /// [`parse`]: https://doc.rust-lang.org/std/primitive.str.html#method.parse
 /// [element content handlers]: struct.Settings.html#structfield.element_content_handlers
@@ -2,12 +2,18 @@ use super::SelectorState;
 use super::ast::NthChild;
 use super::program::AddressRange;
 use crate::html::{LocalName, Namespace, Tag};
-use crate::memory::{LimitedVec, MemoryLimitExceededError, SharedMemoryLimiter};
+use crate::memory::{
+    LimitedVec, MaxNestingDepthExceededError, MemoryLimitExceededError, SharedMemoryLimiter,
+};
+use crate::rewritable_units::{Ancestor, RetainedAttributeValues};
+use crate::rewriter::RewritingError;
 use crate::selectors_vm::DenseHashSet;
+use encoding_rs::Encoding;
 // use hashbrown for raw entry, switch back to std once it stablizes there
 use hashbrown::HashMap;
 use hashbrown::hash_map::RawEntryMut;
 use std::hash::BuildHasher;
+use std::sync::Arc;
 
 #[inline]
 fn is_void_element(local_name: &LocalName<'_>, enable_esi_tags: bool) -> bool {
@@ -108,6 +114,11 @@ impl TypedChildCounterMap {
         Self(HashMap::new())
     }
 
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
     fn hash_name(&self, name: &LocalName<'_>) -> u64 {
         self.0.hasher().hash_one(name)
     }
@@ -173,24 +184,40 @@ impl TypedChildCounterMap {
 
 pub(crate) struct StackItem<'i, E: ElementData> {
     pub local_name: LocalName<'i>,
+    pub ns: Namespace,
     pub element_data: E,
     pub jumps: Vec<AddressRange>,
     pub hereditary_jumps: Vec<AddressRange>,
     pub child_counter: ChildCounter,
     pub stack_directive: StackDirective,
+    /// Id of the element in the tree builder, in the full tree construction mode. Reset once
+    /// the tree builder closes the element.
+    pub tree_builder_id: Option<u64>,
+    /// Created once a rewritable unit inside the item is handled, and shared by the units
+    /// that follow it.
+    pub ancestor: Option<Arc<Ancestor>>,
+    pub retained_attributes: RetainedAttributeValues,
+    /// Memory charged to the limiter for the item's retained attributes and ancestor,
+    /// released once it's popped.
+    pub charged_memory: usize,
 }
 
 impl<'i, E: ElementData> StackItem<'i, E> {
     #[inline]
     #[must_use]
-    pub fn new(local_name: LocalName<'i>) -> Self {
+    pub fn new(local_name: LocalName<'i>, ns: Namespace) -> Self {
         StackItem {
             local_name,
+            ns,
             element_data: E::new(),
             jumps: Vec::default(),
             hereditary_jumps: Vec::default(),
             child_counter: Default::default(),
             stack_directive: StackDirective::Push,
+            tree_builder_id: None,
+            ancestor: None,
+            retained_attributes: Vec::new(),
+            charged_memory: 0,
         }
     }
 
@@ -198,11 +225,16 @@ impl<'i, E: ElementData> StackItem<'i, E> {
     pub fn into_owned(self) -> StackItem<'static, E> {
         StackItem {
             local_name: self.local_name.into_owned(),
+            ns: self.ns,
             element_data: self.element_data,
             jumps: self.jumps,
             hereditary_jumps: self.hereditary_jumps,
             child_counter: self.child_counter,
             stack_directive: self.stack_directive,
+            tree_builder_id: self.tree_builder_id,
+            ancestor: self.ancestor,
+            retained_attributes: self.retained_attributes,
+            charged_memory: self.charged_memory,
         }
     }
 }
@@ -213,25 +245,53 @@ pub(crate) struct Stack<E: ElementData> {
     /// A typed counter for all elements on all frames. This is optional to indicate if types are actually being counted.
     typed_child_counters: Option<TypedChildCounterMap>,
     items: LimitedVec<StackItem<'static, E>>,
+    memory_limiter: SharedMemoryLimiter,
+    max_depth: usize,
     /// Per-name open-item counts so `pop_up_to` can reject a stray end tag in O(1).
     open_name_counts: HashMap<LocalName<'static>, usize>,
     /// Distinct hereditary-jump ranges from open items, with the shallowest depth that introduced each.
     active_hereditary_jumps: Vec<(AddressRange, usize)>,
 }
 
+impl<E: ElementData> Drop for Stack<E> {
+    fn drop(&mut self) {
+        self.release_item_memory(0);
+    }
+}
+
 impl<E: ElementData> Stack<E> {
     #[must_use]
     #[inline]
-    pub fn new(memory_limiter: SharedMemoryLimiter, enable_nth_of_type: bool) -> Self {
+    pub fn new(
+        memory_limiter: SharedMemoryLimiter,
+        max_depth: usize,
+        enable_nth_of_type: bool,
+    ) -> Self {
         Self {
             root_child_counter: Default::default(),
             typed_child_counters: enable_nth_of_type.then(TypedChildCounterMap::new),
-            items: LimitedVec::new(memory_limiter),
+            items: LimitedVec::new(memory_limiter.clone()),
+            memory_limiter: memory_limiter.clone(),
+            max_depth,
             open_name_counts: HashMap::new(),
             active_hereditary_jumps: Vec::new(),
         }
     }
 
+    /// Removes all the items and child counters, keeping the allocated memory.
+    pub fn reset(&mut self) {
+        self.root_child_counter = ChildCounter::default();
+
+        if let Some(typed_child_counters) = &mut self.typed_child_counters {
+            typed_child_counters.clear();
+        }
+
+        self.release_item_memory(0);
+        self.items.clear();
+        self.open_name_counts.clear();
+        self.active_hereditary_jumps.clear();
+    }
+
     /// Adds a child to child counters. Called before pushing the element to the stack.
     pub fn add_child(&mut self, name: &LocalName<'_>) {
         match self.items.last_mut() {
@@ -284,7 +344,7 @@ impl<E: ElementData> Stack<E> {
     pub fn pop_up_to(
         &mut self,
         local_name: LocalName<'_>,
-        mut popped_element_data_handler: impl FnMut(E),
+        popped_element_data_handler: impl FnMut(E),
     ) {
         if !self.open_name_counts.contains_key(&local_name) {
             return;
@@ -294,23 +354,84 @@ impl<E: ElementData> Stack<E> {
             .iter()
             .rposition(|item| item.local_name == local_name);
         if let Some(index) = pop_to_index {
-            if let Some(c) = self.typed_child_counters.as_mut() {
-                c.pop_to(index);
+            self.pop_from(index, popped_element_data_handler);
+        }
+    }
+
+    /// Closes the item of the element with the given tree builder id. If there are items
+    /// inside of it, e.g. when the adoption agency algorithm moves them out of the element,
+    /// the item stays on the stack for them with its data passed to the handler, and is
+    /// popped along with them. Such elements, and the open ones inside of them, are misnested
+    /// and passed to `misnested_element_data_handler` first.
+    pub fn close(
+        &mut self,
+        tree_builder_id: u64,
+        mut misnested_element_data_handler: impl FnMut(&E),
+        mut popped_element_data_handler: impl FnMut(E),
+    ) {
+        let Some(index) = self
+            .items
+            .iter()
+            .rposition(|item| item.tree_builder_id == Some(tree_builder_id))
+        else {
+            return;
+        };
+
+        if index + 1 < self.items.len() {
+            for item in &(*self.items)[index..] {
+                if item.tree_builder_id.is_some() {
+                    misnested_element_data_handler(&item.element_data);
+                }
             }
-            self.active_hereditary_jumps.retain(|(_, d)| *d < index);
-            for item in self.items.drain(index..) {
-                if let RawEntryMut::Occupied(mut e) = self
-                    .open_name_counts
-                    .raw_entry_mut()
-                    .from_key(&item.local_name)
-                {
-                    *e.get_mut() -= 1;
-                    if *e.get() == 0 {
-                        e.remove();
-                    }
+
+            let item = &mut (*self.items)[index];
+
+            item.tree_builder_id = None;
+            popped_element_data_handler(std::mem::replace(&mut item.element_data, E::new()));
+        } else {
+            let closed_count = (*self.items)[..index]
+                .iter()
+                .rev()
+                .take_while(|item| item.tree_builder_id.is_none())
+                .count();
+
+            let mut skipped_count = 0;
+
+            // NOTE: the data of the already closed elements has been passed to the handler.
+            self.pop_from(index - closed_count, |element_data| {
+                if skipped_count < closed_count {
+                    skipped_count += 1;
+                } else {
+                    popped_element_data_handler(element_data);
+                }
+            });
+        }
+    }
+
+    /// Pops everything, e.g. at the end of the document.
+    pub fn pop_all(&mut self, popped_element_data_handler: impl FnMut(E)) {
+        self.pop_from(0, popped_element_data_handler);
+    }
+
+    fn pop_from(&mut self, index: usize, mut popped_element_data_handler: impl FnMut(E)) {
+        if let Some(c) = self.typed_child_counters.as_mut() {
+            c.pop_to(index);
+        }
+        self.active_hereditary_jumps.retain(|(_, d)| *d < index);
+
+        self.release_item_memory(index);
+        for item in self.items.drain(index..) {
+            if let RawEntryMut::Occupied(mut e) = self
+                .open_name_counts
+                .raw_entry_mut()
+                .from_key(&item.local_name)
+            {
+                *e.get_mut() -= 1;
+                if *e.get() == 0 {
+                    e.remove();
                 }
-                popped_element_data_handler(item.element_data);
             }
+            popped_element_data_handler(item.element_data);
         }
     }
 
@@ -326,19 +447,104 @@ impl<E: ElementData> Stack<E> {
         &self.items
     }
 
+    /// Charges the memory used by the `item` to the limiter.
+    #[inline]
+    pub fn charge_item_memory(
+        &self,
+        item: &mut StackItem<'_, E>,
+        byte_count: usize,
+    ) -> Result<(), MemoryLimitExceededError> {
+        self.memory_limiter.increase_usage(byte_count)?;
+        item.charged_memory += byte_count;
+
+        Ok(())
+    }
+
+    /// Releases the memory charged for the items starting from the `index`.
+    fn release_item_memory(&mut self, index: usize) {
+        let charged_memory = self
+            .items
+            .iter()
+            .skip(index)
+            .map(|item| item.charged_memory)
+            .sum();
+
+        self.memory_limiter.decrease_usage(charged_memory);
+    }
+
+    /// Returns the innermost of the first `count` open items as an ancestor, creating
+    /// the ancestors that don't exist yet.
+    pub fn ancestor(
+        &mut self,
+        count: usize,
+        encoding: &'static Encoding,
+        attribute_names: &Arc<[String]>,
+    ) -> Result<Option<Arc<Ancestor>>, MemoryLimitExceededError> {
+        let len = count.min(self.items.len());
+        let items = &mut (*self.items)[..len];
+        let start = items.iter().rposition(|i| i.ancestor.is_some());
+        let mut parent = start.and_then(|i| items[i].ancestor.clone());
+
+        for (depth, item) in items
+            .iter_mut()
+            .enumerate()
+            .skip(start.map_or(0, |i| i + 1))
+        {
+            // NOTE: the reference counts are allocated along with the ancestor.
+            let mut byte_count = size_of::<Ancestor>() + 2 * size_of::<usize>();
+
+            if let LocalName::Bytes(name) = &item.local_name {
+                byte_count += name.len();
+            }
+
+            self.memory_limiter.increase_usage(byte_count)?;
+            item.charged_memory += byte_count;
+
+            let ancestor = Arc::new(Ancestor::new(
+                item.local_name.clone(),
+                item.ns,
+                encoding,
+                depth,
+                Arc::clone(attribute_names),
+                // NOTE: the attributes are only needed by the ancestor from now on.
+                std::mem::take(&mut item.retained_attributes),
+                parent,
+            ));
+
+            item.ancestor = Some(Arc::clone(&ancestor));
+            parent = Some(ancestor);
+        }
+
+        Ok(parent)
+    }
+
     #[inline]
     pub fn current_element_data_mut(&mut self) -> Option<&mut E> {
         self.items.last_mut().map(|i| &mut i.element_data)
     }
 
     #[inline]
-    pub fn push_item(
-        &mut self,
-        item: StackItem<'static, E>,
-    ) -> Result<(), MemoryLimitExceededError> {
+    pub fn push_item(&mut self, item: StackItem<'static, E>) -> Result<(), RewritingError> {
         let depth = self.items.len();
-        self.items.push(item)?;
+
+        // NOTE: the memory charged for an item that doesn't get on the stack is released here,
+        // as it won't be popped.
+        if depth >= self.max_depth {
+            self.memory_limiter.decrease_usage(item.charged_memory);
+
+            return Err(RewritingError::MaxNestingDepthExceeded(
+                MaxNestingDepthExceededError,
+            ));
+        }
+
+        let charged_memory = item.charged_memory;
+
+        self.items.push(item).map_err(|e| {
+            self.memory_limiter.decrease_usage(charged_memory);
+            RewritingError::MemoryLimitExceeded(e)
+        })?;
         let item = self.items.last().expect("just pushed");
+
         *self
             .open_name_counts
             .entry(item.local_name.clone())
@@ -348,6 +554,7 @@ impl<E: ElementData> Stack<E> {
                 self.active_hereditary_jumps.push((r.clone(), depth));
             }
         }
+
         Ok(())
     }
 }
@@ -376,7 +583,7 @@ mod tests {
     }
 
     fn item(name: &'static str, data: usize) -> StackItem<'static, TestElementData> {
-        let mut item = StackItem::new(local_name(name));
+        let mut item = StackItem::new(local_name(name), Namespace::Html);
 
         item.element_data = TestElementData(data);
 
@@ -393,7 +600,7 @@ mod tests {
 
     #[test]
     fn active_hereditary_jumps_dedup_and_prune() {
-        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), false);
+        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), usize::MAX, false);
 
         stack.push_item(item("a", 0)).unwrap();
 
@@ -420,7 +627,7 @@ mod tests {
 
     #[test]
     fn open_name_counts_track_push_and_drain() {
-        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), false);
+        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), usize::MAX, false);
 
         stack.push_item(item("a", 0)).unwrap();
         stack.push_item(item("b", 1)).unwrap();
@@ -445,7 +652,7 @@ mod tests {
     fn pop_up_to() {
         macro_rules! assert_pop_result {
             ($up_to:expr, $expected_unmatched:expr, $expected_items:expr) => {{
-                let mut stack = Stack::new(SharedMemoryLimiter::new(2048), false);
+                let mut stack = Stack::new(SharedMemoryLimiter::new(2048), usize::MAX, false);
 
                 stack.push_item(item("html", 0)).unwrap();
                 stack.push_item(item("body", 1)).unwrap();
@@ -487,7 +694,7 @@ mod tests {
 
     #[test]
     fn pop_up_to_on_empty_stack() {
-        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), false);
+        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), usize::MAX, false);
         let mut handler_called = false;
 
         stack.pop_up_to(local_name("div"), |_: TestElementData| {
@@ -497,4 +704,36 @@ mod tests {
         assert!(!handler_called);
         assert_eq!(stack.items().len(), 0);
     }
+
+    #[test]
+    fn close() {
+        let mut stack = Stack::new(SharedMemoryLimiter::new(2048), usize::MAX, false);
+
+        for (id, name) in ["form", "div", "span"].into_iter().enumerate() {
+            let mut item = item(name, id);
+
+            item.tree_builder_id = Some(id as u64);
+            stack.push_item(item).unwrap();
+        }
+
+        let mut misnested = Vec::default();
+        let mut closed = Vec::default();
+
+        // NOTE: closing an element that has open descendants only stops its matching.
+        stack.close(0, |d| misnested.push(d.0), |d| closed.push(d.0));
+        assert_eq!(misnested, vec![0, 1, 2]);
+        assert_eq!(closed, vec![0]);
+        assert_eq!(stack.items().len(), 3);
+
+        stack.close(0, |_| unreachable!(), |_| unreachable!("already closed"));
+
+        stack.close(2, |_| unreachable!("not misnested"), |d| closed.push(d.0));
+        assert_eq!(closed, vec![0, 2]);
+        assert_eq!(stack.items().len(), 2);
+
+        // NOTE: the previously closed ancestors are removed with the last open element.
+        stack.close(1, |_| unreachable!(), |d| closed.push(d.0));
+        assert_eq!(closed, vec![0, 2, 1]);
+        assert!(stack.items().is_empty());
+    }
 }
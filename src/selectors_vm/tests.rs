@@ -86,9 +86,18 @@ mod vm_tests {
             output_sink: |_: &[u8]| {},
             preallocated_parsing_buffer_size: 0,
             encoding: AsciiCompatibleEncoding::new(encoding).unwrap(),
+            transcoded_encoding: None,
+            output_encoding: None,
+            encoding_sniffing: false,
+            read_only: false,
+            min_output_chunk_size: 0,
+            max_output_chunk_size: usize::MAX,
             next_encoding: SharedEncoding::default(),
             memory_limiter: SharedMemoryLimiter::new(2048),
             strict: true,
+            full_tree_construction: false,
+            max_attributes_per_tag: usize::MAX,
+            max_start_tag_size: usize::MAX,
             graceful_bail_out_on_memory_limit_exceeded: false,
             graceful_bail_out_on_content_handler_error: false,
         });
@@ -120,8 +129,15 @@ mod vm_tests {
 
             let memory_limiter = SharedMemoryLimiter::new(2048);
             let enable_esi_tags = false;
-            let vm: SelectorMatchingVm<TestElementData> =
-                SelectorMatchingVm::new(ast, UTF_8, memory_limiter, enable_esi_tags);
+            let vm: SelectorMatchingVm<TestElementData> = SelectorMatchingVm::new(
+                ast,
+                UTF_8,
+                memory_limiter,
+                usize::MAX,
+                enable_esi_tags,
+                false,
+                Vec::new(),
+            );
 
             vm
         }};
@@ -161,7 +177,7 @@ mod vm_tests {
                                         &mut match_handler,
                                     )
                                     .unwrap(),
-                                    VmError::MemoryLimitExceeded(e) => panic!("{}", e),
+                                    VmError::RewritingError(e) => panic!("{}", e),
                                 }
                             } else {
                                 // NOTE: can't use unwrap() or expect() here, because
@@ -1264,8 +1280,15 @@ mod vm_tests {
         ast.add_selector(&"span".parse().unwrap(), 1);
 
         let memory_limiter = SharedMemoryLimiter::new(2048);
-        let mut vm: SelectorMatchingVm<TestElementData> =
-            SelectorMatchingVm::new(ast, UTF_8, memory_limiter, false);
+        let mut vm: SelectorMatchingVm<TestElementData> = SelectorMatchingVm::new(
+            ast,
+            UTF_8,
+            memory_limiter,
+            usize::MAX,
+            false,
+            false,
+            Vec::new(),
+        );
 
         test_with_token("<span foo=bar>", UTF_8, |t| {
             let Token::StartTag(t) = t else {
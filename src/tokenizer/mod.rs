@@ -0,0 +1,412 @@
+mod tokens;
+
+use self::tokens::TokenInput;
+pub use self::tokens::{Attribute, Comment, Doctype, EndTag, StartTag, Text, Token};
+use crate::base::Range;
+use crate::html::{LocalName, Namespace, TextType};
+use crate::memory::SharedMemoryLimiter;
+use crate::parser::{
+    ActionResult, AttributeOutline, LexemeSink, NonTagContentLexeme, NonTagContentTokenOutline,
+    Parser, ParserDirective, ParserOutputSink, TagHintSink, TagLexeme, TagLimits, TagTokenOutline,
+};
+use crate::rewriter::{AsciiCompatibleEncoding, RewritingError};
+use encoding_rs::Encoding;
+
+enum TokenRecord {
+    StartTag {
+        raw: Range,
+        name: Range,
+        attributes: Range,
+        ns: Namespace,
+        self_closing: bool,
+    },
+    EndTag {
+        raw: Range,
+        name: Range,
+    },
+    Text {
+        raw: Range,
+        text_type: TextType,
+    },
+    Comment {
+        raw: Range,
+        text: Range,
+    },
+    Doctype {
+        raw: Range,
+        name: Option<Range>,
+        public_id: Option<Range>,
+        system_id: Option<Range>,
+        force_quirks: bool,
+    },
+}
+
+/// Collects outlines of the tokens produced by the parser from the current input.
+#[derive(Default)]
+struct TokenCollector {
+    records: Vec<TokenRecord>,
+    attributes: Vec<AttributeOutline>,
+}
+
+impl LexemeSink for TokenCollector {
+    fn handle_tag(&mut self, lexeme: &TagLexeme<'_>) -> ActionResult<ParserDirective> {
+        let raw = lexeme.raw_range();
+
+        self.records.push(match *lexeme.token_outline() {
+            TagTokenOutline::StartTag {
+                name,
+                ns,
+                ref attributes,
+                self_closing,
+                ..
+            } => {
+                let start = self.attributes.len();
+
+                self.attributes.extend_from_slice(attributes);
+
+                TokenRecord::StartTag {
+                    raw,
+                    name,
+                    attributes: Range {
+                        start,
+                        end: self.attributes.len(),
+                    },
+                    ns,
+                    self_closing,
+                }
+            }
+            TagTokenOutline::EndTag { name, .. } => TokenRecord::EndTag { raw, name },
+        });
+
+        Ok(ParserDirective::Lex)
+    }
+
+    fn handle_non_tag_content(&mut self, lexeme: &NonTagContentLexeme<'_>) -> ActionResult {
+        let raw = lexeme.raw_range();
+
+        let record = match lexeme.token_outline() {
+            Some(NonTagContentTokenOutline::Text(text_type)) => TokenRecord::Text {
+                raw,
+                text_type: *text_type,
+            },
+            Some(NonTagContentTokenOutline::Comment(text)) => {
+                TokenRecord::Comment { raw, text: *text }
+            }
+            Some(NonTagContentTokenOutline::Doctype(doctype)) => TokenRecord::Doctype {
+                raw,
+                name: doctype.name,
+                public_id: doctype.public_id,
+                system_id: doctype.system_id,
+                force_quirks: doctype.force_quirks,
+            },
+            // NOTE: CDATA section markup and the end of input don't produce tokens.
+            Some(NonTagContentTokenOutline::Eof) | None => return Ok(()),
+        };
+
+        self.records.push(record);
+
+        Ok(())
+    }
+}
+
+impl TagHintSink for TokenCollector {
+    fn handle_start_tag_hint(
+        &mut self,
+        _name: LocalName<'_>,
+        _ns: Namespace,
+    ) -> Result<ParserDirective, RewritingError> {
+        Ok(ParserDirective::Lex)
+    }
+
+    fn handle_end_tag_hint(
+        &mut self,
+        _name: LocalName<'_>,
+    ) -> Result<ParserDirective, RewritingError> {
+        Ok(ParserDirective::Lex)
+    }
+}
+
+impl ParserOutputSink for TokenCollector {}
+
+/// A streaming HTML tokenizer.
+///
+/// Unlike [`HtmlRewriter`], the tokenizer doesn't produce any output: it's fed with chunks of
+/// input and yields the tokens that have been completed so far. Text types are determined the
+/// same way [`HtmlRewriter`] does it, by simulating the tree builder.
+///
+/// Input that can't be tokenized yet (e.g. a tag that continues in the next chunk) is
+/// buffered until the next call to [`feed`].
+///
+/// # Example
+/// ```
+/// use lol_html::tokenizer::{Token, Tokenizer};
+///
+/// let mut tokenizer = Tokenizer::default();
+/// let mut tags = Vec::new();
+///
+/// for chunk in ["<div cla", "ss='foo'>Hello</d", "iv>"] {
+///     for token in tokenizer.feed(chunk.as_bytes()).unwrap() {
+///         match token {
+///             Token::StartTag(tag) => tags.push(format!("<{}>", tag.name())),
+///             Token::EndTag(tag) => tags.push(format!("</{}>", tag.name())),
+///             _ => (),
+///         }
+///     }
+/// }
+///
+/// tokenizer.end().unwrap().for_each(drop);
+///
+/// assert_eq!(tags, ["<div>", "</div>"]);
+/// ```
+///
+/// [`HtmlRewriter`]: ../struct.HtmlRewriter.html
+/// [`feed`]: #method.feed
+pub struct Tokenizer {
+    parser: Parser<TokenCollector>,
+    encoding: &'static Encoding,
+    /// Unconsumed input from the previous calls followed by the current chunk.
+    input: Vec<u8>,
+    consumed_byte_count: usize,
+}
+
+impl Tokenizer {
+    /// Creates a new tokenizer for the input in the given `encoding`.
+    #[must_use]
+    pub fn new(encoding: AsciiCompatibleEncoding) -> Self {
+        let parser = Parser::new(
+            TokenCollector::default(),
+            ParserDirective::Lex,
+            false,
+            false,
+            SharedMemoryLimiter::new(usize::MAX),
+            TagLimits {
+                max_attributes_per_tag: usize::MAX,
+                max_start_tag_size: usize::MAX,
+            },
+        );
+
+        Self {
+            parser,
+            encoding: encoding.into(),
+            input: Vec::new(),
+            consumed_byte_count: 0,
+        }
+    }
+
+    /// Feeds a chunk of input to the tokenizer and returns an iterator over the tokens that
+    /// have been completed.
+    ///
+    /// # Errors
+    /// Returns an error if the parser fails internally. Such errors are unrecoverable.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Tokens<'_>, RewritingError> {
+        self.tokenize(chunk, false)
+    }
+
+    /// Finalizes the tokenization and returns an iterator over the remaining tokens.
+    ///
+    /// Should be called once the last chunk of the input is fed.
+    ///
+    /// # Errors
+    /// See [`feed`](#method.feed).
+    pub fn end(&mut self) -> Result<Tokens<'_>, RewritingError> {
+        self.tokenize(&[], true)
+    }
+
+    fn tokenize(&mut self, chunk: &[u8], last: bool) -> Result<Tokens<'_>, RewritingError> {
+        let offset = self.parser.consumed_byte_count();
+
+        self.input.drain(..self.consumed_byte_count);
+        self.input.extend_from_slice(chunk);
+
+        let collector = self.parser.get_dispatcher();
+
+        collector.records.clear();
+        collector.attributes.clear();
+
+        self.consumed_byte_count = self.parser.parse(&self.input, last)?;
+
+        let collector = self.parser.get_dispatcher();
+
+        Ok(Tokens {
+            input: TokenInput {
+                bytes: &self.input,
+                offset,
+                encoding: self.encoding,
+            },
+            records: collector.records.iter(),
+            attributes: &collector.attributes,
+        })
+    }
+}
+
+impl Default for Tokenizer {
+    /// Creates a new tokenizer for UTF-8 input.
+    #[inline]
+    fn default() -> Self {
+        Self::new(AsciiCompatibleEncoding::utf_8())
+    }
+}
+
+/// An iterator over the tokens produced by a [`Tokenizer`] from a chunk of input.
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+pub struct Tokens<'t> {
+    input: TokenInput<'t>,
+    records: std::slice::Iter<'t, TokenRecord>,
+    attributes: &'t [AttributeOutline],
+}
+
+impl<'t> Iterator for Tokens<'t> {
+    type Item = Token<'t>;
+
+    fn next(&mut self) -> Option<Token<'t>> {
+        let input = self.input;
+
+        Some(match *self.records.next()? {
+            TokenRecord::StartTag {
+                raw,
+                name,
+                attributes,
+                ns,
+                self_closing,
+            } => Token::StartTag(StartTag {
+                input,
+                raw,
+                name,
+                attributes: self
+                    .attributes
+                    .get(attributes.start..attributes.end)
+                    .unwrap_or_default(),
+                ns,
+                self_closing,
+            }),
+            TokenRecord::EndTag { raw, name } => Token::EndTag(EndTag { input, raw, name }),
+            TokenRecord::Text { raw, text_type } => Token::Text(Text {
+                input,
+                raw,
+                text_type,
+            }),
+            TokenRecord::Comment { raw, text } => Token::Comment(Comment { input, raw, text }),
+            TokenRecord::Doctype {
+                raw,
+                name,
+                public_id,
+                system_id,
+                force_quirks,
+            } => Token::Doctype(Doctype {
+                input,
+                raw,
+                name,
+                public_id,
+                system_id,
+                force_quirks,
+            }),
+        })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.records.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Tokens<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1251;
+
+    fn tokenize(chunks: &[&[u8]], encoding: &'static Encoding) -> Vec<String> {
+        let mut tokenizer = Tokenizer::new(encoding.try_into().unwrap());
+        let mut tokens = Vec::new();
+
+        let mut push = |token: Token<'_>| {
+            let location = token.source_location().bytes();
+
+            tokens.push(match token {
+                Token::StartTag(t) => format!(
+                    "StartTag({}, {:?}, {}, {}) {location:?}",
+                    t.name(),
+                    t.attributes()
+                        .map(|a| (a.name(), a.value()))
+                        .collect::<Vec<_>>(),
+                    t.namespace_uri(),
+                    t.self_closing(),
+                ),
+                Token::EndTag(t) => format!("EndTag({}) {location:?}", t.name()),
+                Token::Text(t) => format!(
+                    "Text({:?}, {:?}) {location:?}",
+                    encoding.decode_without_bom_handling(t.as_bytes()).0,
+                    t.text_type()
+                ),
+                Token::Comment(t) => format!("Comment({}) {location:?}", t.text()),
+                Token::Doctype(t) => format!("Doctype({:?}) {location:?}", t.name()),
+            });
+        };
+
+        for chunk in chunks {
+            tokenizer.feed(chunk).unwrap().for_each(&mut push);
+        }
+
+        tokenizer.end().unwrap().for_each(push);
+
+        tokens
+    }
+
+    #[test]
+    fn tokens() {
+        let html = concat!(
+            "<!DOCTYPE html><!--c--><DIV Class=\"a\" id=b>x</div>",
+            "<svg><path/></svg><script>1<2</script>"
+        );
+
+        let expected = [
+            "Doctype(Some(\"html\")) 0..15",
+            "Comment(c) 15..23",
+            "StartTag(div, [(\"class\", \"a\"), (\"id\", \"b\")], http://www.w3.org/1999/xhtml, false) 23..43",
+            "Text(\"x\", Data) 43..44",
+            "EndTag(div) 44..50",
+            "StartTag(svg, [], http://www.w3.org/2000/svg, false) 50..55",
+            "StartTag(path, [], http://www.w3.org/2000/svg, true) 55..62",
+            "EndTag(svg) 62..68",
+            "StartTag(script, [], http://www.w3.org/1999/xhtml, false) 68..76",
+            "Text(\"1\", ScriptData) 76..77",
+            "Text(\"<\", ScriptData) 77..78",
+            "Text(\"2\", ScriptData) 78..79",
+            "EndTag(script) 79..88",
+        ];
+
+        assert_eq!(tokenize(&[html.as_bytes()], encoding_rs::UTF_8), expected);
+
+        // NOTE: text is split at the chunk boundaries, other tokens are buffered.
+        let chunks = html.as_bytes().chunks(4).collect::<Vec<_>>();
+
+        let tokens = tokenize(&chunks, encoding_rs::UTF_8)
+            .into_iter()
+            .filter(|t| !t.starts_with("Text"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            expected
+                .into_iter()
+                .filter(|t| !t.starts_with("Text"))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn non_utf8_encoding() {
+        let (html, ..) = WINDOWS_1251.encode("<p title=\"Привет\">мир</p>");
+
+        assert_eq!(
+            tokenize(&[&html], WINDOWS_1251),
+            [
+                "StartTag(p, [(\"title\", \"Привет\")], http://www.w3.org/1999/xhtml, false) 0..18",
+                "Text(\"мир\", Data) 18..21",
+                "EndTag(p) 21..25",
+            ]
+        );
+    }
+}
@@ -0,0 +1,445 @@
+use crate::base::{Bytes, Range, SourceLocation};
+use crate::html::{Namespace, TextType};
+use crate::parser::AttributeOutline;
+use encoding_rs::Encoding;
+use std::fmt::{self, Debug};
+
+/// The input a token has been produced from.
+#[derive(Clone, Copy)]
+pub(super) struct TokenInput<'t> {
+    pub bytes: &'t [u8],
+    /// Number of bytes in the document before `bytes`.
+    pub offset: usize,
+    pub encoding: &'static Encoding,
+}
+
+impl<'t> TokenInput<'t> {
+    #[inline]
+    fn part(&self, range: Range) -> &'t [u8] {
+        self.bytes.get(range.start..range.end).unwrap_or_default()
+    }
+
+    #[inline]
+    fn string(&self, range: Range) -> String {
+        Bytes::new(self.part(range)).as_string(self.encoding)
+    }
+
+    #[inline]
+    fn lowercase_string(&self, range: Range) -> String {
+        Bytes::new(self.part(range)).as_lowercase_string(self.encoding)
+    }
+
+    #[inline]
+    fn source_location(&self, range: Range) -> SourceLocation {
+        SourceLocation::from_start_len(self.offset + range.start, range.end - range.start)
+    }
+}
+
+/// A token produced by the [`Tokenizer`].
+///
+/// Tokens borrow the tokenizer's input, so they can't outlive the next call to
+/// [`Tokenizer::feed`] or [`Tokenizer::end`].
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+/// [`Tokenizer::feed`]: struct.Tokenizer.html#method.feed
+/// [`Tokenizer::end`]: struct.Tokenizer.html#method.end
+#[derive(Debug)]
+pub enum Token<'t> {
+    /// A start tag.
+    StartTag(StartTag<'t>),
+    /// An end tag.
+    EndTag(EndTag<'t>),
+    /// A chunk of text.
+    Text(Text<'t>),
+    /// A comment.
+    Comment(Comment<'t>),
+    /// A document type declaration.
+    Doctype(Doctype<'t>),
+}
+
+impl<'t> Token<'t> {
+    /// Returns the raw bytes of the token in the input's encoding.
+    #[inline]
+    #[must_use]
+    pub fn raw(&self) -> &'t [u8] {
+        match self {
+            Self::StartTag(t) => t.raw(),
+            Self::EndTag(t) => t.raw(),
+            Self::Text(t) => t.as_bytes(),
+            Self::Comment(t) => t.raw(),
+            Self::Doctype(t) => t.raw(),
+        }
+    }
+
+    /// Returns the position of the token in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        match self {
+            Self::StartTag(t) => t.source_location(),
+            Self::EndTag(t) => t.source_location(),
+            Self::Text(t) => t.source_location(),
+            Self::Comment(t) => t.source_location(),
+            Self::Doctype(t) => t.source_location(),
+        }
+    }
+}
+
+/// A start tag produced by the [`Tokenizer`].
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+pub struct StartTag<'t> {
+    pub(super) input: TokenInput<'t>,
+    pub(super) raw: Range,
+    pub(super) name: Range,
+    pub(super) attributes: &'t [AttributeOutline],
+    pub(super) ns: Namespace,
+    pub(super) self_closing: bool,
+}
+
+impl<'t> StartTag<'t> {
+    /// Returns the name of the tag, always ASCII lowercased.
+    #[inline]
+    #[must_use]
+    pub fn name(&self) -> String {
+        self.input.lowercase_string(self.name)
+    }
+
+    /// Returns the name of the tag as it appears in the input.
+    #[inline]
+    #[must_use]
+    pub fn name_raw(&self) -> &'t [u8] {
+        self.input.part(self.name)
+    }
+
+    /// Returns the [namespace URI] of the tag's element.
+    ///
+    /// [namespace URI]: https://developer.mozilla.org/en-US/docs/Web/API/Element/namespaceURI
+    #[inline]
+    #[must_use]
+    pub fn namespace_uri(&self) -> &'static str {
+        self.ns.uri()
+    }
+
+    /// Returns an iterator over the attributes of the tag, in the order of the input.
+    #[inline]
+    pub fn attributes(&self) -> impl ExactSizeIterator<Item = Attribute<'t>> + use<'t> {
+        let input = self.input;
+
+        self.attributes
+            .iter()
+            .map(move |&outline| Attribute { input, outline })
+    }
+
+    /// Returns the value of the first attribute with the `name`. The value may have HTML/XML
+    /// entities.
+    ///
+    /// Returns `None` if the tag doesn't have an attribute with the `name`.
+    #[inline]
+    #[must_use]
+    pub fn get_attribute(&self, name: &str) -> Option<String> {
+        self.attributes()
+            .find(|attr| attr.name_raw().eq_ignore_ascii_case(name.as_bytes()))
+            .map(|attr| attr.value())
+    }
+
+    /// Returns `true` if the tag is self-closing (e.g. `<foo />`).
+    #[inline]
+    #[must_use]
+    pub const fn self_closing(&self) -> bool {
+        self.self_closing
+    }
+
+    /// Returns the raw bytes of the tag in the input's encoding.
+    #[inline]
+    #[must_use]
+    pub fn raw(&self) -> &'t [u8] {
+        self.input.part(self.raw)
+    }
+
+    /// Returns the position of the tag in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.input.source_location(self.raw)
+    }
+}
+
+impl Debug for StartTag<'_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StartTag")
+            .field("name", &self.name())
+            .field("attributes", &self.attributes().collect::<Vec<_>>())
+            .field("self_closing", &self.self_closing)
+            .finish()
+    }
+}
+
+/// An attribute of a [`StartTag`].
+///
+/// [`StartTag`]: struct.StartTag.html
+pub struct Attribute<'t> {
+    input: TokenInput<'t>,
+    outline: AttributeOutline,
+}
+
+impl<'t> Attribute<'t> {
+    /// Returns the name of the attribute, always ASCII lowercased.
+    #[inline]
+    #[must_use]
+    pub fn name(&self) -> String {
+        self.input.lowercase_string(self.outline.name)
+    }
+
+    /// Returns the name of the attribute as it appears in the input.
+    #[inline]
+    #[must_use]
+    pub fn name_raw(&self) -> &'t [u8] {
+        self.input.part(self.outline.name)
+    }
+
+    /// Returns the value of the attribute. The value may have HTML/XML entities.
+    #[inline]
+    #[must_use]
+    pub fn value(&self) -> String {
+        self.input.string(self.outline.value)
+    }
+
+    /// Returns the value of the attribute as it appears in the input, without quotes.
+    #[inline]
+    #[must_use]
+    pub fn value_raw(&self) -> &'t [u8] {
+        self.input.part(self.outline.value)
+    }
+
+    /// Returns the position of the attribute in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.input.source_location(self.outline.raw_range)
+    }
+}
+
+impl Debug for Attribute<'_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Attribute")
+            .field("name", &self.name())
+            .field("value", &self.value())
+            .finish()
+    }
+}
+
+/// An end tag produced by the [`Tokenizer`].
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+pub struct EndTag<'t> {
+    pub(super) input: TokenInput<'t>,
+    pub(super) raw: Range,
+    pub(super) name: Range,
+}
+
+impl<'t> EndTag<'t> {
+    /// Returns the name of the tag, always ASCII lowercased.
+    #[inline]
+    #[must_use]
+    pub fn name(&self) -> String {
+        self.input.lowercase_string(self.name)
+    }
+
+    /// Returns the name of the tag as it appears in the input.
+    #[inline]
+    #[must_use]
+    pub fn name_raw(&self) -> &'t [u8] {
+        self.input.part(self.name)
+    }
+
+    /// Returns the raw bytes of the tag in the input's encoding.
+    #[inline]
+    #[must_use]
+    pub fn raw(&self) -> &'t [u8] {
+        self.input.part(self.raw)
+    }
+
+    /// Returns the position of the tag in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.input.source_location(self.raw)
+    }
+}
+
+impl Debug for EndTag<'_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EndTag")
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+/// A chunk of text produced by the [`Tokenizer`].
+///
+/// Text is split into chunks at the boundaries of the input chunks, so a chunk can end in the
+/// middle of a multi-byte character. Use a streaming decoder (e.g. [`encoding_rs::Decoder`])
+/// to decode text that spans several chunks.
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+/// [`encoding_rs::Decoder`]: https://docs.rs/encoding_rs/latest/encoding_rs/struct.Decoder.html
+pub struct Text<'t> {
+    pub(super) input: TokenInput<'t>,
+    pub(super) raw: Range,
+    pub(super) text_type: TextType,
+}
+
+impl<'t> Text<'t> {
+    /// Returns the text in the input's encoding. The text may have HTML/XML entities.
+    #[inline]
+    #[must_use]
+    pub fn as_bytes(&self) -> &'t [u8] {
+        self.input.part(self.raw)
+    }
+
+    /// Returns the type of the text, determined by the element that contains it.
+    #[inline]
+    #[must_use]
+    pub const fn text_type(&self) -> TextType {
+        self.text_type
+    }
+
+    /// Returns the position of the text in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.input.source_location(self.raw)
+    }
+}
+
+impl Debug for Text<'_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Text")
+            .field("text", &Bytes::new(self.as_bytes()).as_debug_string())
+            .field("text_type", &self.text_type)
+            .finish()
+    }
+}
+
+/// A comment produced by the [`Tokenizer`].
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+pub struct Comment<'t> {
+    pub(super) input: TokenInput<'t>,
+    pub(super) raw: Range,
+    pub(super) text: Range,
+}
+
+impl<'t> Comment<'t> {
+    /// Returns the text of the comment.
+    #[inline]
+    #[must_use]
+    pub fn text(&self) -> String {
+        self.input.string(self.text)
+    }
+
+    /// Returns the text of the comment in the input's encoding.
+    #[inline]
+    #[must_use]
+    pub fn text_raw(&self) -> &'t [u8] {
+        self.input.part(self.text)
+    }
+
+    /// Returns the raw bytes of the comment in the input's encoding.
+    #[inline]
+    #[must_use]
+    pub fn raw(&self) -> &'t [u8] {
+        self.input.part(self.raw)
+    }
+
+    /// Returns the position of the comment in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.input.source_location(self.raw)
+    }
+}
+
+impl Debug for Comment<'_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Comment")
+            .field("text", &self.text())
+            .finish()
+    }
+}
+
+/// A document type declaration produced by the [`Tokenizer`].
+///
+/// [`Tokenizer`]: struct.Tokenizer.html
+pub struct Doctype<'t> {
+    pub(super) input: TokenInput<'t>,
+    pub(super) raw: Range,
+    pub(super) name: Option<Range>,
+    pub(super) public_id: Option<Range>,
+    pub(super) system_id: Option<Range>,
+    pub(super) force_quirks: bool,
+}
+
+impl<'t> Doctype<'t> {
+    /// The name of the doctype.
+    #[inline]
+    #[must_use]
+    pub fn name(&self) -> Option<String> {
+        self.name.map(|n| self.input.lowercase_string(n))
+    }
+
+    /// The public identifier of the doctype.
+    #[inline]
+    #[must_use]
+    pub fn public_id(&self) -> Option<String> {
+        self.public_id.map(|i| self.input.string(i))
+    }
+
+    /// The system identifier of the doctype.
+    #[inline]
+    #[must_use]
+    pub fn system_id(&self) -> Option<String> {
+        self.system_id.map(|i| self.input.string(i))
+    }
+
+    /// Returns `true` if the doctype puts the document into [quirks mode].
+    ///
+    /// [quirks mode]: https://html.spec.whatwg.org/multipage/parsing.html#force-quirks-flag
+    #[inline]
+    #[must_use]
+    pub const fn force_quirks(&self) -> bool {
+        self.force_quirks
+    }
+
+    /// Returns the raw bytes of the doctype in the input's encoding.
+    #[inline]
+    #[must_use]
+    pub fn raw(&self) -> &'t [u8] {
+        self.input.part(self.raw)
+    }
+
+    /// Returns the position of the doctype in the document.
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.input.source_location(self.raw)
+    }
+}
+
+impl Debug for Doctype<'_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Doctype")
+            .field("name", &self.name())
+            .field("public_id", &self.public_id())
+            .field("system_id", &self.system_id())
+            .field("force_quirks", &self.force_quirks)
+            .finish()
+    }
+}
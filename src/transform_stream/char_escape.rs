@@ -0,0 +1,114 @@
+use super::OutputSink;
+use crate::html::TextType;
+use std::fmt::Write as _;
+
+/// How a character is escaped in the output, depending on where it occurs.
+// Pub only for integration tests
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CharEscape {
+    /// A numeric character reference, e.g. `&#26085;`. Used where the references are decoded:
+    /// in text, `<title>`, `<textarea>` and attribute values.
+    Reference,
+    /// A JavaScript escape, e.g. `\u65E5`. Used in `<script>`, where it's valid in string,
+    /// template and regular expression literals.
+    Script,
+    /// A CSS escape, e.g. `\65E5 `. Used in `<style>`.
+    Style,
+    /// There is no escape that is decoded in comments, CDATA sections and other raw text
+    /// elements (e.g. `<xmp>`), so the character is replaced with `?`.
+    Replacement,
+}
+
+impl CharEscape {
+    /// Returns the escape for the text of `text_type`. `is_html_style` is set if the text is
+    /// the content of the last start tag, which is an HTML `<style>`.
+    #[inline]
+    #[must_use]
+    pub const fn for_text(text_type: TextType, is_html_style: bool) -> Self {
+        match text_type {
+            TextType::Data | TextType::RCData => Self::Reference,
+            TextType::ScriptData => Self::Script,
+            TextType::RawText if is_html_style => Self::Style,
+            TextType::RawText | TextType::PlainText | TextType::CDataSection => Self::Replacement,
+        }
+    }
+
+    /// Appends the escaped `ch` to the `output`.
+    pub fn write(self, ch: char, output: &mut String) {
+        match self {
+            Self::Reference => write!(output, "&#{};", u32::from(ch)),
+            Self::Script => ch
+                .encode_utf16(&mut [0; 2])
+                .iter()
+                .try_for_each(|unit| write!(output, "\\u{unit:04X}")),
+            Self::Style => write!(output, "\\{:X} ", u32::from(ch)),
+            Self::Replacement => write!(output, "?"),
+        }
+        .unwrap_or_else(|_| unreachable!("writing to a string doesn't fail"));
+    }
+}
+
+/// An output sink that escapes the characters that can't be represented in the output encoding.
+///
+/// The parser knows where the output occurs, so it sets the escape before the output is passed
+/// to the sink.
+pub trait EscapingOutputSink: OutputSink {
+    /// Returns `true` if the sink needs to know the escape of the output.
+    fn escapes_chars(&self) -> bool;
+
+    /// Sets the escape of the characters in the following output.
+    fn set_char_escape(&mut self, escape: CharEscape);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn for_text() {
+        use CharEscape::*;
+
+        assert_eq!(
+            [
+                TextType::Data,
+                TextType::RCData,
+                TextType::ScriptData,
+                TextType::RawText,
+                TextType::PlainText,
+                TextType::CDataSection,
+            ]
+            .map(|text_type| CharEscape::for_text(text_type, false)),
+            [
+                Reference,
+                Reference,
+                Script,
+                Replacement,
+                Replacement,
+                Replacement
+            ]
+        );
+
+        assert_eq!(CharEscape::for_text(TextType::RawText, true), Style);
+        assert_eq!(CharEscape::for_text(TextType::Data, true), Reference);
+    }
+
+    #[test]
+    fn write() {
+        let mut output = String::new();
+
+        for escape in [
+            CharEscape::Reference,
+            CharEscape::Script,
+            CharEscape::Style,
+            CharEscape::Replacement,
+        ] {
+            escape.write('日', &mut output);
+            escape.write('😀', &mut output);
+        }
+
+        assert_eq!(
+            output,
+            "&#26085;&#128512;\\u65E5\\uD83D\\uDE00\\65E5 \\1F600 ??"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use super::OutputSink;
+use crate::AsciiCompatibleEncoding;
+use crate::memory::{Arena, SharedMemoryLimiter};
+
+/// Wraps the output sink to coalesce small output chunks and to split large ones.
+// Pub only for integration tests
+pub struct ChunkingOutputSink<O> {
+    sink: O,
+    min_chunk_size: usize,
+    max_chunk_size: usize,
+    /// The coalesced output, shorter than the minimal chunk size.
+    buffer: Arena,
+}
+
+impl<O: OutputSink> ChunkingOutputSink<O> {
+    #[inline]
+    #[must_use]
+    pub fn new(
+        sink: O,
+        min_chunk_size: usize,
+        max_chunk_size: usize,
+        memory_limiter: SharedMemoryLimiter,
+    ) -> Self {
+        let max_chunk_size = max_chunk_size.max(1);
+
+        Self {
+            sink,
+            min_chunk_size: min_chunk_size.min(max_chunk_size),
+            max_chunk_size,
+            buffer: Arena::new(memory_limiter, 0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner_mut(&mut self) -> &mut O {
+        &mut self.sink
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> O {
+        self.sink
+    }
+
+    /// Flushes the coalesced output and replaces the sink with `sink`, returning the previous one.
+    #[inline]
+    pub(crate) fn replace_inner(&mut self, sink: O) -> O {
+        self.flush();
+
+        std::mem::replace(&mut self.sink, sink)
+    }
+
+    /// Passes the coalesced output to the sink, even if it's smaller than the minimal chunk size.
+    #[inline]
+    pub fn flush(&mut self) {
+        if !self.buffer.bytes().is_empty() {
+            self.sink.handle_chunk(self.buffer.bytes());
+            self.buffer.clear();
+        }
+    }
+
+    /// Appends `bytes` to the coalesced output. If the buffer can't grow within the memory
+    /// limit, the output is passed to the sink without coalescing instead.
+    #[inline]
+    fn coalesce(&mut self, bytes: &[u8]) {
+        if self.buffer.append(bytes).is_err() {
+            self.flush();
+            self.sink.handle_chunk(bytes);
+        }
+    }
+}
+
+impl<O: OutputSink> OutputSink for ChunkingOutputSink<O> {
+    fn handle_chunk(&mut self, mut chunk: &[u8]) {
+        if !self.buffer.bytes().is_empty() {
+            let fill_len = (self.max_chunk_size - self.buffer.bytes().len()).min(chunk.len());
+
+            self.coalesce(&chunk[..fill_len]);
+            chunk = &chunk[fill_len..];
+
+            if self.buffer.bytes().len() < self.min_chunk_size {
+                return;
+            }
+
+            self.flush();
+        }
+
+        // NOTE: large chunks are passed to the sink without copying them into the buffer.
+        while !chunk.is_empty() && chunk.len() >= self.min_chunk_size {
+            let (piece, rest) = chunk.split_at(chunk.len().min(self.max_chunk_size));
+
+            self.sink.handle_chunk(piece);
+            chunk = rest;
+        }
+
+        if !chunk.is_empty() {
+            self.coalesce(chunk);
+        }
+    }
+
+    #[inline]
+    fn handle_end(&mut self) {
+        self.flush();
+        self.sink.handle_end();
+    }
+
+    #[inline]
+    fn set_encoding(&mut self, new_encoding: AsciiCompatibleEncoding) {
+        self.sink.set_encoding(new_encoding);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(min_chunk_size: usize, max_chunk_size: usize, input: &[&str]) -> Vec<String> {
+        chunks_with_memory_limit(min_chunk_size, max_chunk_size, usize::MAX, input)
+    }
+
+    fn chunks_with_memory_limit(
+        min_chunk_size: usize,
+        max_chunk_size: usize,
+        max_memory_usage: usize,
+        input: &[&str],
+    ) -> Vec<String> {
+        let mut chunks = Vec::new();
+
+        let mut sink = ChunkingOutputSink::new(
+            |c: &[u8]| chunks.push(String::from_utf8(c.to_vec()).unwrap()),
+            min_chunk_size,
+            max_chunk_size,
+            SharedMemoryLimiter::new(max_memory_usage),
+        );
+
+        for chunk in input {
+            sink.handle_chunk(chunk.as_bytes());
+        }
+
+        sink.handle_end();
+
+        chunks
+    }
+
+    #[test]
+    fn passthrough() {
+        assert_eq!(
+            chunks(0, usize::MAX, &["<a", ">", "text", "</a>"]),
+            ["<a", ">", "text", "</a>", ""]
+        );
+
+        // NOTE: empty chunks in the middle of the output are dropped.
+        assert_eq!(chunks(0, usize::MAX, &["<a", "", ">"]), ["<a", ">", ""]);
+        assert_eq!(chunks(4, usize::MAX, &["<a", "", ">"]), ["<a>", ""]);
+    }
+
+    #[test]
+    fn coalescing() {
+        assert_eq!(
+            chunks(4, usize::MAX, &["<a", ">", "text", "</a>", "!"]),
+            ["<a>text", "</a>", "!", ""]
+        );
+
+        assert_eq!(chunks(4, usize::MAX, &["<a", ">"]), ["<a>", ""]);
+    }
+
+    #[test]
+    fn coalescing_memory_limit() {
+        // NOTE: the output that doesn't fit into the memory limit isn't coalesced.
+        assert_eq!(
+            chunks_with_memory_limit(8, usize::MAX, 4, &["<a", ">", "te", "xt", "</a>"]),
+            ["<a>", "te", "xt", "</a>", ""]
+        );
+    }
+
+    #[test]
+    fn splitting() {
+        assert_eq!(
+            chunks(0, 3, &["<a>", "long text", "</a>"]),
+            ["<a>", "lon", "g t", "ext", "</a", ">", ""]
+        );
+
+        assert_eq!(
+            chunks(2, 3, &["<", "a>long", "!"]),
+            ["<a>", "lon", "g!", ""]
+        );
+
+        // NOTE: the minimal chunk size is clamped to the maximal one.
+        assert_eq!(chunks(5, 2, &["<", "a>"]), ["<a", ">", ""]);
+    }
+}
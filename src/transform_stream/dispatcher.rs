@@ -1,6 +1,7 @@
+use super::char_escape::{CharEscape, EscapingOutputSink};
 use crate::AsciiCompatibleEncoding;
 use crate::base::{Bytes, Range, SharedEncoding, SourceLocation};
-use crate::html::{LocalName, Namespace};
+use crate::html::{LocalName, Namespace, Tag};
 use crate::html_content::{TextChunk, TextType};
 use crate::parser::{
     ActionError, ActionResult, AttributeBuffer, Lexeme, LexemeSink, NonTagContentLexeme,
@@ -9,7 +10,10 @@ use crate::parser::{
 };
 use crate::rewritable_units::TextDecoder;
 use crate::rewritable_units::ToTokenResult;
-use crate::rewritable_units::{BailOut, DocumentEnd, Serialize, ToToken, Token, TokenCaptureFlags};
+use crate::rewritable_units::{
+    BailOut, DocumentEnd, ElementCloseReason, Relocations, Serialize, ToToken, Token,
+    TokenCaptureFlags,
+};
 use crate::rewriter::RewritingError;
 use encoding_rs::Encoding;
 
@@ -45,10 +49,49 @@ pub trait TransformController: Sized {
     fn handle_end(&mut self, document_end: &mut DocumentEnd<'_>) -> Result<(), RewritingError>;
     fn should_emit_content(&self) -> bool;
 
+    /// Returns `true` if elements have been closed without an end tag token since the last
+    /// call to [`handle_implied_end_tags()`][Self::handle_implied_end_tags].
+    fn has_implied_end_tags(&self) -> bool {
+        false
+    }
+
+    /// Passes end tags of the implicitly closed elements to `emit_token`.
+    fn handle_implied_end_tags(
+        &mut self,
+        _source_location_byte_start: usize,
+        _encoding: &'static Encoding,
+        _emit_token: &mut dyn FnMut(Token<'_>) -> Result<(), RewritingError>,
+    ) -> Result<(), RewritingError> {
+        Ok(())
+    }
+
+    /// Closes elements that remain open at the end of the document.
+    fn close_open_elements(&mut self) {}
+
+    /// Invoked before each tag in the full tree construction mode with the ids of the elements
+    /// that the tree builder has closed since the previous tag, and the id of the element it
+    /// has inserted for the tag, if the element is left open.
+    fn handle_open_elements_change(
+        &mut self,
+        _closed_elements: &mut dyn Iterator<Item = (u64, ElementCloseReason)>,
+        _inserted_element_id: Option<u64>,
+    ) {
+    }
+
+    /// Returns the state of the elements moved or copied to anchors, if the controller
+    /// supports it. The output is passed through it before reaching the output sink.
+    #[allow(private_interfaces)]
+    fn relocations(&self) -> Option<Relocations> {
+        None
+    }
+
     /// Invoked when the rewriter triggers a graceful bail-out. Default impl does nothing;
     /// the production `HtmlRewriteController` overrides this to run the user-registered
     /// bail-out handlers.
     fn handle_bail_out(&mut self, _error: &RewritingError, _bail_out: &mut BailOut<'_>) {}
+
+    /// Restores the initial state for a new document, keeping the handlers.
+    fn reset(&mut self) {}
 }
 
 /// Defines an interface for the [`HtmlRewriter`]'s output.
@@ -62,9 +105,22 @@ pub trait OutputSink {
     /// Handles rewriter's output chunk.
     ///
     /// # Note
-    /// The last chunk of the output has zero length.
+    /// The last chunk of the output has zero length, unless [`handle_end`] is implemented.
+    /// Chunks in the middle of the output can have zero length too.
+    ///
+    /// [`handle_end`]: #method.handle_end
     fn handle_chunk(&mut self, chunk: &[u8]);
 
+    /// Called once after the last chunk of the output.
+    ///
+    /// The default implementation passes the last chunk of zero length to `handle_chunk`.
+    ///
+    /// Implementations for closures won't be able to access this method
+    #[inline]
+    fn handle_end(&mut self) {
+        self.handle_chunk(&[]);
+    }
+
     /// Called before the first `handle_chunk` and once when `<meta charset>` is applied.
     ///
     /// Implementations for closures won't be able to access this method
@@ -82,6 +138,8 @@ pub struct Dispatcher<C, O> {
     delegate: DispatcherDelegate<C, O>,
     text_decoder: TextDecoder,
     last_text_type: TextType,
+    /// Set if the last start tag is an HTML `<style>`, whose raw text is CSS.
+    is_in_html_style: bool,
     got_flags_from_hint: bool,
     pending_element_aux_info_req: Option<AuxStartTagInfoRequest<C>>,
     encoding: AsciiCompatibleEncoding,
@@ -95,36 +153,114 @@ struct DispatcherDelegate<C, O> {
     remaining_content_start: usize,
     capture_flags: TokenCaptureFlags,
     emission_enabled: bool,
+    /// If set, nothing is written to the output sink and content modifications are errors.
+    read_only: bool,
+    relocations: Option<Relocations>,
+}
+
+/// Writes the `chunk` to the `output_sink`, unless it's captured by an element that is being
+/// moved to an anchor.
+#[inline]
+fn write_output<O: OutputSink>(
+    output_sink: &mut O,
+    relocations: Option<&Relocations>,
+    chunk: &[u8],
+) {
+    match relocations {
+        Some(relocations) => relocations.write(chunk, &mut |c| output_sink.handle_chunk(c)),
+        None => output_sink.handle_chunk(chunk),
+    }
+}
+
+/// Serializes the `token` to the output. In the read-only mode the token is only checked for
+/// modifications, so nothing is serialized.
+#[inline]
+fn emit_token<O: OutputSink>(
+    token: Token<'_>,
+    read_only: bool,
+    emission_enabled: bool,
+    output_sink: &mut O,
+    relocations: Option<&Relocations>,
+) -> Result<(), RewritingError> {
+    if read_only {
+        if token.is_modified() {
+            return Err(RewritingError::ModifiedInReadOnlyMode);
+        }
+    } else if emission_enabled {
+        token.into_bytes(&mut |c| write_output(output_sink, relocations, c))?;
+    }
+
+    Ok(())
 }
 
 impl<C, O> DispatcherDelegate<C, O>
 where
     C: TransformController,
-    O: OutputSink,
+    O: EscapingOutputSink,
 {
     fn flush_remaining_input(&mut self, input: &[u8], consumed_byte_count: usize) {
-        if self.emission_enabled {
+        if self.emission_enabled && !self.read_only {
             let output = input
                 .get(self.remaining_content_start..consumed_byte_count)
                 .unwrap_or_default();
 
             if !output.is_empty() {
-                self.output_sink.handle_chunk(output);
+                write_output(&mut self.output_sink, self.relocations.as_ref(), output);
             }
         }
 
         self.remaining_content_start = 0;
     }
 
-    fn finish(&mut self, encoding: &'static Encoding, input: &[u8]) -> Result<(), RewritingError> {
+    fn finish(
+        &mut self,
+        encoding: &'static Encoding,
+        input: &[u8],
+        document_byte_count: usize,
+    ) -> Result<(), RewritingError> {
         self.flush_remaining_input(input, input.len());
 
+        self.transform_controller.close_open_elements();
+
+        if self.transform_controller.has_implied_end_tags() {
+            if self.should_stop_removing_element_content() {
+                self.emission_enabled = true;
+            }
+
+            self.implied_end_tags_produced(document_byte_count, encoding)?;
+        }
+
+        if self.read_only {
+            let mut modified = false;
+            let mut sink = |chunk: &[u8]| modified |= !chunk.is_empty();
+            let mut document_end = DocumentEnd::new(&mut sink, encoding);
+
+            self.transform_controller.handle_end(&mut document_end)?;
+
+            return if modified {
+                Err(RewritingError::ModifiedInReadOnlyMode)
+            } else {
+                Ok(())
+            };
+        }
+
+        // NOTE: the content moved or copied to the anchors that haven't been reached
+        // is inserted at the end of the document.
+        if let Some(relocations) = &self.relocations {
+            self.check_relocations()?;
+
+            let content = relocations.take_pending(|_| true);
+
+            if !content.is_empty() {
+                self.output_sink.handle_chunk(&content);
+            }
+        }
+
         let mut document_end = DocumentEnd::new(&mut self.output_sink, encoding);
 
         self.transform_controller.handle_end(&mut document_end)?;
 
-        // NOTE: output the finalizing chunk.
-        self.output_sink.handle_chunk(&[]);
+        self.output_sink.handle_end();
 
         Ok(())
     }
@@ -150,13 +286,21 @@ where
 
         let chunk = lexeme.input().slice(chunk_range);
 
-        if self.emission_enabled && !chunk.is_empty() {
-            self.output_sink.handle_chunk(&chunk);
+        if self.emission_enabled && !self.read_only && !chunk.is_empty() {
+            write_output(&mut self.output_sink, self.relocations.as_ref(), &chunk);
         }
 
         self.remaining_content_start = lexeme_range.start;
     }
 
+    /// Emits the output that precedes `lexeme` with the current escape and switches to `escape`
+    /// for the characters of `lexeme` that can't be represented in the output encoding.
+    #[inline]
+    fn set_char_escape<T>(&mut self, lexeme: &Lexeme<'_, T>, escape: CharEscape) {
+        self.emit_chunk_before_lexeme(lexeme);
+        self.output_sink.set_char_escape(escape);
+    }
+
     /// Advance `remaining_content_start` past the end of `lexeme`, marking it as committed.
     /// Must only be called after the lexeme's token has been successfully emitted; see
     /// [`emit_chunk_before_lexeme()`].
@@ -172,10 +316,7 @@ where
 
         self.transform_controller.handle_token(&mut token)?;
 
-        if self.emission_enabled {
-            token.into_bytes(&mut |c| self.output_sink.handle_chunk(c))?;
-        }
-        Ok(())
+        self.emit_token(token)
     }
 
     fn text_token_produced(
@@ -198,10 +339,48 @@ where
 
         self.transform_controller.handle_token(&mut token)?;
 
-        if self.emission_enabled {
-            token.into_bytes(&mut |c| self.output_sink.handle_chunk(c))?;
+        self.emit_token(token)
+    }
+
+    #[inline]
+    fn emit_token(&mut self, token: Token<'_>) -> Result<(), RewritingError> {
+        emit_token(
+            token,
+            self.read_only,
+            self.emission_enabled,
+            &mut self.output_sink,
+            self.relocations.as_ref(),
+        )?;
+
+        self.check_relocations()
+    }
+
+    /// Returns an error if the output captured for relocation has exceeded the memory limit.
+    #[inline]
+    fn check_relocations(&self) -> Result<(), RewritingError> {
+        match self.relocations.as_ref().and_then(Relocations::take_error) {
+            Some(e) => Err(RewritingError::MemoryLimitExceeded(e)),
+            None => Ok(()),
         }
-        Ok(())
+    }
+
+    fn implied_end_tags_produced(
+        &mut self,
+        source_location_byte_start: usize,
+        encoding: &'static Encoding,
+    ) -> Result<(), RewritingError> {
+        let read_only = self.read_only;
+        let emission_enabled = self.emission_enabled;
+        let output_sink = &mut self.output_sink;
+        let relocations = self.relocations.as_ref();
+
+        self.transform_controller.handle_implied_end_tags(
+            source_location_byte_start,
+            encoding,
+            &mut |token| emit_token(token, read_only, emission_enabled, output_sink, relocations),
+        )?;
+
+        self.check_relocations()
     }
 
     #[inline]
@@ -213,7 +392,7 @@ where
 impl<C, O> Dispatcher<C, O>
 where
     C: TransformController,
-    O: OutputSink,
+    O: EscapingOutputSink,
 {
     #[inline]
     pub fn new(
@@ -221,8 +400,16 @@ where
         mut output_sink: O,
         encoding: AsciiCompatibleEncoding,
         next_encoding: SharedEncoding,
+        read_only: bool,
     ) -> Self {
         let capture_flags = transform_controller.initial_capture_flags();
+        // NOTE: nothing is written in the read-only mode, so there is no output to relocate.
+        let relocations = if read_only {
+            None
+        } else {
+            transform_controller.relocations()
+        };
+
         output_sink.set_encoding(encoding);
 
         Self {
@@ -232,9 +419,12 @@ where
                 capture_flags,
                 remaining_content_start: 0,
                 emission_enabled: true,
+                read_only,
+                relocations,
             },
             text_decoder: TextDecoder::new(encoding),
             last_text_type: TextType::Data,
+            is_in_html_style: false,
             encoding,
             got_flags_from_hint: false,
             pending_element_aux_info_req: None,
@@ -242,8 +432,55 @@ where
         }
     }
 
+    /// Restores the initial state for a new document, starting with `encoding`.
+    pub(crate) fn reset(&mut self, encoding: AsciiCompatibleEncoding) {
+        self.delegate.transform_controller.reset();
+        self.delegate.capture_flags = self.initial_capture_flags();
+        self.delegate.remaining_content_start = 0;
+        self.delegate.emission_enabled = true;
+        self.delegate.output_sink.set_encoding(encoding);
+        self.text_decoder.reset(encoding);
+        self.last_text_type = TextType::Data;
+        self.is_in_html_style = false;
+        self.encoding = encoding;
+        self.got_flags_from_hint = false;
+        self.pending_element_aux_info_req = None;
+        self.next_encoding.reset();
+    }
+
+    #[inline]
+    fn initial_capture_flags(&self) -> TokenCaptureFlags {
+        self.delegate.transform_controller.initial_capture_flags()
+    }
+
+    /// Returns the directive the parser starts a document with.
+    pub(crate) fn initial_parser_directive(&self) -> ParserDirective {
+        if !self.initial_capture_flags().is_empty() || self.delegate.output_sink.escapes_chars() {
+            ParserDirective::Lex
+        } else {
+            ParserDirective::WherePossibleScanForTagsOnly
+        }
+    }
+
+    /// Switches to `encoding` and prevents `<meta charset>` from changing it later on.
+    pub(crate) fn set_final_encoding(&mut self, encoding: AsciiCompatibleEncoding) {
+        self.next_encoding.set(encoding);
+
+        self.flush_encoding_change();
+    }
+
+    #[inline]
+    pub(crate) fn output_sink_mut(&mut self) -> &mut O {
+        &mut self.delegate.output_sink
+    }
+
+    #[inline]
+    pub(crate) fn into_output_sink(self) -> O {
+        self.delegate.output_sink
+    }
+
     fn flush_encoding_change(&mut self) {
-        if let Some(&next_encoding) = self.next_encoding.get()
+        if let Some(next_encoding) = self.next_encoding.get()
             && next_encoding != self.encoding
         {
             self.encoding = next_encoding;
@@ -288,9 +525,11 @@ where
         Ok(())
     }
 
+    /// NOTE: the tag scanner doesn't tell where text, comments and raw text occur, so the output
+    /// is lexed when the escape of the characters depends on it.
     #[inline]
-    const fn get_next_parser_directive(&self) -> ParserDirective {
-        if !self.delegate.capture_flags.is_empty() {
+    fn get_next_parser_directive(&self) -> ParserDirective {
+        if !self.delegate.capture_flags.is_empty() || self.delegate.output_sink.escapes_chars() {
             ParserDirective::Lex
         } else {
             ParserDirective::WherePossibleScanForTagsOnly
@@ -418,6 +657,10 @@ where
     /// raw. The alternative (skipping the flush) would lose bytes from the input chunk entirely,
     /// which the caller cannot recover from since they don't buffer their input.
     pub fn flush_for_bail_out(&mut self, input: &[u8]) {
+        if self.delegate.read_only {
+            return;
+        }
+
         let output = input
             .get(self.delegate.remaining_content_start..)
             .unwrap_or_default();
@@ -436,21 +679,33 @@ where
     ///
     /// [`flush_for_bail_out()`]: Self::flush_for_bail_out
     pub fn run_bail_out_handlers(&mut self, error: &RewritingError) {
-        let mut bail_out = BailOut::new(&mut self.delegate.output_sink, self.encoding.get());
+        let mut discard = |_: &[u8]| {};
+        let output_sink: &mut dyn OutputSink = if self.delegate.read_only {
+            &mut discard
+        } else {
+            &mut self.delegate.output_sink
+        };
+
+        let mut bail_out = BailOut::new(output_sink, self.encoding.get());
         self.delegate
             .transform_controller
             .handle_bail_out(error, &mut bail_out);
     }
 
-    pub fn finish(&mut self, input: &[u8]) -> Result<(), RewritingError> {
-        self.delegate.finish(self.encoding.get(), input)
+    pub fn finish(
+        &mut self,
+        input: &[u8],
+        document_byte_count: usize,
+    ) -> Result<(), RewritingError> {
+        self.delegate
+            .finish(self.encoding.get(), input, document_byte_count)
     }
 }
 
 impl<C, O> LexemeSink for Dispatcher<C, O>
 where
     C: TransformController,
-    O: OutputSink,
+    O: EscapingOutputSink,
 {
     fn handle_tag(&mut self, lexeme: &TagLexeme<'_>) -> ActionResult<ParserDirective> {
         // NOTE: flush pending text before reporting tag to the transform controller.
@@ -460,17 +715,33 @@ where
         // handlers will be disabled before the receive the finalizing chunk.
         self.flush_pending_captured_text()?;
 
+        // NOTE: the end tag is ASCII, so the content appended to the element keeps the escape
+        // of the element's content.
+        if self.delegate.output_sink.escapes_chars() {
+            if let TagTokenOutline::StartTag { name_hash, ns, .. } = *lexeme.token_outline() {
+                self.is_in_html_style = ns == Namespace::Html && name_hash == Tag::Style;
+                self.delegate.set_char_escape(lexeme, CharEscape::Reference);
+            }
+        }
+
         if self.got_flags_from_hint {
             self.got_flags_from_hint = false;
         } else {
             self.adjust_capture_flags_for_tag_lexeme(lexeme)?;
         }
 
-        if let TagTokenOutline::EndTag { .. } = lexeme.token_outline() {
-            if self.delegate.should_stop_removing_element_content() {
-                self.delegate.emission_enabled = true;
-                self.delegate.remaining_content_start = lexeme.raw_range().start;
-            }
+        // NOTE: element content removal stops at its end tag, which may be implied by a start tag.
+        if self.delegate.should_stop_removing_element_content() {
+            self.delegate.emission_enabled = true;
+            self.delegate.remaining_content_start = lexeme.raw_range().start;
+        }
+
+        if self.delegate.transform_controller.has_implied_end_tags() {
+            self.delegate.emit_chunk_before_lexeme(lexeme);
+            self.delegate.implied_end_tags_produced(
+                lexeme.spanned().source_location().bytes().start,
+                self.encoding.get(),
+            )?;
         }
 
         self.try_produce_token_from_lexeme(lexeme)?;
@@ -479,6 +750,21 @@ where
         Ok(self.get_next_parser_directive())
     }
 
+    fn handle_open_elements_change(
+        &mut self,
+        closed_elements: &mut dyn Iterator<Item = (u64, ElementCloseReason)>,
+        inserted_element_id: Option<u64>,
+    ) -> ActionResult {
+        // NOTE: the text inside of the closed elements is passed to their text handlers first.
+        self.flush_pending_captured_text()?;
+
+        self.delegate
+            .transform_controller
+            .handle_open_elements_change(closed_elements, inserted_element_id);
+
+        Ok(())
+    }
+
     #[inline]
     fn handle_non_tag_content(&mut self, lexeme: &NonTagContentLexeme<'_>) -> ActionResult {
         match lexeme.token_outline() {
@@ -486,6 +772,17 @@ where
             // when it's None, it still needs a flush for CDATA
             _ => self.flush_pending_captured_text()?,
         }
+
+        if self.delegate.output_sink.escapes_chars() {
+            let escape = match lexeme.token_outline() {
+                Some(NonTagContentTokenOutline::Text(text_type)) => {
+                    CharEscape::for_text(*text_type, self.is_in_html_style)
+                }
+                _ => CharEscape::Replacement,
+            };
+
+            self.delegate.set_char_escape(lexeme, escape);
+        }
         self.try_produce_token_from_lexeme(lexeme)
     }
 }
@@ -493,7 +790,7 @@ where
 impl<C, O> TagHintSink for Dispatcher<C, O>
 where
     C: TransformController,
-    O: OutputSink,
+    O: EscapingOutputSink,
 {
     fn handle_start_tag_hint(
         &mut self,
@@ -505,7 +802,13 @@ where
             .transform_controller
             .handle_start_tag(name, ns)
         {
-            Ok(flags) => {
+            Ok(mut flags) => {
+                // NOTE: the start tag may imply the end tag of an element whose content
+                // is being removed, so we need its lexeme to know where to resume output.
+                if self.delegate.should_stop_removing_element_content() {
+                    flags |= TokenCaptureFlags::NEXT_START_TAG;
+                }
+
                 Ok(self.apply_capture_flags_from_hint_and_get_next_parser_directive(flags))
             }
             Err(DispatcherError::InfoRequest(aux_info_req)) => {
@@ -541,6 +844,6 @@ where
 impl<C, O> ParserOutputSink for Dispatcher<C, O>
 where
     C: TransformController,
-    O: OutputSink,
+    O: EscapingOutputSink,
 {
 }
@@ -0,0 +1,388 @@
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252, X_USER_DEFINED};
+
+/// The number of bytes the [prescan] looks at.
+///
+/// [prescan]: https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+pub(crate) const PRESCAN_BYTE_COUNT: usize = 1024;
+
+/// How sure the rewriter is about the encoding it has detected.
+///
+/// See the [HTML spec] for details.
+///
+/// [HTML spec]: https://html.spec.whatwg.org/multipage/parsing.html#concept-encoding-confidence
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EncodingConfidence {
+    /// The encoding is determined by a byte order mark.
+    Certain,
+    /// The encoding is determined by a `<meta>` tag at the start of the document or is the
+    /// fallback encoding.
+    Tentative,
+}
+
+/// The encoding of the input detected with [encoding sniffing].
+///
+/// [encoding sniffing]: ../struct.Settings.html#method.with_encoding_sniffing
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DetectedEncoding {
+    encoding: &'static Encoding,
+    confidence: EncodingConfidence,
+}
+
+impl DetectedEncoding {
+    #[inline]
+    #[must_use]
+    pub(crate) const fn new(encoding: &'static Encoding, confidence: EncodingConfidence) -> Self {
+        Self {
+            encoding,
+            confidence,
+        }
+    }
+
+    /// Returns the detected encoding of the input.
+    ///
+    /// Can be a non-ASCII-compatible encoding (`UTF-16LE`, `UTF-16BE` or `ISO-2022-JP`), in which
+    /// case the input is [transcoded].
+    ///
+    /// [transcoded]: ../struct.Settings.html#method.with_transcoded_encoding
+    #[inline]
+    #[must_use]
+    pub const fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    /// Returns the confidence of the detection.
+    #[inline]
+    #[must_use]
+    pub const fn confidence(&self) -> EncodingConfidence {
+        self.confidence
+    }
+}
+
+/// Detects the encoding from a [byte order mark] or a `<meta>` tag at the start of the input.
+///
+/// Returns `None` if the encoding should fall back to the default one.
+///
+/// [byte order mark]: https://encoding.spec.whatwg.org/#bom-sniff
+pub(crate) fn sniff_encoding(input: &[u8]) -> Option<DetectedEncoding> {
+    let encoding = match input {
+        [0xEF, 0xBB, 0xBF, ..] => Some(UTF_8),
+        [0xFE, 0xFF, ..] => Some(UTF_16BE),
+        [0xFF, 0xFE, ..] => Some(UTF_16LE),
+        _ => None,
+    };
+
+    if let Some(encoding) = encoding {
+        return Some(DetectedEncoding::new(encoding, EncodingConfidence::Certain));
+    }
+
+    let input = input.get(..PRESCAN_BYTE_COUNT).unwrap_or(input);
+
+    prescan(input).map(|encoding| DetectedEncoding::new(encoding, EncodingConfidence::Tentative))
+}
+
+#[inline]
+const fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+#[inline]
+fn find(input: &[u8], pos: usize, pred: impl Fn(u8) -> bool) -> Option<usize> {
+    input
+        .get(pos..)?
+        .iter()
+        .position(|&b| pred(b))
+        .map(|i| pos + i)
+}
+
+/// [Prescans] the input for a `<meta>` tag that declares the encoding.
+///
+/// [Prescans]: https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+fn prescan(input: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let rest = &input[pos..];
+
+        if rest.starts_with(b"<!--") {
+            // NOTE: the dashes of `<!--` can also be the dashes of `-->`.
+            let end = input.get(pos + 2..)?.windows(3).position(|w| w == b"-->")?;
+
+            pos += 2 + end + 3;
+        } else if rest.len() > 5
+            && rest[..5].eq_ignore_ascii_case(b"<meta")
+            && (is_whitespace(rest[5]) || rest[5] == b'/')
+        {
+            pos += 6;
+
+            let (encoding, next_pos) = prescan_meta(input, pos)?;
+
+            if let Some(encoding) = encoding {
+                return Some(encoding);
+            }
+
+            pos = next_pos;
+        } else if (rest.len() > 1 && rest[0] == b'<' && rest[1].is_ascii_alphabetic())
+            || (rest.len() > 2 && rest.starts_with(b"</") && rest[2].is_ascii_alphabetic())
+        {
+            pos = find(input, pos, |b| is_whitespace(b) || b == b'>')?;
+
+            while let Some((_, next_pos)) = get_attribute(input, pos)? {
+                pos = next_pos;
+            }
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            pos = find(input, pos, |b| b == b'>')? + 1;
+        } else {
+            pos += 1;
+        }
+    }
+
+    None
+}
+
+/// Processes the attributes of a `<meta>` tag. Returns `None` if the input ends before
+/// the end of the tag, otherwise returns the declared encoding, if any, and the position
+/// after the tag.
+fn prescan_meta(input: &[u8], mut pos: usize) -> Option<(Option<&'static Encoding>, usize)> {
+    let mut attribute_list: Vec<Vec<u8>> = Vec::new();
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+
+    while let Some(((name, value), next_pos)) = get_attribute(input, pos)? {
+        pos = next_pos;
+
+        if attribute_list.contains(&name) {
+            continue;
+        }
+
+        match name.as_slice() {
+            b"http-equiv" => got_pragma |= value == b"content-type",
+            b"content" if charset.is_none() => {
+                if let Some(encoding) = extract_encoding_from_content(&value) {
+                    charset = Some(Some(encoding));
+                    need_pragma = Some(true);
+                }
+            }
+            b"charset" => {
+                charset = Some(Encoding::for_label_no_replacement(&value));
+                need_pragma = Some(false);
+            }
+            _ => (),
+        }
+
+        attribute_list.push(name);
+    }
+
+    let encoding = match (need_pragma, charset) {
+        (Some(true), _) if !got_pragma => None,
+        (Some(_), Some(Some(encoding))) => Some(encoding),
+        _ => None,
+    };
+
+    let encoding = encoding.map(|encoding| {
+        if encoding == UTF_16BE || encoding == UTF_16LE {
+            UTF_8
+        } else if encoding == X_USER_DEFINED {
+            WINDOWS_1252
+        } else {
+            encoding
+        }
+    });
+
+    Some((encoding, pos))
+}
+
+type AttributeWithPos = ((Vec<u8>, Vec<u8>), usize);
+
+/// Implements the prescan's [get an attribute] algorithm. Returns `None` if the input ends
+/// before the end of the attribute, `Some(None)` if there are no more attributes in the tag.
+///
+/// [get an attribute]: https://html.spec.whatwg.org/multipage/parsing.html#concept-get-attributes-when-sniffing
+fn get_attribute(input: &[u8], pos: usize) -> Option<Option<AttributeWithPos>> {
+    let mut pos = find(input, pos, |b| !is_whitespace(b) && b != b'/')?;
+
+    if input[pos] == b'>' {
+        return Some(None);
+    }
+
+    let mut name = Vec::new();
+    let mut value = Vec::new();
+
+    loop {
+        let b = *input.get(pos)?;
+
+        match b {
+            b'=' if !name.is_empty() => {
+                pos += 1;
+                break;
+            }
+            _ if is_whitespace(b) => {
+                pos = find(input, pos, |b| !is_whitespace(b))?;
+
+                if input[pos] != b'=' {
+                    return Some(Some(((name, value), pos)));
+                }
+
+                pos += 1;
+                break;
+            }
+            b'/' | b'>' => return Some(Some(((name, value), pos))),
+            _ => name.push(b.to_ascii_lowercase()),
+        }
+
+        pos += 1;
+    }
+
+    pos = find(input, pos, |b| !is_whitespace(b))?;
+
+    match input[pos] {
+        quote @ (b'"' | b'\'') => {
+            let end = find(input, pos + 1, |b| b == quote)?;
+
+            value.extend(input[pos + 1..end].iter().map(u8::to_ascii_lowercase));
+            pos = end + 1;
+        }
+        b'>' => return Some(Some(((name, value), pos))),
+        _ => loop {
+            let b = *input.get(pos)?;
+
+            if is_whitespace(b) || b == b'>' {
+                break;
+            }
+
+            value.push(b.to_ascii_lowercase());
+            pos += 1;
+        },
+    }
+
+    Some(Some(((name, value), pos)))
+}
+
+/// Implements the [algorithm for extracting a character encoding from a `meta` element].
+///
+/// [algorithm for extracting a character encoding from a `meta` element]: https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn extract_encoding_from_content(content: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+
+    loop {
+        pos += content
+            .get(pos..)?
+            .windows(7)
+            .position(|w| w == b"charset")?
+            + 7;
+        pos = find(content, pos, |b| !is_whitespace(b))?;
+
+        if content[pos] == b'=' {
+            break;
+        }
+    }
+
+    pos = find(content, pos + 1, |b| !is_whitespace(b))?;
+
+    let value = match content[pos] {
+        quote @ (b'"' | b'\'') => {
+            let end = find(content, pos + 1, |b| b == quote)?;
+
+            &content[pos + 1..end]
+        }
+        _ => {
+            let end =
+                find(content, pos, |b| is_whitespace(b) || b == b';').unwrap_or(content.len());
+
+            &content[pos..end]
+        }
+    };
+
+    Encoding::for_label_no_replacement(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{ISO_2022_JP, WINDOWS_1251};
+
+    fn sniff(input: &str) -> Option<(&'static str, EncodingConfidence)> {
+        sniff_encoding(input.as_bytes()).map(|d| (d.encoding().name(), d.confidence()))
+    }
+
+    #[test]
+    fn bom() {
+        use EncodingConfidence::Certain;
+
+        for (bom, encoding) in [
+            (&b"\xEF\xBB\xBF"[..], UTF_8),
+            (b"\xFE\xFF", UTF_16BE),
+            (b"\xFF\xFE", UTF_16LE),
+        ] {
+            let mut input = bom.to_vec();
+
+            input.extend_from_slice(b"<meta charset=windows-1251>");
+
+            assert_eq!(
+                sniff_encoding(&input),
+                Some(DetectedEncoding::new(encoding, Certain))
+            );
+        }
+    }
+
+    #[test]
+    fn meta_prescan() {
+        use EncodingConfidence::Tentative;
+
+        let expected = Some((WINDOWS_1251.name(), Tentative));
+
+        assert_eq!(sniff("<meta charset=windows-1251>"), expected);
+        assert_eq!(sniff("<META CHARSET='Windows-1251'>"), expected);
+        assert_eq!(sniff(r#"<meta/charset = "cp1251"/>"#), expected);
+        assert_eq!(
+            sniff(r#"<meta http-equiv="Content-Type" content="text/html; charset=windows-1251">"#),
+            expected
+        );
+        assert_eq!(
+            sniff(r#"<meta content='text/html;charset = "windows-1251"' http-equiv=content-type>"#),
+            expected
+        );
+        assert_eq!(
+            sniff(concat!(
+                "<!doctype html><html lang=en><!-- <meta charset=utf-8> -->",
+                r#"<head title="<meta charset=utf-8>"><meta name=x><meta charset=windows-1251>"#
+            )),
+            expected
+        );
+
+        assert_eq!(
+            sniff("<meta charset=iso-2022-jp>"),
+            Some((ISO_2022_JP.name(), Tentative))
+        );
+        assert_eq!(
+            sniff("<meta charset=utf-16le>"),
+            Some((UTF_8.name(), Tentative))
+        );
+        assert_eq!(
+            sniff("<meta charset=x-user-defined>"),
+            Some((WINDOWS_1252.name(), Tentative))
+        );
+    }
+
+    #[test]
+    fn meta_prescan_no_match() {
+        for input in [
+            "",
+            "<p>no meta</p>",
+            "<meta charset=unknown-label>",
+            r#"<meta content="text/html; charset=windows-1251">"#,
+            "<!-- <meta charset=windows-1251> -->",
+            "<metacharset=windows-1251>",
+            "<meta charset=windows-1251",
+            "<script><meta charset=windows-1251",
+        ] {
+            assert_eq!(sniff(input), None, "{input}");
+        }
+
+        // NOTE: only the first 1024 bytes are prescanned.
+        let input = format!("{}<meta charset=windows-1251>", " ".repeat(1024));
+
+        assert_eq!(sniff(&input), None);
+    }
+}
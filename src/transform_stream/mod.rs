@@ -1,14 +1,23 @@
+mod char_escape;
+mod chunker;
 mod dispatcher;
+mod encoding_sniffer;
+mod transcoder;
 
+use self::chunker::ChunkingOutputSink;
 use self::dispatcher::Dispatcher;
 pub use self::dispatcher::OutputSink;
 pub(crate) use self::dispatcher::{AuxStartTagInfo, DispatcherError};
 pub use self::dispatcher::{StartTagHandlingResult, TransformController};
+pub use self::encoding_sniffer::{DetectedEncoding, EncodingConfidence};
+use self::encoding_sniffer::{PRESCAN_BYTE_COUNT, sniff_encoding};
+use self::transcoder::{InputDecoder, TranscodingOutputSink};
 use crate::AsciiCompatibleEncoding;
 use crate::base::SharedEncoding;
 use crate::memory::{Arena, SharedMemoryLimiter};
-use crate::parser::{Parser, ParserDirective};
+use crate::parser::{Parser, TagLimits};
 use crate::rewriter::RewritingError;
+use encoding_rs::Encoding;
 
 // Pub only for integration tests
 pub struct TransformStreamSettings<C, O>
@@ -21,8 +30,23 @@ where
     pub preallocated_parsing_buffer_size: usize,
     pub memory_limiter: SharedMemoryLimiter,
     pub encoding: AsciiCompatibleEncoding,
+    /// If set, the input in this encoding is decoded into UTF-8 before parsing, and the output
+    /// is encoded back into it. `encoding` must be UTF-8 in this case.
+    pub transcoded_encoding: Option<&'static Encoding>,
+    /// If set, the output is encoded into this encoding regardless of the input's encoding.
+    pub output_encoding: Option<&'static Encoding>,
+    /// If set, `encoding` is only a fallback for the encoding detected from the start of the input.
+    pub encoding_sniffing: bool,
+    /// If set, the output sink is never called and content modifications are errors.
+    pub read_only: bool,
+    /// Output chunks smaller than this are coalesced until `end()` or `flush()`.
+    pub min_output_chunk_size: usize,
+    pub max_output_chunk_size: usize,
     pub next_encoding: SharedEncoding,
     pub strict: bool,
+    pub full_tree_construction: bool,
+    pub max_attributes_per_tag: usize,
+    pub max_start_tag_size: usize,
     pub graceful_bail_out_on_memory_limit_exceeded: bool,
     pub graceful_bail_out_on_content_handler_error: bool,
 }
@@ -33,7 +57,16 @@ where
     C: TransformController,
     O: OutputSink,
 {
-    parser: Parser<Dispatcher<C, O>>,
+    parser: Parser<Dispatcher<C, TranscodingOutputSink<ChunkingOutputSink<O>>>>,
+    input_decoder: Option<InputDecoder>,
+    transcoded_encoding: Option<&'static Encoding>,
+    output_encoding: Option<&'static Encoding>,
+    /// The start of the input buffered until its encoding is detected. Set if encoding sniffing
+    /// is enabled.
+    sniffing_buffer: Option<Arena>,
+    is_sniffing: bool,
+    fallback_encoding: AsciiCompatibleEncoding,
+    detected_encoding: Option<DetectedEncoding>,
     buffer: Arena,
     has_buffered_data: bool,
     graceful_bail_out_on_memory_limit_exceeded: bool,
@@ -46,32 +79,62 @@ where
     O: OutputSink,
 {
     pub fn new(settings: TransformStreamSettings<C, O>) -> Self {
-        let initial_parser_directive = if settings
-            .transform_controller
-            .initial_capture_flags()
-            .is_empty()
-        {
-            ParserDirective::WherePossibleScanForTagsOnly
-        } else {
-            ParserDirective::Lex
-        };
+        debug_assert!(
+            settings.transcoded_encoding.is_none()
+                || settings.encoding == AsciiCompatibleEncoding::utf_8()
+        );
+
+        let encoding_sniffing =
+            settings.encoding_sniffing && settings.transcoded_encoding.is_none();
 
         let dispatcher = Dispatcher::new(
             settings.transform_controller,
-            settings.output_sink,
+            TranscodingOutputSink::new(
+                ChunkingOutputSink::new(
+                    settings.output_sink,
+                    settings.min_output_chunk_size,
+                    settings.max_output_chunk_size,
+                    settings.memory_limiter.clone(),
+                ),
+                settings.output_encoding,
+                settings.transcoded_encoding,
+            ),
             settings.encoding,
             settings.next_encoding,
+            settings.read_only,
         );
 
         let buffer = Arena::new(
-            settings.memory_limiter,
+            settings.memory_limiter.clone(),
             settings.preallocated_parsing_buffer_size,
         );
 
-        let parser = Parser::new(dispatcher, initial_parser_directive, settings.strict);
+        let sniffing_buffer =
+            encoding_sniffing.then(|| Arena::new(settings.memory_limiter.clone(), 0));
+
+        let initial_parser_directive = dispatcher.initial_parser_directive();
+
+        let parser = Parser::new(
+            dispatcher,
+            initial_parser_directive,
+            settings.strict,
+            settings.full_tree_construction,
+            settings.memory_limiter,
+            TagLimits {
+                max_attributes_per_tag: settings.max_attributes_per_tag,
+                max_start_tag_size: settings.max_start_tag_size,
+            },
+        );
 
         Self {
             parser,
+            input_decoder: settings.transcoded_encoding.map(InputDecoder::new),
+            transcoded_encoding: settings.transcoded_encoding,
+            output_encoding: settings.output_encoding,
+            sniffing_buffer,
+            is_sniffing: encoding_sniffing,
+            fallback_encoding: settings.encoding,
+            detected_encoding: None,
             buffer,
             has_buffered_data: false,
             graceful_bail_out_on_memory_limit_exceeded: settings
@@ -86,19 +149,212 @@ where
     /// never recovered from (the whole point of strict mode is to refuse uncertain markup).
     fn should_bail_out_for(&self, err: &RewritingError) -> bool {
         match err {
-            RewritingError::MemoryLimitExceeded(_) => {
+            RewritingError::MemoryLimitExceeded(_)
+            | RewritingError::MaxNestingDepthExceeded(_)
+            | RewritingError::TagLimitExceeded(_) => {
                 self.graceful_bail_out_on_memory_limit_exceeded
             }
             RewritingError::ContentHandlerError(_) => {
                 self.graceful_bail_out_on_content_handler_error
             }
-            RewritingError::ParsingAmbiguity(_) => false,
+            RewritingError::ParsingAmbiguity(_)
+            | RewritingError::ModifiedInReadOnlyMode
+            | RewritingError::EndHandlersNotReusable => false,
         }
     }
 
     pub fn write(&mut self, data: &[u8]) -> Result<(), RewritingError> {
         trace!(@write data);
 
+        // NOTE: on an error the sink must have all the output produced so far, so the caller
+        // can continue the response after a graceful bail-out.
+        self.write_sniffed(data)
+            .inspect_err(|_| self.flush_output_chunks())
+    }
+
+    /// Passes everything that can be output without more input to the output sink.
+    ///
+    /// Incomplete characters and lexemes (e.g. a tag split between chunks) are still held back,
+    /// since they can't be decoded or handled until the rest of them is written.
+    pub fn flush(&mut self) -> Result<(), RewritingError> {
+        // NOTE: the encoding is detected from the input written so far, like browsers do it
+        // if the input stalls before the prescan is complete.
+        let res = if self.is_sniffing
+            && self
+                .sniffing_buffer
+                .as_ref()
+                .is_some_and(|b| !b.bytes().is_empty())
+        {
+            self.finish_sniffing()
+        } else {
+            Ok(())
+        };
+
+        self.flush_output_chunks();
+
+        res
+    }
+
+    fn finish_sniffing(&mut self) -> Result<(), RewritingError> {
+        if !self.is_sniffing {
+            return Ok(());
+        }
+
+        self.is_sniffing = false;
+
+        match self.sniffing_buffer.take() {
+            Some(mut sniffing_buffer) => {
+                self.apply_detected_encoding(sniff_encoding(sniffing_buffer.bytes()));
+
+                let res = self.write_transcoded(sniffing_buffer.bytes());
+
+                sniffing_buffer.clear();
+                self.sniffing_buffer = Some(sniffing_buffer);
+
+                res
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn flush_output_chunks(&mut self) {
+        self.parser
+            .get_dispatcher()
+            .output_sink_mut()
+            .inner_mut()
+            .flush();
+    }
+
+    fn write_sniffed(&mut self, data: &[u8]) -> Result<(), RewritingError> {
+        if !self.is_sniffing {
+            return self.write_transcoded(data);
+        }
+
+        let Some(mut sniffing_buffer) = self.sniffing_buffer.take() else {
+            return self.write_transcoded(data);
+        };
+
+        let buffered_len = sniffing_buffer.bytes().len();
+
+        if buffered_len == 0 && data.len() >= PRESCAN_BYTE_COUNT {
+            self.sniffing_buffer = Some(sniffing_buffer);
+            self.is_sniffing = false;
+            self.apply_detected_encoding(sniff_encoding(data));
+
+            return self.write_transcoded(data);
+        }
+
+        // NOTE: only the bytes needed for the prescan are buffered, the rest of `data` is
+        // written as is once the encoding is detected.
+        let (prescanned, rest) = data.split_at(data.len().min(PRESCAN_BYTE_COUNT - buffered_len));
+
+        if let Err(e) = sniffing_buffer.append(prescanned) {
+            // NOTE: nothing has been parsed yet, so on a graceful bail-out the buffered bytes
+            // and `data` are flushed as is.
+            let err = RewritingError::MemoryLimitExceeded(e);
+
+            if self.should_bail_out_for(&err) {
+                let dispatcher = self.parser.get_dispatcher();
+                dispatcher.run_bail_out_handlers(&err);
+                dispatcher.flush_for_bail_out(sniffing_buffer.bytes());
+                dispatcher.flush_for_bail_out(data);
+            }
+
+            self.sniffing_buffer = Some(sniffing_buffer);
+
+            return Err(err);
+        }
+
+        let is_buffer_full = sniffing_buffer.bytes().len() == PRESCAN_BYTE_COUNT;
+
+        self.sniffing_buffer = Some(sniffing_buffer);
+
+        if !is_buffer_full {
+            return Ok(());
+        }
+
+        if let Err(err) = self.finish_sniffing() {
+            if self.should_bail_out_for(&err) {
+                self.flush_transcoded_for_bail_out(rest);
+            }
+
+            return Err(err);
+        }
+
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            self.write_transcoded(rest)
+        }
+    }
+
+    /// Flushes `data` that hasn't been parsed as is on a graceful bail-out, decoding it first
+    /// if the input is transcoded.
+    fn flush_transcoded_for_bail_out(&mut self, data: &[u8]) {
+        let data = match &mut self.input_decoder {
+            Some(input_decoder) => input_decoder.decode(data, false),
+            None => data,
+        };
+
+        self.parser.get_dispatcher().flush_for_bail_out(data);
+    }
+
+    /// Returns the encoding detected from the start of the input, if encoding sniffing is enabled
+    /// and enough input has been written to detect it.
+    #[inline]
+    pub fn detected_encoding(&self) -> Option<DetectedEncoding> {
+        self.detected_encoding
+    }
+
+    /// Switches to the detected encoding before any input is parsed. Unless the encoding is the
+    /// fallback one, it's final and can't be changed by a `<meta>` tag later on.
+    fn apply_detected_encoding(&mut self, detected: Option<DetectedEncoding>) {
+        let Some(detected) = detected else {
+            self.detected_encoding = Some(DetectedEncoding::new(
+                self.fallback_encoding.into(),
+                EncodingConfidence::Tentative,
+            ));
+
+            return;
+        };
+
+        self.detected_encoding = Some(detected);
+
+        let dispatcher = self.parser.get_dispatcher();
+
+        match AsciiCompatibleEncoding::new(detected.encoding()) {
+            Some(encoding) => dispatcher.set_final_encoding(encoding),
+            None => {
+                dispatcher
+                    .output_sink_mut()
+                    .set_transcoded_encoding(detected.encoding());
+                dispatcher.set_final_encoding(AsciiCompatibleEncoding::utf_8());
+
+                // NOTE: nothing has been parsed yet, so the parser can start over with the lexer
+                // if the output encoding needs the escapes of the characters (e.g. ISO-2022-JP).
+                let initial_parser_directive = dispatcher.initial_parser_directive();
+
+                self.parser.reset(initial_parser_directive);
+                self.input_decoder = Some(InputDecoder::new(detected.encoding()));
+            }
+        }
+    }
+
+    fn write_transcoded(&mut self, data: &[u8]) -> Result<(), RewritingError> {
+        match self.input_decoder.take() {
+            Some(mut input_decoder) => {
+                let res = self.write_decoded(input_decoder.decode(data, false));
+
+                self.input_decoder = Some(input_decoder);
+
+                res
+            }
+            None => self.write_decoded(data),
+        }
+    }
+
+    fn write_decoded(&mut self, data: &[u8]) -> Result<(), RewritingError> {
         let chunk = if self.has_buffered_data {
             match self.buffer.append(data) {
                 Ok(()) => self.buffer.bytes(),
@@ -180,6 +436,23 @@ where
     pub fn end(&mut self) -> Result<(), RewritingError> {
         trace!(@end);
 
+        self.end_sniffed()
+            .inspect_err(|_| self.flush_output_chunks())
+    }
+
+    fn end_sniffed(&mut self) -> Result<(), RewritingError> {
+        self.finish_sniffing()?;
+
+        // NOTE: the decoder may hold an incomplete character at the end of the input,
+        // which gets flushed as a replacement character.
+        if let Some(mut input_decoder) = self.input_decoder.take() {
+            let tail = input_decoder.decode(&[], true);
+
+            if !tail.is_empty() {
+                self.write_decoded(tail)?;
+            }
+        }
+
         let chunk = if self.has_buffered_data {
             self.buffer.bytes()
         } else {
@@ -204,12 +477,67 @@ where
         // so a `ContentHandlerError` from the end handler arrives after the sink already has
         // every input byte. No additional flush needed; the caller continues from where the
         // rewriter left off.
-        self.parser.get_dispatcher().finish(chunk)
+        let document_byte_count = self.parser.consumed_byte_count();
+
+        self.parser
+            .get_dispatcher()
+            .finish(chunk, document_byte_count)
+    }
+
+    /// Restores the initial state for a new document that is written into `output_sink`, keeping
+    /// the allocated buffers. Returns the previous output sink.
+    pub(crate) fn reset(&mut self, output_sink: O) -> O {
+        let initial_encoding = self.fallback_encoding;
+        let dispatcher = self.parser.get_dispatcher();
+        let transcoding_sink = dispatcher.output_sink_mut();
+        let output_sink = transcoding_sink.inner_mut().replace_inner(output_sink);
+
+        transcoding_sink.reset(self.output_encoding, self.transcoded_encoding);
+        dispatcher.reset(initial_encoding);
+
+        let initial_parser_directive = dispatcher.initial_parser_directive();
+
+        self.parser.reset(initial_parser_directive);
+        self.input_decoder = self.transcoded_encoding.map(InputDecoder::new);
+        self.reset_sniffing_buffer();
+        self.detected_encoding = None;
+        self.buffer.clear();
+        self.has_buffered_data = false;
+
+        output_sink
+    }
+
+    /// Restores the sniffing buffer for a new document, keeping its allocation.
+    fn reset_sniffing_buffer(&mut self) {
+        if let Some(sniffing_buffer) = &mut self.sniffing_buffer {
+            sniffing_buffer.clear();
+            self.is_sniffing = true;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn output_sink_mut(&mut self) -> &mut O {
+        self.parser
+            .get_dispatcher()
+            .output_sink_mut()
+            .inner_mut()
+            .inner_mut()
+    }
+
+    #[inline]
+    pub(crate) fn into_output_sink(self) -> O {
+        self.parser
+            .into_dispatcher()
+            .into_output_sink()
+            .into_inner()
+            .into_inner()
     }
 
     #[cfg(feature = "_integration_test")]
     #[allow(private_interfaces)]
-    pub fn parser(&mut self) -> &mut Parser<Dispatcher<C, O>> {
+    pub fn parser(
+        &mut self,
+    ) -> &mut Parser<Dispatcher<C, TranscodingOutputSink<ChunkingOutputSink<O>>>> {
         &mut self.parser
     }
 }
@@ -0,0 +1,406 @@
+use super::OutputSink;
+use super::char_escape::{CharEscape, EscapingOutputSink};
+use crate::AsciiCompatibleEncoding;
+use encoding_rs::{Decoder, Encoder, EncoderResult, Encoding, GB18030, UTF_8, UTF_16BE, UTF_16LE};
+
+/// Decodes input in an arbitrary encoding into UTF-8 that is fed to the parser.
+pub(crate) struct InputDecoder {
+    decoder: Decoder,
+    output: String,
+}
+
+impl InputDecoder {
+    #[must_use]
+    pub fn new(encoding: &'static Encoding) -> Self {
+        Self {
+            // NOTE: a BOM is kept as a part of the content, so it gets re-encoded into
+            // the output the same way it would have been passed through for UTF-8 input.
+            decoder: encoding.new_decoder_without_bom_handling(),
+            output: String::new(),
+        }
+    }
+
+    pub fn decode(&mut self, input: &[u8], last: bool) -> &[u8] {
+        self.output.clear();
+        self.output.reserve(
+            self.decoder
+                .max_utf8_buffer_length(input.len())
+                .unwrap_or(usize::MAX),
+        );
+
+        let _ = self.decoder.decode_to_string(input, &mut self.output, last);
+
+        self.output.as_bytes()
+    }
+}
+
+/// The encoding the output is converted into from the UTF-8 produced by the rewriter.
+enum TargetEncoding {
+    Utf16 {
+        big_endian: bool,
+        /// Set until the first character is output.
+        needs_bom: bool,
+    },
+    Other {
+        encoder: Encoder,
+        /// The escape of the characters in the current output. Set if the encoding can't
+        /// represent all characters.
+        escape: Option<CharEscape>,
+    },
+}
+
+struct OutputEncoder {
+    source: &'static Encoding,
+    decoder: Decoder,
+    target_encoding: &'static Encoding,
+    target: TargetEncoding,
+    utf8: String,
+    output: Vec<u8>,
+}
+
+impl OutputEncoder {
+    /// Creates the encoder into the `encoding`. If `is_converted` is set, the output is
+    /// converted from the encoding of the input, rather than transcoded back into it.
+    fn new(encoding: &'static Encoding, is_converted: bool) -> Self {
+        let target = if encoding == UTF_16LE || encoding == UTF_16BE {
+            TargetEncoding::Utf16 {
+                big_endian: encoding == UTF_16BE,
+                needs_bom: is_converted,
+            }
+        } else {
+            TargetEncoding::Other {
+                encoder: encoding.new_encoder(),
+                escape: (encoding != UTF_8 && encoding != GB18030).then_some(CharEscape::Reference),
+            }
+        };
+
+        Self {
+            source: UTF_8,
+            // NOTE: a character can be split between two output chunks.
+            decoder: UTF_8.new_decoder_without_bom_handling(),
+            target_encoding: encoding,
+            target,
+            utf8: String::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Sets the encoding of the rewriter's output that gets converted into the target encoding.
+    ///
+    /// Returns the output for the character that has been partially decoded with the previous
+    /// encoding, if any. It can't be completed in the new encoding, so it's replaced with
+    /// U+FFFD.
+    fn set_source(&mut self, encoding: &'static Encoding) -> &[u8] {
+        self.output.clear();
+
+        if encoding != self.source {
+            self.decode(&[], true);
+            self.encode_utf8(false);
+            self.source = encoding;
+            self.decoder = encoding.new_decoder_without_bom_handling();
+        }
+
+        &self.output
+    }
+
+    #[inline]
+    fn is_passthrough(&self) -> bool {
+        self.source == self.target_encoding
+    }
+
+    fn encode(&mut self, chunk: &[u8], last: bool) -> &[u8] {
+        self.output.clear();
+        self.decode(chunk, last);
+        self.encode_utf8(last);
+
+        &self.output
+    }
+
+    fn decode(&mut self, chunk: &[u8], last: bool) {
+        self.utf8.clear();
+        self.utf8.reserve(
+            self.decoder
+                .max_utf8_buffer_length(chunk.len())
+                .unwrap_or(usize::MAX),
+        );
+
+        let _ = self.decoder.decode_to_string(chunk, &mut self.utf8, last);
+    }
+
+    /// Appends the decoded UTF-8 converted into the target encoding to the output.
+    fn encode_utf8(&mut self, last: bool) {
+        match &mut self.target {
+            TargetEncoding::Utf16 {
+                big_endian,
+                needs_bom,
+            } => {
+                // NOTE: browsers ignore `<meta charset>` that declares UTF-16, so the converted
+                // output starts with a BOM, unless the content already has one. The transcoded
+                // output keeps the BOM of the input, if any.
+                if *needs_bom && !self.utf8.is_empty() {
+                    if !self.utf8.starts_with('\u{FEFF}') {
+                        self.utf8.insert(0, '\u{FEFF}');
+                    }
+
+                    *needs_bom = false;
+                }
+
+                for unit in self.utf8.encode_utf16() {
+                    self.output.extend_from_slice(&if *big_endian {
+                        unit.to_be_bytes()
+                    } else {
+                        unit.to_le_bytes()
+                    });
+                }
+            }
+            TargetEncoding::Other { encoder, escape } => {
+                let mut input = self.utf8.as_str();
+                let mut escaped = String::new();
+
+                // NOTE: characters that can't be represented in the target encoding are
+                // escaped in the way that is decoded where they occur.
+                loop {
+                    let required = encoder
+                        .max_buffer_length_from_utf8_without_replacement(input.len())
+                        .unwrap_or(usize::MAX);
+
+                    self.output.reserve(required);
+
+                    let (result, read) = encoder.encode_from_utf8_to_vec_without_replacement(
+                        input,
+                        &mut self.output,
+                        last,
+                    );
+
+                    input = &input[read..];
+
+                    match result {
+                        EncoderResult::InputEmpty => break,
+                        EncoderResult::OutputFull => (),
+                        EncoderResult::Unmappable(ch) => {
+                            escaped.clear();
+                            escape
+                                .unwrap_or(CharEscape::Reference)
+                                .write(ch, &mut escaped);
+
+                            // NOTE: the escape is encoded too, so that the encoder switches
+                            // to the state where ASCII can be written (e.g. for ISO-2022-JP).
+                            self.output.reserve(
+                                encoder
+                                    .max_buffer_length_from_utf8_without_replacement(escaped.len())
+                                    .unwrap_or(usize::MAX),
+                            );
+
+                            let (result, _) = encoder.encode_from_utf8_to_vec_without_replacement(
+                                &escaped,
+                                &mut self.output,
+                                false,
+                            );
+
+                            debug_assert_eq!(result, EncoderResult::InputEmpty);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wraps the output sink to convert the rewriter's output into the output encoding, if it
+/// differs from the encoding the rewriter operates on (e.g. if the input has been transcoded).
+// Pub only for integration tests
+pub struct TranscodingOutputSink<O> {
+    sink: O,
+    encoder: Option<Box<OutputEncoder>>,
+}
+
+impl<O: OutputSink> TranscodingOutputSink<O> {
+    #[inline]
+    #[must_use]
+    pub fn new(
+        mut sink: O,
+        output_encoding: Option<&'static Encoding>,
+        transcoded_encoding: Option<&'static Encoding>,
+    ) -> Self {
+        if let Some(encoding) = output_encoding.and_then(AsciiCompatibleEncoding::new) {
+            sink.set_encoding(encoding);
+        }
+
+        Self {
+            sink,
+            encoder: Self::new_encoder(output_encoding, transcoded_encoding),
+        }
+    }
+
+    fn new_encoder(
+        output_encoding: Option<&'static Encoding>,
+        transcoded_encoding: Option<&'static Encoding>,
+    ) -> Option<Box<OutputEncoder>> {
+        match (output_encoding, transcoded_encoding) {
+            (Some(encoding), _) => Some(Box::new(OutputEncoder::new(encoding, true))),
+            (None, Some(encoding)) => Some(Box::new(OutputEncoder::new(encoding, false))),
+            (None, None) => None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner_mut(&mut self) -> &mut O {
+        &mut self.sink
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> O {
+        self.sink
+    }
+
+    /// Restores the initial state for a new output, dropping the output encoder's state.
+    pub(crate) fn reset(
+        &mut self,
+        output_encoding: Option<&'static Encoding>,
+        transcoded_encoding: Option<&'static Encoding>,
+    ) {
+        if let Some(encoding) = output_encoding.and_then(AsciiCompatibleEncoding::new) {
+            self.sink.set_encoding(encoding);
+        }
+
+        self.encoder = Self::new_encoder(output_encoding, transcoded_encoding);
+    }
+
+    /// Starts encoding the output into `encoding`, unless the output encoding is already set.
+    /// Must be called before any output is produced.
+    #[inline]
+    pub fn set_transcoded_encoding(&mut self, encoding: &'static Encoding) {
+        if self.encoder.is_none() {
+            self.encoder = Self::new_encoder(None, Some(encoding));
+        }
+    }
+}
+
+impl<O: OutputSink> OutputSink for TranscodingOutputSink<O> {
+    #[inline]
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        let encoder = match &mut self.encoder {
+            Some(encoder) if !encoder.is_passthrough() => encoder,
+            _ => {
+                self.sink.handle_chunk(chunk);
+                return;
+            }
+        };
+
+        let output = encoder.encode(chunk, false);
+
+        if !output.is_empty() {
+            self.sink.handle_chunk(output);
+        }
+    }
+
+    #[inline]
+    fn handle_end(&mut self) {
+        // NOTE: flush the encoder's state (e.g. ISO-2022-JP needs to switch back to ASCII at
+        // the end of the output).
+        if let Some(encoder) = self.encoder.as_mut().filter(|e| !e.is_passthrough()) {
+            let output = encoder.encode(&[], true);
+
+            if !output.is_empty() {
+                self.sink.handle_chunk(output);
+            }
+        }
+
+        self.sink.handle_end();
+    }
+
+    #[inline]
+    fn set_encoding(&mut self, new_encoding: AsciiCompatibleEncoding) {
+        // NOTE: the output encoding is fixed, only the encoding it's converted from changes.
+        match &mut self.encoder {
+            Some(encoder) => {
+                let output = encoder.set_source(new_encoding.into());
+
+                if !output.is_empty() {
+                    self.sink.handle_chunk(output);
+                }
+            }
+            None => self.sink.set_encoding(new_encoding),
+        }
+    }
+}
+
+impl<O: OutputSink> EscapingOutputSink for TranscodingOutputSink<O> {
+    #[inline]
+    fn escapes_chars(&self) -> bool {
+        self.encoder.as_ref().is_some_and(|encoder| {
+            matches!(
+                encoder.target,
+                TargetEncoding::Other {
+                    escape: Some(_),
+                    ..
+                }
+            )
+        })
+    }
+
+    #[inline]
+    fn set_char_escape(&mut self, new_escape: CharEscape) {
+        if let Some(encoder) = &mut self.encoder
+            && let TargetEncoding::Other {
+                escape: Some(escape),
+                ..
+            } = &mut encoder.target
+        {
+            *escape = new_escape;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{ISO_2022_JP, WINDOWS_1251};
+
+    #[test]
+    fn source_encoding_change_flushes_partial_character() {
+        let mut output = Vec::new();
+
+        {
+            let mut sink = TranscodingOutputSink::new(
+                |chunk: &[u8]| output.extend_from_slice(chunk),
+                Some(UTF_16LE),
+                None,
+            );
+
+            sink.handle_chunk(b"a\xD0");
+            sink.set_encoding(AsciiCompatibleEncoding::new(WINDOWS_1251).unwrap());
+            sink.handle_chunk(b"\xC0");
+            sink.handle_end();
+        }
+
+        let expected: Vec<u8> = "\u{FEFF}a\u{FFFD}\u{410}"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn empty_chunks_dont_end_output() {
+        let mut output = Vec::new();
+
+        {
+            let mut sink = TranscodingOutputSink::new(
+                |chunk: &[u8]| output.extend_from_slice(chunk),
+                Some(ISO_2022_JP),
+                None,
+            );
+
+            sink.handle_chunk("日".as_bytes());
+            sink.handle_chunk(&[]);
+            sink.handle_chunk(&"本".as_bytes()[..1]);
+            sink.handle_chunk(&[]);
+            sink.handle_chunk(&"本".as_bytes()[1..]);
+            sink.handle_end();
+        }
+
+        assert_eq!(output, &*ISO_2022_JP.encode("日本").0);
+    }
+}
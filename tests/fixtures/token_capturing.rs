@@ -88,6 +88,7 @@ pub fn parse(
     capture_flags: TokenCaptureFlags,
     initial_text_type: TextType,
     last_start_tag_name_hash: LocalNameHash,
+    full_tree_construction: bool,
     token_handler: TokenHandler<'_>,
 ) -> Result<String, RewritingError> {
     let encoding = input
@@ -96,7 +97,13 @@ pub fn parse(
 
     let mut output = Output::new(encoding.into());
     let transform_controller = TestTransformController::new(token_handler, capture_flags);
-    let memory_limiter = SharedMemoryLimiter::new(2048);
+    // NOTE: the tree builder's element stacks are charged to the limiter as well,
+    // and some of the tests have thousands of nested elements.
+    let memory_limiter = SharedMemoryLimiter::new(if full_tree_construction {
+        1 << 20
+    } else {
+        2048
+    });
 
     let mut transform_stream = TransformStream::new(TransformStreamSettings {
         transform_controller,
@@ -104,8 +111,17 @@ pub fn parse(
         preallocated_parsing_buffer_size: 0,
         memory_limiter,
         encoding,
+        transcoded_encoding: None,
+        output_encoding: None,
+        encoding_sniffing: false,
+        read_only: false,
+        min_output_chunk_size: 0,
+        max_output_chunk_size: usize::MAX,
         next_encoding: Default::default(),
         strict: true,
+        full_tree_construction,
+        max_attributes_per_tag: usize::MAX,
+        max_start_tag_size: usize::MAX,
         graceful_bail_out_on_memory_limit_exceeded: false,
         graceful_bail_out_on_content_handler_error: false,
     });
@@ -170,6 +186,7 @@ impl TokenCapturingTests {
         test: &TestCase,
         initial_text_type: TextType,
         last_start_tag_name_hash: LocalNameHash,
+        full_tree_construction: bool,
     ) {
         [
             TokenCaptureFlags::all(),
@@ -191,6 +208,7 @@ impl TokenCapturingTests {
                 capture_flags,
                 initial_text_type,
                 last_start_tag_name_hash,
+                full_tree_construction,
                 Box::new(|t| token_list.push(t)),
             );
 
@@ -233,8 +251,9 @@ impl TokenCapturingTests {
                     );
                 }
                 Err(_) => {
+                    // NOTE: there are no ambiguities in the full tree construction mode.
                     expect!(
-                        test.expected_bailout.is_some(),
+                        test.expected_bailout.is_some() && !full_tree_construction,
                         initial_text_type,
                         test.input,
                         format!("Unexpected bailout (capture: {:#?})", capture_flags)
@@ -256,8 +275,15 @@ impl TestFixture<TestCase> for TokenCapturingTests {
                 test,
                 TextType::from(cs.as_str()),
                 test.last_start_tag.as_str().into(),
+                false,
             );
         }
+
+        // NOTE: expected tokens for the tree construction tests are produced by the
+        // full-featured parser, so we can use them to verify our tree builder as well.
+        if test.description.contains("(with feedback)") {
+            Self::run_test_case(test, TextType::Data, LocalNameHash::default(), true);
+        }
     }
 }
 